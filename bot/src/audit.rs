@@ -0,0 +1,192 @@
+//! A record of every processed gallery, for auditing who synced what.
+//!
+//! Records are written when a sync ends, each submission of a gallery has its own
+//! even if the sync is shared with other users.
+
+use std::{
+    fs::{File, OpenOptions},
+    future::Future,
+    io::Write,
+    path::Path,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use eh2telegraph::config;
+use serde::{Deserialize, Serialize};
+
+const CONFIG_KEY: &str = "audit";
+
+type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AuditConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// JSON lines file the records are appended to.
+    pub path: String,
+}
+
+/// Who submitted a gallery and where.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Submission {
+    pub user_id: Option<u64>,
+    pub chat_id: i64,
+    pub source: String,
+}
+
+/// Field names are part of the log format, only add new ones.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    /// Unix time in seconds the sync ended at.
+    pub timestamp: u64,
+    pub user_id: Option<u64>,
+    pub chat_id: i64,
+    pub source: String,
+    pub links: Vec<String>,
+    pub outcome: Outcome,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum Outcome {
+    Synced,
+    Failed { error: String },
+    Cancelled,
+}
+
+impl AuditRecord {
+    pub fn new(submission: Submission, links: Vec<String>, outcome: Outcome) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        Self {
+            timestamp,
+            user_id: submission.user_id,
+            chat_id: submission.chat_id,
+            source: submission.source,
+            links,
+            outcome,
+        }
+    }
+}
+
+/// Object safe, so the sink can be picked by the config.
+pub trait AuditSink: Send + Sync {
+    fn record<'a>(&'a self, record: &'a AuditRecord) -> BoxFuture<'a, anyhow::Result<()>>;
+}
+
+/// Used when auditing is disabled.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoopSink;
+
+impl AuditSink for NoopSink {
+    fn record<'a>(&'a self, _record: &'a AuditRecord) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Appends one JSON record per line.
+#[derive(Debug, Clone)]
+pub struct JsonLinesSink {
+    file: Arc<Mutex<File>>,
+}
+
+impl JsonLinesSink {
+    pub fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+}
+
+impl AuditSink for JsonLinesSink {
+    fn record<'a>(&'a self, record: &'a AuditRecord) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            let mut line = serde_json::to_vec(record)?;
+            line.push(b'\n');
+            let file = self.file.clone();
+            // a single write, so lines of concurrent records are not interleaved
+            tokio::task::spawn_blocking(move || file.lock().unwrap().write_all(&line)).await??;
+            Ok(())
+        })
+    }
+}
+
+/// Keeps the records in memory, for tests.
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemorySink(pub Mutex<Vec<AuditRecord>>);
+
+#[cfg(test)]
+impl AuditSink for MemorySink {
+    fn record<'a>(&'a self, record: &'a AuditRecord) -> BoxFuture<'a, anyhow::Result<()>> {
+        self.0.lock().unwrap().push(record.clone());
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// The sink of the `audit` config, a no-op one if it is missing or disabled.
+pub fn sink_from_config() -> anyhow::Result<Arc<dyn AuditSink>> {
+    match config::parse::<AuditConfig>(CONFIG_KEY)? {
+        Some(config) if config.enabled => Ok(Arc::new(JsonLinesSink::open(&config.path)?)),
+        _ => Ok(Arc::new(NoopSink)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn submission() -> Submission {
+        Submission {
+            user_id: Some(42),
+            chat_id: -100,
+            source: "https://e-hentai.org/g/1/abc/".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_record_format() {
+        let record = AuditRecord::new(
+            submission(),
+            Vec::new(),
+            Outcome::Failed {
+                error: "gallery not found".to_string(),
+            },
+        );
+        assert!(record.timestamp > 0);
+        let json = serde_json::to_value(&record).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "timestamp": record.timestamp,
+                "user_id": 42,
+                "chat_id": -100,
+                "source": "https://e-hentai.org/g/1/abc/",
+                "links": [],
+                "outcome": {"status": "failed", "error": "gallery not found"},
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_json_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        let sink = JsonLinesSink::open(&path).unwrap();
+        for outcome in [Outcome::Synced, Outcome::Cancelled] {
+            let record = AuditRecord::new(submission(), Vec::new(), outcome);
+            sink.record(&record).await.unwrap();
+        }
+        let content = std::fs::read_to_string(&path).unwrap();
+        let outcomes = content
+            .lines()
+            .map(|l| serde_json::from_str::<AuditRecord>(l).unwrap().outcome)
+            .collect::<Vec<_>>();
+        assert_eq!(outcomes, [Outcome::Synced, Outcome::Cancelled]);
+    }
+}
@@ -0,0 +1,157 @@
+//! POST a JSON payload to a configured url when a sync ends, for running the bot as
+//! a part of a larger pipeline. It is sent once per job, after its last attempt, and
+//! not for the jobs left in the queue on shutdown.
+//!
+//! Callbacks are sent through the shared `ProxiedClient`, so `proxy.no_proxy` decides
+//! whether they go through the forwarding proxy. Their retries are only bounded by
+//! `max_attempts`, they do not take from the retry budget of the syncs. With a
+//! `secret` the body is signed with HMAC-SHA256 in
+//! `X-Eh2telegraph-Signature: sha256=<hex>`.
+
+use std::time::Duration;
+
+use eh2telegraph::{
+    config,
+    http_proxy::{ProxiedClient, RetryPolicy},
+};
+use hmac::{Hmac, Mac};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::audit::Outcome;
+
+const CONFIG_KEY: &str = "callback";
+pub const SIGNATURE_HEADER: &str = "x-eh2telegraph-signature";
+pub const DEFAULT_MAX_ATTEMPTS: usize = 3;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CallbackConfig {
+    pub url: String,
+    /// Key of the signature, the body is not signed if not set.
+    pub secret: Option<String>,
+    /// Attempts including the first one, 3 if not set.
+    pub max_attempts: Option<usize>,
+}
+
+/// Field names are part of the callback format, only add new ones.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CallbackPayload {
+    pub source: String,
+    pub links: Vec<String>,
+    /// Pages of the gallery, None if it is not known, like for a failed sync.
+    pub page_count: Option<usize>,
+    #[serde(flatten)]
+    pub outcome: Outcome,
+    /// From the submission to the end of the sync, the time in the queue included.
+    pub duration_ms: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Callback {
+    url: String,
+    secret: Option<String>,
+    retry: RetryPolicy,
+    client: ProxiedClient,
+}
+
+impl Callback {
+    pub fn new(config: CallbackConfig, client: ProxiedClient) -> Self {
+        let attempts = config.max_attempts.unwrap_or(DEFAULT_MAX_ATTEMPTS).max(1);
+        let retry = RetryPolicy::new(attempts, Duration::from_secs(1)).with_retry_status(vec![
+            StatusCode::TOO_MANY_REQUESTS,
+            StatusCode::INTERNAL_SERVER_ERROR,
+            StatusCode::BAD_GATEWAY,
+            StatusCode::SERVICE_UNAVAILABLE,
+            StatusCode::GATEWAY_TIMEOUT,
+        ]);
+        Self {
+            url: config.url,
+            secret: config.secret,
+            retry,
+            client: client.without_retry_budget(),
+        }
+    }
+
+    /// The callback of the `callback` config, None if it is missing.
+    pub fn from_config(client: ProxiedClient) -> anyhow::Result<Option<Self>> {
+        Ok(config::parse::<CallbackConfig>(CONFIG_KEY)?.map(|c| Self::new(c, client)))
+    }
+
+    /// Send the payload, retrying on connection errors and 5xx.
+    pub async fn send(&self, payload: &CallbackPayload) -> anyhow::Result<()> {
+        let body = serde_json::to_vec(payload)?;
+        let mut req = self
+            .client
+            .post(&self.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json");
+        if let Some(secret) = &self.secret {
+            req = req.header(SIGNATURE_HEADER, sign(secret.as_bytes(), &body));
+        }
+        let resp = self
+            .client
+            .send_with_retry(req.body(body), self.retry.clone())
+            .await?;
+        let status = resp.upstream_status();
+        anyhow::ensure!(status.is_success(), "callback answered with {status}");
+        Ok(())
+    }
+}
+
+/// `sha256=<hex>` of the HMAC-SHA256 of `body`.
+pub fn sign(secret: &[u8], body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("hmac takes keys of any size");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_payload() {
+        let payload = CallbackPayload {
+            source: "https://e-hentai.org/g/1/x/".to_string(),
+            links: vec!["https://telegra.ph/p".to_string()],
+            page_count: Some(24),
+            outcome: Outcome::Synced,
+            duration_ms: 1500,
+        };
+        assert_eq!(
+            serde_json::to_value(&payload).unwrap(),
+            serde_json::json!({
+                "source": "https://e-hentai.org/g/1/x/",
+                "links": ["https://telegra.ph/p"],
+                "page_count": 24,
+                "status": "synced",
+                "duration_ms": 1500,
+            })
+        );
+        let failed = CallbackPayload {
+            links: Vec::new(),
+            page_count: None,
+            outcome: Outcome::Failed {
+                error: "not found".to_string(),
+            },
+            ..payload
+        };
+        let value = serde_json::to_value(&failed).unwrap();
+        assert_eq!(value["status"], "failed");
+        assert_eq!(value["error"], "not found");
+        assert!(value["page_count"].is_null());
+    }
+
+    #[test]
+    fn test_sign() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            sign(b"Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_ne!(
+            sign(b"other", b"what do ya want for nothing?"),
+            sign(b"Jefe", b"")
+        );
+    }
+}
@@ -0,0 +1,156 @@
+//! Outgoing Telegram API calls, paced by a process wide limiter and retried when
+//! Telegram answers with a flood wait instead of failing.
+//!
+//! Progress edits of several syncs and the replies of a message with many links
+//! come in bursts, which would hit the limits of Telegram otherwise.
+
+use std::{future::Future, time::Duration};
+
+use once_cell::sync::Lazy;
+use teloxide::{
+    payloads::SendMessageSetters,
+    requests::{Output, Request, Requester},
+    types::Message,
+    RequestError,
+};
+use tokio::{sync::Mutex, time::Instant};
+
+// telegram allows about 30 messages a second in total
+const MIN_INTERVAL: Duration = Duration::from_millis(1000 / 30);
+const MAX_RETRIES: usize = 3;
+// a longer wait is given up, the message would be stale by then
+const MAX_WAIT: Duration = Duration::from_secs(60);
+
+static GUARD: Lazy<FloodGuard> = Lazy::new(|| FloodGuard::new(MIN_INTERVAL, MAX_RETRIES));
+
+/// Send the request through the process wide guard.
+pub async fn send<R>(request: R) -> Result<Output<R>, RequestError>
+where
+    R: Request<Err = RequestError>,
+{
+    GUARD.send(request).await
+}
+
+/// Reply to the message through the process wide guard.
+pub async fn reply<B>(
+    bot: &B,
+    msg: &Message,
+    text: impl Into<String>,
+) -> Result<Message, RequestError>
+where
+    B: Requester<Err = RequestError>,
+{
+    send(
+        bot.send_message(msg.chat.id, text)
+            .reply_to_message_id(msg.id),
+    )
+    .await
+}
+
+#[derive(Debug)]
+pub struct FloodGuard {
+    // the earliest time the next call may start
+    next: Mutex<Instant>,
+    interval: Duration,
+    max_retries: usize,
+}
+
+impl FloodGuard {
+    pub fn new(interval: Duration, max_retries: usize) -> Self {
+        Self {
+            next: Mutex::new(Instant::now()),
+            interval,
+            max_retries,
+        }
+    }
+
+    pub async fn send<R>(&self, request: R) -> Result<Output<R>, RequestError>
+    where
+        R: Request<Err = RequestError>,
+    {
+        self.call(|| request.send_ref()).await
+    }
+
+    /// Run the call when the limiter allows, again after the wait Telegram asks for.
+    pub async fn call<T, F, Fut>(&self, mut call: F) -> Result<T, RequestError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, RequestError>>,
+    {
+        let mut retries = 0;
+        loop {
+            self.wait_turn().await;
+            match call().await {
+                Err(RequestError::RetryAfter(wait))
+                    if retries < self.max_retries && wait <= MAX_WAIT =>
+                {
+                    retries += 1;
+                    tracing::warn!("[flood] telegram asks to wait {wait:?}, retry {retries}");
+                    tokio::time::sleep(wait).await;
+                }
+                r => return r,
+            }
+        }
+    }
+
+    async fn wait_turn(&self) {
+        let turn = {
+            let mut next = self.next.lock().await;
+            let turn = (*next).max(Instant::now());
+            *next = turn + self.interval;
+            turn
+        };
+        tokio::time::sleep_until(turn).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use teloxide::ApiError;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_flood_wait() {
+        let guard = FloodGuard::new(Duration::from_millis(10), 2);
+        let calls = AtomicUsize::new(0);
+        // a mock telegram asking to wait once
+        let mock = || async {
+            match calls.fetch_add(1, Ordering::Relaxed) {
+                0 => Err(RequestError::RetryAfter(Duration::from_millis(20))),
+                n => Ok(n),
+            }
+        };
+        let start = Instant::now();
+        assert_eq!(guard.call(mock).await.unwrap(), 1);
+        assert!(start.elapsed() >= Duration::from_millis(20));
+
+        // other errors are returned at once, and flood waits after the retries
+        let result = guard
+            .call(|| async { Err::<(), _>(RequestError::Api(ApiError::MessageIsTooLong)) })
+            .await;
+        assert!(matches!(result, Err(RequestError::Api(_))));
+        calls.store(0, Ordering::Relaxed);
+        let result = guard
+            .call(|| async {
+                calls.fetch_add(1, Ordering::Relaxed);
+                Err::<(), _>(RequestError::RetryAfter(Duration::ZERO))
+            })
+            .await;
+        assert!(matches!(result, Err(RequestError::RetryAfter(_))));
+        assert_eq!(calls.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn test_pacing() {
+        let guard = FloodGuard::new(Duration::from_millis(20), 0);
+        let start = Instant::now();
+        for _ in 0..4 {
+            guard.call(|| async { Ok(()) }).await.unwrap();
+        }
+        // the first call is not delayed
+        assert!(start.elapsed() >= Duration::from_millis(60));
+    }
+}
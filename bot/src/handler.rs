@@ -1,35 +1,70 @@
-use std::{borrow::Cow, collections::HashSet, sync::Arc};
+use std::{
+    borrow::Cow,
+    collections::HashSet,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use eh2telegraph::{
-    collector::{e_hentai::EHCollector, exhentai::EXCollector, nhentai::NHCollector},
     config::{self, WhitelistConfig}, // Add whitelist
+    gallery::GalleryUrl,
+    http_proxy::{self, ProxiedClient},
     searcher::{
         f_hash::FHashConvertor,
         saucenao::{SaucenaoOutput, SaucenaoParsed, SaucenaoSearcher},
         ImageSearcher,
     },
-    storage::KVStorage,
-    sync::Synchronizer,
+    storage::{
+        queue::{Job, JobQueue, JobStatus, QueueError},
+        rate_limit::{RateLimit, RateLimiter},
+        KVStorage,
+    },
+    sync::{
+        DryRunReport, DuplicateSlot, MetaSlot, ProgressReporter, SizeLimit, SkippedSlot, SyncError,
+        SyncProgress, Synchronizer, UploadOptions,
+    },
+    util::canonicalize_url,
 };
 
-use reqwest::Url;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::sync::Mutex;
 use teloxide::{
     adaptors::DefaultParseMode,
     prelude::*,
+    types::{
+        InlineQuery, InlineQueryResultArticle, InputMessageContent, InputMessageContentText,
+        MessageId, ParseMode, PhotoSize,
+    },
     utils::{
         command::BotCommands,
         markdown::{code_inline, escape, link},
     },
 };
-use tokio::sync::oneshot;
+use tokio_util::sync::CancellationToken;
 use tracing::{info, trace};
 
-use crate::{ok_or_break, util::PrettyChat};
+use crate::{
+    audit::{AuditRecord, AuditSink, NoopSink, Outcome, Submission},
+    callback::{Callback, CallbackPayload},
+    flood,
+    i18n::Messages,
+    ok_or_break,
+    pool::{self, DEFAULT_WORKERS},
+    publish::Publisher,
+    shutdown::Shutdown,
+    stats::Stats,
+    util::PrettyChat,
+};
 
-const MIN_SIMILARITY: u8 = 70;
-const MIN_SIMILARITY_PRIVATE: u8 = 50;
+// Telegram limits message edits, so progress is shown at most once in it
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(3);
+// links of a message beyond it are ignored
+const MAX_URLS_PER_MESSAGE: usize = 10;
+// an inline answer has one result only
+const INLINE_RESULT_ID: &str = "sync";
+// inline queries are answered in time, even if the gallery is slow to download
+const CONTENT_LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[derive(BotCommands, Clone)]
 #[command(
@@ -38,7 +73,11 @@ const MIN_SIMILARITY_PRIVATE: u8 = 50;
     This is a gallery synchronization robot that is convenient for users to view pictures directly in Telegram.\n\
     这是一个方便用户直接在 Telegram 里看图的画廊同步机器人。\n\
     Bot supports sync with command, text url, or image(private chat search thrashold is lower).\n\
-    机器人支持通过 命令、直接发送链接、图片(私聊搜索相似度阈值会更低) 的形式同步。\n\n\
+    机器人支持通过 命令、直接发送链接、图片(私聊搜索相似度阈值会更低) 的形式同步。\n\
+    Append #pages=10-40 to the url to sync only these pages.\n\
+    在链接后加上 #pages=10-40 可以只同步这些页。\n\
+    Reply /search to an image to find and sync its gallery.\n\
+    回复 /search 到一张图片可以搜索并同步它的画廊。\n\n\
     Bot develop group / Bot 开发群 https://t.me/TGSyncBotWorkGroup\n\
     And welcome to join image channel / 频道推荐 https://t.me/sesecollection\n\n\
     These commands are supported:\n\
@@ -52,9 +91,13 @@ pub enum Command {
     #[command(description = "Show your account id. 显示你的账号 ID")]
     Id,
     #[command(
-        description = "Sync a gallery(e-hentai/exhentai/nhentai are supported now). 同步一个画廊(目前支持 EH/EX/NH)"
+        description = "Sync a gallery(e-hentai/exhentai/nhentai/hitomi are supported now). 同步一个画廊(目前支持 EH/EX/NH/Hitomi)"
     )]
     Sync(String),
+    #[command(
+        description = "Search the replied image and sync the matched gallery. 搜索回复的图片并同步匹配的画廊"
+    )]
+    Search,
     #[command(description = "Cancel all ongoing sync operations. 取消所有正在进行的同步操作。")]
     Cancel,
 }
@@ -64,6 +107,86 @@ pub enum Command {
 pub enum AdminCommand {
     #[command(description = "Delete cache with given key.")]
     Delete(String),
+    #[command(description = "Report what syncing the url would upload, without uploading.")]
+    DryRun(String),
+    #[command(description = "Sync the url again, even if it or the same content has been synced.")]
+    Resync(String),
+    #[command(description = "Show the counters since started.")]
+    Stats,
+    #[command(description = "Show the queued syncs.")]
+    Queue,
+}
+
+type ActiveSync = (String, CancellationToken);
+
+/// A submitted gallery waiting in the queue, with the status message to edit.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncJob {
+    pub url: String,
+    pub chat_id: i64,
+    /// None for inline queries, they have no message to edit.
+    pub message_id: Option<i32>,
+    pub user_id: Option<u64>,
+    pub lang: Option<String>,
+    /// The message of the urls if it has several, the summary replies to it.
+    #[serde(default)]
+    pub batch: Option<i32>,
+    /// Upload even if it has been synced, see `UploadOptions::force`.
+    #[serde(default)]
+    pub force: bool,
+    /// Unix time in milliseconds of the submission, None for the jobs queued before
+    /// it was recorded.
+    #[serde(default)]
+    pub submitted_at: Option<u64>,
+}
+
+impl SyncJob {
+    /// Jobs of different users are taken in turn.
+    pub fn owner(&self) -> i64 {
+        self.user_id.map_or(self.chat_id, |id| id as i64)
+    }
+}
+
+/// Results of the urls of one message, summarized once all of them are done. It is
+/// kept in memory, so a batch interrupted by a restart is not summarized.
+#[derive(Debug)]
+struct Batch {
+    lang: Option<String>,
+    pending: usize,
+    synced: usize,
+    /// The url and the reply of its failure.
+    failed: Vec<(String, String)>,
+}
+
+/// Chat and message of the urls.
+type BatchKey = (i64, i32);
+
+/// Filled by the sync of one request.
+#[derive(Debug, Clone, Default)]
+struct SyncSlots {
+    /// Also filled on cache hit, the gallery is only published if it was fetched.
+    meta: MetaSlot,
+    duplicate: DuplicateSlot,
+    skipped: SkippedSlot,
+}
+
+/// The reply of a sync, and its result for the callback.
+struct SyncReply {
+    text: String,
+    error: Option<SyncError>,
+    links: Vec<String>,
+    /// Pages of the gallery, None if it is not known.
+    page_count: Option<usize>,
+    outcome: Outcome,
+}
+
+fn audit_outcome(e: &SyncError) -> Outcome {
+    match e {
+        SyncError::Cancelled => Outcome::Cancelled,
+        e => Outcome::Failed {
+            error: e.to_string(),
+        },
+    }
 }
 
 pub struct Handler<C> {
@@ -72,85 +195,170 @@ pub struct Handler<C> {
     pub convertor: FHashConvertor,
     pub admins: HashSet<i64>,
     pub whitelist: HashSet<i64>, // Add whitelist
-
-    single_flight: singleflight_async::SingleFlight<String>,
+    pub denylist: HashSet<i64>,
+    /// Syncs per user, admins and whitelisted users are not limited.
+    pub rate_limit: Option<RateLimit>,
+    pub messages: Messages,
+    /// Background syncs, drained before exiting.
+    pub shutdown: Shutdown,
+    /// Workers taking the queued syncs, so syncs running at the same time.
+    pub sync_workers: usize,
+    /// Submitted syncs, persisted in the cache storage.
+    pub queue: JobQueue<SyncJob>,
+    /// Channel fresh uploads are posted to.
+    pub publisher: Option<Publisher>,
+    /// The client shared by collectors, telegraph and the searcher, for its request
+    /// counters.
+    pub proxy: ProxiedClient,
+    /// Where the processed galleries are recorded.
+    pub audit: Arc<dyn AuditSink>,
+    /// Notified when a sync ends.
+    pub callback: Option<Callback>,
 
     // One user can have multiple active syncs
-    active_syncs: Arc<Mutex<HashMap<i64, Vec<(String, oneshot::Sender<()>)>>>>,
+    active_syncs: Arc<Mutex<HashMap<i64, Vec<ActiveSync>>>>,
+    batches: Mutex<HashMap<BatchKey, Batch>>,
 }
 
 impl<C> Handler<C>
 where
     C: KVStorage<String> + Send + Sync + 'static,
 {
-    pub fn new(synchronizer: Synchronizer<C>, admins: HashSet<i64>) -> Self {
+    /// `proxy` is the client shared by the collectors and telegraph of `synchronizer`,
+    /// the searcher uses it too and `/stats` reports its counters.
+    pub fn new(synchronizer: Synchronizer<C>, admins: HashSet<i64>, proxy: ProxiedClient) -> Self {
         // Read whitelist ids
-        let whitelist = match config::parse::<WhitelistConfig>("whitelist")
+        let (whitelist, denylist) = match config::parse::<WhitelistConfig>("whitelist")
             .ok()
             .and_then(|x| x)
         {
             Some(config) => {
+                let denylist = config.deny_ids.into_iter().collect();
                 if config.enabled {
                     // use ids in config
-                    config.ids.into_iter().collect()
+                    (config.ids.into_iter().collect(), denylist)
                 } else {
                     // Allow all ppl to use
-                    HashSet::from([i64::MIN])
+                    (HashSet::from([i64::MIN]), denylist)
                 }
             }
             None => {
                 // No whitelist, all ppl can use
-                HashSet::from([i64::MIN])
+                (HashSet::from([i64::MIN]), HashSet::new())
             }
         };
+        let rate_limit =
+            config::parse::<RateLimit>("rate_limit").expect("unable to parse rate_limit config");
 
         Self {
             synchronizer,
-            searcher: SaucenaoSearcher::new_from_config(),
+            searcher: SaucenaoSearcher::new_from_config(proxy.clone()),
             convertor: FHashConvertor::new_from_config(),
             admins,
             whitelist,
-            single_flight: Default::default(),
+            denylist,
+            rate_limit,
+            messages: Messages::default(),
+            shutdown: Shutdown::default(),
+            sync_workers: DEFAULT_WORKERS,
+            queue: JobQueue::default().with_owner(SyncJob::owner),
+            publisher: None,
+            proxy,
+            audit: Arc::new(NoopSink),
+            callback: None,
             active_syncs: Arc::new(Mutex::new(HashMap::new())),
+            batches: Mutex::new(HashMap::new()),
         }
     }
 
-    // Whitelist is allowed to use
-    fn is_allowed(&self, chat_id: i64) -> bool {
+    /// Equivalent links are deduplicated and share the single flight by the canonical
+    /// form, by the hosts of the collectors.
+    fn canonical_link(&self, link: &str) -> String {
+        canonicalize_url(link, self.synchronizer.registry().hosts())
+            .map_or_else(|_| link.to_string(), String::from)
+    }
+
+    fn is_allowed(&self, msg: &Message) -> bool {
+        self.is_id_allowed(msg.chat.id.0, msg.from().map(|u| u.id.0 as i64))
+    }
+
+    // Whitelist is allowed to use, the denylist is checked for both the chat and the sender
+    fn is_id_allowed(&self, chat_id: i64, sender: Option<i64>) -> bool {
         if self.admins.contains(&chat_id) {
             return true;
         }
+        if self.denylist.contains(&chat_id) || sender.is_some_and(|id| self.denylist.contains(&id))
+        {
+            return false;
+        }
         if self.whitelist.contains(&i64::MIN) {
             return true;
         }
         self.whitelist.contains(&chat_id)
     }
 
+    /// Take a sync of the sender, return the time to wait if limited.
+    async fn check_rate_limit(&self, msg: &Message) -> Option<Duration> {
+        let chat_id = msg.chat.id.0;
+        let user_id = msg.from().map(|u| u.id.0 as i64).unwrap_or(chat_id);
+        self.check_id_rate_limit(chat_id, user_id).await
+    }
+
+    async fn check_id_rate_limit(&self, chat_id: i64, user_id: i64) -> Option<Duration> {
+        let limit = self.rate_limit?;
+        let exempt = |id: i64| {
+            self.admins.contains(&id)
+                || (!self.whitelist.contains(&i64::MIN) && self.whitelist.contains(&id))
+        };
+        if exempt(chat_id) || exempt(user_id) {
+            return None;
+        }
+        match RateLimiter::new(self.synchronizer.cache(), limit)
+            .acquire(user_id)
+            .await
+        {
+            Ok(r) => r.err(),
+            Err(e) => {
+                // do not block users when the storage is down
+                tracing::warn!("[rate limit] unable to check user {user_id}: {e}");
+                None
+            }
+        }
+    }
+
     // Support Multiple Sync Task
-    fn register_sync(&self, user_id: i64, url: &str) -> oneshot::Receiver<()> {
-        let (tx, rx) = oneshot::channel();
+    fn register_sync(&self, user_id: i64, url: &str) -> CancellationToken {
+        let token = self.shutdown.child_token();
 
         let mut active_syncs = self.active_syncs.lock().unwrap();
 
-        let user_syncs = active_syncs.entry(user_id).or_insert_with(Vec::new);
+        let user_syncs = active_syncs.entry(user_id).or_default();
 
-        user_syncs.push((url.to_string(), tx));
+        user_syncs.push((url.to_string(), token.clone()));
 
-        rx
+        token
     }
 
     fn unregister_sync(&self, user_id: i64, url: &str) {
         let mut active_syncs = self.active_syncs.lock().unwrap();
-        
+
         if let Some(user_syncs) = active_syncs.get_mut(&user_id) {
             user_syncs.retain(|(sync_url, _)| sync_url != url);
-            
+
             if user_syncs.is_empty() {
                 active_syncs.remove(&user_id);
             }
         }
     }
 
+    fn is_syncing(&self, url: &str) -> bool {
+        let active_syncs = self.active_syncs.lock().unwrap();
+        active_syncs
+            .values()
+            .flatten()
+            .any(|(sync_url, _)| sync_url == url)
+    }
+
     fn cancel_all_syncs(&self, user_id: i64) -> usize {
         let mut active_syncs = self.active_syncs.lock().unwrap();
 
@@ -158,12 +366,12 @@ where
             let count = user_syncs.len();
 
             // Send all cancellation signal
-            for (url, tx) in user_syncs {
+            for (url, token) in user_syncs {
                 info!(
                     "[cancel handler] cancelling sync for user {} and url {}",
                     user_id, url
                 );
-                let _ = tx.send(());
+                token.cancel();
             }
 
             info!(
@@ -177,14 +385,39 @@ where
         }
     }
 
+    /// Drop the queued syncs of the chat and tell their status messages.
+    async fn cancel_queued_syncs(&self, bot: &DefaultParseMode<Bot>, chat_id: i64) -> usize {
+        let removed = self
+            .queue
+            .remove_pending(|job| job.chat_id == chat_id)
+            .await;
+        for Job { payload: job, .. } in &removed {
+            info!(
+                "[cancel handler] dequeue sync for chat {chat_id} and url {}",
+                job.url
+            );
+            let Some(message_id) = job.message_id else {
+                continue;
+            };
+            let text = self
+                .messages
+                .format(job.lang.as_deref(), "sync_cancelled", &[]);
+            let edit = bot.edit_message_text(ChatId(chat_id), MessageId(message_id), text);
+            let _ = flood::send(edit).await;
+        }
+        removed.len()
+    }
+
     // Add unauthorized response
     async fn send_unauthorized(&self, bot: &DefaultParseMode<Bot>, msg: &Message) {
         // Only send in PM
         if msg.chat.is_private() {
-            let _ = bot
-                .send_message(msg.chat.id, escape("User not authorized!"))
-                .reply_to_message_id(msg.id)
-                .await;
+            let _ = flood::reply(
+                &bot,
+                msg,
+                self.messages.format(lang(msg), "unauthorized", &[]),
+            )
+            .await;
         }
     }
 
@@ -197,40 +430,37 @@ where
     ) -> ControlFlow<()> {
         match command {
             Command::Help => {
-                let _ = bot
-                    .send_message(msg.chat.id, escape(&Command::descriptions().to_string()))
-                    .reply_to_message_id(msg.id)
-                    .await;
+                let _ =
+                    flood::reply(&bot, &msg, escape(&Command::descriptions().to_string())).await;
             }
             Command::Version => {
-                let _ = bot
-                    .send_message(msg.chat.id, escape(crate::version::VERSION))
-                    .reply_to_message_id(msg.id)
-                    .await;
+                let _ = flood::reply(&bot, &msg, escape(crate::version::VERSION)).await;
             }
             Command::Id => {
-                let _ = bot
-                    .send_message(
-                        msg.chat.id,
-                        format!(
-                            "Current chat id is {} \\(in private chat this is your account id\\)",
-                            code_inline(&msg.chat.id.to_string())
-                        ),
-                    )
-                    .reply_to_message_id(msg.id)
-                    .await;
+                let _ = flood::reply(
+                    &bot,
+                    &msg,
+                    self.messages.format(
+                        lang(&msg),
+                        "chat_id",
+                        &[("id", &code_inline(&msg.chat.id.to_string()))],
+                    ),
+                )
+                .await;
             }
             Command::Sync(url) => {
                 // Add white list check
-                if !self.is_allowed(msg.chat.id.0) {
+                if !self.is_allowed(&msg) {
                     self.send_unauthorized(&bot, &msg).await;
                     return ControlFlow::Break(());
                 }
                 if url.is_empty() {
-                    let _ = bot
-                        .send_message(msg.chat.id, escape("Usage: /sync url"))
-                        .reply_to_message_id(msg.id)
-                        .await;
+                    let _ = flood::reply(
+                        &bot,
+                        &msg,
+                        self.messages.format(lang(&msg), "sync_usage", &[]),
+                    )
+                    .await;
                     return ControlFlow::Break(());
                 }
 
@@ -238,38 +468,82 @@ where
                     "[cmd handler] receive sync request from {:?} for {url}",
                     PrettyChat(&msg.chat)
                 );
-                let msg: Message = ok_or_break!(
-                    bot.send_message(msg.chat.id, escape(&format!("Syncing url {url}")))
-                        .reply_to_message_id(msg.id)
-                        .await
-                );
-
-                let url_clone = url.clone();
-                let cancel_rx = self.register_sync(msg.chat.id.0, &url);
-
-                tokio::spawn(async move {
-                    let result = self.sync_response(&url, cancel_rx).await;
-
-                    self.unregister_sync(msg.chat.id.0, &url_clone);
+                ok_or_break!(self.start_syncs(bot, &msg, vec![url]).await);
+            }
+            Command::Search => {
+                if !self.is_allowed(&msg) {
+                    self.send_unauthorized(&bot, &msg).await;
+                    return ControlFlow::Break(());
+                }
+                let photo = match msg
+                    .reply_to_message()
+                    .and_then(Message::photo)
+                    .and_then(|x| x.first())
+                {
+                    Some(p) => p,
+                    None => {
+                        let _ = flood::reply(
+                            &bot,
+                            &msg,
+                            self.messages.format(lang(&msg), "search_usage", &[]),
+                        )
+                        .await;
+                        return ControlFlow::Break(());
+                    }
+                };
 
-                    let _ = bot.edit_message_text(msg.chat.id, msg.id, result).await;
-                });
+                let threshold = self.searcher.min_similarity(msg.chat.is_private());
+                match self.search_photo(&bot, photo, threshold).await {
+                    Ok(Some((url, sim))) => {
+                        info!(
+                            "[cmd handler] receive search request from {:?} for {url} with similarity {sim}",
+                            PrettyChat(&msg.chat)
+                        );
+                        ok_or_break!(self.start_syncs(bot, &msg, vec![url]).await);
+                    }
+                    Ok(None) => {
+                        let _ = flood::reply(
+                            &bot,
+                            &msg,
+                            self.messages.format(lang(&msg), "search_no_match", &[]),
+                        )
+                        .await;
+                    }
+                    Err(e) => {
+                        let _ = flood::reply(
+                            &bot,
+                            &msg,
+                            self.messages.format(
+                                lang(&msg),
+                                "search_failed",
+                                &[("error", &escape(&e.to_string()))],
+                            ),
+                        )
+                        .await;
+                    }
+                }
             }
             Command::Cancel => {
-                let cancelled_count = self.cancel_all_syncs(msg.chat.id.0);
+                let queued = self.cancel_queued_syncs(&bot, msg.chat.id.0).await;
+                let cancelled_count = self.cancel_all_syncs(msg.chat.id.0) + queued;
                 if cancelled_count > 0 {
-                    let _ = bot
-                        .send_message(
-                            msg.chat.id,
-                            escape(&format!("Cancelled {} sync operations.", cancelled_count)),
-                        )
-                        .reply_to_message_id(msg.id)
-                        .await;
+                    let _ = flood::reply(
+                        &bot,
+                        &msg,
+                        self.messages.format(
+                            lang(&msg),
+                            "cancel_done",
+                            &[("count", &cancelled_count.to_string())],
+                        ),
+                    )
+                    .await;
                 } else {
-                    let _ = bot
-                        .send_message(msg.chat.id, escape("No active sync operations to cancel."))
-                        .reply_to_message_id(msg.id)
-                        .await;
+                    let _ = flood::reply(
+                        &bot,
+                        &msg,
+                        self.messages.format(lang(&msg), "cancel_none", &[]),
+                    )
+                    .await;
                 }
             }
         };
@@ -287,10 +561,55 @@ where
             AdminCommand::Delete(key) => {
                 tokio::spawn(async move {
                     let _ = self.synchronizer.delete_cache(&key).await;
-                    let _ = bot
-                        .send_message(msg.chat.id, escape(&format!("Key {key} deleted.")))
-                        .reply_to_message_id(msg.id)
-                        .await;
+                    let _ = flood::reply(
+                        &bot,
+                        &msg,
+                        self.messages.format(
+                            lang(&msg),
+                            "cache_deleted",
+                            &[("key", &escape(&key))],
+                        ),
+                    )
+                    .await;
+                });
+                ControlFlow::Break(())
+            }
+            AdminCommand::Stats => {
+                let text = self.format_stats(lang(&msg), &self.stats());
+                ok_or_break!(flood::reply(&bot, &msg, text).await);
+                ControlFlow::Break(())
+            }
+            AdminCommand::Queue => {
+                let text = self.format_queue(lang(&msg), &self.queue.snapshot().await);
+                ok_or_break!(flood::reply(&bot, &msg, text).await);
+                ControlFlow::Break(())
+            }
+            AdminCommand::Resync(url) => {
+                let url = url.trim().to_string();
+                if url.is_empty() {
+                    let text = self.messages.format(lang(&msg), "sync_usage", &[]);
+                    let _ = flood::reply(&bot, &msg, text).await;
+                    return ControlFlow::Break(());
+                }
+                info!(
+                    "[admin handler] receive resync request from {:?} for {url}",
+                    PrettyChat(&msg.chat)
+                );
+                ok_or_break!(self.start_sync(bot, &msg, url, None, true).await);
+                ControlFlow::Break(())
+            }
+            AdminCommand::DryRun(url) => {
+                tokio::spawn(async move {
+                    let lang = lang(&msg);
+                    let text = match self.dry_run(url.trim()).await {
+                        Ok(report) => self.format_dry_run(lang, &report),
+                        Err(e) => self.messages.format(
+                            lang,
+                            "sync_failed",
+                            &[("error", &escape(&format!("{e:#}")))],
+                        ),
+                    };
+                    let _ = flood::reply(&bot, &msg, text).await;
                 });
                 ControlFlow::Break(())
             }
@@ -303,17 +622,21 @@ where
         msg: Message,
     ) -> ControlFlow<()> {
         // Add white list check
-        if !self.is_allowed(msg.chat.id.0) {
+        if !self.is_allowed(&msg) {
             self.send_unauthorized(&bot, &msg).await;
             return ControlFlow::Break(());
         }
-        let maybe_link = {
+        let mut links = Vec::new();
+        {
+            let registry = self.synchronizer.registry();
             let entries = msg
                 .entities()
                 .map(|es| {
                     es.iter().filter_map(|e| {
                         if let teloxide::types::MessageEntityKind::TextLink { url } = &e.kind {
-                            Synchronizer::match_url_from_text(url.as_ref()).map(ToOwned::to_owned)
+                            registry
+                                .match_url_from_text(url.as_ref())
+                                .map(ToOwned::to_owned)
                         } else {
                             None
                         }
@@ -321,36 +644,26 @@ where
                 })
                 .into_iter()
                 .flatten();
-            msg.text()
-                .and_then(|content| {
-                    Synchronizer::match_url_from_text(content).map(ToOwned::to_owned)
-                })
+            let texts = msg
+                .text()
+                .map(|text| registry.match_urls_from_text(text))
+                .unwrap_or_default()
                 .into_iter()
-                .chain(entries)
-                .next()
-        };
+                .map(ToOwned::to_owned);
+            for link in texts.chain(entries) {
+                let link = self.canonical_link(&link);
+                if !links.contains(&link) && links.len() < MAX_URLS_PER_MESSAGE {
+                    links.push(link);
+                }
+            }
+        }
 
-        if let Some(url) = maybe_link {
+        if !links.is_empty() {
             info!(
-                "[text handler] receive sync request from {:?} for {url}",
+                "[text handler] receive sync request from {:?} for {links:?}",
                 PrettyChat(&msg.chat)
             );
-            let msg: Message = ok_or_break!(
-                bot.send_message(msg.chat.id, escape(&format!("Syncing url {url}")))
-                    .reply_to_message_id(msg.id)
-                    .await
-            );
-
-            let url_clone = url.clone();
-            let cancel_rx = self.register_sync(msg.chat.id.0, &url);
-
-            tokio::spawn(async move {
-                let result = self.sync_response(&url, cancel_rx).await;
-
-                self.unregister_sync(msg.chat.id.0, &url_clone);
-
-                let _ = bot.edit_message_text(msg.chat.id, msg.id, result).await;
-            });
+            ok_or_break!(self.start_syncs(bot, &msg, links).await);
             return ControlFlow::Break(());
         }
 
@@ -364,12 +677,12 @@ where
         msg: Message,
     ) -> ControlFlow<()> {
         // Add white list check
-        if !self.is_allowed(msg.chat.id.0) {
+        if !self.is_allowed(&msg) {
             self.send_unauthorized(&bot, &msg).await;
             return ControlFlow::Break(());
         }
         let caption_entities = msg.caption_entities();
-        let mut final_url = None;
+        let mut links = Vec::new();
         for entry in caption_entities.map(|x| x.iter()).into_iter().flatten() {
             let url = match &entry.kind {
                 teloxide::types::MessageEntityKind::Url => {
@@ -389,41 +702,28 @@ where
                     continue;
                 }
             };
-            let url = if let Some(c) = Synchronizer::match_url_from_url(&url) {
-                c
+            let url = if let Some(c) = self.synchronizer.registry().match_url_from_url(&url) {
+                self.canonical_link(c)
             } else {
                 continue;
             };
-            final_url = Some(url.to_string());
-            break;
+            if !links.contains(&url) {
+                links.push(url);
+            }
+            if links.len() >= MAX_URLS_PER_MESSAGE {
+                break;
+            }
         }
 
-        match final_url {
-            Some(url) => {
-                info!(
-                    "[caption handler] receive sync request from {:?} for {url}",
-                    PrettyChat(&msg.chat)
-                );
-                let msg: Message = ok_or_break!(
-                    bot.send_message(msg.chat.id, escape(&format!("Syncing url {url}")))
-                        .reply_to_message_id(msg.id)
-                        .await
-                );
-
-                let url_clone = url.clone();
-                let cancel_rx = self.register_sync(msg.chat.id.0, &url);
-
-                tokio::spawn(async move {
-                    let result = self.sync_response(&url, cancel_rx).await;
-
-                    self.unregister_sync(msg.chat.id.0, &url_clone);
-
-                    let _ = bot.edit_message_text(msg.chat.id, msg.id, result).await;
-                });
-                ControlFlow::Break(())
-            }
-            None => ControlFlow::Continue(()),
+        if links.is_empty() {
+            return ControlFlow::Continue(());
         }
+        info!(
+            "[caption handler] receive sync request from {:?} for {links:?}",
+            PrettyChat(&msg.chat)
+        );
+        ok_or_break!(self.start_syncs(bot, &msg, links).await);
+        ControlFlow::Break(())
     }
 
     pub async fn respond_photo(
@@ -432,7 +732,7 @@ where
         msg: Message,
     ) -> ControlFlow<()> {
         // Add white list check
-        if !self.is_allowed(msg.chat.id.0) {
+        if !self.is_allowed(&msg) {
             self.send_unauthorized(&bot, &msg).await;
             return ControlFlow::Break(());
         }
@@ -443,39 +743,8 @@ where
             }
         };
 
-        let f = ok_or_break!(bot.get_file(&first_photo.file.id).await);
-        let mut buf: Vec<u8> = Vec::with_capacity(f.size as usize);
-        ok_or_break!(teloxide::net::Download::download_file(&bot, &f.path, &mut buf).await);
-        let search_result: SaucenaoOutput = ok_or_break!(self.searcher.search(buf).await);
-
-        let mut url_sim = None;
-        let threshold = if msg.chat.is_private() {
-            MIN_SIMILARITY_PRIVATE
-        } else {
-            MIN_SIMILARITY
-        };
-        for element in search_result
-            .data
-            .into_iter()
-            .filter(|x| x.similarity >= threshold)
-        {
-            match element.parsed {
-                SaucenaoParsed::EHentai(f_hash) => {
-                    url_sim = Some((
-                        ok_or_break!(self.convertor.convert_to_gallery(&f_hash).await),
-                        element.similarity,
-                    ));
-                    break;
-                }
-                SaucenaoParsed::NHentai(nid) => {
-                    url_sim = Some((format!("https://nhentai.net/g/{nid}/"), element.similarity));
-                    break;
-                }
-                _ => continue,
-            }
-        }
-
-        let (url, sim) = match url_sim {
+        let threshold = self.searcher.min_similarity(msg.chat.is_private());
+        let (url, sim) = match ok_or_break!(self.search_photo(&bot, first_photo, threshold).await) {
             Some(u) => u,
             None => {
                 trace!("[photo handler] image not found");
@@ -488,23 +757,100 @@ where
             PrettyChat(&msg.chat)
         );
 
-        if let Ok(msg) = bot
-            .send_message(msg.chat.id, escape(&format!("Syncing url {url}")))
-            .reply_to_message_id(msg.id)
-            .await
-        {
-            let url_clone = url.clone();
-            let cancel_rx = self.register_sync(msg.chat.id.0, &url);
-
-            tokio::spawn(async move {
-                let result = self.sync_response(&url, cancel_rx).await;
+        let _ = self.start_syncs(bot, &msg, vec![url]).await;
+        ControlFlow::Break(())
+    }
 
-                self.unregister_sync(msg.chat.id.0, &url_clone);
+    /// Inline queries must be answered quickly, so only cached galleries are answered
+    /// with the links. Others are synced in background and a placeholder asks to retry.
+    pub async fn respond_inline(
+        &'static self,
+        bot: DefaultParseMode<Bot>,
+        query: InlineQuery,
+    ) -> ControlFlow<()> {
+        let registry = self.synchronizer.registry();
+        let Some(url) = registry
+            .match_url_from_text(&query.query)
+            .map(|url| self.canonical_link(url))
+        else {
+            return ControlFlow::Break(());
+        };
+        let user_id = query.from.id.0 as i64;
+        let lang = query.from.language_code.as_deref();
+        let messages = &self.messages;
 
-                let _ = bot.edit_message_text(msg.chat.id, msg.id, result).await;
-            });
-        }
+        let (title, text) = if !self.is_id_allowed(user_id, Some(user_id)) {
+            (
+                messages.get(lang, "unauthorized"),
+                messages.format(lang, "unauthorized", &[]),
+            )
+        } else if let Some(sync_urls) = self.cached_sync(&url).await.unwrap_or_else(|e| {
+            trace!("[inline handler] no cache for {url}: {e}");
+            None
+        }) {
+            (
+                messages.get(lang, "inline_synced"),
+                messages.format(
+                    lang,
+                    "sync_finished",
+                    &[("links", &render_links(&sync_urls))],
+                ),
+            )
+        } else if self.is_syncing(&url) || self.is_queued(&url).await {
+            (
+                messages.get(lang, "inline_syncing"),
+                messages.format(lang, "sync_started", &[("url", &escape(&url))]),
+            )
+        } else if self.shutdown.is_closed() {
+            (
+                messages.get(lang, "shutting_down"),
+                messages.format(lang, "shutting_down", &[]),
+            )
+        } else if let Some(wait) = self.check_id_rate_limit(user_id, user_id).await {
+            let minutes = wait.as_secs().div_ceil(60).to_string();
+            (
+                messages.get(lang, "inline_rate_limited"),
+                messages.format(lang, "rate_limited", &[("minutes", &minutes)]),
+            )
+        } else {
+            info!("[inline handler] receive sync request from {user_id} for {url}");
+            // inline queries come from the private chat with the user
+            let job = SyncJob {
+                url: url.clone(),
+                chat_id: user_id,
+                message_id: None,
+                user_id: Some(query.from.id.0),
+                lang: lang.map(str::to_owned),
+                batch: None,
+                force: false,
+                submitted_at: Some(unix_millis()),
+            };
+            match self.queue.push(job).await {
+                Ok(_) => (
+                    messages.get(lang, "inline_syncing"),
+                    messages.format(lang, "sync_started", &[("url", &escape(&url))]),
+                ),
+                Err(e) => {
+                    tracing::warn!("[queue] unable to queue {url}: {e}");
+                    let text = self.queue_error(lang, &e);
+                    (messages.get(lang, "inline_failed"), text)
+                }
+            }
+        };
 
+        let content = InputMessageContentText::new(text).parse_mode(ParseMode::MarkdownV2);
+        let article = InlineQueryResultArticle::new(
+            INLINE_RESULT_ID,
+            title,
+            InputMessageContent::Text(content),
+        )
+        .description(url);
+        // answers change as the sync goes, so they must not be cached
+        let answer = bot
+            .answer_inline_query(&query.id, [article.into()])
+            .cache_time(0)
+            .is_personal(true);
+        ok_or_break!(flood::send(answer).await);
         ControlFlow::Break(())
     }
 
@@ -515,9 +861,12 @@ where
     ) -> ControlFlow<()> {
         if msg.chat.is_private() {
             ok_or_break!(
-                bot.send_message(msg.chat.id, escape("Unrecognized message. Maybe /help ?"))
-                    .reply_to_message_id(msg.id)
-                    .await
+                flood::reply(
+                    &bot,
+                    &msg,
+                    self.messages.format(lang(&msg), "unrecognized", &[])
+                )
+                .await
             );
         }
         #[cfg(debug_assertions)]
@@ -525,55 +874,1127 @@ where
         ControlFlow::Break(())
     }
 
+    /// Search the image and find the first gallery with enough similarity.
+    async fn search_photo(
+        &self,
+        bot: &DefaultParseMode<Bot>,
+        photo: &PhotoSize,
+        threshold: u8,
+    ) -> anyhow::Result<Option<(String, u8)>> {
+        let f = flood::send(bot.get_file(&photo.file.id)).await?;
+        let mut buf: Vec<u8> = Vec::with_capacity(f.size as usize);
+        teloxide::net::Download::download_file(bot, &f.path, &mut buf).await?;
+        let search_result: SaucenaoOutput = self.searcher.search(buf).await?;
+
+        for element in search_result
+            .data
+            .into_iter()
+            .filter(|x| x.similarity >= threshold)
+        {
+            match element.parsed {
+                SaucenaoParsed::EHentai(f_hash) => {
+                    let url = self.convertor.convert_to_gallery(&f_hash).await?;
+                    return Ok(Some((url, element.similarity)));
+                }
+                SaucenaoParsed::NHentai(nid) => {
+                    return Ok(Some((
+                        format!("https://nhentai.net/g/{nid}/"),
+                        element.similarity,
+                    )));
+                }
+                _ => continue,
+            }
+        }
+        Ok(None)
+    }
+
+    /// Queue a sync for each url, each reports its result once done. With several
+    /// urls a summary of them is replied once all are done.
+    async fn start_syncs(
+        &'static self,
+        bot: DefaultParseMode<Bot>,
+        msg: &Message,
+        urls: Vec<String>,
+    ) -> anyhow::Result<()> {
+        let batch = (urls.len() > 1).then(|| {
+            let batch = Batch {
+                lang: lang(msg).map(str::to_owned),
+                pending: urls.len(),
+                synced: 0,
+                failed: Vec::new(),
+            };
+            self.batches
+                .lock()
+                .unwrap()
+                .insert((msg.chat.id.0, msg.id.0), batch);
+            msg.id.0
+        });
+        for url in urls {
+            let queued = self.start_sync(bot.clone(), msg, url, batch, false).await;
+            if !matches!(queued, Ok(true)) {
+                // there is no result to wait for
+                self.finish_batch(&bot, msg.chat.id.0, batch, None).await;
+            }
+            queued?;
+        }
+        Ok(())
+    }
+
+    /// Reply a status message and queue the sync, the message is edited with the
+    /// progress and then the result once a worker takes it. Returns whether it is
+    /// queued. With `force` it is uploaded again even if synced before.
+    async fn start_sync(
+        &'static self,
+        bot: DefaultParseMode<Bot>,
+        msg: &Message,
+        url: String,
+        batch: Option<i32>,
+        force: bool,
+    ) -> anyhow::Result<bool> {
+        if self.shutdown.is_closed() {
+            flood::reply(
+                &bot,
+                msg,
+                self.messages.format(lang(msg), "shutting_down", &[]),
+            )
+            .await?;
+            return Ok(false);
+        }
+        // a cached gallery is answered by the worker without syncing, it takes no token
+        let limited = match !force && self.is_cached(&url).await {
+            true => None,
+            false => self.check_rate_limit(msg).await,
+        };
+        if let Some(wait) = limited {
+            info!(
+                "[rate limit] reject sync request from {:?} for {url}",
+                PrettyChat(&msg.chat)
+            );
+            let text = self.messages.format(
+                lang(msg),
+                "rate_limited",
+                &[("minutes", &wait.as_secs().div_ceil(60).to_string())],
+            );
+            flood::reply(&bot, msg, text).await?;
+            return Ok(false);
+        }
+        let lang = lang(msg).map(str::to_owned);
+        let text = self
+            .messages
+            .format(lang.as_deref(), "sync_started", &[("url", &escape(&url))]);
+        let reply: Message = flood::reply(&bot, msg, text).await?;
+        let job = SyncJob {
+            url,
+            chat_id: reply.chat.id.0,
+            message_id: Some(reply.id.0),
+            user_id: msg.from().map(|u| u.id.0),
+            lang,
+            batch,
+            force,
+            submitted_at: Some(unix_millis()),
+        };
+        if let Err(e) = self.queue.push(job.clone()).await {
+            tracing::warn!("[queue] unable to queue {}: {e}", job.url);
+            let text = self.queue_error(job.lang.as_deref(), &e);
+            flood::send(bot.edit_message_text(reply.chat.id, reply.id, text)).await?;
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    /// Count a url of the batch, `result` is the reply of its failure if failed, or
+    /// None if it is not synced at all. Returns the batch once all are done.
+    fn record_batch(
+        &self,
+        key: BatchKey,
+        result: Option<Option<String>>,
+        url: &str,
+    ) -> Option<Batch> {
+        let mut batches = self.batches.lock().unwrap();
+        let batch = batches.get_mut(&key)?;
+        match result {
+            Some(None) => batch.synced += 1,
+            Some(Some(reply)) => batch.failed.push((url.to_string(), reply)),
+            None => {}
+        }
+        batch.pending -= 1;
+        match batch.pending {
+            0 => batches.remove(&key),
+            _ => None,
+        }
+    }
+
+    fn format_batch(&self, batch: &Batch) -> String {
+        let failed = batch
+            .failed
+            .iter()
+            .map(|(url, reply)| format!("\n{}: {reply}", escape(url)))
+            .collect::<String>();
+        self.messages.format(
+            batch.lang.as_deref(),
+            "batch_summary",
+            &[
+                ("synced", &batch.synced.to_string()),
+                ("total", &(batch.synced + batch.failed.len()).to_string()),
+                ("failed", &failed),
+            ],
+        )
+    }
+
+    /// Record the url in the batch, and reply the summary once it is the last one.
+    /// Nothing is replied if at most one url is synced, its own message says it all.
+    async fn finish_batch(
+        &self,
+        bot: &DefaultParseMode<Bot>,
+        chat_id: i64,
+        batch: Option<i32>,
+        result: Option<(&str, Option<String>)>,
+    ) {
+        let Some(message) = batch else {
+            return;
+        };
+        let (url, result) = match result {
+            Some((url, result)) => (url, Some(result)),
+            None => ("", None),
+        };
+        let Some(batch) = self.record_batch((chat_id, message), result, url) else {
+            return;
+        };
+        if batch.synced + batch.failed.len() < 2 {
+            return;
+        }
+        let summary = bot
+            .send_message(ChatId(chat_id), self.format_batch(&batch))
+            .reply_to_message_id(MessageId(message));
+        if let Err(e) = flood::send(summary).await {
+            tracing::warn!("[batch] unable to send the summary to chat {chat_id}: {e}");
+        }
+    }
+
+    fn queue_error(&self, lang: Option<&str>, e: &QueueError) -> String {
+        match e {
+            QueueError::Full(_) => self.messages.format(lang, "queue_full", &[]),
+            e => self
+                .messages
+                .format(lang, "sync_failed", &[("error", &escape(&e.to_string()))]),
+        }
+    }
+
+    /// Whether a sync of the url is waiting in the queue.
+    async fn is_queued(&self, url: &str) -> bool {
+        self.queue
+            .snapshot()
+            .await
+            .iter()
+            .any(|j| j.status == JobStatus::Pending && j.payload.url == url)
+    }
+
+    /// Start `sync_workers` workers taking the queued syncs, they stop once the
+    /// shutdown begins.
+    pub fn spawn_workers(&'static self, bot: DefaultParseMode<Bot>) {
+        for _ in 0..self.sync_workers.max(1) {
+            let bot = bot.clone();
+            self.shutdown.spawn(pool::run_worker(
+                &self.queue,
+                self.shutdown.closing_token(),
+                move |job| self.run_job(bot.clone(), job),
+            ));
+        }
+    }
+
+    async fn run_job(&'static self, bot: DefaultParseMode<Bot>, job: Job<SyncJob>) {
+        let Job { id, payload, .. } = job;
+        let SyncJob {
+            url,
+            chat_id,
+            message_id,
+            user_id,
+            lang,
+            batch,
+            force,
+            submitted_at,
+        } = payload;
+        let submitted_at = submitted_at.unwrap_or_else(unix_millis);
+        let chat = ChatId(chat_id);
+        let message = message_id.map(MessageId);
+        info!("[queue] take sync {id} for chat {chat_id} and url {url}");
+        let cancel = self.register_sync(chat_id, &url);
+        let text = self
+            .messages
+            .format(lang.as_deref(), "sync_started", &[("url", &escape(&url))]);
+        let submission = Submission {
+            user_id,
+            chat_id,
+            source: url.clone(),
+        };
+
+        let (reporter, mut progress_rx) = ProgressReporter::channel();
+        let status = message.map(|message| {
+            let bot = bot.clone();
+            let lang = lang.clone();
+            tokio::spawn(async move {
+                while progress_rx.changed().await.is_ok() {
+                    let Some(progress) = *progress_rx.borrow_and_update() else {
+                        continue;
+                    };
+                    let progress = self.format_progress(lang.as_deref(), progress);
+                    let edit = bot.edit_message_text(chat, message, format!("{text}\n{progress}"));
+                    let _ = flood::send(edit).await;
+                    tokio::time::sleep(PROGRESS_INTERVAL).await;
+                }
+            })
+        });
+        let options = UploadOptions {
+            progress: Some(reporter),
+            cancel: Some(cancel),
+            force,
+            ..Default::default()
+        };
+        let reply = self
+            .sync_response(&bot, &url, lang.as_deref(), submission, options)
+            .await;
+        let mut result = reply.text;
+        // no progress edits after the result
+        if let Some(status) = status {
+            status.abort();
+            let _ = status.await;
+        }
+        self.unregister_sync(chat_id, &url);
+
+        // the result for the batch, None if the job is run again
+        let done = match reply.error {
+            None => {
+                self.queue.finish(id, true).await;
+                Some(None)
+            }
+            // left running, so it is taken again after the restart
+            Some(SyncError::Cancelled) if self.shutdown.is_closed() => {
+                result = self.messages.format(lang.as_deref(), "sync_requeued", &[]);
+                None
+            }
+            Some(e) if e.is_transient() && self.queue.retry(id).await => {
+                let retry = self.messages.format(lang.as_deref(), "sync_retry", &[]);
+                result = format!("{result}\n{retry}");
+                None
+            }
+            Some(_) => {
+                self.queue.finish(id, false).await;
+                Some(Some(result.clone()))
+            }
+        };
+        match message {
+            Some(message) => {
+                let _ = flood::send(bot.edit_message_text(chat, message, result)).await;
+            }
+            None => trace!("[inline handler] sync {url} done: {result}"),
+        }
+        if let Some(done) = done {
+            let payload = CallbackPayload {
+                source: url.clone(),
+                links: reply.links,
+                page_count: reply.page_count,
+                outcome: reply.outcome,
+                duration_ms: unix_millis().saturating_sub(submitted_at),
+            };
+            self.notify(payload).await;
+            self.finish_batch(&bot, chat_id, batch, Some((&url, done)))
+                .await;
+        }
+    }
+
+    /// Send the callback of a finished job, if it is configured.
+    async fn notify(&self, payload: CallbackPayload) {
+        let Some(callback) = self.callback.clone() else {
+            return;
+        };
+        let notify = || {
+            let (callback, payload) = (callback.clone(), payload.clone());
+            async move {
+                if let Err(e) = callback.send(&payload).await {
+                    tracing::warn!("[callback] unable to notify {}: {e}", payload.source);
+                }
+            }
+        };
+        // in background, so the reply does not wait for the retries. Drained on
+        // shutdown, and sent before the job ends once closed.
+        if !self.shutdown.spawn(notify()) {
+            notify().await;
+        }
+    }
+
     // Updated sync_response method with cancellation
-    async fn sync_response(&self, url: &str, mut cancel_rx: oneshot::Receiver<()>) -> String {
-        tokio::select! {
-            result = self.single_flight.work(url, || async {
-                match self.route_sync(url).await {
-                    Ok(sync_url) => {
-                        format!("Sync to telegraph finished: {}", link(&sync_url, &escape(&sync_url)))
-                    }
+    // concurrent syncs of a gallery share one upload and all of them get its progress,
+    // it is stopped once all of them are cancelled
+    async fn sync_response(
+        &self,
+        bot: &DefaultParseMode<Bot>,
+        url: &str,
+        lang: Option<&str>,
+        submission: Submission,
+        options: UploadOptions,
+    ) -> SyncReply {
+        let slots = SyncSlots::default();
+        let cancel = options.cancel.clone().unwrap_or_default();
+        let result = tokio::select! {
+            result = self.route_sync(url, options, slots.clone()) => result,
+            _ = cancel.cancelled() => Err(SyncError::Cancelled),
+        };
+        let (links, outcome) = match &result {
+            Ok(links) => (links.clone(), Outcome::Synced),
+            Err(e) => (Vec::new(), audit_outcome(e)),
+        };
+        self.audit(submission, links.clone(), outcome.clone()).await;
+        let error = result.as_ref().err().cloned();
+        let text = self.render_result(bot, url, lang, result, &slots).await;
+        SyncReply {
+            text,
+            error,
+            links,
+            page_count: slots.meta.get().and_then(|m| m.page_count),
+            outcome,
+        }
+    }
+
+    async fn render_result(
+        &self,
+        bot: &DefaultParseMode<Bot>,
+        url: &str,
+        lang: Option<&str>,
+        result: Result<Vec<String>, SyncError>,
+        slots: &SyncSlots,
+    ) -> String {
+        match result {
+            Ok(sync_urls) => {
+                let mut finished = self.messages.format(
+                    lang,
+                    "sync_finished",
+                    &[("links", &render_links(&sync_urls))],
+                );
+                if let Some(prior) = slots.duplicate.get() {
+                    let links = render_links(&[prior.to_string()]);
+                    let warning =
+                        self.messages
+                            .format(lang, "sync_near_duplicate", &[("link", &links)]);
+                    finished = format!("{finished}\n{warning}");
+                }
+                let skipped = slots.skipped.get();
+                if !skipped.is_empty() {
+                    let warning = self.messages.format(
+                        lang,
+                        "sync_skipped_pages",
+                        &[("count", &skipped.len().to_string())],
+                    );
+                    finished = format!("{finished}\n{warning}");
+                }
+                let (Some(publisher), Some(meta)) = (&self.publisher, slots.meta.fetched()) else {
+                    return finished;
+                };
+                match publisher.publish(bot, meta, &sync_urls).await {
+                    Ok(()) if publisher.reply_to_user => finished,
+                    Ok(()) => self.messages.format(lang, "sync_published", &[]),
                     Err(e) => {
-                        format!("Sync to telegraph failed: {}", escape(&e.to_string()))
+                        tracing::warn!("[publish] unable to post {url}: {e}");
+                        let error = escape(&e.to_string());
+                        let failed =
+                            self.messages
+                                .format(lang, "publish_failed", &[("error", &error)]);
+                        format!("{finished}\n{failed}")
                     }
                 }
-            }) => result,
-            _ = &mut cancel_rx => {
-                "Sync operation was cancelled.".to_string()
             }
+            Err(SyncError::Cancelled) => {
+                let key = if self.shutdown.is_closed() {
+                    "sync_interrupted"
+                } else {
+                    "sync_cancelled"
+                };
+                self.messages.format(lang, key, &[])
+            }
+            Err(SyncError::InvalidUrl(url) | SyncError::UnsupportedUrl(url)) => self
+                .messages
+                .format(lang, "sync_unsupported", &[("url", &escape(&url))]),
+            Err(SyncError::NotFound) => self.messages.format(lang, "sync_not_found", &[]),
+            Err(SyncError::RequiresAuth) => self.messages.format(lang, "sync_requires_auth", &[]),
+            Err(SyncError::Proxy(e)) => self.messages.format(
+                lang,
+                "sync_proxy_failed",
+                &[("error", &escape(&format!("{e:#}")))],
+            ),
+            Err(SyncError::Telegraph(e)) => self.messages.format(
+                lang,
+                "sync_telegraph_failed",
+                &[("error", &escape(&format!("{e:#}")))],
+            ),
+            Err(SyncError::TooLarge(SizeLimit::Pages(max))) => {
+                self.messages
+                    .format(lang, "sync_too_many_pages", &[("max", &max.to_string())])
+            }
+            Err(SyncError::TooLarge(SizeLimit::Bytes(max))) => {
+                let size = escape(&format!("{:.1} MiB", max as f64 / (1024.0 * 1024.0)));
+                self.messages
+                    .format(lang, "sync_too_large", &[("size", &size)])
+            }
+            Err(e) => {
+                self.messages
+                    .format(lang, "sync_failed", &[("error", &escape(&e.to_string()))])
+            }
+        }
+    }
+
+    async fn audit(&self, submission: Submission, links: Vec<String>, outcome: Outcome) {
+        let record = AuditRecord::new(submission, links, outcome);
+        if let Err(e) = self.audit.record(&record).await {
+            tracing::warn!("[audit] unable to record {}: {e}", record.source);
         }
     }
 
-    async fn route_sync(&self, url: &str) -> anyhow::Result<String> {
-        let u = Url::parse(url).map_err(|_| anyhow::anyhow!("Invalid url"))?;
-        let host = u.host_str().unwrap_or_default();
-        let path = u.path().to_string();
+    fn format_progress(&self, lang: Option<&str>, progress: SyncProgress) -> String {
+        let (key, page, total, received) = match progress {
+            SyncProgress::Downloading {
+                page,
+                total,
+                received,
+            } => ("progress_downloading", page, total, received),
+            SyncProgress::Uploading { page, total } => ("progress_uploading", page, total, 0),
+        };
+        let total = total.map_or_else(|| "?".to_string(), |t| t.to_string());
+        let received = escape(&format!("{:.1} MiB", received as f64 / (1024.0 * 1024.0)));
+        self.messages.format(
+            lang,
+            key,
+            &[
+                ("page", &page.to_string()),
+                ("total", &total),
+                ("received", &received),
+            ],
+        )
+    }
+
+    async fn route_sync(
+        &self,
+        url: &str,
+        options: UploadOptions,
+        slots: SyncSlots,
+    ) -> Result<Vec<String>, SyncError> {
+        let gallery = GalleryUrl::parse(url, self.synchronizer.registry())?;
+        let options = UploadOptions {
+            meta: Some(slots.meta),
+            duplicate: Some(slots.duplicate),
+            skipped: Some(slots.skipped),
+            ..options
+        };
+        // shared by the requests of the gallery, to find them in the logs of the proxy
+        let request_id = http_proxy::new_request_id();
+        info!("[sync] syncing {url} with request id {request_id}");
+        http_proxy::with_request_id(
+            request_id,
+            self.synchronizer.sync_gallery(&gallery, options),
+        )
+        .await
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            sync: self.synchronizer.metrics(),
+            proxy: self.proxy.metrics(),
+        }
+    }
+
+    fn format_stats(&self, lang: Option<&str>, stats: &Stats) -> String {
+        let sync = &stats.sync;
+        let average = sync
+            .average_sync_time()
+            .map_or_else(|| "-".to_string(), |d| format!("{:.1}s", d.as_secs_f64()));
+        let downloaded = format!(
+            "{:.1} MiB",
+            sync.bytes_downloaded as f64 / (1024.0 * 1024.0)
+        );
+        let args = [
+            ("synced", sync.galleries_synced.to_string()),
+            ("failed", sync.galleries_failed.to_string()),
+            ("cache_hits", sync.cache_hits.to_string()),
+            ("images", sync.images_uploaded.to_string()),
+            ("downloaded", downloaded),
+            ("average", average),
+            ("requests", stats.proxy.total_requests.to_string()),
+            ("proxy_errors", stats.proxy.proxy_errors.to_string()),
+        ]
+        .map(|(name, value)| (name, escape(&value)));
+        let args = args
+            .iter()
+            .map(|(n, v)| (*n, v.as_str()))
+            .collect::<Vec<_>>();
+        self.messages.format(lang, "stats", &args)
+    }
 
-        // TODO: use macro to generate them
-        #[allow(clippy::single_match)]
-        match host {
-            "e-hentai.org" => {
-                info!("[registry] sync e-hentai for path {}", path);
-                self.synchronizer
-                    .sync::<EHCollector>(path)
-                    .await
-                    .map_err(anyhow::Error::from)
+    fn format_queue(&self, lang: Option<&str>, jobs: &[Job<SyncJob>]) -> String {
+        let list = |status: JobStatus| {
+            jobs.iter()
+                .filter(|j| j.status == status)
+                .map(|j| format!("\n#{} {} ({})", j.id, j.payload.url, j.payload.chat_id))
+                .collect::<String>()
+        };
+        let pending = jobs
+            .iter()
+            .filter(|j| j.status == JobStatus::Pending)
+            .count();
+        let args = [
+            ("pending", pending.to_string()),
+            ("running", list(JobStatus::Running)),
+            ("failed", list(JobStatus::Failed)),
+        ]
+        .map(|(name, value)| (name, escape(&value)));
+        let args = args
+            .iter()
+            .map(|(n, v)| (*n, v.as_str()))
+            .collect::<Vec<_>>();
+        self.messages.format(lang, "queue_status", &args)
+    }
+
+    async fn dry_run(&self, url: &str) -> anyhow::Result<DryRunReport> {
+        let gallery = GalleryUrl::parse(url, self.synchronizer.registry())?;
+        self.synchronizer.dry_run_gallery(&gallery).await
+    }
+
+    fn format_dry_run(&self, lang: Option<&str>, report: &DryRunReport) -> String {
+        let mib = |bytes: usize| format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0));
+        let formats = report
+            .formats
+            .iter()
+            .map(|(kind, count)| format!("{kind:?} x{count}"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let oversized = report
+            .oversized
+            .iter()
+            .map(|image| {
+                let prepared = image
+                    .prepared
+                    .iter()
+                    .map(|r| match r {
+                        Ok(size) => mib(*size),
+                        Err(reason) => reason.clone(),
+                    })
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                format!("\n{} ({} -> {prepared})", image.url, mib(image.size))
+            })
+            .collect::<String>();
+        let failed = report
+            .failed
+            .iter()
+            .map(|e| format!("\n{e}"))
+            .collect::<String>();
+        let args = [
+            ("name", report.name.as_str()),
+            ("pages", &report.pages.to_string()),
+            ("size", &mib(report.total_bytes)),
+            ("formats", &formats),
+            ("oversized", &report.oversized.len().to_string()),
+            ("oversized_list", &oversized),
+            ("failed", &report.failed.len().to_string()),
+            ("failed_list", &failed),
+        ]
+        .map(|(name, value)| (name, escape(value)));
+        let args = args
+            .iter()
+            .map(|(n, v)| (*n, v.as_str()))
+            .collect::<Vec<_>>();
+        self.messages.format(lang, "dry_run_report", &args)
+    }
+
+    /// Whether the url itself is in the cache, the content is not looked up.
+    async fn is_cached(&self, url: &str) -> bool {
+        let Ok(gallery) = GalleryUrl::parse(url, self.synchronizer.registry()) else {
+            return false;
+        };
+        matches!(
+            self.synchronizer.cached_gallery(&gallery).await,
+            Ok(Some(_))
+        )
+    }
+
+    /// Links of the gallery if synced before, by the url or else by the content.
+    async fn cached_sync(&self, url: &str) -> anyhow::Result<Option<Vec<String>>> {
+        let gallery = GalleryUrl::parse(url, self.synchronizer.registry())?;
+        if let Some(links) = self.synchronizer.cached_gallery(&gallery).await? {
+            return Ok(Some(links));
+        }
+        // the leading images are downloaded, so it is given up if slow
+        let content = self.synchronizer.cached_gallery_content(&gallery);
+        tokio::time::timeout(CONTENT_LOOKUP_TIMEOUT, content)
+            .await
+            .unwrap_or(Ok(None))
+    }
+}
+
+/// Language of the sender, replies are localized by it.
+fn lang(msg: &Message) -> Option<&str> {
+    msg.from()?.language_code.as_deref()
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+fn render_links(urls: &[String]) -> String {
+    urls.iter()
+        .map(|u| link(u, &escape(u)))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use eh2telegraph::{
+        collector::Registry,
+        mock_server::{MockRequest, MockResponse, MockServer},
+        storage::SimpleMemStorage,
+        telegraph::{Telegraph, TokenPool},
+    };
+
+    use super::*;
+    use crate::{audit::MemorySink, callback::CallbackConfig};
+
+    const GALLERY: &str = r#"{"media_id": "987", "title": {"pretty": "Title"}, "images": {"pages": [{"t": "j"}, {"t": "j"}]}}"#;
+
+    /// The message of every bot API call, replies and edits alike.
+    const BOT_MESSAGE: &str = r#"{"ok":true,"result":{"message_id":2,"date":0,"chat":{"id":-100,"type":"group","title":"t"},"text":"t"}}"#;
+
+    /// The bot API, and nhentai and telegraph reached through the proxy.
+    fn respond(idx: usize, req: &MockRequest) -> MockResponse {
+        if req.path.starts_with("/bottoken/") {
+            return MockResponse::new(200, BOT_MESSAGE);
+        }
+        match req.header("x-forwarded-for").unwrap_or_default() {
+            t if t.starts_with("https://nhentai.net/api/gallery/") => {
+                MockResponse::new(200, GALLERY)
+            }
+            t if t.ends_with("/createPage") => MockResponse::new(
+                200,
+                r#"{"ok":true,"result":{"path":"p","url":"https://telegra.ph/p","title":"t","description":"","views":0}}"#,
+            ),
+            t if t.contains(".nhentai.net/galleries/") => {
+                MockResponse::new(200, format!("\u{FF}\u{D8}\u{FF}image-{idx}"))
+            }
+            _ => MockResponse::new(200, format!("https://files.catbox.moe/{idx}.jpg")),
+        }
+    }
+
+    /// A handler syncing through `server`, without the config.
+    fn handler(server: &MockServer) -> Handler<SimpleMemStorage<String>> {
+        let proxy = ProxiedClient::new(&server.url("/"), "token").unwrap();
+        let tg = Telegraph::<TokenPool>::new(vec!["token".to_string()]).with_proxy(proxy.clone());
+        let registry = Registry::default().with_proxy(proxy.clone());
+        let synchronizer = Synchronizer::new(tg, registry, SimpleMemStorage::default());
+        Handler {
+            synchronizer,
+            searcher: SaucenaoSearcher::new(proxy.clone()),
+            convertor: FHashConvertor::with_fetchers(
+                Arc::new(reqwest::Client::new()),
+                Arc::new(reqwest::Client::new()),
+            ),
+            admins: HashSet::new(),
+            whitelist: HashSet::from([i64::MIN]),
+            denylist: HashSet::new(),
+            rate_limit: None,
+            messages: Messages::default(),
+            shutdown: Shutdown::default(),
+            sync_workers: DEFAULT_WORKERS,
+            queue: JobQueue::default().with_owner(SyncJob::owner),
+            publisher: None,
+            proxy,
+            audit: Arc::new(NoopSink),
+            callback: None,
+            active_syncs: Arc::new(Mutex::new(HashMap::new())),
+            batches: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// A message of user 42 in group -100.
+    fn message(text: &str) -> Message {
+        serde_json::from_value(serde_json::json!({
+            "message_id": 1,
+            "date": 0,
+            "chat": {"id": -100, "type": "group", "title": "t"},
+            "from": {"id": 42, "is_bot": false, "first_name": "u"},
+            "text": text,
+        }))
+        .unwrap()
+    }
+
+    fn bot(server: &MockServer) -> DefaultParseMode<Bot> {
+        let api = reqwest::Url::parse(&server.url("/")).unwrap();
+        Bot::new("token")
+            .set_api_url(api)
+            .parse_mode(ParseMode::MarkdownV2)
+    }
+
+    #[tokio::test]
+    async fn test_audit() {
+        let server = MockServer::start(respond).await;
+        let sink = Arc::new(MemorySink::default());
+        let handler = Handler {
+            audit: sink.clone(),
+            ..handler(&server)
+        };
+        let bot = bot(&server);
+        let sync = |url: &'static str, cancel: CancellationToken| {
+            let submission = Submission {
+                user_id: Some(42),
+                chat_id: -100,
+                source: url.to_string(),
+            };
+            let (progress, _) = ProgressReporter::channel();
+            let options = UploadOptions {
+                progress: Some(progress),
+                cancel: Some(cancel),
+                ..Default::default()
+            };
+            handler.sync_response(&bot, url, None, submission, options)
+        };
+
+        let reply = sync("https://nhentai.net/g/1/", CancellationToken::new()).await;
+        assert!(reply.error.is_none());
+        let reply = sync("https://example.com/g/1/", CancellationToken::new()).await;
+        assert!(matches!(reply.error, Some(SyncError::UnsupportedUrl(_))));
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let reply = sync("https://nhentai.net/g/2/", cancel).await;
+        assert!(matches!(reply.error, Some(SyncError::Cancelled)));
+
+        let records = sink.0.lock().unwrap().clone();
+        let outcomes = records
+            .iter()
+            .map(|r| (r.source.as_str(), r.links.clone(), &r.outcome))
+            .collect::<Vec<_>>();
+        assert_eq!(outcomes[0].0, "https://nhentai.net/g/1/");
+        assert_eq!(outcomes[0].1, ["https://telegra.ph/p"]);
+        assert_eq!(outcomes[0].2, &Outcome::Synced);
+        assert!(matches!(outcomes[1].2, Outcome::Failed { .. }));
+        assert!(outcomes[1].1.is_empty());
+        assert_eq!(outcomes[2].2, &Outcome::Cancelled);
+        assert!(records
+            .iter()
+            .all(|r| r.user_id == Some(42) && r.chat_id == -100));
+    }
+
+    /// A handler with one worker, sending callbacks to `https://hooks.example/cb`.
+    fn callback_handler(server: &MockServer) -> &'static Handler<SimpleMemStorage<String>> {
+        let config = CallbackConfig {
+            url: "https://hooks.example/cb".to_string(),
+            secret: None,
+            max_attempts: None,
+        };
+        let handler = handler(server);
+        Box::leak(Box::new(Handler {
+            callback: Some(Callback::new(config, handler.proxy.clone())),
+            sync_workers: 1,
+            queue: JobQueue::default()
+                .with_owner(SyncJob::owner)
+                .with_backoff(Duration::from_millis(10)),
+            ..handler
+        }))
+    }
+
+    /// Run the queued jobs until all of them are done, then wait for the callbacks.
+    async fn run_jobs(
+        handler: &'static Handler<SimpleMemStorage<String>>,
+        server: &MockServer,
+    ) -> Vec<CallbackPayload> {
+        handler.spawn_workers(bot(server));
+        let done = async {
+            loop {
+                let jobs = handler.queue.snapshot().await;
+                if jobs.iter().all(|j| j.status == JobStatus::Done) {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
             }
-            "nhentai.to" | "nhentai.net" => {
-                info!("[registry] sync nhentai for path {}", path);
-                self.synchronizer
-                    .sync::<NHCollector>(path)
-                    .await
-                    .map_err(anyhow::Error::from)
+        };
+        tokio::time::timeout(Duration::from_secs(10), done)
+            .await
+            .unwrap();
+        // sent in background and waited for on shutdown
+        handler.shutdown.drain(Duration::from_secs(5)).await;
+        server
+            .requests()
+            .iter()
+            .filter(|r| r.header("x-forwarded-for") == Some("https://hooks.example/cb"))
+            .map(|r| serde_json::from_slice::<CallbackPayload>(&r.body).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_callback() {
+        let server = MockServer::start(respond).await;
+        let handler = callback_handler(&server);
+        let url = "https://nhentai.net/g/1/";
+        for _ in 0..2 {
+            handler
+                .start_sync(bot(&server), &message(""), url.to_string(), None, false)
+                .await
+                .unwrap();
+        }
+        let payloads = run_jobs(handler, &server).await;
+        assert_eq!(payloads.len(), 2);
+        // the second one is a cache hit
+        assert!(payloads.iter().all(|p| p.page_count == Some(2)));
+        assert!(payloads.iter().all(|p| p.outcome == Outcome::Synced));
+        assert_eq!(handler.stats().sync.cache_hits, 1);
+    }
+
+    #[tokio::test]
+    async fn test_callback_retried() {
+        // the first fetch of the gallery fails, so the job is queued again
+        let fetched = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let server = MockServer::start({
+            let fetched = fetched.clone();
+            move |idx, req| {
+                let target = req.header("x-forwarded-for").unwrap_or_default();
+                match target.starts_with("https://nhentai.net/api/gallery/")
+                    && !fetched.swap(true, std::sync::atomic::Ordering::Relaxed)
+                {
+                    true => MockResponse::new(200, "not json"),
+                    false => respond(idx, req),
+                }
             }
-            "exhentai.org" => {
-                info!("[registry] sync exhentai for path {}", path);
-                self.synchronizer
-                    .sync::<EXCollector>(path)
-                    .await
-                    .map_err(anyhow::Error::from)
+        })
+        .await;
+        let handler = callback_handler(&server);
+        handler
+            .start_sync(
+                bot(&server),
+                &message(""),
+                "https://nhentai.net/g/1/".to_string(),
+                None,
+                false,
+            )
+            .await
+            .unwrap();
+        let payloads = run_jobs(handler, &server).await;
+        let jobs = handler.queue.snapshot().await;
+        assert_eq!(jobs[0].attempts, 2);
+        // only once it is finished
+        assert_eq!(payloads.len(), 1);
+        assert_eq!(payloads[0].outcome, Outcome::Synced);
+        assert_eq!(payloads[0].links, ["https://telegra.ph/p"]);
+    }
+
+    #[tokio::test]
+    async fn test_batch() {
+        let server = MockServer::start(respond).await;
+        let handler = handler(&server);
+        let key = (-100, 7);
+        let batch = Batch {
+            lang: None,
+            pending: 3,
+            synced: 0,
+            failed: Vec::new(),
+        };
+        handler.batches.lock().unwrap().insert(key, batch);
+
+        assert!(handler.record_batch(key, Some(None), "a").is_none());
+        // not queued
+        assert!(handler.record_batch(key, None, "").is_none());
+        let removed = "The gallery is not found.".to_string();
+        let batch = handler
+            .record_batch(key, Some(Some(removed)), "b&c")
+            .unwrap();
+        assert!(handler.batches.lock().unwrap().is_empty());
+        assert_eq!(
+            handler.format_batch(&batch),
+            "Synced 1 of 2 galleries\\.\nb&c: The gallery is not found."
+        );
+        // unknown, like after a restart
+        assert!(handler.record_batch(key, Some(None), "a").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_cached() {
+        let server = MockServer::start(respond).await;
+        let handler: &'static _ = Box::leak(Box::new(Handler {
+            rate_limit: Some(RateLimit {
+                per_hour: 1,
+                burst: None,
+            }),
+            ..handler(&server)
+        }));
+        let bot = bot(&server);
+        let msg = message("");
+        let cached = "https://nhentai.net/g/1/";
+        let submission = Submission {
+            user_id: Some(42),
+            chat_id: -100,
+            source: cached.to_string(),
+        };
+        let sync = handler.sync_response(&bot, cached, None, submission, Default::default());
+        assert!(sync.await.error.is_none());
+
+        let start = |url: &str| handler.start_sync(bot.clone(), &msg, url.to_string(), None, false);
+        // cache hits take no token
+        assert!(start(cached).await.unwrap());
+        assert!(start("https://nhentai.net/g/2/").await.unwrap());
+        assert!(!start("https://nhentai.net/g/3/").await.unwrap());
+        assert!(start(cached).await.unwrap());
+        let queued = handler.queue.snapshot().await;
+        let urls = queued
+            .iter()
+            .map(|j| j.payload.url.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(urls, [cached, "https://nhentai.net/g/2/", cached]);
+    }
+
+    #[tokio::test]
+    async fn test_workers() {
+        let server = MockServer::start(respond).await;
+        let handler: &'static _ = Box::leak(Box::new(Handler {
+            sync_workers: 1,
+            ..handler(&server)
+        }));
+        let bot = bot(&server);
+        let urls = (1..=3)
+            .map(|id| format!("https://nhentai.net/g/{id}/"))
+            .collect();
+        handler
+            .start_syncs(bot.clone(), &message(""), urls)
+            .await
+            .unwrap();
+        handler.spawn_workers(bot);
+        // the summary is replied after the last one is done
+        let summary =
+            |req: &MockRequest| String::from_utf8_lossy(&req.body).contains("Synced 3 of 3");
+        let done = async {
+            while !server.requests().iter().any(summary) {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(10), done)
+            .await
+            .unwrap();
+        handler.shutdown.close();
+        let jobs = handler.queue.snapshot().await;
+        assert!(jobs.iter().all(|j| j.status == JobStatus::Done));
+
+        // with one worker each gallery is fetched after the previous one is uploaded
+        let requests = server.requests();
+        let mut uploaded = 0;
+        let mut fetched = 0;
+        for req in &requests {
+            match req.header("x-forwarded-for").unwrap_or_default() {
+                t if t.ends_with("/createPage") => uploaded += 1,
+                t if t.starts_with("https://nhentai.net/api/gallery/") => {
+                    assert_eq!(uploaded, fetched);
+                    fetched += 1;
+                }
+                _ => {}
             }
-            _ => Err(anyhow::anyhow!("no matching collector")),
         }
+        assert_eq!((fetched, uploaded), (3, 3));
+    }
+
+    #[tokio::test]
+    async fn test_cancel() {
+        // the images take long, so the sync is still running
+        let server = MockServer::start(|idx, req| {
+            let target = req.header("x-forwarded-for").unwrap_or_default();
+            match target.contains(".nhentai.net/galleries/") {
+                true => respond(idx, req).delay(Duration::from_secs(30)),
+                false => respond(idx, req),
+            }
+        })
+        .await;
+        let handler: &'static _ = Box::leak(Box::new(handler(&server)));
+        let bot = bot(&server);
+        let url = "https://nhentai.net/g/1/";
+        // to the bot API, or to the target of the proxy
+        let sent = |text: &'static str| {
+            move |req: &MockRequest| {
+                String::from_utf8_lossy(&req.body).contains(text)
+                    || req
+                        .header("x-forwarded-for")
+                        .unwrap_or_default()
+                        .contains(text)
+            }
+        };
+        let requests = || server.requests();
+        let wait = |text: &'static str| async move {
+            let found = async {
+                while !requests().iter().any(sent(text)) {
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                }
+            };
+            tokio::time::timeout(Duration::from_secs(10), found)
+                .await
+                .unwrap();
+        };
+        handler
+            .start_sync(bot.clone(), &message(""), url.to_string(), None, false)
+            .await
+            .unwrap();
+        handler.spawn_workers(bot.clone());
+        wait(".nhentai.net/galleries/").await;
+        assert!(handler.is_syncing(url));
+
+        let cancel = handler.respond_cmd(bot.clone(), message("/cancel"), Command::Cancel);
+        assert!(cancel.await.is_break());
+        wait("Cancelled 1 sync operations").await;
+        // the status message is edited to tell it
+        wait("Sync operation was cancelled").await;
+        assert!(!handler.is_syncing(url));
+        let jobs = handler.queue.snapshot().await;
+        assert!(jobs.iter().all(|j| j.status == JobStatus::Failed));
+        assert!(!server.requests().iter().any(|r| r
+            .header("x-forwarded-for")
+            .unwrap_or_default()
+            .ends_with("/createPage")));
+
+        // nothing left to cancel
+        let cancel = handler.respond_cmd(bot.clone(), message("/cancel"), Command::Cancel);
+        assert!(cancel.await.is_break());
+        wait("No active sync operations to cancel").await;
+        handler.shutdown.close();
+    }
+
+    #[tokio::test]
+    async fn test_force() {
+        let server = MockServer::start(respond).await;
+        let handler = handler(&server);
+        let bot = bot(&server);
+        let url = "https://nhentai.net/g/1/";
+        let created = || {
+            server
+                .requests()
+                .iter()
+                .filter(|r| {
+                    r.header("x-forwarded-for")
+                        .unwrap_or_default()
+                        .ends_with("/createPage")
+                })
+                .count()
+        };
+        let sync = |force: bool| {
+            let submission = Submission {
+                user_id: Some(42),
+                chat_id: -100,
+                source: url.to_string(),
+            };
+            let options = UploadOptions {
+                force,
+                ..Default::default()
+            };
+            handler.sync_response(&bot, url, None, submission, options)
+        };
+
+        assert!(sync(false).await.error.is_none());
+        assert_eq!(created(), 1);
+        // cached
+        assert!(sync(false).await.error.is_none());
+        assert_eq!(created(), 1);
+        assert!(sync(true).await.error.is_none());
+        assert_eq!(created(), 2);
     }
 }
@@ -0,0 +1,137 @@
+//! Reply messages in the language of the user.
+
+use std::collections::HashMap;
+
+use teloxide::utils::markdown::escape;
+
+const DEFAULT_LOCALE: &str = "en";
+const LOCALES: &[(&str, &str)] = &[
+    ("en", include_str!("../i18n/en.yaml")),
+    ("zh", include_str!("../i18n/zh.yaml")),
+];
+
+/// Keyed message templates of all locales.
+/// Keys missing in a locale fall back to the default locale, then the key itself.
+#[derive(Debug)]
+pub struct Messages {
+    locales: HashMap<String, HashMap<String, String>>,
+    default: String,
+}
+
+impl Default for Messages {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl Messages {
+    /// Load the embedded locales, `default` falls back to English if not supported.
+    pub fn new(default: Option<&str>) -> Self {
+        Self::from_sources(LOCALES, default.unwrap_or(DEFAULT_LOCALE))
+            .expect("embedded messages must be valid")
+    }
+
+    fn from_sources(sources: &[(&str, &str)], default: &str) -> anyhow::Result<Self> {
+        let mut locales = HashMap::with_capacity(sources.len());
+        for (locale, content) in sources {
+            locales.insert(locale.to_string(), serde_yaml::from_str(content)?);
+        }
+        let mut messages = Self {
+            locales,
+            default: DEFAULT_LOCALE.to_string(),
+        };
+        match messages.locale(Some(default)) {
+            Some(locale) => messages.default = locale.to_string(),
+            None => tracing::warn!("[i18n] unsupported default locale {default}, use English"),
+        }
+        Ok(messages)
+    }
+
+    /// Supported locale of a language code like `zh-hans`, matched by the primary subtag.
+    fn locale<'a>(&'a self, lang: Option<&str>) -> Option<&'a str> {
+        let lang = lang?.to_ascii_lowercase().replace('_', "-");
+        let primary = lang.split('-').next().unwrap_or_default();
+        let locale = [lang.as_str(), primary]
+            .into_iter()
+            .find_map(|l| self.locales.get_key_value(l))
+            .map(|(k, _)| k.as_str());
+        locale
+    }
+
+    /// The raw template in the language, or the default one.
+    pub fn get<'a>(&'a self, lang: Option<&str>, key: &'a str) -> &'a str {
+        self.locale(lang)
+            .into_iter()
+            .chain([self.default.as_str()])
+            .find_map(|l| self.locales.get(l)?.get(key))
+            .map(String::as_str)
+            .unwrap_or(key)
+    }
+
+    /// Render the template as MarkdownV2, `args` are inserted as is, so plain
+    /// values must be escaped by the caller.
+    pub fn format(&self, lang: Option<&str>, key: &str, args: &[(&str, &str)]) -> String {
+        // placeholders are escaped with the template
+        let mut text = escape(self.get(lang, key));
+        for (name, value) in args {
+            text = text.replace(&format!("\\{{{name}\\}}"), value);
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fallback() {
+        let sources = [
+            ("en", "hello: Hello {name}!\nbye: Bye."),
+            ("zh", "hello: 你好 {name}！"),
+        ];
+        let messages = Messages::from_sources(&sources, "en").unwrap();
+        assert_eq!(messages.get(Some("zh-hans"), "hello"), "你好 {name}！");
+        assert_eq!(messages.get(Some("en-US"), "hello"), "Hello {name}!");
+        // missing in the locale, missing locale and missing everywhere
+        assert_eq!(messages.get(Some("zh"), "bye"), "Bye.");
+        assert_eq!(messages.get(Some("ja"), "bye"), "Bye.");
+        assert_eq!(messages.get(None, "bye"), "Bye.");
+        assert_eq!(messages.get(Some("zh"), "unknown"), "unknown");
+
+        let messages = Messages::from_sources(&sources, "zh-CN").unwrap();
+        assert_eq!(messages.get(Some("ja"), "hello"), "你好 {name}！");
+        let messages = Messages::from_sources(&sources, "ja").unwrap();
+        assert_eq!(messages.get(None, "hello"), "Hello {name}!");
+    }
+
+    #[test]
+    fn test_format() {
+        let messages = Messages::default();
+        assert_eq!(
+            messages.format(
+                Some("en"),
+                "sync_started",
+                &[("url", "https://e\\-hentai\\.org")]
+            ),
+            "Syncing url https://e\\-hentai\\.org"
+        );
+        assert_eq!(
+            messages.format(Some("en"), "chat_id", &[("id", "`1`")]),
+            "Current chat id is `1` \\(in private chat this is your account id\\)"
+        );
+    }
+
+    #[test]
+    fn test_embedded_locales() {
+        let messages = Messages::default();
+        let keys = |locale: &str| {
+            let mut keys = messages.locales[locale].keys().collect::<Vec<_>>();
+            keys.sort();
+            keys
+        };
+        for (locale, _) in LOCALES {
+            assert_eq!(keys(locale), keys(DEFAULT_LOCALE), "{locale}");
+        }
+    }
+}
@@ -1,37 +1,51 @@
-#![feature(control_flow_enum)]
-
 use eh2telegraph::{
-    collector::Registry,
+    collector::{exhentai::EXCollector, Param, Registry},
     config::{self},
     http_proxy::ProxiedClient,
-    storage,
-    sync::Synchronizer,
+    phash::NearDuplicate,
+    reencode::ImageReencoder,
+    slicer::ImageSlicer,
+    sniff::TypeAllowlist,
+    storage::{self, queue::JobQueue, KVStorage},
+    sync::{SyncLimits, Synchronizer},
     telegraph::Telegraph,
+    title::TitleTemplate,
 };
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
 use once_cell::sync::OnceCell;
 use teloxide::{
     adaptors::DefaultParseMode,
     error_handlers::IgnoringErrorHandler,
     prelude::*,
-    types::{AllowedUpdate, ChatPermissions, ParseMode, UpdateKind},
+    types::{AllowedUpdate, ChatPermissions, InlineQuery, ParseMode, UpdateKind},
     update_listeners,
 };
 use tracing::level_filters::LevelFilter;
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
-use handler::{Command, Handler};
+use handler::{Command, Handler, SyncJob};
+use webhook::{BotConfig, BotMode};
 
 use crate::{
     handler::AdminCommand,
     util::{wrap_endpoint, PrettyChat},
 };
 
+mod audit;
+mod callback;
+mod flood;
 mod handler;
+mod i18n;
+mod pool;
+mod publish;
+mod shutdown;
+mod stats;
 mod util;
+mod validate;
 mod version;
+mod webhook;
 
 #[derive(Debug, serde::Deserialize)]
 pub struct BaseConfig {
@@ -46,13 +60,69 @@ pub struct TelegraphConfig {
     pub tokens: Vec<String>,
     pub author_name: Option<String>,
     pub author_url: Option<String>,
+    pub upload_concurrency: Option<usize>,
+    /// Images larger than this are re-encoded before uploading.
+    pub reencode_threshold: Option<usize>,
+    /// JPEG quality of re-encoded images and of the segments of the slicer.
+    pub reencode_quality: Option<u8>,
+    /// Images wider than this are downscaled.
+    pub max_image_width: Option<u32>,
+    /// Images taller than this are sliced if `split_tall_images`, downscaled otherwise.
+    pub max_image_height: Option<u32>,
+    #[serde(default)]
+    pub split_tall_images: bool,
+    /// MIME types of the images uploaded, JPEG, PNG, GIF and WebP if not set.
+    pub allowed_image_types: Option<Vec<String>>,
+    /// Like `{title} [{artist}] ({pages}p)`, see `eh2telegraph::title`.
+    pub title_template: Option<String>,
+    /// Base url of the API calls, `https://api.telegra.ph` if not set.
+    pub api_base: Option<String>,
+    /// Put the gallery metadata after the cover image, disabled if not set.
+    pub metadata_header: Option<bool>,
+    /// End each page with the capture date and `footer_text`, disabled if not set.
+    pub footer: Option<bool>,
+    /// Extra line of the footer.
+    pub footer_text: Option<String>,
 }
 
 #[derive(Parser, Debug)]
 #[clap(author, version=version::VERSION, about, long_about = "eh2telegraph sync bot")]
 struct Args {
-    #[clap(short, long, help = "Config file path")]
+    #[clap(
+        short,
+        long,
+        help = "Config file path(.yaml, .toml or .json), EH2TG_CONFIG env is used if not set"
+    )]
     config: Option<String>,
+    #[clap(subcommand)]
+    command: Option<SubCommand>,
+}
+
+#[derive(Subcommand, Debug)]
+enum SubCommand {
+    /// Request a page through the configured proxy and report how it went, then exit.
+    TestProxy {
+        #[clap(long, default_value = "https://e-hentai.org/")]
+        url: String,
+    },
+}
+
+/// Exits with 1 if the page can not be loaded.
+async fn test_proxy(url: &str) -> ! {
+    let proxy = match ProxiedClient::try_from_config() {
+        Ok(Some(proxy)) => proxy,
+        Ok(None) => {
+            println!("no proxy is configured");
+            ProxiedClient::default()
+        }
+        Err(e) => {
+            println!("invalid proxy config: {e}");
+            std::process::exit(1);
+        }
+    };
+    let report = proxy.probe(url).await;
+    println!("{report}");
+    std::process::exit(if report.is_ok() { 0 } else { 1 });
 }
 
 static PROCESS_MESSAGE_DATE: OnceCell<chrono::DateTime<chrono::Utc>> = OnceCell::new();
@@ -83,26 +153,149 @@ async fn main() {
     tracing::info!("initializing...");
 
     config::init(args.config);
+    // the rest of the config is not needed to check the proxy
+    if let Some(SubCommand::TestProxy { url }) = args.command {
+        test_proxy(&url).await;
+    }
+    let errors = validate::validate(config::global());
+    if !errors.is_empty() {
+        tracing::error!("{errors}");
+        std::process::exit(1);
+    }
     let base_config: BaseConfig = config::parse("base")
         .expect("unable to parse base config")
         .expect("base config can not be empty");
     let telegraph_config = base_config.telegraph;
-    let telegraph =
-        Telegraph::new(telegraph_config.tokens).with_proxy(ProxiedClient::new_from_config());
+    let bot_config: BotConfig = config::parse("bot")
+        .expect("unable to parse bot config")
+        .unwrap_or_default();
+    // the only proxied client, everything below gets a clone of it
+    let proxy = ProxiedClient::new_from_config();
+    #[cfg(feature = "hot-reload")]
+    eh2telegraph::http_proxy::spawn_config_watcher(
+        proxy.clone(),
+        std::time::Duration::from_secs(10),
+    );
+    let mut telegraph = Telegraph::new(telegraph_config.tokens).with_proxy(proxy.clone());
+    if let Some(concurrency) = telegraph_config.upload_concurrency {
+        telegraph = telegraph.with_upload_concurrency(concurrency);
+    }
+    if let Some(api_base) = &telegraph_config.api_base {
+        telegraph = telegraph.with_api_base(api_base);
+    }
 
-    let registry = Registry::new_from_config();
+    let mut registry = Registry::new_from_config().with_proxy(proxy.clone());
+    if let Some(image_cache) =
+        storage::image_cache::ImageCache::new_from_config().expect("unable to open image cache")
+    {
+        registry = registry.with_image_cache(image_cache);
+    }
+    if EXCollector::preflight_enabled().expect("unable to parse exhentai config") {
+        let collector: &EXCollector = registry.get();
+        match collector.check_session().await {
+            Ok(()) => tracing::info!("exhentai cookies are valid"),
+            Err(e) => tracing::warn!("exhentai cookies are not accepted, update them: {e}"),
+        }
+    }
     #[cfg(debug_assertions)]
-    let cache = storage::SimpleMemStorage::default();
-    #[cfg(not(debug_assertions))]
+    let cache = storage::SimpleMemStorage::new_from_config();
+    #[cfg(all(not(debug_assertions), feature = "redis"))]
+    let cache =
+        storage::redis::RedisStorage::new_from_config().expect("unable to build redis storage");
+    #[cfg(all(not(debug_assertions), not(feature = "redis"), feature = "sqlite"))]
+    let cache =
+        storage::sqlite::SqliteStorage::new_from_config().expect("unable to open sqlite storage");
+    #[cfg(all(not(debug_assertions), not(feature = "redis"), not(feature = "sqlite")))]
     let cache = storage::cloudflare_kv::CFOrMemStorage::new_from_config();
+    // fail fast rather than on the first sync
+    if let Err(e) = KVStorage::<String>::get(&cache, "eh2telegraph|startup").await {
+        tracing::error!("storage is unreachable: {e:#}");
+        std::process::exit(1);
+    }
     let mut synchronizer = Synchronizer::new(telegraph, registry, cache);
-    if telegraph_config.author_name.is_some() {
+    if telegraph_config.author_name.is_some() || telegraph_config.author_url.is_some() {
         synchronizer =
             synchronizer.with_author(telegraph_config.author_name, telegraph_config.author_url);
     }
+    if telegraph_config.reencode_threshold.is_some() || telegraph_config.reencode_quality.is_some()
+    {
+        let default = ImageReencoder::default();
+        synchronizer = synchronizer.with_reencoder(Some(ImageReencoder::new(
+            telegraph_config
+                .reencode_threshold
+                .unwrap_or(default.threshold),
+            telegraph_config.reencode_quality.unwrap_or(default.quality),
+        )));
+    }
+    if telegraph_config.max_image_width.is_some() || telegraph_config.max_image_height.is_some() {
+        let slicer = ImageSlicer::new(
+            telegraph_config.max_image_width,
+            telegraph_config.max_image_height,
+            telegraph_config.split_tall_images,
+        );
+        let slicer = match telegraph_config.reencode_quality {
+            Some(quality) => slicer.with_quality(quality),
+            None => slicer,
+        };
+        synchronizer = synchronizer.with_slicer(Some(slicer));
+    }
+    // checked by validate
+    if let Some(allowlist) = telegraph_config
+        .allowed_image_types
+        .as_deref()
+        .and_then(|types| TypeAllowlist::from_mimes(types).ok())
+    {
+        synchronizer = synchronizer.with_allowlist(allowlist);
+    }
+
+    synchronizer = synchronizer.with_metadata_header(telegraph_config.metadata_header);
+    if telegraph_config.footer.is_some() || telegraph_config.footer_text.is_some() {
+        synchronizer =
+            synchronizer.with_footer(telegraph_config.footer, telegraph_config.footer_text);
+    }
+    if let Some(template) = telegraph_config.title_template {
+        synchronizer = synchronizer.with_title_template(Some(TitleTemplate::new(template)));
+    }
+    if let Some(limits) = config::parse::<SyncLimits>("limits").expect("unable to parse limits") {
+        synchronizer = synchronizer.with_limits(limits);
+    }
+    let near_duplicate =
+        NearDuplicate::from_config().expect("unable to parse near duplicate config");
+    synchronizer = synchronizer.with_near_duplicate(near_duplicate);
 
     let admins = base_config.admins.into_iter().collect();
-    let handler = Box::leak(Box::new(Handler::new(synchronizer, admins))) as &Handler<_>;
+    let mut handler = Handler::new(synchronizer, admins, proxy);
+    handler.audit = audit::sink_from_config().expect("unable to open audit log");
+    handler.callback = callback::Callback::from_config(handler.proxy.clone())
+        .expect("unable to parse callback config");
+    handler.messages = i18n::Messages::new(bot_config.default_locale.as_deref());
+    if let Some(workers) = bot_config.sync_workers {
+        handler.sync_workers = workers;
+    }
+    // checked by validate
+    if let Some(channel) = bot_config
+        .publish_channel
+        .as_deref()
+        .and_then(publish::parse_channel)
+    {
+        let mut publisher = publish::Publisher::new(channel);
+        publisher.reply_to_user = bot_config.reply_to_user.unwrap_or(true);
+        handler.publisher = Some(publisher);
+    }
+    // syncs left by the last run are taken again
+    handler.queue = JobQueue::new_from_config()
+        .expect("unable to open the job queue")
+        .with_owner(SyncJob::owner);
+    let handler = Box::leak(Box::new(handler)) as &Handler<_>;
+    if let Some(bind) = bot_config.metrics_bind {
+        match stats::serve(bind, move || handler.stats()) {
+            Ok((addr, server)) => {
+                tracing::info!("[stats] serving metrics at http://{addr}/metrics");
+                tokio::spawn(server);
+            }
+            Err(e) => tracing::error!("[stats] unable to serve metrics at {bind}: {e}"),
+        }
+    }
 
     // === Bot related ===
     let command_handler = move |bot: DefaultParseMode<Bot>, message: Message, command: Command| async move {
@@ -124,6 +317,9 @@ async fn main() {
     let default_handler = move |bot: DefaultParseMode<Bot>, message: Message| async move {
         handler.respond_default(bot, message).await
     };
+    let inline_handler = move |bot: DefaultParseMode<Bot>, query: InlineQuery| async move {
+        handler.respond_inline(bot, query).await
+    };
     let permission_filter = |bot: DefaultParseMode<Bot>, message: Message| async move {
         // If the bot is blocked, we will leave chat and not respond.
         let blocked = message
@@ -136,7 +332,7 @@ async fn main() {
                 "[permission filter] leave chat {:?}",
                 PrettyChat(&message.chat)
             );
-            let _ = bot.leave_chat(message.chat.id).await;
+            let _ = flood::send(bot.leave_chat(message.chat.id)).await;
             None
         } else {
             Some(message)
@@ -154,67 +350,78 @@ async fn main() {
     };
 
     let bot = Bot::new(base_config.bot_token).parse_mode(ParseMode::MarkdownV2);
+    handler.spawn_workers(bot.clone());
     let mut bot_dispatcher = Dispatcher::builder(
         bot.clone(),
         dptree::entry()
-            .chain(dptree::filter_map(move |update: Update| {
-                match update.kind {
-                    UpdateKind::Message(x) | UpdateKind::EditedMessage(x) => Some(x),
-                    _ => None,
-                }
-            }))
-            .chain(dptree::filter_map_async(time_filter))
-            .chain(dptree::filter_map_async(permission_filter))
-            .branch(
-                dptree::entry()
-                    .chain(dptree::filter(move |message: Message| {
-                        handler.admins.contains(&message.chat.id.0)
-                    }))
-                    .filter_command::<AdminCommand>()
-                    .branch(wrap_endpoint(admin_command_handler)),
-            )
-            .branch(
-                dptree::entry()
-                    .filter_command::<Command>()
-                    .branch(wrap_endpoint(command_handler)),
-            )
-            .branch(
-                dptree::entry()
-                    .chain(dptree::filter_map(move |message: Message| {
-                        // Ownership mechanism does not allow using map.
-                        #[allow(clippy::manual_map)]
-                        match message.text() {
-                            Some(v) if !v.is_empty() => Some(message),
-                            _ => None,
-                        }
-                    }))
-                    .branch(wrap_endpoint(text_handler)),
-            )
             .branch(
-                dptree::entry()
-                    .chain(dptree::filter_map(move |message: Message| {
-                        // Ownership mechanism does not allow using map.
-                        #[allow(clippy::manual_map)]
-                        match message.caption_entities() {
-                            Some(v) if !v.is_empty() => Some(message),
-                            _ => None,
-                        }
-                    }))
-                    .branch(wrap_endpoint(caption_handler)),
+                dptree::filter_map(move |update: Update| match update.kind {
+                    UpdateKind::InlineQuery(x) => Some(x),
+                    _ => None,
+                })
+                .branch(wrap_endpoint(inline_handler)),
             )
             .branch(
                 dptree::entry()
-                    .chain(dptree::filter_map(move |message: Message| {
-                        // Ownership mechanism does not allow using map.
-                        #[allow(clippy::manual_map)]
-                        match message.photo() {
-                            Some(v) if !v.is_empty() => Some(message),
+                    .chain(dptree::filter_map(move |update: Update| {
+                        match update.kind {
+                            UpdateKind::Message(x) | UpdateKind::EditedMessage(x) => Some(x),
                             _ => None,
                         }
                     }))
-                    .branch(wrap_endpoint(photo_handler)),
-            )
-            .branch(wrap_endpoint(default_handler)),
+                    .chain(dptree::filter_map_async(time_filter))
+                    .chain(dptree::filter_map_async(permission_filter))
+                    .branch(
+                        dptree::entry()
+                            .chain(dptree::filter(move |message: Message| {
+                                handler.admins.contains(&message.chat.id.0)
+                            }))
+                            .filter_command::<AdminCommand>()
+                            .branch(wrap_endpoint(admin_command_handler)),
+                    )
+                    .branch(
+                        dptree::entry()
+                            .filter_command::<Command>()
+                            .branch(wrap_endpoint(command_handler)),
+                    )
+                    .branch(
+                        dptree::entry()
+                            .chain(dptree::filter_map(move |message: Message| {
+                                // Ownership mechanism does not allow using map.
+                                #[allow(clippy::manual_map)]
+                                match message.text() {
+                                    Some(v) if !v.is_empty() => Some(message),
+                                    _ => None,
+                                }
+                            }))
+                            .branch(wrap_endpoint(text_handler)),
+                    )
+                    .branch(
+                        dptree::entry()
+                            .chain(dptree::filter_map(move |message: Message| {
+                                // Ownership mechanism does not allow using map.
+                                #[allow(clippy::manual_map)]
+                                match message.caption_entities() {
+                                    Some(v) if !v.is_empty() => Some(message),
+                                    _ => None,
+                                }
+                            }))
+                            .branch(wrap_endpoint(caption_handler)),
+                    )
+                    .branch(
+                        dptree::entry()
+                            .chain(dptree::filter_map(move |message: Message| {
+                                // Ownership mechanism does not allow using map.
+                                #[allow(clippy::manual_map)]
+                                match message.photo() {
+                                    Some(v) if !v.is_empty() => Some(message),
+                                    _ => None,
+                                }
+                            }))
+                            .branch(wrap_endpoint(photo_handler)),
+                    )
+                    .branch(wrap_endpoint(default_handler)),
+            ),
     )
     .default_handler(Box::new(|_upd| {
         #[cfg(debug_assertions)]
@@ -222,18 +429,45 @@ async fn main() {
         Box::pin(async {})
     }))
     .error_handler(std::sync::Arc::new(IgnoringErrorHandler))
-    .enable_ctrlc_handler()
     .build();
-    let bot_listener = update_listeners::Polling::builder(bot)
-        .allowed_updates(vec![AllowedUpdate::Message])
-        .timeout(std::time::Duration::from_secs(10))
-        .build();
-
-    tracing::info!("initializing finished, bot is running");
-    bot_dispatcher
-        .dispatch_with_listener(
-            bot_listener,
-            LoggingErrorHandler::with_custom_text("An error from the update listener"),
-        )
-        .await;
+    let dispatcher_token = bot_dispatcher.shutdown_token();
+    tokio::spawn(async move {
+        shutdown::signal().await;
+        tracing::info!("[shutdown] signal received, stop taking new syncs");
+        handler.shutdown.close();
+        if let Ok(f) = dispatcher_token.shutdown() {
+            f.await;
+        }
+    });
+    let error_handler = LoggingErrorHandler::with_custom_text("An error from the update listener");
+    match bot_config.mode {
+        BotMode::Polling => {
+            let bot_listener = update_listeners::Polling::builder(bot)
+                .allowed_updates(vec![AllowedUpdate::Message, AllowedUpdate::InlineQuery])
+                .timeout(std::time::Duration::from_secs(10))
+                .build();
+            tracing::info!("initializing finished, bot is running");
+            bot_dispatcher
+                .dispatch_with_listener(bot_listener, error_handler)
+                .await;
+        }
+        BotMode::Webhook => {
+            let webhook_config = bot_config
+                .webhook
+                .expect("webhook config(key: bot.webhook) is required in webhook mode");
+            let bot_listener = webhook::listener(bot, &webhook_config)
+                .await
+                .expect("unable to set up webhook");
+            tracing::info!("initializing finished, bot is running with webhook");
+            bot_dispatcher
+                .dispatch_with_listener(bot_listener, error_handler)
+                .await;
+        }
+    }
+    let grace = bot_config.shutdown_grace_period.map_or(
+        shutdown::DEFAULT_GRACE_PERIOD,
+        std::time::Duration::from_secs,
+    );
+    handler.shutdown.drain(grace).await;
+    tracing::info!("bot is stopped");
 }
@@ -0,0 +1,84 @@
+//! A fixed number of workers taking the queued syncs in order, so a burst of
+//! submissions waits in the queue instead of running all at once.
+//!
+//! Requests of all workers are sent through the shared `ProxiedClient`, so its
+//! `max_concurrent` still bounds the outstanding requests in total.
+
+use std::future::Future;
+
+use eh2telegraph::storage::queue::{Job, JobQueue};
+use serde::{de::DeserializeOwned, Serialize};
+use tokio_util::sync::CancellationToken;
+
+pub const DEFAULT_WORKERS: usize = 3;
+
+/// Take the jobs of `queue` one by one and run `task` on each, until `closed` is
+/// cancelled. A running task is finished first.
+pub async fn run_worker<T, F, Fut>(queue: &JobQueue<T>, closed: &CancellationToken, task: F)
+where
+    T: Clone + Serialize + DeserializeOwned + Send + 'static,
+    F: Fn(Job<T>) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    loop {
+        let job = tokio::select! {
+            job = queue.next() => job,
+            _ = closed.cancelled() => return,
+        };
+        task(job).await;
+        if closed.is_cancelled() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Mutex,
+        },
+        time::Duration,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_workers() {
+        let queue = JobQueue::default();
+        for id in 1..=5 {
+            queue.push(id).await.unwrap();
+        }
+        let closed = CancellationToken::new();
+        let (in_flight, max_in_flight) = (AtomicUsize::new(0), AtomicUsize::new(0));
+        let started = Mutex::new(Vec::new());
+        let (queue, closed) = (&queue, &closed);
+        let (in_flight, max_in_flight, started) = (&in_flight, &max_in_flight, &started);
+        let task = move |job: Job<u32>| async move {
+            started.lock().unwrap().push(job.payload);
+            let n = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            max_in_flight.fetch_max(n, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(30)).await;
+            in_flight.fetch_sub(1, Ordering::SeqCst);
+            queue.finish(job.id, true).await;
+            if started.lock().unwrap().len() == 5 {
+                closed.cancel();
+            }
+        };
+        tokio::join!(
+            run_worker(queue, closed, task),
+            run_worker(queue, closed, task),
+        );
+
+        assert_eq!(*started.lock().unwrap(), [1, 2, 3, 4, 5]);
+        assert_eq!(max_in_flight.load(Ordering::SeqCst), 2);
+        // the running ones were finished
+        assert_eq!(in_flight.load(Ordering::SeqCst), 0);
+
+        // idle workers stop at once
+        tokio::time::timeout(Duration::from_secs(1), run_worker(queue, closed, task))
+            .await
+            .unwrap();
+    }
+}
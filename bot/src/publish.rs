@@ -0,0 +1,218 @@
+//! Post the uploaded galleries to a channel, for running the bot as an archive.
+//!
+//! Only fresh uploads are posted, galleries served from the cache were posted when
+//! they were uploaded.
+
+use std::future::Future;
+
+use eh2telegraph::collector::AlbumMeta;
+use teloxide::{
+    adaptors::DefaultParseMode,
+    prelude::*,
+    types::{ChatId, Recipient},
+    utils::markdown::{bold, escape, link},
+    ApiError, RequestError,
+};
+
+#[derive(thiserror::Error, Debug)]
+pub enum PublishError {
+    #[error("the bot can not post in the channel, add it as an admin with the right to post messages: {0}")]
+    NoRights(RequestError),
+    #[error("unable to post in the channel: {0}")]
+    Request(RequestError),
+}
+
+impl From<RequestError> for PublishError {
+    fn from(e: RequestError) -> Self {
+        match e {
+            RequestError::Api(
+                ApiError::NotEnoughRightsToPostMessages
+                | ApiError::ChatNotFound
+                | ApiError::BotKicked
+                | ApiError::BotKickedFromSupergroup,
+            ) => Self::NoRights(e),
+            e => Self::Request(e),
+        }
+    }
+}
+
+/// Sends the channel posts, the bot in production and a mock in tests.
+pub trait ChannelSender {
+    fn send_post(
+        &self,
+        channel: Recipient,
+        text: String,
+    ) -> impl Future<Output = Result<(), RequestError>> + Send;
+}
+
+impl ChannelSender for DefaultParseMode<Bot> {
+    async fn send_post(&self, channel: Recipient, text: String) -> Result<(), RequestError> {
+        crate::flood::send(self.send_message(channel, text))
+            .await
+            .map(|_| ())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Publisher {
+    pub channel: Recipient,
+    /// Also reply the links to the submitter, otherwise they are only told it is posted.
+    pub reply_to_user: bool,
+}
+
+impl Publisher {
+    pub fn new(channel: Recipient) -> Self {
+        Self {
+            channel,
+            reply_to_user: true,
+        }
+    }
+
+    pub async fn publish<S: ChannelSender>(
+        &self,
+        sender: &S,
+        meta: &AlbumMeta,
+        links: &[String],
+    ) -> Result<(), PublishError> {
+        if links.is_empty() {
+            return Ok(());
+        }
+        let text = caption(meta, links);
+        sender.send_post(self.channel.clone(), text).await?;
+        Ok(())
+    }
+}
+
+/// A chat id like `-1001234567890` or a `@username`.
+pub fn parse_channel(s: &str) -> Option<Recipient> {
+    let s = s.trim();
+    match s.strip_prefix('@') {
+        Some(name) if !name.is_empty() => Some(Recipient::ChannelUsername(s.to_string())),
+        Some(_) => None,
+        None => s.parse().ok().map(|id| Recipient::Id(ChatId(id))),
+    }
+}
+
+/// The title linked to the first page, followed by the metadata and the source.
+fn caption(meta: &AlbumMeta, links: &[String]) -> String {
+    let mut lines = vec![bold(&link(&links[0], &escape(&meta.name)))];
+    if let Some(name) = &meta.japanese_name {
+        lines.push(escape(name));
+    }
+    let authors = meta.authors.as_ref().map(|a| a.join(", "));
+    let pages = meta.page_count.map(|n| n.to_string());
+    for (label, value) in [
+        ("Artist", authors.as_ref()),
+        ("Category", meta.class.as_ref()),
+        ("Language", meta.language.as_ref()),
+        ("Pages", pages.as_ref()),
+    ] {
+        if let Some(value) = value {
+            lines.push(format!("{label}: {}", escape(value)));
+        }
+    }
+    if links.len() > 1 {
+        let parts = links
+            .iter()
+            .enumerate()
+            .map(|(idx, l)| link(l, &(idx + 1).to_string()))
+            .collect::<Vec<_>>()
+            .join(" ");
+        lines.push(format!("Parts: {parts}"));
+    }
+    lines.push(format!("Source: {}", link(&meta.link, &escape(&meta.link))));
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct MockSender {
+        posts: Mutex<Vec<(Recipient, String)>>,
+        error: Option<ApiError>,
+    }
+
+    impl ChannelSender for MockSender {
+        async fn send_post(&self, channel: Recipient, text: String) -> Result<(), RequestError> {
+            if let Some(e) = &self.error {
+                return Err(RequestError::Api(e.clone()));
+            }
+            self.posts.lock().unwrap().push((channel, text));
+            Ok(())
+        }
+    }
+
+    fn meta() -> AlbumMeta {
+        AlbumMeta {
+            link: "https://e-hentai.org/g/1/x".to_string(),
+            name: "Title (1)".to_string(),
+            japanese_name: None,
+            class: Some("Doujinshi".to_string()),
+            description: None,
+            authors: Some(vec!["alice".to_string()]),
+            language: None,
+            tags: None,
+            page_count: Some(120),
+        }
+    }
+
+    #[test]
+    fn test_parse_channel() {
+        assert_eq!(
+            parse_channel("@archive"),
+            Some(Recipient::ChannelUsername("@archive".to_string()))
+        );
+        assert_eq!(
+            parse_channel(" -1001234567890"),
+            Some(Recipient::Id(ChatId(-1001234567890)))
+        );
+        assert_eq!(parse_channel("@"), None);
+        assert_eq!(parse_channel("archive"), None);
+    }
+
+    #[tokio::test]
+    async fn test_publish() {
+        let publisher = Publisher::new(Recipient::Id(ChatId(-100)));
+        let sender = MockSender::default();
+        let links = [
+            "https://telegra.ph/a".to_string(),
+            "https://telegra.ph/b".to_string(),
+        ];
+        publisher.publish(&sender, &meta(), &links).await.unwrap();
+        let posts = sender.posts.lock().unwrap().clone();
+        let (channel, text) = &posts[0];
+        assert_eq!(*channel, Recipient::Id(ChatId(-100)));
+        assert_eq!(
+            text,
+            "*[Title \\(1\\)](https://telegra.ph/a)*\n\
+            Artist: alice\n\
+            Category: Doujinshi\n\
+            Pages: 120\n\
+            Parts: [1](https://telegra.ph/a) [2](https://telegra.ph/b)\n\
+            Source: [https://e\\-hentai\\.org/g/1/x](https://e-hentai.org/g/1/x)"
+        );
+
+        let sender = MockSender {
+            error: Some(ApiError::NotEnoughRightsToPostMessages),
+            ..Default::default()
+        };
+        let err = publisher
+            .publish(&sender, &meta(), &links)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, PublishError::NoRights(_)));
+        let sender = MockSender {
+            error: Some(ApiError::MessageIsTooLong),
+            ..Default::default()
+        };
+        let err = publisher
+            .publish(&sender, &meta(), &links)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, PublishError::Request(_)));
+    }
+}
@@ -0,0 +1,164 @@
+//! Drain the running syncs on SIGTERM/SIGINT instead of killing them mid upload.
+//!
+//! Once closed no new sync is taken. The running ones get a grace period to finish,
+//! then they are cancelled, which stops them at the next batch with the checkpoint
+//! saved, and the ones still running shortly after are aborted.
+
+use std::{future::Future, sync::Mutex, time::Duration};
+
+use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
+
+pub const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(30);
+// time for cancelled syncs to report before being aborted
+const CANCEL_WAIT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Default)]
+pub struct Shutdown {
+    tasks: Mutex<Tasks>,
+    token: CancellationToken,
+    // cancelled on close, stops the idle workers
+    closing: CancellationToken,
+}
+
+#[derive(Debug, Default)]
+struct Tasks {
+    set: JoinSet<()>,
+    closed: bool,
+}
+
+impl Shutdown {
+    /// Token for a sync, cancelled when the grace period is exceeded.
+    pub fn child_token(&self) -> CancellationToken {
+        self.token.child_token()
+    }
+
+    /// Run the sync in background, return false if closed.
+    pub fn spawn<F>(&self, task: F) -> bool
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let mut tasks = self.tasks.lock().unwrap();
+        if tasks.closed {
+            return false;
+        }
+        // reap the finished ones
+        while tasks.set.try_join_next().is_some() {}
+        tasks.set.spawn(task);
+        true
+    }
+
+    /// Cancelled once closed, so the workers stop taking queued syncs.
+    pub fn closing_token(&self) -> &CancellationToken {
+        &self.closing
+    }
+
+    /// Stop taking new syncs.
+    pub fn close(&self) {
+        self.tasks.lock().unwrap().closed = true;
+        self.closing.cancel();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.tasks.lock().unwrap().closed
+    }
+
+    /// Close and wait for the running syncs, return how many were aborted.
+    pub async fn drain(&self, grace: Duration) -> usize {
+        let mut set = {
+            let mut tasks = self.tasks.lock().unwrap();
+            tasks.closed = true;
+            std::mem::take(&mut tasks.set)
+        };
+        self.closing.cancel();
+        if !set.is_empty() {
+            tracing::info!(
+                "[shutdown] waiting up to {grace:?} for {} running syncs",
+                set.len()
+            );
+        }
+        if join_all(&mut set, grace).await {
+            return 0;
+        }
+        tracing::warn!(
+            "[shutdown] grace period exceeded, cancel {} syncs",
+            set.len()
+        );
+        self.token.cancel();
+        if join_all(&mut set, CANCEL_WAIT).await {
+            return 0;
+        }
+        let aborted = set.len();
+        tracing::warn!("[shutdown] abort {aborted} syncs");
+        set.shutdown().await;
+        aborted
+    }
+}
+
+/// Whether all tasks are finished within the timeout.
+async fn join_all(set: &mut JoinSet<()>, timeout: Duration) -> bool {
+    tokio::time::timeout(timeout, async { while set.join_next().await.is_some() {} })
+        .await
+        .is_ok()
+}
+
+/// Resolve on the first SIGTERM or SIGINT.
+pub async fn signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("unable to listen SIGTERM");
+        tokio::select! {
+            _ = terminate.recv() => (),
+            _ = tokio::signal::ctrl_c() => (),
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_drain() {
+        let shutdown = Shutdown::default();
+        let (fast, slow) = (
+            Arc::new(AtomicBool::new(false)),
+            Arc::new(AtomicBool::new(false)),
+        );
+        let done = |flag: &Arc<AtomicBool>, delay| {
+            let flag = flag.clone();
+            async move {
+                tokio::time::sleep(delay).await;
+                flag.store(true, Ordering::SeqCst);
+            }
+        };
+        assert!(shutdown.spawn(done(&fast, Duration::from_millis(50))));
+        // ignores the cancellation
+        assert!(shutdown.spawn(done(&slow, Duration::from_secs(60))));
+        let token = shutdown.child_token();
+
+        shutdown.close();
+        assert!(!shutdown.spawn(async {}));
+        assert_eq!(shutdown.drain(Duration::from_millis(300)).await, 1);
+        assert!(fast.load(Ordering::SeqCst));
+        assert!(!slow.load(Ordering::SeqCst));
+        assert!(token.is_cancelled());
+    }
+
+    #[tokio::test]
+    async fn test_drain_in_grace() {
+        let shutdown = Shutdown::default();
+        let token = shutdown.child_token();
+        assert!(shutdown.spawn(tokio::time::sleep(Duration::from_millis(50))));
+        assert_eq!(shutdown.drain(Duration::from_secs(10)).await, 0);
+        assert!(!token.is_cancelled());
+    }
+}
@@ -0,0 +1,144 @@
+//! Runtime counters for `/stats` and the optional Prometheus `/metrics` endpoint.
+
+use std::{fmt::Write, future::Future, net::SocketAddr};
+
+use eh2telegraph::{http_proxy::ProxyMetrics, metrics::SyncMetrics};
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Stats {
+    pub sync: SyncMetrics,
+    pub proxy: ProxyMetrics,
+}
+
+impl Stats {
+    /// In the Prometheus text format.
+    pub fn prometheus(&self) -> String {
+        let sync = &self.sync;
+        let counters: [(&str, &str, String); 8] = [
+            (
+                "galleries_synced_total",
+                "Galleries uploaded.",
+                sync.galleries_synced.to_string(),
+            ),
+            (
+                "galleries_failed_total",
+                "Galleries failed to sync.",
+                sync.galleries_failed.to_string(),
+            ),
+            (
+                "cache_hits_total",
+                "Syncs answered by the cache.",
+                sync.cache_hits.to_string(),
+            ),
+            (
+                "images_uploaded_total",
+                "Images uploaded to telegraph.",
+                sync.images_uploaded.to_string(),
+            ),
+            (
+                "downloaded_bytes_total",
+                "Bytes of the downloaded images.",
+                sync.bytes_downloaded.to_string(),
+            ),
+            (
+                "sync_seconds_total",
+                "Time spent in the uploaded galleries.",
+                sync.sync_time.as_secs_f64().to_string(),
+            ),
+            (
+                "proxy_requests_total",
+                "Requests sent by the proxied client.",
+                self.proxy.total_requests.to_string(),
+            ),
+            (
+                "proxy_errors_total",
+                "Proxied requests failed without a response.",
+                self.proxy.proxy_errors.to_string(),
+            ),
+        ];
+        let mut text = String::new();
+        for (name, help, value) in counters {
+            let _ = write!(
+                text,
+                "# HELP eh2telegraph_{name} {help}\n\
+                # TYPE eh2telegraph_{name} counter\n\
+                eh2telegraph_{name} {value}\n"
+            );
+        }
+        text
+    }
+}
+
+/// Serve `/metrics` at `bind`, return the bound address and the server to run.
+pub fn serve<F>(
+    bind: SocketAddr,
+    stats: F,
+) -> anyhow::Result<(SocketAddr, impl Future<Output = ()>)>
+where
+    F: Fn() -> Stats + Clone + Send + Sync + 'static,
+{
+    let app = axum::Router::new().route(
+        "/metrics",
+        axum::routing::get(move || {
+            let stats = stats.clone();
+            async move {
+                (
+                    [(
+                        axum::http::header::CONTENT_TYPE,
+                        "text/plain; version=0.0.4",
+                    )],
+                    stats().prometheus(),
+                )
+            }
+        }),
+    );
+    let server = axum::Server::try_bind(&bind)?.serve(app.into_make_service());
+    let addr = server.local_addr();
+    Ok((addr, async move {
+        if let Err(e) = server.await {
+            tracing::error!("[stats] metrics server stopped: {e}");
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_serve() {
+        let stats = Stats {
+            sync: SyncMetrics {
+                galleries_synced: 2,
+                cache_hits: 5,
+                sync_time: Duration::from_millis(1500),
+                ..Default::default()
+            },
+            proxy: ProxyMetrics {
+                total_requests: 10,
+                proxy_errors: 1,
+                direct_requests: 0,
+            },
+        };
+        let (addr, server) = serve("127.0.0.1:0".parse().unwrap(), move || stats).unwrap();
+        tokio::spawn(server);
+
+        let resp = reqwest::get(format!("http://{addr}/metrics"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        let text = resp.text().await.unwrap();
+        for line in [
+            "# TYPE eh2telegraph_galleries_synced_total counter",
+            "eh2telegraph_galleries_synced_total 2",
+            "eh2telegraph_cache_hits_total 5",
+            "eh2telegraph_sync_seconds_total 1.5",
+            "eh2telegraph_proxy_errors_total 1",
+        ] {
+            assert!(text.lines().any(|l| l == line), "{line} in {text}");
+        }
+        assert_eq!(text.lines().count(), 8 * 3);
+    }
+}
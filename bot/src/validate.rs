@@ -0,0 +1,278 @@
+//! Check the whole config before starting, so that all problems are reported at once
+//! instead of panicking in whichever part reads a bad value first.
+
+use eh2telegraph::{
+    config::{Config, ConfigErrors},
+    sniff::TypeAllowlist,
+};
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+use crate::{
+    audit::AuditConfig,
+    callback::CallbackConfig,
+    publish::parse_channel,
+    webhook::{BotConfig, BotMode},
+    BaseConfig,
+};
+
+// like 123456:ABC-DEF1234ghIkl-zyx57W2v1u123ew11
+static BOT_TOKEN_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"^\d+:[\w-]{30,}$").unwrap());
+
+pub fn validate(config: &Config) -> ConfigErrors {
+    let mut errors = config.validate();
+    if let Some(base) = config.required::<BaseConfig>("base", &mut errors) {
+        validate_base(&base, &mut errors);
+    }
+    if let Some(bot) = config.section::<BotConfig>("bot", &mut errors) {
+        validate_bot(&bot, &mut errors);
+    }
+    if let Some(audit) = config.section::<AuditConfig>("audit", &mut errors) {
+        validate_audit(&audit, &mut errors);
+    }
+    if let Some(callback) = config.section::<CallbackConfig>("callback", &mut errors) {
+        validate_callback(&callback, &mut errors);
+    }
+    #[cfg(all(not(debug_assertions), feature = "redis"))]
+    if !has_storage_backend(config, "redis") {
+        errors.push("storage.redis", "required by the redis build but missing");
+    }
+    #[cfg(all(not(debug_assertions), not(feature = "redis"), feature = "sqlite"))]
+    if !has_storage_backend(config, "sqlite") {
+        errors.push("storage.sqlite", "required by the sqlite build but missing");
+    }
+    errors
+}
+
+#[cfg(all(not(debug_assertions), any(feature = "redis", feature = "sqlite")))]
+fn has_storage_backend(config: &Config, backend: &str) -> bool {
+    config
+        .parse::<std::collections::HashMap<String, serde::de::IgnoredAny>>("storage")
+        .ok()
+        .flatten()
+        .is_some_and(|s| s.contains_key(backend))
+}
+
+fn validate_base(base: &BaseConfig, errors: &mut ConfigErrors) {
+    if !BOT_TOKEN_RE.is_match(&base.bot_token) {
+        errors.push(
+            "base.bot_token",
+            "not a telegram bot token like 123456:ABC-DEF..., get one from BotFather",
+        );
+    }
+    let tokens = &base.telegraph.tokens;
+    if tokens.is_empty() {
+        errors.push("base.telegraph.tokens", "at least one token is required");
+    }
+    for (idx, token) in tokens.iter().enumerate() {
+        if token.trim().is_empty() {
+            errors.push("base.telegraph.tokens", format!("token {idx} is empty"));
+        }
+    }
+    if base
+        .telegraph
+        .reencode_quality
+        .is_some_and(|q| q == 0 || q > 100)
+    {
+        errors.push("base.telegraph.reencode_quality", "must be in 1..=100");
+    }
+    for (key, max) in [
+        ("max_image_width", base.telegraph.max_image_width),
+        ("max_image_height", base.telegraph.max_image_height),
+    ] {
+        if max == Some(0) {
+            errors.push(&format!("base.telegraph.{key}"), "must be positive");
+        }
+    }
+    match base.telegraph.allowed_image_types.as_deref() {
+        Some([]) => errors.push("base.telegraph.allowed_image_types", "can not be empty"),
+        Some(types) => {
+            if let Err(mime) = TypeAllowlist::from_mimes(types) {
+                errors.push(
+                    "base.telegraph.allowed_image_types",
+                    format!("{mime} is not an image type"),
+                );
+            }
+        }
+        None => (),
+    }
+    if let Some(api_base) = &base.telegraph.api_base {
+        match reqwest::Url::parse(api_base) {
+            Ok(url) if url.scheme() == "https" && url.has_host() => (),
+            Ok(_) => errors.push("base.telegraph.api_base", "must be an https url"),
+            Err(e) => errors.push("base.telegraph.api_base", e),
+        }
+    }
+}
+
+fn validate_bot(bot: &BotConfig, errors: &mut ConfigErrors) {
+    if bot
+        .publish_channel
+        .as_deref()
+        .is_some_and(|c| parse_channel(c).is_none())
+    {
+        errors.push(
+            "bot.publish_channel",
+            "not a chat id like -1001234567890 or a @username",
+        );
+    }
+    if bot.mode != BotMode::Webhook {
+        return;
+    }
+    let Some(webhook) = &bot.webhook else {
+        errors.push("bot.webhook", "required in webhook mode but missing");
+        return;
+    };
+    match reqwest::Url::parse(&webhook.url) {
+        Ok(url) if url.scheme() == "https" => (),
+        Ok(_) => errors.push(
+            "bot.webhook.url",
+            "telegram only sends webhooks to https urls",
+        ),
+        Err(e) => errors.push("bot.webhook.url", e),
+    }
+    let valid_secret = |s: &str| {
+        (1..=256).contains(&s.len())
+            && s.bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-')
+    };
+    if webhook
+        .secret_token
+        .as_deref()
+        .is_some_and(|s| !valid_secret(s))
+    {
+        errors.push(
+            "bot.webhook.secret_token",
+            "only 1-256 characters of A-Z, a-z, 0-9, _ and - are allowed",
+        );
+    }
+}
+
+/// The log is created on start, so only its directory must exist.
+fn validate_audit(audit: &AuditConfig, errors: &mut ConfigErrors) {
+    if !audit.enabled {
+        return;
+    }
+    let path = std::path::Path::new(&audit.path);
+    if audit.path.is_empty() || path.is_dir() {
+        errors.push("audit.path", "must be a file");
+        return;
+    }
+    match path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        Some(parent) if !parent.is_dir() => {
+            errors.push(
+                "audit.path",
+                format!("directory {} does not exist", parent.display()),
+            );
+        }
+        _ => (),
+    }
+}
+
+fn validate_callback(callback: &CallbackConfig, errors: &mut ConfigErrors) {
+    match reqwest::Url::parse(&callback.url) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") && url.has_host() => (),
+        Ok(_) => errors.push("callback.url", "must be an http or https url"),
+        Err(e) => errors.push("callback.url", e),
+    }
+    if callback.secret.as_deref().is_some_and(str::is_empty) {
+        errors.push("callback.secret", "can not be empty");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use eh2telegraph::config::ConfigFormat;
+
+    use super::*;
+
+    fn errors(yaml: &str) -> Vec<String> {
+        let config = Config::load(ConfigFormat::Yaml, yaml).unwrap();
+        validate(&config).iter().map(ToOwned::to_owned).collect()
+    }
+
+    #[test]
+    fn test_valid() {
+        let yaml = r#"
+base:
+  bot_token: 123456:ABC-DEF1234ghIkl-zyx57W2v1u123ew11
+  telegraph:
+    tokens: [xxxxxxxx]
+bot:
+  mode: polling
+proxy:
+  endpoint: https://proxy.example.com/
+  authorization: xxx
+"#;
+        assert_eq!(errors(yaml), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_all_reported() {
+        let yaml = r#"
+base:
+  bot_token: xxx:xxxx
+  telegraph:
+    tokens: []
+    reencode_quality: 0
+    max_image_height: 0
+    allowed_image_types: [image/png, text/html]
+    api_base: http://telegraph.example.com
+bot:
+  mode: webhook
+  publish_channel: archive
+  webhook:
+    url: http://bot.example.com/webhook
+    bind: 127.0.0.1:8080
+    secret_token: not allowed!
+proxy:
+  endpoint: https://proxy.example.com/
+whitelist:
+  enabled: maybe
+audit:
+  enabled: true
+  path: /nonexistent/audit.jsonl
+callback:
+  url: ftp://pipeline.example.com/done
+  secret: ""
+"#;
+        let errors = errors(yaml);
+        let keys = errors
+            .iter()
+            .map(|e| e.split_once(':').unwrap().0)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            keys,
+            [
+                "proxy",
+                "whitelist",
+                "base.bot_token",
+                "base.telegraph.tokens",
+                "base.telegraph.reencode_quality",
+                "base.telegraph.max_image_height",
+                "base.telegraph.allowed_image_types",
+                "base.telegraph.api_base",
+                "bot.publish_channel",
+                "bot.webhook.url",
+                "bot.webhook.secret_token",
+                "audit.path",
+                "callback.url",
+                "callback.secret",
+            ],
+            "{errors:#?}"
+        );
+        assert!(errors[0].contains("incomplete"), "{errors:#?}");
+    }
+
+    #[test]
+    fn test_missing_sections() {
+        let errors = errors("bot:\n  mode: webhook\n");
+        assert_eq!(
+            errors,
+            [
+                "base: section is required but missing",
+                "bot.webhook: required in webhook mode but missing",
+            ]
+        );
+    }
+}
@@ -0,0 +1,163 @@
+//! Receive updates by webhook instead of long polling.
+//!
+//! The server speaks plain HTTP, so TLS must be terminated by a reverse proxy
+//! (or a tunnel) which forwards the public `url` to `bind` with the path kept.
+
+use std::{convert::Infallible, net::SocketAddr};
+
+use reqwest::Url;
+use teloxide::{
+    prelude::*,
+    requests::HasPayload,
+    types::AllowedUpdate,
+    update_listeners::{webhooks, UpdateListener},
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BotMode {
+    #[default]
+    Polling,
+    Webhook,
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+pub struct BotConfig {
+    #[serde(default)]
+    pub mode: BotMode,
+    pub webhook: Option<WebhookConfig>,
+    /// Locale of replies to users whose language is not supported, `en` if not set.
+    pub default_locale: Option<String>,
+    /// Seconds to wait for running syncs on SIGTERM/SIGINT before cancelling them.
+    pub shutdown_grace_period: Option<u64>,
+    /// Workers taking the queued syncs, so galleries synced at the same time, 3 if not set.
+    pub sync_workers: Option<usize>,
+    /// Chat id or `@username` of the channel uploaded galleries are posted to.
+    pub publish_channel: Option<String>,
+    /// Also reply the links to the submitter when publishing, true if not set.
+    pub reply_to_user: Option<bool>,
+    /// Serve the counters of `/stats` at `/metrics` in the Prometheus format.
+    pub metrics_bind: Option<SocketAddr>,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct WebhookConfig {
+    /// Public url Telegram sends updates to, its path is served at `bind`.
+    pub url: String,
+    pub bind: SocketAddr,
+    /// Sent by Telegram in `X-Telegram-Bot-Api-Secret-Token`, requests without it
+    /// are rejected. A random one is used if not set.
+    pub secret_token: Option<String>,
+}
+
+impl WebhookConfig {
+    fn options(&self) -> anyhow::Result<webhooks::Options> {
+        let url = Url::parse(&self.url)?;
+        let mut options = webhooks::Options::new(self.bind, url);
+        if let Some(token) = &self.secret_token {
+            options = options.secret_token(token.clone());
+        }
+        Ok(options)
+    }
+}
+
+/// Register the webhook and serve it, updates are yielded by the returned listener.
+pub async fn listener<R>(
+    bot: R,
+    config: &WebhookConfig,
+) -> anyhow::Result<impl UpdateListener<Err = Infallible>>
+where
+    R: Requester,
+    R::Err: std::error::Error + Send + Sync + 'static,
+{
+    let mut options = config.options()?;
+    let secret = options.get_or_gen_secret_token().to_owned();
+    let mut req = bot.set_webhook(options.url.clone());
+    // same as polling
+    req.payload_mut().allowed_updates =
+        Some(vec![AllowedUpdate::Message, AllowedUpdate::InlineQuery]);
+    req.payload_mut().secret_token = Some(secret);
+    req.await?;
+
+    let (listener, addr) = serve(options)?;
+    tracing::info!("[webhook] listening on {addr} for {}", config.url);
+    Ok(listener)
+}
+
+/// Serve the webhook endpoint in background until the listener is stopped.
+fn serve(
+    options: webhooks::Options,
+) -> anyhow::Result<(impl UpdateListener<Err = Infallible>, SocketAddr)> {
+    let address = options.address;
+    let (mut listener, stop_flag, router) = webhooks::axum_no_setup(options);
+    let stop_token = listener.stop_token();
+    let server = axum::Server::try_bind(&address)?.serve(router.into_make_service());
+    let addr = server.local_addr();
+    tokio::spawn(async move {
+        if let Err(e) = server.with_graceful_shutdown(stop_flag).await {
+            tracing::error!("[webhook] server error: {e}");
+            stop_token.stop();
+        }
+    });
+    Ok((listener, addr))
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+    use teloxide::{
+        types::UpdateKind,
+        update_listeners::{AsUpdateStream, UpdateListener},
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_receive_update() {
+        let config = WebhookConfig {
+            url: "https://bot.example.com/telegram/webhook".to_string(),
+            bind: "127.0.0.1:0".parse().unwrap(),
+            secret_token: Some("test-secret".to_string()),
+        };
+        let (mut listener, addr) = serve(config.options().unwrap()).unwrap();
+        let endpoint = format!("http://{addr}/telegram/webhook");
+        let update = r#"{
+            "update_id": 42,
+            "message": {
+                "message_id": 1,
+                "date": 1700000000,
+                "chat": {"id": 1, "type": "private", "first_name": "user"},
+                "from": {"id": 1, "is_bot": false, "first_name": "user"},
+                "text": "/help"
+            }
+        }"#;
+        let client = reqwest::Client::new();
+
+        // without the secret token
+        let resp = client.post(&endpoint).body(update).send().await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+        let resp = client
+            .post(&endpoint)
+            .header("X-Telegram-Bot-Api-Secret-Token", "test-secret")
+            .header("Content-Type", "application/json")
+            .body(update)
+            .send()
+            .await
+            .unwrap();
+        assert!(resp.status().is_success());
+
+        let stop_token = listener.stop_token();
+        let update = Box::pin(listener.as_stream())
+            .next()
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(update.id, 42);
+        match update.kind {
+            UpdateKind::Message(msg) => assert_eq!(msg.text(), Some("/help")),
+            kind => panic!("unexpected update {kind:?}"),
+        }
+        stop_token.stop();
+    }
+}
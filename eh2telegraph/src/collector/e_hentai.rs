@@ -1,71 +1,255 @@
-/// nhentai collector.
-/// Host matching: e-hentai.org
+/// e-hentai collector.
+/// Host matching: e-hentai.org, or the hosts of `collectors.ehentai.hosts`
 use crate::{
+    config,
     http_client::{GhostClient, GhostClientBuilder},
+    http_proxy::ProxiedClient,
+    sniff::ImageKind,
+    storage::image_cache::{download_cached, ImageCache},
     stream::AsyncStream,
-    util::match_first_group,
-    util::{get_bytes, get_string},
+    telegraph::MAX_SINGLE_FILE_SIZE,
+    util::{get_string, match_first_group, unescape_html},
 };
 use again::RetryPolicy;
 use ipnet::Ipv6Net;
 use regex::Regex;
 use reqwest::header;
+use serde::Deserialize;
 
-use std::time::Duration;
+use std::{
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::io::AsyncWriteExt;
 
 use super::{
-    utils::paged::{PageFormatter, PageIndicator, Paged},
-    AlbumMeta, Collector, ImageData, ImageMeta,
+    hosts::{Hosts, SiteHosts},
+    utils::{
+        paged::{PageFormatter, PageIndicator, Paged},
+        zip,
+    },
+    AlbumMeta, Collector, CollectorError, ImageData, ImageMeta,
 };
 
 lazy_static::lazy_static! {
-    static ref PAGE_RE: Regex = Regex::new(r#"<a href="(https://e-hentai\.org/s/\w+/[\w-]+)">"#).unwrap();
+    // on any host, the links follow the host the gallery is requested from
+    static ref PAGE_RE: Regex = Regex::new(r#"<a href="(https://[\w.-]+/s/\w+/[\w-]+)">"#).unwrap();
     static ref IMG_RE: Regex = Regex::new(r#"<img id="img" src="(.*?)""#).unwrap();
+    // only shown when the image is resampled
+    static ref ORIGINAL_RE: Regex = Regex::new(r#"<a href="(https://[\w.-]+/fullimg[^"]+)">Download original"#).unwrap();
+    // like `Download original 1280 x 1810 2.1 MiB source`
+    static ref ORIGINAL_SIZE_RE: Regex = Regex::new(r"Download original \d+ x \d+ ([\d.]+ [KMG]iB) source").unwrap();
+    static ref ARCHIVER_RE: Regex = Regex::new(r#"popUp\('(https://[\w.-]+/archiver\.php\?[^']+)'"#).unwrap();
+    static ref ARCHIVE_LOCATION_RE: Regex = Regex::new(r#"document\.location = "([^"]+)""#).unwrap();
     static ref TITLE_RE: Regex = Regex::new(r#"<h1 id="gn">(.*?)</h1>"#).unwrap();
+    static ref JAPANESE_TITLE_RE: Regex = Regex::new(r#"<h1 id="gj">(.*?)</h1>"#).unwrap();
+    static ref CATEGORY_RE: Regex = Regex::new(r#"<div id="gdc"><div class="cs [^"]*"[^>]*>([^<]+)</div>"#).unwrap();
+    static ref LANGUAGE_RE: Regex = Regex::new(r#"<td class="gdt1">Language:</td><td class="gdt2">([^<&]+)"#).unwrap();
+    static ref LENGTH_RE: Regex = Regex::new(r#"<td class="gdt1">Length:</td><td class="gdt2">(\d+) pages?</td>"#).unwrap();
+    static ref TAG_ROW_RE: Regex = Regex::new(r#"<tr><td class="tc">([\w ]+):</td><td>(.*?)</td></tr>"#).unwrap();
+    static ref TAG_RE: Regex = Regex::new(r#"<a id="ta_[^"]*"[^>]*>([^<]+)</a>"#).unwrap();
 
     static ref RETRY_POLICY: RetryPolicy = RetryPolicy::fixed(Duration::from_millis(200))
         .with_max_retries(5)
         .with_jitter(true);
 }
-const TIMEOUT: Duration = Duration::from_secs(30);
+// shown with 200 instead of 404, also by exhentai
+const REMOVED_MARKERS: [&str; 2] = [
+    "This gallery has been removed or is unavailable.",
+    "Key missing, or incorrect key provided.",
+];
+const CONFIG_KEY: &str = "ehentai";
+const ARCHIVE_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Images downloaded from e-hentai. The originals are only offered to members, they
+/// are larger, use the image quota of the account, and cost GP beyond it. Pages
+/// whose original can not be downloaded, e.g. without enough GP, fall back to the
+/// resampled image.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Resolution {
+    /// The originals which fit in the upload limit, so they are not re-encoded, and
+    /// the resampled images otherwise.
+    Auto,
+    /// At most 1280 pixels wide, free for everyone.
+    #[default]
+    Resampled,
+    Original,
+}
+
+/// Login cookies of e-hentai, optional unlike exhentai. Members see galleries hidden
+/// from anonymous users and may download the originals.
+#[derive(Debug, Clone, Deserialize)]
+pub struct EHConfig {
+    pub ipb_member_id: String,
+    pub ipb_pass_hash: String,
+    /// Resampled if not set, see `Resolution`.
+    pub resolution: Option<Resolution>,
+    /// Same as `resolution: original`, kept for old configs.
+    #[serde(default)]
+    pub original: bool,
+    /// Download the archive of the gallery instead when more than this fraction of
+    /// the pages fail, like 0.2. Disabled if not set, since archives cost GP or credits.
+    /// A sync still gives up after 10 failed pages in a row, keep it below that.
+    pub archive_fallback: Option<f64>,
+}
+
+impl EHConfig {
+    fn login(&self) -> anyhow::Result<Login> {
+        let cookie = format!(
+            "ipb_member_id={};ipb_pass_hash={};nw=1",
+            self.ipb_member_id, self.ipb_pass_hash
+        );
+        Ok(Login {
+            cookie: header::HeaderValue::from_str(&cookie)
+                .map_err(|_| anyhow::anyhow!("invalid e-hentai cookies in config"))?,
+            archive_fallback: self.archive_fallback,
+        })
+    }
+
+    fn resolution(&self) -> Resolution {
+        match (self.resolution, self.original) {
+            (Some(resolution), _) => resolution,
+            (None, true) => Resolution::Original,
+            (None, false) => Resolution::Resampled,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Login {
+    cookie: header::HeaderValue,
+    archive_fallback: Option<f64>,
+}
+
+/// Gallery and image pages are requested with the login cookies if there are.
+fn client_builder(login: Option<&Login>, hosts: &SiteHosts) -> GhostClientBuilder {
+    let cookie = match login {
+        Some(login) => login.cookie.clone(),
+        None => header::HeaderValue::from_static("nw=1"),
+    };
+    let mut request_headers = header::HeaderMap::new();
+    request_headers.insert(header::COOKIE, cookie);
+    GhostClientBuilder::default()
+        .with_default_headers(request_headers)
+        .with_cf_resolve(hosts.iter())
+}
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone)]
 pub struct EHCollector {
+    // gallery pages, image pages and archives, from a random address of the prefix
     client: GhostClient,
-    raw_client: reqwest::Client,
+    // images, direct if it is not given by `with_proxy`
+    proxy: ProxiedClient,
+    image_cache: Option<ImageCache>,
+    login: Option<Login>,
+    // originals are only downloaded with the login
+    resolution: Resolution,
+    // of the galleries, `collectors.ehentai.hosts`
+    hosts: SiteHosts,
+    // the first one of `hosts` if not set
+    host: Option<String>,
+    // of a downloaded archive
+    archive_max_bytes: Option<u64>,
 }
 
 impl EHCollector {
+    /// With the default hosts.
     pub fn new(prefix: Option<Ipv6Net>) -> Self {
-        let mut request_headers = header::HeaderMap::new();
-        request_headers.insert(
-            header::COOKIE,
-            header::HeaderValue::from_str("nw=1").unwrap(),
-        );
+        let hosts = Hosts::default().ehentai;
+        Self::with_client(client_builder(None, &hosts).build(prefix), hosts)
+    }
+
+    /// Logged in with the cookies of `config`, with the default hosts.
+    pub fn new_with_login(config: &EHConfig, prefix: Option<Ipv6Net>) -> anyhow::Result<Self> {
+        let login = config.login()?;
+        let hosts = Hosts::default().ehentai;
+        let client = client_builder(Some(&login), &hosts).build(prefix);
+        Ok(Self {
+            login: Some(login),
+            resolution: config.resolution(),
+            ..Self::with_client(client, hosts)
+        })
+    }
 
+    /// Logged in if there are cookies under `ehentai` in the config.
+    pub fn new_from_config(hosts: &Hosts) -> anyhow::Result<Self> {
+        let config = config::parse::<EHConfig>(CONFIG_KEY)?;
+        let login = config.as_ref().map(|c| c.login()).transpose()?;
+        let hosts = hosts.ehentai.clone();
+        let client = client_builder(login.as_ref(), &hosts).build_from_config()?;
+        Ok(Self {
+            login,
+            resolution: config.map(|c| c.resolution()).unwrap_or_default(),
+            ..Self::with_client(client, hosts)
+        })
+    }
+
+    fn with_client(client: GhostClient, hosts: SiteHosts) -> Self {
         Self {
-            client: GhostClientBuilder::default()
-                .with_default_headers(request_headers)
-                .with_cf_resolve(&["e-hentai.org"])
-                .build(prefix),
-            raw_client: reqwest::Client::builder().timeout(TIMEOUT).build().unwrap(),
+            client,
+            proxy: ProxiedClient::default(),
+            image_cache: None,
+            login: None,
+            resolution: Resolution::default(),
+            hosts,
+            host: None,
+            archive_max_bytes: None,
         }
     }
 
-    pub fn new_from_config() -> anyhow::Result<Self> {
-        let mut request_headers = header::HeaderMap::new();
-        request_headers.insert(
-            header::COOKIE,
-            header::HeaderValue::from_str("nw=1").unwrap(),
-        );
+    /// The hosts galleries are matched by.
+    pub fn hosts(&self) -> &SiteHosts {
+        &self.hosts
+    }
 
-        Ok(Self {
-            client: GhostClientBuilder::default()
-                .with_default_headers(request_headers)
-                .with_cf_resolve(&["e-hentai.org"])
-                .build_from_config()?,
-            raw_client: reqwest::Client::builder().timeout(TIMEOUT).build().unwrap(),
-        })
+    /// Download images through the proxy, gallery pages still use the ghost client.
+    pub fn with_proxy(mut self, client: ProxiedClient) -> Self {
+        self.proxy = client;
+        self
+    }
+
+    pub fn with_image_cache(mut self, cache: ImageCache) -> Self {
+        self.image_cache = Some(cache);
+        self
+    }
+
+    /// Give up downloading an archive beyond `max_bytes`, see `EHConfig::archive_fallback`.
+    pub fn with_archive_limit(mut self, max_bytes: Option<u64>) -> Self {
+        self.archive_max_bytes = max_bytes;
+        self
+    }
+
+    /// Override the resolution of the config, originals still need the login.
+    pub fn with_resolution(mut self, resolution: Resolution) -> Self {
+        self.resolution = resolution;
+        self
+    }
+
+    /// Request galleries from `host` instead of the configured one.
+    pub fn with_host(mut self, host: impl Into<String>) -> Self {
+        self.host = Some(host.into());
+        self
+    }
+
+    /// The gallery url of paths like `/g/2127986/da1deffea5/`, with the gallery id.
+    fn gallery_url<'a>(&self, path: &'a str) -> anyhow::Result<(String, &'a str)> {
+        let mut parts = path.trim_matches(|c| c == '/').split('/');
+        let (album_id, album_token) = match (parts.next(), parts.next(), parts.next()) {
+            (Some("g"), Some(album_id), Some(album_token)) => (album_id, album_token),
+            _ => {
+                return Err(anyhow::anyhow!("invalid input path({path}), gallery url is expected(like https://e-hentai.org/g/2127986/da1deffea5)"));
+            }
+        };
+        let host = self.host.as_deref().unwrap_or_else(|| self.hosts.primary());
+        Ok((
+            format!("https://{host}/g/{album_id}/{album_token}"),
+            album_id,
+        ))
     }
 }
 
@@ -79,33 +263,25 @@ impl Collector for EHCollector {
         "e-hentai"
     }
 
+    fn matches(&self, url: &url::Url) -> bool {
+        self.hosts.matches_gallery(url)
+    }
+
     async fn fetch(
         &self,
         path: String,
     ) -> Result<(AlbumMeta, Self::ImageStream), Self::FetchError> {
-        // normalize url
-        let mut parts = path.trim_matches(|c| c == '/').split('/');
-        let g = parts.next();
-        let album_id = parts.next();
-        let album_token = parts.next();
-        let (album_id, album_token) = match (g, album_id, album_token) {
-            (Some("g"), Some(album_id), Some(album_token)) => (album_id, album_token),
-            _ => {
-                return Err(anyhow::anyhow!("invalid input path({path}), gallery url is expected(like https://e-hentai.org/g/2127986/da1deffea5)"));
-            }
-        };
-        let url = format!("https://e-hentai.org/g/{album_id}/{album_token}");
+        let (url, album_id) = self.gallery_url(&path)?;
         tracing::info!("[e-hentai] process {url}");
 
         // clone client to force changing ip
         let client = self.client.clone();
         let mut paged = Paged::new(0, EHPageIndicator { base: url.clone() });
         let gallery_pages = paged.pages(&client).await?;
+        check_removed(&gallery_pages[0])?;
 
         // Since paged returns at least one page, we can safely get it.
-        let title = match_first_group(&TITLE_RE, &gallery_pages[0])
-            .map(|s| s.to_string())
-            .unwrap_or_else(|| format!("e-hentai-{album_id}"));
+        let meta = parse_gallery_meta(&gallery_pages[0], url, || format!("e-hentai-{album_id}"));
 
         let mut image_page_links = Vec::new();
         for gallery_page in gallery_pages.iter() {
@@ -120,46 +296,380 @@ impl Collector for EHCollector {
                 "invalid url, maybe resource has been deleted."
             ));
         }
+        let fallback = self
+            .login
+            .as_ref()
+            .and_then(|l| Some((l, l.archive_fallback?)))
+            .and_then(|(_, fraction)| {
+                let archiver = match_first_group(&ARCHIVER_RE, &gallery_pages[0]);
+                if archiver.is_none() {
+                    tracing::warn!("[e-hentai] no archive of {}", meta.link);
+                }
+                let fallback = ArchiveFallback::new(
+                    unescape_html(archiver?),
+                    self.resolution == Resolution::Original,
+                    (fraction * image_page_links.len() as f64) as usize,
+                );
+                Some(Arc::new(fallback.with_max_bytes(self.archive_max_bytes)))
+            });
 
         Ok((
-            AlbumMeta {
-                link: url,
-                name: title,
-                class: None,
-                description: None,
-                authors: None,
-                tags: None,
-            },
+            meta,
             EHImageStream {
                 client,
-                raw_client: self.raw_client.clone(),
-                image_page_links: image_page_links.into_iter(),
+                proxy: self.proxy.clone(),
+                image_cache: self.image_cache.clone(),
+                login: self.login.clone(),
+                resolution: self.resolution,
+                fallback,
+                links: image_page_links.clone(),
+                image_page_links: image_page_links.into_iter().enumerate(),
             },
         ))
     }
 }
 
+pub(crate) fn validate_config(config: &config::Config, errors: &mut config::ConfigErrors) {
+    let fraction = config
+        .section::<EHConfig>(CONFIG_KEY, errors)
+        .and_then(|c| c.archive_fallback);
+    if fraction.is_some_and(|f| !(0.0..=1.0).contains(&f)) {
+        errors.push("ehentai.archive_fallback", "must be between 0 and 1");
+    }
+}
+
+/// Removed galleries and wrong tokens are answered with an error page, not a status.
+pub(crate) fn check_removed(html: &str) -> Result<(), CollectorError> {
+    match REMOVED_MARKERS.iter().any(|m| html.contains(m)) {
+        true => Err(CollectorError::NotFound),
+        false => Ok(()),
+    }
+}
+
+/// Parse the metadata block of a gallery page, which is shared by e-hentai and exhentai.
+pub(crate) fn parse_gallery_meta(
+    html: &str,
+    link: String,
+    fallback_name: impl FnOnce() -> String,
+) -> AlbumMeta {
+    let field = |re: &Regex| {
+        match_first_group(re, html)
+            .map(|s| unescape_html(s.trim()))
+            .filter(|s| !s.is_empty())
+    };
+    let mut authors = Vec::new();
+    let mut tags = Vec::new();
+    for row in TAG_ROW_RE.captures_iter(html) {
+        let namespace = &row[1];
+        for tag in TAG_RE.captures_iter(&row[2]) {
+            let tag = unescape_html(tag[1].trim());
+            if namespace == "artist" {
+                authors.push(tag.clone());
+            }
+            tags.push(format!("{namespace}:{tag}"));
+        }
+    }
+    AlbumMeta {
+        link,
+        name: field(&TITLE_RE).unwrap_or_else(fallback_name),
+        japanese_name: field(&JAPANESE_TITLE_RE),
+        class: field(&CATEGORY_RE),
+        description: None,
+        authors: (!authors.is_empty()).then_some(authors),
+        language: field(&LANGUAGE_RE),
+        tags: (!tags.is_empty()).then_some(tags),
+        page_count: field(&LENGTH_RE).and_then(|n| n.parse().ok()),
+    }
+}
+
 #[derive(Debug)]
 pub struct EHImageStream {
     client: GhostClient,
-    raw_client: reqwest::Client,
-    image_page_links: std::vec::IntoIter<String>,
+    proxy: ProxiedClient,
+    image_cache: Option<ImageCache>,
+    login: Option<Login>,
+    resolution: Resolution,
+    fallback: Option<Arc<ArchiveFallback>>,
+    image_page_links: std::iter::Enumerate<std::vec::IntoIter<String>>,
+    // to retry the failed pages from the archive
+    links: Vec<String>,
+}
+
+/// Shared by the pages of a gallery. Once too many pages fail, the archive is
+/// downloaded and it provides the failed page and all the later ones. The pages
+/// failed before are retried from it by `AsyncStream::retry`.
+#[derive(Debug)]
+struct ArchiveFallback {
+    archiver: String,
+    original: bool,
+    max_failures: usize,
+    max_bytes: Option<u64>,
+    failures: AtomicUsize,
+    images: tokio::sync::OnceCell<Result<ArchiveImages, String>>,
+}
+
+#[derive(Debug)]
+struct ArchiveImages {
+    archive: Arc<zip::Archive>,
+    // entries of the pages in order
+    pages: Vec<zip::Entry>,
+}
+
+impl ArchiveFallback {
+    fn new(archiver: String, original: bool, max_failures: usize) -> Self {
+        Self {
+            archiver,
+            original,
+            max_failures,
+            max_bytes: None,
+            failures: AtomicUsize::new(0),
+            images: tokio::sync::OnceCell::new(),
+        }
+    }
+
+    fn with_max_bytes(mut self, max_bytes: Option<u64>) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    fn is_active(&self) -> bool {
+        self.failures.load(Ordering::Relaxed) > self.max_failures
+    }
+
+    /// Count a failed page, true if the archive is used from now on.
+    fn record_failure(&self) -> bool {
+        self.failures.fetch_add(1, Ordering::Relaxed) + 1 > self.max_failures
+    }
+
+    /// The image of the `index`th page, the archive is downloaded by the first caller.
+    async fn image(
+        &self,
+        client: &GhostClient,
+        index: usize,
+        link: String,
+    ) -> anyhow::Result<(ImageMeta, ImageData)> {
+        let images = self
+            .images
+            .get_or_init(|| async {
+                tracing::info!(
+                    "[e-hentai] too many pages failed, download {}",
+                    self.archiver
+                );
+                download_archive(client, &self.archiver, self.original, self.max_bytes)
+                    .await
+                    .map_err(|e| format!("{e:#}"))
+            })
+            .await
+            .as_ref()
+            .map_err(|e| anyhow::anyhow!("unable to download the archive: {e}"))?;
+        let entry = images
+            .pages
+            .get(index)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("page {} is not in the archive", index + 1))?;
+        let archive = images.archive.clone();
+        let meta = ImageMeta {
+            id: link,
+            url: format!("{}#{}", self.archiver, entry.name),
+            description: None,
+        };
+        let data = tokio::task::spawn_blocking(move || archive.read(&entry)).await??;
+        Ok((meta, data))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum NameChunk {
+    Number(u64),
+    Text(String),
+}
+
+/// Digits are compared as numbers, so `2.jpg` comes before `10.jpg`.
+fn name_key(name: &str) -> Vec<NameChunk> {
+    let mut chunks = Vec::new();
+    let mut rest = name;
+    while let Some(c) = rest.chars().next() {
+        let digit = c.is_ascii_digit();
+        let end = rest
+            .find(|c: char| c.is_ascii_digit() != digit)
+            .unwrap_or(rest.len());
+        let (chunk, tail) = rest.split_at(end);
+        chunks.push(match digit {
+            true => NameChunk::Number(chunk.parse().unwrap_or(u64::MAX)),
+            false => NameChunk::Text(chunk.to_lowercase()),
+        });
+        rest = tail;
+    }
+    chunks
+}
+
+/// Request an archive by the archiver page and download it, which is paid by the account.
+/// It is written to a temporary file and given up beyond `max_bytes`.
+async fn download_archive(
+    client: &GhostClient,
+    archiver: &str,
+    original: bool,
+    max_bytes: Option<u64>,
+) -> anyhow::Result<ArchiveImages> {
+    let form = match original {
+        true => [("dltype", "org"), ("dlcheck", "Download Original Archive")],
+        false => [("dltype", "res"), ("dlcheck", "Download Resample Archive")],
+    };
+    let page = client
+        .post(archiver)
+        .form(&form)
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    let location = match_first_group(&ARCHIVE_LOCATION_RE, &page)
+        .ok_or_else(|| anyhow::anyhow!("no archive is prepared, maybe not enough GP or credits"))?;
+    let separator = if location.contains('?') { '&' } else { '?' };
+    let mut resp = client
+        .get(format!("{location}{separator}start=1"))
+        .timeout(ARCHIVE_TIMEOUT)
+        .send()
+        .await?
+        .error_for_status()?;
+    let too_large = |len: u64| max_bytes.filter(|&max| len > max);
+    if let Some(max) = resp.content_length().and_then(too_large) {
+        anyhow::bail!("the archive is larger than {max} bytes");
+    }
+
+    let path = std::env::temp_dir().join(format!("eh2telegraph-{}.zip", uuid::Uuid::new_v4()));
+    let written = write_archive(&mut resp, &path, max_bytes).await;
+    let opened = match written {
+        Ok(len) => tokio::task::spawn_blocking({
+            let path = path.clone();
+            move || anyhow::Ok((zip::Archive::open(std::fs::File::open(path)?)?, len))
+        })
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|r| r),
+        Err(e) => Err(e),
+    };
+    // the opened file is still readable, the space is freed with the archive
+    if let Err(e) = tokio::fs::remove_file(&path).await {
+        tracing::warn!("[e-hentai] unable to remove {}: {e}", path.display());
+    }
+    let (archive, len) = opened?;
+
+    let archive = Arc::new(archive);
+    let pages = tokio::task::spawn_blocking({
+        let archive = archive.clone();
+        move || {
+            let mut pages = Vec::new();
+            for entry in archive.entries() {
+                // like the info file of the gallery
+                if ImageKind::sniff(&archive.read_head(entry, 32)?) != ImageKind::Unknown {
+                    pages.push(entry.clone());
+                }
+            }
+            pages.sort_by_cached_key(|e| name_key(&e.name));
+            anyhow::Ok(pages)
+        }
+    })
+    .await??;
+    tracing::info!(
+        "[e-hentai] downloaded the archive of {} images, {len} bytes",
+        pages.len(),
+    );
+    Ok(ArchiveImages { archive, pages })
+}
+
+/// Stream the body into the file at `path`, up to `max_bytes`.
+async fn write_archive(
+    resp: &mut reqwest::Response,
+    path: &Path,
+    max_bytes: Option<u64>,
+) -> anyhow::Result<u64> {
+    let mut file = tokio::fs::File::create(path).await?;
+    let mut written = 0;
+    while let Some(chunk) = resp.chunk().await? {
+        written += chunk.len() as u64;
+        if let Some(max) = max_bytes.filter(|&max| written > max) {
+            anyhow::bail!("the archive is larger than {max} bytes");
+        }
+        file.write_all(&chunk).await?;
+    }
+    file.flush().await?;
+    Ok(written)
 }
 
 impl EHImageStream {
     async fn load_image(
         client: &GhostClient,
-        raw_client: &reqwest::Client,
+        proxy: &ProxiedClient,
+        image_cache: Option<&ImageCache>,
+        login: Option<&Login>,
+        resolution: Resolution,
         link: String,
     ) -> anyhow::Result<(ImageMeta, ImageData)> {
+        // cached by the image page, so a hit needs no request. The url of the image
+        // is only known from the page, the link stands for it then.
+        if let Some(cache) = image_cache {
+            let originals = login.is_some() && resolution != Resolution::Resampled;
+            let keys = [format!("{link}#original"), link.clone()];
+            for key in keys.iter().skip(usize::from(!originals)) {
+                if let Some(data) = cache.get(key).await {
+                    let meta = ImageMeta {
+                        id: link.clone(),
+                        url: link,
+                        description: None,
+                    };
+                    return Ok((meta, data));
+                }
+            }
+        }
+
         let content = RETRY_POLICY
             .retry(|| async { get_string(client, &link).await })
             .await?;
         let img_url = match_first_group(&IMG_RE, &content)
             .ok_or_else(|| anyhow::anyhow!("unable to find image in page"))?;
-        let image_data = RETRY_POLICY
-            .retry(|| async { get_bytes(raw_client, img_url).await })
-            .await?;
+        // the image nodes reject requests not referred by the image page
+        let mut headers = header::HeaderMap::new();
+        headers.insert(header::REFERER, header::HeaderValue::from_str(&link)?);
+        // resumed on retries
+        let download = |key: String, url: String, headers: header::HeaderMap| async move {
+            download_cached(image_cache, &key, proxy, &url, headers, &RETRY_POLICY).await
+        };
+
+        // the original is redirected to an image node by e-hentai, for members only
+        if let Some((login, original)) =
+            login.and_then(|l| Some((l, original_url(&content, resolution)?)))
+        {
+            let mut headers = headers.clone();
+            headers.insert(header::COOKIE, login.cookie.clone());
+            // the image nodes change between visits
+            let key = format!("{link}#original");
+            let fetch = async {
+                let data = download(key.clone(), original.clone(), headers).await?;
+                // like the text telling there is not enough GP
+                if ImageKind::sniff(&data) == ImageKind::Unknown {
+                    if let Some(cache) = image_cache {
+                        cache.remove(&key).await;
+                    }
+                    anyhow::bail!("{}", String::from_utf8_lossy(&data[..data.len().min(200)]));
+                }
+                Ok(data)
+            };
+            match fetch.await {
+                Ok(image_data) => {
+                    let meta = ImageMeta {
+                        id: link,
+                        url: original,
+                        description: None,
+                    };
+                    return Ok((meta, image_data));
+                }
+                Err(e) => tracing::warn!(
+                    "[e-hentai] unable to download the original of {link}, use the resampled one: {e}"
+                ),
+            };
+        }
+        let image_data = download(link.clone(), img_url.to_string(), headers).await?;
 
         tracing::trace!(
             "download e-hentai image with size {}, link: {link}",
@@ -174,22 +684,88 @@ impl EHImageStream {
     }
 }
 
+/// The original of an image page to download by the resolution, if it is resampled.
+fn original_url(content: &str, resolution: Resolution) -> Option<String> {
+    let original = unescape_html(match_first_group(&ORIGINAL_RE, content)?);
+    match resolution {
+        Resolution::Resampled => None,
+        Resolution::Original => Some(original),
+        Resolution::Auto => match_first_group(&ORIGINAL_SIZE_RE, content)
+            .and_then(parse_size)
+            .filter(|size| *size < MAX_SINGLE_FILE_SIZE)
+            .map(|_| original),
+    }
+}
+
+/// Bytes of sizes like `2.1 MiB`.
+fn parse_size(size: &str) -> Option<usize> {
+    let (value, unit) = size.split_once(' ')?;
+    let unit = match unit {
+        "KiB" => 1 << 10,
+        "MiB" => 1 << 20,
+        "GiB" => 1 << 30,
+        _ => return None,
+    };
+    Some((value.parse::<f64>().ok()? * unit as f64) as usize)
+}
+
+impl EHImageStream {
+    fn page(
+        &self,
+        index: usize,
+        link: String,
+    ) -> impl std::future::Future<Output = anyhow::Result<(ImageMeta, ImageData)>> {
+        let client = self.client.clone();
+        let proxy = self.proxy.clone();
+        let image_cache = self.image_cache.clone();
+        let login = self.login.clone();
+        let resolution = self.resolution;
+        let fallback = self.fallback.clone();
+        async move {
+            if let Some(fallback) = fallback.as_ref().filter(|f| f.is_active()) {
+                return fallback.image(&client, index, link).await;
+            }
+            let result = Self::load_image(
+                &client,
+                &proxy,
+                image_cache.as_ref(),
+                login.as_ref(),
+                resolution,
+                link.clone(),
+            )
+            .await;
+            match (result, fallback) {
+                (Err(e), Some(fallback)) if fallback.record_failure() => {
+                    tracing::warn!("[e-hentai] unable to load {link}, use the archive: {e}");
+                    fallback.image(&client, index, link).await
+                }
+                (result, _) => result,
+            }
+        }
+    }
+}
+
 impl AsyncStream for EHImageStream {
     type Item = anyhow::Result<(ImageMeta, ImageData)>;
 
     type Future = impl std::future::Future<Output = Self::Item>;
 
     fn next(&mut self) -> Option<Self::Future> {
-        let link = self.image_page_links.next()?;
-        let client = self.client.clone();
-        let raw_client = self.raw_client.clone();
-        Some(async move { Self::load_image(&client, &raw_client, link).await })
+        let (index, link) = self.image_page_links.next()?;
+        Some(self.page(index, link))
     }
 
     #[inline]
     fn size_hint(&self) -> (usize, Option<usize>) {
         self.image_page_links.size_hint()
     }
+
+    /// Only once the archive is used, which provides all the pages.
+    fn retry(&mut self, pos: usize) -> Option<Self::Future> {
+        self.fallback.as_ref().filter(|f| f.is_active())?;
+        let link = self.links.get(pos)?.clone();
+        Some(self.page(pos, link))
+    }
 }
 
 struct EHPageIndicator {
@@ -219,10 +795,7 @@ mod tests {
     #[ignore]
     #[tokio::test]
     async fn demo() {
-        let collector = EHCollector {
-            raw_client: Default::default(),
-            client: Default::default(),
-        };
+        let collector = EHCollector::new(None);
         let (album, mut image_stream) = collector
             .fetch("/g/2122174/fd2525031e".to_string())
             .await
@@ -236,6 +809,427 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_check_removed() {
+        for html in [
+            "<div class=\"d\"><p>This gallery has been removed or is unavailable.</p></div>",
+            "Key missing, or incorrect key provided.",
+        ] {
+            assert!(matches!(check_removed(html), Err(CollectorError::NotFound)));
+        }
+        let gallery = include_str!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/fixtures/e_hentai_gallery.html"
+        ));
+        assert!(check_removed(gallery).is_ok());
+    }
+
+    #[test]
+    fn test_parse_gallery_meta() {
+        let html = include_str!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/fixtures/e_hentai_gallery.html"
+        ));
+        let meta = parse_gallery_meta(
+            html,
+            "https://e-hentai.org/g/1/x".to_string(),
+            || unreachable!(),
+        );
+        assert_eq!(
+            meta.name,
+            "[Circle (Artist Name)] Sample Gallery Title [English]"
+        );
+        assert_eq!(
+            meta.japanese_name.as_deref(),
+            Some("[サークル (作者名)] サンプル画廊 [英訳]")
+        );
+        assert_eq!(meta.class.as_deref(), Some("Doujinshi"));
+        assert_eq!(meta.language.as_deref(), Some("English"));
+        assert_eq!(meta.page_count, Some(24));
+        assert_eq!(meta.authors.unwrap(), ["artist name"]);
+        assert_eq!(
+            meta.tags.unwrap(),
+            [
+                "language:english",
+                "language:translated",
+                "parody:original",
+                "group:circle",
+                "artist:artist name",
+                "female:glasses",
+                "female:twintails",
+                "other:full color"
+            ]
+        );
+
+        // titles and tags are html text
+        let escaped = html
+            .replace("Sample Gallery Title", "Tom &amp; Jerry&#039;s")
+            .replace(">artist name<", ">&quot;artist&quot;<");
+        let meta = parse_gallery_meta(&escaped, String::new(), || unreachable!());
+        assert_eq!(meta.name, "[Circle (Artist Name)] Tom & Jerry's [English]");
+        assert_eq!(meta.authors.unwrap(), [r#""artist""#]);
+        assert!(meta
+            .tags
+            .unwrap()
+            .contains(&r#"artist:"artist""#.to_string()));
+
+        let meta = parse_gallery_meta("", "https://e-hentai.org/g/1/x".to_string(), || {
+            "e-hentai-1".to_string()
+        });
+        assert_eq!(meta.name, "e-hentai-1");
+        assert!(meta.tags.is_none() && meta.language.is_none() && meta.page_count.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_load_image_referer() {
+        use crate::mock_server::{MockResponse, MockServer};
+
+        let server = MockServer::start(|_, req| match req.path.as_str() {
+            // proxied to the image node
+            "/s/abc/1-1" => {
+                MockResponse::new(200, r#"<img id="img" src="https://ehgt.org/1.jpg" />"#)
+            }
+            // the image is on the mock server itself
+            "/s/abc/1-2" => {
+                let host = req.header("host").unwrap();
+                MockResponse::new(
+                    200,
+                    format!(r#"<img id="img" src="http://{host}/2.jpg" />"#),
+                )
+            }
+            _ => MockResponse::new(200, "image"),
+        })
+        .await;
+        let (client, direct) = (GhostClient::default(), ProxiedClient::default());
+        let proxy = ProxiedClient::new(&server.url("/"), "token").unwrap();
+
+        let link = server.url("/s/abc/1-1");
+        let (meta, data) = EHImageStream::load_image(
+            &client,
+            &proxy,
+            None,
+            None,
+            Resolution::default(),
+            link.clone(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(meta.url, "https://ehgt.org/1.jpg");
+        assert_eq!(&data[..], b"image");
+        let requests = server.requests();
+        assert!(requests[0].header("referer").is_none());
+        assert_eq!(
+            requests[1].header("x-forwarded-for"),
+            Some("https://ehgt.org/1.jpg")
+        );
+        assert_eq!(requests[1].header("x-authorization"), Some("token"));
+        assert_eq!(requests[1].header("referer"), Some(link.as_str()));
+
+        let link = server.url("/s/abc/1-2");
+        let (_, data) = EHImageStream::load_image(
+            &client,
+            &direct,
+            None,
+            None,
+            Resolution::default(),
+            link.clone(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(&data[..], b"image");
+        let requests = server.requests();
+        assert_eq!(requests[3].path, "/2.jpg");
+        assert!(requests[3].header("x-forwarded-for").is_none());
+        assert_eq!(requests[3].header("referer"), Some(link.as_str()));
+    }
+
+    #[tokio::test]
+    async fn test_load_image_cached() {
+        use crate::mock_server::{MockResponse, MockServer};
+
+        let server = MockServer::start(|_, req| match req.path.as_str() {
+            "/s/abc/1-1" => {
+                let host = req.header("host").unwrap();
+                MockResponse::new(
+                    200,
+                    format!(r#"<img id="img" src="http://{host}/1.jpg" />"#),
+                )
+            }
+            _ => MockResponse::new(200, "image"),
+        })
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let cache = ImageCache::open(dir.path(), None).unwrap();
+        let (client, proxy) = (GhostClient::default(), ProxiedClient::default());
+
+        let link = server.url("/s/abc/1-1");
+        for _ in 0..2 {
+            let (meta, data) = EHImageStream::load_image(
+                &client,
+                &proxy,
+                Some(&cache),
+                None,
+                Resolution::default(),
+                link.clone(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(meta.id, link);
+            assert_eq!(&data[..], b"image");
+        }
+        // neither the page nor the image is fetched again
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_login() {
+        use crate::mock_server::{MockResponse, MockServer};
+
+        let server = MockServer::start(|_, req| match req.path.as_str() {
+            "/s/abc/1-1" => MockResponse::new(
+                200,
+                r#"<img id="img" src="https://ehgt.org/1.jpg" /><a href="https://e-hentai.org/fullimg/1/1/key/1.png">Download original 1280 x 1810 2.1 MiB source</a>"#,
+            ),
+            "/s/abc/1-2" => MockResponse::new(
+                200,
+                r#"<img id="img" src="https://ehgt.org/2.jpg" /><a href="https://e-hentai.org/fullimg/1/2/key/2.png">Download original 1280 x 1810 2.1 MiB source</a>"#,
+            ),
+            _ => match req.header("x-forwarded-for") {
+                Some(url) if url.contains("/fullimg/1/2/") => {
+                    MockResponse::new(200, "Insufficient GP")
+                }
+                _ => MockResponse::new(200, b"\xFF\xD8\xFFimage".to_vec()),
+            },
+        })
+        .await;
+        let mut config = EHConfig {
+            ipb_member_id: "1".to_string(),
+            ipb_pass_hash: "abc".to_string(),
+            resolution: None,
+            original: true,
+            archive_fallback: None,
+        };
+        let cookie = "ipb_member_id=1;ipb_pass_hash=abc;nw=1";
+        let proxy = ProxiedClient::new(&server.url("/"), "token").unwrap();
+        let load_page = |collector: EHCollector, page: &str| {
+            let (proxy, link) = (proxy.clone(), server.url(page));
+            let gallery = server.url("/g/1/x/?p=0");
+            async move {
+                // gallery pages are requested by the same client
+                get_string(&collector.client, &gallery).await.unwrap();
+                EHImageStream::load_image(
+                    &collector.client,
+                    &proxy,
+                    None,
+                    collector.login.as_ref(),
+                    collector.resolution,
+                    link,
+                )
+                .await
+                .unwrap()
+                .0
+            }
+        };
+        let load = |collector: EHCollector| load_page(collector, "/s/abc/1-1");
+
+        let meta = load(EHCollector::new_with_login(&config, None).unwrap()).await;
+        assert_eq!(meta.url, "https://e-hentai.org/fullimg/1/1/key/1.png");
+        let requests = server.requests();
+        assert_eq!(requests[0].header("cookie"), Some(cookie));
+        assert_eq!(requests[1].header("cookie"), Some(cookie));
+        assert_eq!(
+            requests[2].header("x-forwarded-for"),
+            Some(meta.url.as_str())
+        );
+        assert_eq!(requests[2].header("cookie"), Some(cookie));
+
+        // resampled images do not need the cookies
+        config.original = false;
+        let meta = load(EHCollector::new_with_login(&config, None).unwrap()).await;
+        assert_eq!(meta.url, "https://ehgt.org/1.jpg");
+        let requests = server.requests();
+        assert_eq!(requests[4].header("cookie"), Some(cookie));
+        assert_eq!(requests[5].header("cookie"), None);
+
+        load(EHCollector::new(None)).await;
+        let requests = server.requests();
+        assert_eq!(requests[6].header("cookie"), Some("nw=1"));
+        assert_eq!(requests[7].header("cookie"), Some("nw=1"));
+        assert_eq!(requests[8].header("cookie"), None);
+
+        // without enough GP
+        config.resolution = Some(Resolution::Original);
+        let collector = EHCollector::new_with_login(&config, None).unwrap();
+        let meta = load_page(collector, "/s/abc/1-2").await;
+        assert_eq!(meta.url, "https://ehgt.org/2.jpg");
+    }
+
+    #[test]
+    fn test_original_url() {
+        let page = |size: &str| {
+            format!(
+                r#"<img id="img" src="https://ehgt.org/1.jpg" /><a href="https://e-hentai.org/fullimg/1/1/key/1.png?a=1&amp;b=2">Download original 2400 x 3400 {size} source</a>"#
+            )
+        };
+        let original = "https://e-hentai.org/fullimg/1/1/key/1.png?a=1&b=2";
+        let small = page("2.1 MiB");
+        for (resolution, expected) in [
+            (Resolution::Resampled, None),
+            (Resolution::Original, Some(original)),
+            (Resolution::Auto, Some(original)),
+        ] {
+            assert_eq!(
+                original_url(&small, resolution).as_deref(),
+                expected,
+                "{resolution:?}"
+            );
+        }
+        // too large to upload without re-encoding
+        let large = page("7.5 MiB");
+        assert_eq!(original_url(&large, Resolution::Auto), None);
+        assert_eq!(
+            original_url(&large, Resolution::Original).as_deref(),
+            Some(original)
+        );
+        assert_eq!(
+            original_url(&page("800 KiB"), Resolution::Auto).as_deref(),
+            Some(original)
+        );
+        // already the original
+        let original_page = r#"<img id="img" src="https://ehgt.org/1.jpg" />"#;
+        assert_eq!(original_url(original_page, Resolution::Original), None);
+    }
+
+    #[test]
+    fn test_gallery_url() {
+        let collector = EHCollector::new(None);
+        let (url, id) = collector.gallery_url("/g/1/abc/").unwrap();
+        assert_eq!((url.as_str(), id), ("https://e-hentai.org/g/1/abc", "1"));
+        let collector = collector.with_host("e-hentai.example");
+        let (url, _) = collector.gallery_url("g/1/abc").unwrap();
+        assert_eq!(url, "https://e-hentai.example/g/1/abc");
+        assert!(collector.gallery_url("/s/1/abc").is_err());
+
+        // image pages link to the host the gallery is loaded from
+        let page = r#"<a href="https://e-hentai.example/s/bd2b37d829/1-7"><img"#;
+        assert_eq!(
+            match_first_group(&PAGE_RE, page),
+            Some("https://e-hentai.example/s/bd2b37d829/1-7")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_archive_fallback() {
+        use crate::mock_server::{MockResponse, MockServer};
+
+        let archive = include_bytes!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/fixtures/e_hentai_archive.zip"
+        ));
+        let server = MockServer::start(move |_, req| match req.path.as_str() {
+            "/s/p/3" => {
+                let host = req.header("host").unwrap();
+                MockResponse::new(
+                    200,
+                    format!(r#"<img id="img" src="http://{host}/3.jpg" />"#),
+                )
+            }
+            "/3.jpg" => MockResponse::new(200, "image"),
+            "/archiver.php?gid=1&token=x" => {
+                let host = req.header("host").unwrap();
+                MockResponse::new(
+                    200,
+                    format!(r#"<script>document.location = "http://{host}/archive/1/x";</script>"#),
+                )
+            }
+            "/archive/1/x?start=1" => MockResponse::new(200, archive.to_vec()),
+            _ => MockResponse::new(404, ""),
+        })
+        .await;
+        let load = |max_failures: usize| {
+            let fallback = ArchiveFallback::new(
+                server.url("/archiver.php?gid=1&token=x"),
+                false,
+                max_failures,
+            );
+            let links = ["/s/p/1", "/s/p/2", "/s/p/3"].map(|p| server.url(p));
+            let mut stream = EHImageStream {
+                client: GhostClient::default(),
+                proxy: ProxiedClient::default(),
+                image_cache: None,
+                login: None,
+                resolution: Resolution::Resampled,
+                fallback: Some(Arc::new(fallback)),
+                image_page_links: Vec::from(links.clone()).into_iter().enumerate(),
+                links: links.into(),
+            };
+            async move {
+                let mut results = Vec::new();
+                while let Some(fut) = stream.next() {
+                    results.push(fut.await);
+                }
+                let retried = match stream.retry(0) {
+                    Some(fut) => Some(fut.await),
+                    None => None,
+                };
+                (results, retried)
+            }
+        };
+
+        // one failure of the three pages is allowed
+        let (results, retried) = load(1).await;
+        assert!(results[0].is_err());
+        // the page failed before is retried from the archive
+        let (meta, data) = retried.unwrap().unwrap();
+        assert!(meta.url.ends_with("#01.jpg"));
+        assert_eq!(&data[..], b"\xFF\xD8\xFFstored");
+        let (meta, data) = results[1].as_ref().unwrap();
+        assert_eq!(meta.id, server.url("/s/p/2"));
+        assert!(meta.url.ends_with("#02.png"));
+        assert!(data.starts_with(b"\x89PNG"));
+        // no longer loaded once the archive is used, the info file is not a page
+        let (meta, data) = results[2].as_ref().unwrap();
+        assert!(meta.url.ends_with("#10.jpg"));
+        assert_eq!(&data[..], b"\xFF\xD8\xFFthird");
+        let requests = server.requests();
+        let mut paths = requests.iter().map(|r| r.path.as_str()).collect::<Vec<_>>();
+        // failed pages are retried
+        paths.dedup();
+        assert_eq!(
+            paths,
+            [
+                "/s/p/1",
+                "/s/p/2",
+                "/archiver.php?gid=1&token=x",
+                "/archive/1/x?start=1"
+            ]
+        );
+        let archiver = requests
+            .iter()
+            .find(|r| r.path.starts_with("/archiver"))
+            .unwrap();
+        assert_eq!(archiver.method, "POST");
+        assert_eq!(
+            archiver.body,
+            b"dltype=res&dlcheck=Download+Resample+Archive"
+        );
+
+        // below the threshold the pages are loaded as usual
+        let (results, retried) = load(2).await;
+        assert!(results[0].is_err() && results[1].is_err() && retried.is_none());
+        assert!(results[2].as_ref().unwrap().0.url.ends_with("/3.jpg"));
+        let requests = server.requests();
+        let archive_requests = requests.iter().filter(|r| r.path.starts_with("/archive"));
+        assert_eq!(archive_requests.count(), 2);
+    }
+
+    #[test]
+    fn test_name_key() {
+        let mut names = ["10.jpg", "b.png", "2.jpg", "A.png", "01.jpg"];
+        names.sort_by_cached_key(|n| name_key(n));
+        assert_eq!(names, ["01.jpg", "2.jpg", "10.jpg", "A.png", "b.png"]);
+    }
+
     #[ignore]
     #[test]
     fn regex_match() {
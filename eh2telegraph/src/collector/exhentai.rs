@@ -8,41 +8,66 @@ use serde::Deserialize;
 
 use crate::{
     config,
-    http_client::{GhostClient, GhostClientBuilder},
+    http_client::{GhostClient, GhostClientBuilder, HttpRequestBuilder},
+    http_proxy::{ProxiedClient, ProxyError},
+    storage::image_cache::{download_cached, ImageCache},
     stream::AsyncStream,
-    util::{get_bytes, get_string, match_first_group},
+    util::{get_string, match_first_group},
 };
 
 use super::{
-    utils::paged::{PageFormatter, PageIndicator, Paged},
-    AlbumMeta, Collector, ImageData, ImageMeta,
+    e_hentai::{check_removed, parse_gallery_meta},
+    hosts::{Hosts, SiteHosts},
+    utils::{
+        fetch::{send_gallery_request, GALLERY_RETRY},
+        paged::{PageFormatter, PageIndicator, Paged},
+    },
+    AlbumMeta, Collector, CollectorError, ImageData, ImageMeta,
 };
 
 lazy_static::lazy_static! {
-    static ref PAGE_RE: Regex = Regex::new(r#"<a href="(https://exhentai\.org/s/\w+/[\w-]+)">"#).unwrap();
+    // on any host, the links follow the host the gallery is requested from
+    static ref PAGE_RE: Regex = Regex::new(r#"<a href="(https://[\w.-]+/s/\w+/[\w-]+)">"#).unwrap();
     static ref IMG_RE: Regex = Regex::new(r#"<img id="img" src="(.*?)""#).unwrap();
-    static ref TITLE_RE: Regex = Regex::new(r#"<h1 id="gn">(.*?)</h1>"#).unwrap();
 
     static ref RETRY_POLICY: RetryPolicy = RetryPolicy::fixed(Duration::from_millis(200))
         .with_max_retries(5)
         .with_jitter(true);
 }
 const CONFIG_KEY: &str = "exhentai";
-const TIMEOUT: Duration = Duration::from_secs(30);
+// pages served instead of the gallery when the cookies are not accepted
+const INTERSTITIAL_MARKERS: [&str; 2] = [
+    "This page requires you to log on.",
+    "<h1>Content Warning</h1>",
+];
+// small and only served to logged in users
+const SESSION_PATH: &str = "/uconfig.php";
 
 #[derive(Debug, Clone)]
 pub struct EXCollector {
     ghost_client: GhostClient,
-    raw_client: reqwest::Client,
+    // images, direct if it is not given by `with_proxy`
+    proxy: ProxiedClient,
+    image_cache: Option<ImageCache>,
+    // of the galleries, `collectors.exhentai.hosts`
+    hosts: SiteHosts,
+    // the first one of `hosts` if not set
+    host: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize)]
 pub struct ExConfig {
     pub ipb_pass_hash: String,
     pub ipb_member_id: String,
     pub igneous: String,
 }
 
+#[derive(Debug, Default, Deserialize)]
+struct PreflightConfig {
+    #[serde(default)]
+    preflight: bool,
+}
+
 impl ExConfig {
     fn build_header(&self) -> HeaderMap {
         let cookie_value = format!(
@@ -61,31 +86,79 @@ impl ExConfig {
     }
 }
 
+fn client_builder(config: &ExConfig, hosts: &SiteHosts) -> GhostClientBuilder {
+    GhostClientBuilder::default()
+        .with_default_headers(config.build_header())
+        .with_cf_resolve(hosts.iter())
+}
+
 impl EXCollector {
+    /// With the default hosts.
     pub fn new(config: &ExConfig, prefix: Option<Ipv6Net>) -> anyhow::Result<Self> {
-        Ok(Self {
-            ghost_client: GhostClientBuilder::default()
-                .with_default_headers(config.build_header())
-                .with_cf_resolve(&["exhentai.org"])
-                .build(prefix),
-            raw_client: reqwest::Client::builder().timeout(TIMEOUT).build().unwrap(),
-        })
+        let hosts = Hosts::default().exhentai;
+        let client = client_builder(config, &hosts).build(prefix);
+        Ok(Self::with_client(client, hosts))
     }
 
-    pub fn new_from_config() -> anyhow::Result<Self> {
+    pub fn new_from_config(hosts: &Hosts) -> anyhow::Result<Self> {
         let config: ExConfig = config::parse(CONFIG_KEY)?
             .ok_or_else(|| anyhow::anyhow!("exhentai config(key: exhentai) not found"))?;
-        Ok(Self {
-            ghost_client: GhostClientBuilder::default()
-                .with_default_headers(config.build_header())
-                .with_cf_resolve(&["exhentai.org"])
-                .build_from_config()?,
-            raw_client: reqwest::Client::builder().timeout(TIMEOUT).build().unwrap(),
-        })
+        let hosts = hosts.exhentai.clone();
+        let client = client_builder(&config, &hosts).build_from_config()?;
+        Ok(Self::with_client(client, hosts))
+    }
+
+    fn with_client(ghost_client: GhostClient, hosts: SiteHosts) -> Self {
+        Self {
+            ghost_client,
+            proxy: ProxiedClient::default(),
+            image_cache: None,
+            hosts,
+            host: None,
+        }
+    }
+
+    /// The hosts galleries are matched by.
+    pub fn hosts(&self) -> &SiteHosts {
+        &self.hosts
+    }
+
+    /// Download images through the proxy, gallery pages still use the ghost client.
+    pub fn with_proxy(mut self, client: ProxiedClient) -> Self {
+        self.proxy = client;
+        self
+    }
+
+    pub fn with_image_cache(mut self, cache: ImageCache) -> Self {
+        self.image_cache = Some(cache);
+        self
+    }
+
+    /// The client images are downloaded with.
+    pub fn get_client(&self) -> ProxiedClient {
+        self.proxy.clone()
+    }
+
+    /// Request galleries from `host` instead of the configured one.
+    pub fn with_host(mut self, host: impl Into<String>) -> Self {
+        self.host = Some(host.into());
+        self
+    }
+
+    /// Whether `exhentai.preflight` asks for `check_session` at startup.
+    pub fn preflight_enabled() -> anyhow::Result<bool> {
+        Ok(config::parse::<PreflightConfig>(CONFIG_KEY)?.is_some_and(|c| c.preflight))
     }
 
-    pub fn get_client(&self) -> reqwest::Client {
-        self.raw_client.clone()
+    /// Request a page with the cookies, failing with `CollectorError::RequiresAuth`
+    /// if they are not accepted, so stale cookies are found before the first sync.
+    pub async fn check_session(&self) -> Result<(), CollectorError> {
+        let url = format!("https://{}{SESSION_PATH}", self.host());
+        check_session(&self.ghost_client, &url).await
+    }
+
+    fn host(&self) -> &str {
+        self.host.as_deref().unwrap_or_else(|| self.hosts.primary())
     }
 }
 
@@ -99,6 +172,10 @@ impl Collector for EXCollector {
         "e-hentai"
     }
 
+    fn matches(&self, url: &url::Url) -> bool {
+        self.hosts.matches_gallery(url)
+    }
+
     async fn fetch(
         &self,
         path: String,
@@ -114,7 +191,7 @@ impl Collector for EXCollector {
                 return Err(anyhow::anyhow!("invalid input path({path}), gallery url is expected(like https://exhentai.org/g/2129939/01a6e086b9)"));
             }
         };
-        let url = format!("https://exhentai.org/g/{album_id}/{album_token}");
+        let url = format!("https://{}/g/{album_id}/{album_token}", self.host());
         tracing::info!("[exhentai] process {url}");
 
         let mut paged = Paged::new(0, EXPageIndicator { base: url.clone() });
@@ -123,11 +200,11 @@ impl Collector for EXCollector {
             e
         })?;
         tracing::info!("[exhentai] pages loaded for {album_id}/{album_token}");
+        check_interstitial(&gallery_pages[0])?;
+        check_removed(&gallery_pages[0])?;
 
         // Since paged returns at least one page, we can safely get it.
-        let title = match_first_group(&TITLE_RE, &gallery_pages[0])
-            .map(|s| s.to_string())
-            .unwrap_or_else(|| format!("exhentai-{album_id}"));
+        let meta = parse_gallery_meta(&gallery_pages[0], url, || format!("exhentai-{album_id}"));
 
         let mut image_page_links = Vec::new();
         for gallery_page in gallery_pages.iter() {
@@ -144,44 +221,78 @@ impl Collector for EXCollector {
         }
 
         Ok((
-            AlbumMeta {
-                link: url,
-                name: title,
-                class: None,
-                description: None,
-                authors: None,
-                tags: None,
-            },
+            meta,
             EXImageStream {
-                raw_client: self.raw_client.clone(),
+                proxy: self.proxy.clone(),
                 ghost_client: self.ghost_client.clone(),
+                image_cache: self.image_cache.clone(),
                 image_page_links: image_page_links.into_iter(),
             },
         ))
     }
 }
 
+/// Without valid cookies exhentai serves an empty page(the sad panda), a login page,
+/// or the content warning which `nw=1` skips for logged in users only.
+fn check_interstitial(html: &str) -> Result<(), CollectorError> {
+    if html.trim().is_empty() || INTERSTITIAL_MARKERS.iter().any(|m| html.contains(m)) {
+        return Err(CollectorError::RequiresAuth);
+    }
+    Ok(())
+}
+
+async fn check_session<C: HttpRequestBuilder>(client: &C, url: &str) -> Result<(), CollectorError> {
+    let resp = send_gallery_request(client, client.get_builder(url), &GALLERY_RETRY).await?;
+    // redirected to the forums to log on
+    if resp.url().as_str().to_ascii_lowercase().contains("login") {
+        return Err(CollectorError::RequiresAuth);
+    }
+    let html = resp.text().await.map_err(ProxyError::from)?;
+    check_interstitial(&html)
+}
+
 #[derive(Debug)]
 pub struct EXImageStream {
-    raw_client: reqwest::Client,
+    proxy: ProxiedClient,
     ghost_client: GhostClient,
+    image_cache: Option<ImageCache>,
     image_page_links: std::vec::IntoIter<String>,
 }
 
 impl EXImageStream {
     async fn load_image(
         ghost_client: GhostClient,
-        raw_client: reqwest::Client,
+        proxy: ProxiedClient,
+        image_cache: Option<ImageCache>,
         link: String,
     ) -> anyhow::Result<(ImageMeta, ImageData)> {
+        // cached by the image page like e-hentai, so a hit needs no request
+        if let Some(cache) = &image_cache {
+            if let Some(data) = cache.get(&link).await {
+                let meta = ImageMeta {
+                    id: link.clone(),
+                    url: link,
+                    description: None,
+                };
+                return Ok((meta, data));
+            }
+        }
+
         let content = RETRY_POLICY
             .retry(|| async { get_string(&ghost_client, &link).await })
             .await?;
         let img_url = match_first_group(&IMG_RE, &content)
             .ok_or_else(|| anyhow::anyhow!("unable to find image in page"))?;
-        let image_data = RETRY_POLICY
-            .retry(|| async { get_bytes(&raw_client, img_url).await })
-            .await?;
+        // resumed on retries
+        let image_data = download_cached(
+            image_cache.as_ref(),
+            &link,
+            &proxy,
+            img_url,
+            HeaderMap::new(),
+            &RETRY_POLICY,
+        )
+        .await?;
 
         tracing::trace!(
             "download exhentai image with size {}, link: {link}",
@@ -204,8 +315,9 @@ impl AsyncStream for EXImageStream {
     fn next(&mut self) -> Option<Self::Future> {
         let link = self.image_page_links.next()?;
         let ghost_client = self.ghost_client.clone();
-        let raw_client = self.raw_client.clone();
-        Some(async move { Self::load_image(ghost_client, raw_client, link).await })
+        let proxy = self.proxy.clone();
+        let image_cache = self.image_cache.clone();
+        Some(async move { Self::load_image(ghost_client, proxy, image_cache, link).await })
     }
 
     #[inline]
@@ -237,6 +349,65 @@ impl PageIndicator for EXPageIndicator {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::mock_server::{MockResponse, MockServer};
+
+    #[test]
+    fn test_check_interstitial() {
+        let interstitials = [
+            include_str!(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/fixtures/exhentai_login.html"
+            )),
+            include_str!(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/fixtures/exhentai_content_warning.html"
+            )),
+            "\n",
+        ];
+        for html in interstitials {
+            assert!(matches!(
+                check_interstitial(html),
+                Err(CollectorError::RequiresAuth)
+            ));
+        }
+        let gallery = include_str!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/fixtures/e_hentai_gallery.html"
+        ));
+        assert!(check_interstitial(gallery).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_check_session() {
+        let login = include_str!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/fixtures/exhentai_login.html"
+        ));
+        let server = MockServer::start(move |_, req| match req.path.as_str() {
+            "/valid/uconfig.php" => MockResponse::new(200, "<h1>Settings</h1>"),
+            "/login/uconfig.php" => MockResponse::new(200, login),
+            "/bounce/uconfig.php" => {
+                MockResponse::new(302, "").header("location", "/bounce_login.php")
+            }
+            "/bounce_login.php" => MockResponse::new(200, "<form>"),
+            // the sad panda
+            _ => MockResponse::new(200, ""),
+        })
+        .await;
+        let client = reqwest::Client::new();
+        let check = |prefix: &str| {
+            let url = server.url(&format!("{prefix}{SESSION_PATH}"));
+            let client = &client;
+            async move { check_session(client, &url).await }
+        };
+        assert!(check("/valid").await.is_ok());
+        for prefix in ["/login", "/bounce", "/panda"] {
+            assert!(
+                matches!(check(prefix).await, Err(CollectorError::RequiresAuth)),
+                "{prefix}"
+            );
+        }
+    }
 
     #[ignore]
     #[tokio::test]
@@ -0,0 +1,428 @@
+/// hitomi.la collector.
+/// Host matching: hitomi.la
+///
+/// Gallery info is read from `ltn.hitomi.la/galleries/<id>.js`. Image hosts are picked
+/// by hashing the file hash with the rules in `ltn.hitomi.la/gg.js`, which changes
+/// from time to time, so it is fetched for every gallery.
+use std::{collections::HashSet, time::Duration};
+
+use again::RetryPolicy;
+use regex::Regex;
+use reqwest::header;
+use serde::Deserialize;
+
+use crate::{
+    http_client::HttpRequestBuilder,
+    http_proxy::ProxiedClient,
+    storage::image_cache::{download_cached, ImageCache},
+    stream::AsyncStream,
+    util::match_first_group,
+};
+
+use super::{
+    utils::fetch::{send_gallery_request, GALLERY_RETRY},
+    AlbumMeta, Collector, ImageData, ImageMeta,
+};
+
+const LTN: &str = "https://ltn.hitomi.la";
+const IMAGE_DOMAIN: &str = "gold-usergeneratedcontent.net";
+// images are rejected without it
+const REFERER: &str = "https://hitomi.la/";
+
+lazy_static::lazy_static! {
+    static ref ID_RE: Regex = Regex::new(r#"(\d+)\.html$"#).unwrap();
+    static ref GG_DEFAULT_RE: Regex = Regex::new(r#"var o = (\d+)"#).unwrap();
+    static ref GG_CASE_RE: Regex = Regex::new(r#"case (\d+):"#).unwrap();
+    static ref GG_HIT_RE: Regex = Regex::new(r#"o = (\d+); break;"#).unwrap();
+    static ref GG_B_RE: Regex = Regex::new(r#"b: '(\d+/)'"#).unwrap();
+
+    static ref RETRY_POLICY: RetryPolicy = RetryPolicy::fixed(Duration::from_millis(200))
+        .with_max_retries(5)
+        .with_jitter(true);
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct HitomiCollector {
+    client: ProxiedClient,
+    image_cache: Option<ImageCache>,
+}
+
+impl HitomiCollector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn new_from_config() -> anyhow::Result<Self> {
+        Ok(Self::new())
+    }
+
+    /// Send all requests through the given client.
+    pub fn with_proxy(mut self, client: ProxiedClient) -> Self {
+        self.client = client;
+        self
+    }
+
+    pub fn with_image_cache(mut self, cache: ImageCache) -> Self {
+        self.image_cache = Some(cache);
+        self
+    }
+
+    /// Gallery info and scripts, retried on transient failures.
+    async fn get_text(&self, url: &str) -> anyhow::Result<String> {
+        let req = self
+            .client
+            .get_builder(url)
+            .header(header::REFERER, REFERER);
+        Ok(send_gallery_request(&self.client, req, &GALLERY_RETRY)
+            .await?
+            .text()
+            .await?)
+    }
+}
+
+/// Gallery id of paths like `/galleries/1234.html` or `/doujinshi/title-1234.html`.
+fn parse_gallery_id(path: &str) -> anyhow::Result<u64> {
+    match_first_group(&ID_RE, path.trim_end_matches('/'))
+        .and_then(|id| id.parse().ok())
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "invalid input path({path}), gallery url is expected(like https://hitomi.la/galleries/1234.html)"
+            )
+        })
+}
+
+/// The image host rules of gg.js.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct GG {
+    default: u32,
+    hit: u32,
+    cases: HashSet<u32>,
+    b: String,
+}
+
+impl GG {
+    fn parse(js: &str) -> anyhow::Result<Self> {
+        let number = |re: &Regex| -> anyhow::Result<u32> {
+            Ok(match_first_group(re, js)
+                .ok_or_else(|| anyhow::anyhow!("unable to parse gg.js"))?
+                .parse()?)
+        };
+        Ok(Self {
+            default: number(&GG_DEFAULT_RE)?,
+            hit: number(&GG_HIT_RE)?,
+            cases: GG_CASE_RE
+                .captures_iter(js)
+                .filter_map(|c| c[1].parse().ok())
+                .collect(),
+            b: match_first_group(&GG_B_RE, js)
+                .ok_or_else(|| anyhow::anyhow!("unable to parse gg.js"))?
+                .to_string(),
+        })
+    }
+
+    fn m(&self, g: u32) -> u32 {
+        if self.cases.contains(&g) {
+            self.hit
+        } else {
+            self.default
+        }
+    }
+
+    /// The last 3 hex digits of the hash, with the last one moved to the front.
+    fn s(hash: &str) -> Option<u32> {
+        let tail = hash.get(hash.len().checked_sub(3)?..)?;
+        u32::from_str_radix(&format!("{}{}", &tail[2..], &tail[..2]), 16).ok()
+    }
+
+    fn image_url(&self, hash: &str) -> Option<String> {
+        let g = Self::s(hash)?;
+        Some(format!(
+            "https://w{}.{IMAGE_DOMAIN}/{}{g}/{hash}.webp",
+            1 + self.m(g),
+            self.b
+        ))
+    }
+}
+
+#[derive(Deserialize)]
+struct GalleryInfo {
+    title: String,
+    japanese_title: Option<String>,
+    #[serde(rename = "type")]
+    typ: Option<String>,
+    language: Option<String>,
+    files: Vec<HitomiFile>,
+    #[serde(default)]
+    artists: Option<Vec<Artist>>,
+    #[serde(default)]
+    tags: Option<Vec<HitomiTag>>,
+}
+
+#[derive(Deserialize)]
+struct HitomiFile {
+    hash: String,
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct Artist {
+    artist: String,
+}
+
+#[derive(Deserialize)]
+struct HitomiTag {
+    tag: String,
+}
+
+impl GalleryInfo {
+    /// Parse `var galleryinfo = {...}`.
+    fn parse(js: &str) -> anyhow::Result<Self> {
+        let start = js
+            .find('{')
+            .ok_or_else(|| anyhow::anyhow!("unable to parse galleryinfo"))?;
+        Ok(serde_json::from_str(
+            js[start..].trim_end().trim_end_matches(';'),
+        )?)
+    }
+
+    fn into_parts(self, id: u64, gg: &GG) -> anyhow::Result<(AlbumMeta, Vec<ImageMeta>)> {
+        let images = self
+            .files
+            .iter()
+            .map(|f| {
+                let url = gg
+                    .image_url(&f.hash)
+                    .ok_or_else(|| anyhow::anyhow!("invalid file hash {}", f.hash))?;
+                Ok(ImageMeta {
+                    id: f.hash.clone(),
+                    url,
+                    description: Some(f.name.clone()),
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let authors = self
+            .artists
+            .map(|a| a.into_iter().map(|a| a.artist).collect::<Vec<_>>())
+            .filter(|a| !a.is_empty());
+        let tags = self
+            .tags
+            .map(|t| t.into_iter().map(|t| t.tag).collect::<Vec<_>>())
+            .filter(|t| !t.is_empty());
+        let meta = AlbumMeta {
+            link: format!("https://hitomi.la/galleries/{id}.html"),
+            name: self.title,
+            japanese_name: self.japanese_title,
+            class: self.typ,
+            description: None,
+            authors,
+            language: self.language,
+            tags,
+            page_count: Some(images.len()),
+        };
+        Ok((meta, images))
+    }
+}
+
+impl Collector for HitomiCollector {
+    type FetchError = anyhow::Error;
+    type StreamError = anyhow::Error;
+    type ImageStream = HitomiImageStream;
+
+    #[inline]
+    fn name() -> &'static str {
+        "hitomi"
+    }
+
+    fn matches(&self, url: &url::Url) -> bool {
+        url.host_str() == Some("hitomi.la") && url.path().ends_with(".html")
+    }
+
+    async fn fetch(
+        &self,
+        path: String,
+    ) -> Result<(AlbumMeta, Self::ImageStream), Self::FetchError> {
+        let id = parse_gallery_id(&path)?;
+        tracing::info!("[hitomi] process gallery {id}");
+
+        let info = self.get_text(&format!("{LTN}/galleries/{id}.js")).await?;
+        let info = GalleryInfo::parse(&info)?;
+        let gg = GG::parse(&self.get_text(&format!("{LTN}/gg.js")).await?)?;
+        let (meta, images) = info.into_parts(id, &gg)?;
+
+        Ok((
+            meta,
+            HitomiImageStream {
+                collector: self.clone(),
+                images: images.into_iter(),
+            },
+        ))
+    }
+}
+
+#[derive(Debug)]
+pub struct HitomiImageStream {
+    collector: HitomiCollector,
+    images: std::vec::IntoIter<ImageMeta>,
+}
+
+impl HitomiImageStream {
+    async fn load_image(
+        collector: HitomiCollector,
+        meta: ImageMeta,
+    ) -> anyhow::Result<(ImageMeta, ImageData)> {
+        let mut headers = header::HeaderMap::new();
+        headers.insert(header::REFERER, header::HeaderValue::from_static(REFERER));
+        let image_data = download_cached(
+            collector.image_cache.as_ref(),
+            &cache_key(&meta),
+            &collector.client,
+            &meta.url,
+            headers,
+            &RETRY_POLICY,
+        )
+        .await?;
+
+        tracing::trace!(
+            "download hitomi image with size {}, link: {}",
+            image_data.len(),
+            meta.url
+        );
+        Ok((meta, image_data))
+    }
+}
+
+/// By the file hash, the subdomain and the path prefix of the url change with gg.js.
+fn cache_key(meta: &ImageMeta) -> String {
+    format!("hitomi|{}", meta.id)
+}
+
+impl AsyncStream for HitomiImageStream {
+    type Item = anyhow::Result<(ImageMeta, ImageData)>;
+
+    type Future = impl std::future::Future<Output = Self::Item>;
+
+    fn next(&mut self) -> Option<Self::Future> {
+        let meta = self.images.next()?;
+        Some(Self::load_image(self.collector.clone(), meta))
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.images.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GG_JS: &str = r#"'use strict';
+gg = {
+m: function(g) {
+var o = 0;
+switch (g) {
+case 1086:
+case 2319:
+case 3243:
+o = 1; break;
+}
+return o;
+},
+s: function(h) { var m = /(..)(.)$/.exec(h); return parseInt(m[2]+m[1], 16).toString(10); },
+b: '1712345678/'
+};"#;
+
+    #[test]
+    fn test_parse_gallery_id() {
+        assert_eq!(parse_gallery_id("/galleries/1234.html").unwrap(), 1234);
+        assert_eq!(
+            parse_gallery_id("/doujinshi/some-title-english-2345678.html").unwrap(),
+            2345678
+        );
+        assert!(parse_gallery_id("/galleries/").is_err());
+        assert!(parse_gallery_id("/reader/abc.html").is_err());
+    }
+
+    #[test]
+    fn test_gg() {
+        let gg = GG::parse(GG_JS).unwrap();
+        assert_eq!(gg.default, 0);
+        assert_eq!(gg.hit, 1);
+        assert_eq!(gg.b, "1712345678/");
+        assert_eq!(gg.cases.len(), 3);
+
+        // "3" then "e4" -> 0x43e
+        assert_eq!(GG::s("0123e4"), Some(1086));
+        // "c" then "ab" -> 0xcab
+        assert_eq!(GG::s("fabc"), Some(3243));
+        assert_eq!(GG::s("ff"), None);
+        assert_eq!(GG::s("xyz"), None);
+
+        let hash = format!("{}3e4", "0".repeat(61));
+        assert_eq!(
+            gg.image_url(&hash).unwrap(),
+            format!("https://w2.gold-usergeneratedcontent.net/1712345678/1086/{hash}.webp")
+        );
+        let hash = format!("{}001", "0".repeat(61));
+        // "1" then "00" -> 0x100, not in cases
+        assert_eq!(
+            gg.image_url(&hash).unwrap(),
+            format!("https://w1.gold-usergeneratedcontent.net/1712345678/256/{hash}.webp")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_image_cache() {
+        use crate::mock_server::{MockResponse, MockServer};
+
+        let server = MockServer::start(|_, _| MockResponse::new(200, "image")).await;
+        let dir = tempfile::tempdir().unwrap();
+        let collector = HitomiCollector::new()
+            .with_proxy(ProxiedClient::new(&server.url("/"), "token").unwrap())
+            .with_image_cache(ImageCache::open(dir.path(), None).unwrap());
+        let hash = format!("{}3e4", "0".repeat(61));
+        let mut gg = GG::parse(GG_JS).unwrap();
+        for b in ["1712345678/", "1712349999/"] {
+            gg.b = b.to_string();
+            gg.default = 1 - gg.default;
+            let meta = ImageMeta {
+                id: hash.clone(),
+                url: gg.image_url(&hash).unwrap(),
+                description: None,
+            };
+            let (_, data) = HitomiImageStream::load_image(collector.clone(), meta)
+                .await
+                .unwrap();
+            assert_eq!(&data[..], b"image");
+        }
+        // the second url is another subdomain and prefix of the same file
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[test]
+    fn test_gallery_info() {
+        let js = r#"var galleryinfo = {"id":"1234","title":"Title","japanese_title":null,"type":"doujinshi","language":"english",
+"files":[{"hash":"abc3e4","name":"01.jpg","width":1,"height":1,"haswebp":1},{"hash":"abc001","name":"02.jpg","width":1,"height":1,"haswebp":1}],
+"artists":[{"artist":"someone","url":"/artist/someone-all.html"}],
+"tags":[{"tag":"full color","url":"/tag/full%20color-all.html","female":"","male":""}]};"#;
+        let gg = GG::parse(GG_JS).unwrap();
+        let (meta, images) = GalleryInfo::parse(js)
+            .unwrap()
+            .into_parts(1234, &gg)
+            .unwrap();
+        assert_eq!(meta.name, "Title");
+        assert_eq!(meta.link, "https://hitomi.la/galleries/1234.html");
+        assert_eq!(meta.authors.unwrap(), ["someone"]);
+        assert_eq!(meta.tags.unwrap(), ["full color"]);
+        assert_eq!(meta.language.as_deref(), Some("english"));
+        assert_eq!(meta.page_count, Some(2));
+        let urls = images.iter().map(|i| i.url.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            urls,
+            [
+                "https://w2.gold-usergeneratedcontent.net/1712345678/1086/abc3e4.webp",
+                "https://w1.gold-usergeneratedcontent.net/1712345678/256/abc001.webp"
+            ]
+        );
+    }
+}
@@ -0,0 +1,169 @@
+//! Hosts of the sites, configurable under `collectors` so a rotated domain can be
+//! followed without a release. All hosts of a site are matched, the first one is used
+//! to build requests. They are given to the collectors when built, the `Registry`
+//! holds the ones in use.
+
+use serde::Deserialize;
+use url::Url;
+
+use crate::config;
+
+const CONFIG_KEY: &str = "collectors";
+
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct CollectorsConfig {
+    #[serde(default)]
+    ehentai: SiteConfig,
+    #[serde(default)]
+    exhentai: SiteConfig,
+    #[serde(default)]
+    nhentai: SiteConfig,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct SiteConfig {
+    #[serde(default)]
+    hosts: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SiteHosts(Vec<String>);
+
+impl SiteHosts {
+    /// The configured hosts, or `defaults` if there is none.
+    pub fn new(configured: Vec<String>, defaults: &[&str]) -> Self {
+        let hosts: Vec<String> = configured
+            .into_iter()
+            .map(|h| h.trim().to_ascii_lowercase())
+            .filter(|h| !h.is_empty())
+            .collect();
+        match hosts.is_empty() {
+            true => Self(defaults.iter().map(|h| h.to_string()).collect()),
+            false => Self(hosts),
+        }
+    }
+
+    /// The host requests are sent to.
+    pub fn primary(&self) -> &str {
+        &self.0[0]
+    }
+
+    pub fn contains(&self, host: &str) -> bool {
+        self.0.iter().any(|h| h.eq_ignore_ascii_case(host))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.0.iter().map(String::as_str)
+    }
+
+    /// Whether the url is a gallery, `/g/...` on one of the hosts.
+    pub fn matches_gallery(&self, url: &Url) -> bool {
+        url.host_str().is_some_and(|h| self.contains(h)) && url.path().starts_with("/g/")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hosts {
+    pub ehentai: SiteHosts,
+    pub exhentai: SiteHosts,
+    pub nhentai: SiteHosts,
+}
+
+impl Default for Hosts {
+    fn default() -> Self {
+        CollectorsConfig::default().into()
+    }
+}
+
+impl From<CollectorsConfig> for Hosts {
+    fn from(config: CollectorsConfig) -> Self {
+        Self {
+            ehentai: SiteHosts::new(config.ehentai.hosts, &["e-hentai.org"]),
+            exhentai: SiteHosts::new(config.exhentai.hosts, &["exhentai.org"]),
+            nhentai: SiteHosts::new(config.nhentai.hosts, &["nhentai.net", "nhentai.to"]),
+        }
+    }
+}
+
+impl Hosts {
+    pub fn from_config() -> anyhow::Result<Self> {
+        Ok(config::parse::<CollectorsConfig>(CONFIG_KEY)?
+            .unwrap_or_default()
+            .into())
+    }
+
+    /// All sites with the path form of their galleries, `/g/{id}/{token}/` if
+    /// `with_token` or `/g/{id}/` otherwise.
+    pub(crate) fn sites(&self) -> [(&SiteHosts, bool); 3] {
+        [
+            (&self.ehentai, true),
+            (&self.exhentai, true),
+            (&self.nhentai, false),
+        ]
+    }
+}
+
+pub(crate) fn validate_config(config: &config::Config, errors: &mut config::ConfigErrors) {
+    let Some(c) = config.section::<CollectorsConfig>(CONFIG_KEY, errors) else {
+        return;
+    };
+    for (site, hosts) in [
+        ("ehentai", &c.ehentai.hosts),
+        ("exhentai", &c.exhentai.hosts),
+        ("nhentai", &c.nhentai.hosts),
+    ] {
+        for host in hosts {
+            if url::Host::parse(host.trim()).is_err() {
+                errors.push(
+                    &format!("{CONFIG_KEY}.{site}.hosts"),
+                    format!("invalid host {host}, only the domain is expected"),
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hosts() {
+        let config: CollectorsConfig =
+            serde_yaml::from_str("ehentai:\n  hosts: [E-Hentai.example, e-hentai.org]").unwrap();
+        let hosts = Hosts::from(config);
+        assert_eq!(hosts.ehentai.primary(), "e-hentai.example");
+        assert!(hosts.ehentai.contains("e-hentai.org"));
+        // not configured ones keep the defaults
+        assert_eq!(hosts.exhentai, Hosts::default().exhentai);
+        assert_eq!(
+            hosts.nhentai.iter().collect::<Vec<_>>(),
+            ["nhentai.net", "nhentai.to"]
+        );
+
+        let url = |s: &str| Url::parse(s).unwrap();
+        assert!(hosts
+            .ehentai
+            .matches_gallery(&url("https://e-hentai.example/g/1/abc/")));
+        assert!(!hosts
+            .ehentai
+            .matches_gallery(&url("https://e-hentai.example/s/1/abc")));
+        assert!(!Hosts::default()
+            .ehentai
+            .matches_gallery(&url("https://e-hentai.example/g/1/abc/")));
+
+        let config = config::Config::load(
+            config::ConfigFormat::Yaml,
+            "collectors:\n  nhentai:\n    hosts: [\"https://nhentai.example/\"]",
+        )
+        .unwrap();
+        let mut errors = config::ConfigErrors::default();
+        validate_config(&config, &mut errors);
+        let errors = errors.iter().collect::<Vec<_>>();
+        assert_eq!(errors.len(), 1);
+        assert!(
+            errors[0].starts_with("collectors.nhentai.hosts: "),
+            "{errors:?}"
+        );
+    }
+}
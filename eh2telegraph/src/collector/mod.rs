@@ -1,19 +1,30 @@
 //! Built-in collectors and trait.
 
-use once_cell::sync::Lazy;
 use regex::Regex;
 use std::future::Future;
+use url::Url;
 
-use crate::stream::AsyncStream;
+use crate::{
+    http_proxy::{ProxiedClient, ProxyError},
+    storage::image_cache::ImageCache,
+    stream::{AsyncStream, Selected},
+    util::match_first_group,
+};
 
-use self::{e_hentai::EHCollector, exhentai::EXCollector, nhentai::NHCollector};
+use self::{
+    e_hentai::EHCollector, exhentai::EXCollector, hitomi::HitomiCollector, hosts::Hosts,
+    nhentai::NHCollector, selection::PageSelection,
+};
 
 pub mod utils;
 
 pub mod e_hentai;
 pub mod exhentai;
+pub mod hitomi;
+pub mod hosts;
 pub mod nhentai;
 pub mod pixiv;
+pub mod selection;
 
 #[derive(Debug, Clone)]
 pub struct ImageMeta {
@@ -24,14 +35,39 @@ pub struct ImageMeta {
 
 pub type ImageData = bytes::Bytes;
 
-#[derive(Debug, Clone)]
+/// Failures of fetching a gallery, found in the chain of the fetch error.
+#[derive(thiserror::Error, Debug)]
+pub enum CollectorError {
+    #[error("gallery not found, maybe it has been deleted")]
+    NotFound,
+    #[error("this gallery needs login cookies, they are missing or expired")]
+    RequiresAuth,
+    #[error("unexpected status {0} of the gallery")]
+    Status(reqwest::StatusCode),
+    #[error("unable to fetch the gallery: {0}")]
+    Request(#[from] ProxyError),
+}
+
+impl CollectorError {
+    /// Whether retrying later would not help.
+    pub fn is_permanent(&self) -> bool {
+        matches!(self, Self::NotFound | Self::RequiresAuth)
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct AlbumMeta {
     pub link: String,
     pub name: String,
+    /// Title in the original language.
+    pub japanese_name: Option<String>,
     pub class: Option<String>,
     pub description: Option<String>,
     pub authors: Option<Vec<String>>,
+    pub language: Option<String>,
+    /// Tags in `namespace:tag` form if the site has namespaces.
     pub tags: Option<Vec<String>>,
+    pub page_count: Option<usize>,
 }
 
 /// Generic collector.
@@ -44,24 +80,75 @@ pub trait Collector {
     type ImageStream: AsyncStream<Item = Result<(ImageMeta, ImageData), Self::StreamError>>;
 
     fn name() -> &'static str;
+    /// Whether the url is a gallery of this collector.
+    fn matches(&self, url: &Url) -> bool;
     fn fetch(
         &self,
         path: String,
     ) -> impl Future<Output = Result<(AlbumMeta, Self::ImageStream), Self::FetchError>>;
+
+    /// Like `fetch`, but only the selected pages are yielded and downloaded.
+    /// The selection is checked against the page count when the stream knows it.
+    fn fetch_pages(
+        &self,
+        path: String,
+        pages: Option<&PageSelection>,
+    ) -> impl Future<Output = anyhow::Result<(AlbumMeta, Selected<Self::ImageStream>)>>
+    where
+        Self::FetchError: Into<anyhow::Error>,
+    {
+        async move {
+            let (meta, stream) = self.fetch(path).await.map_err(Into::into)?;
+            let Some(pages) = pages else {
+                return Ok((meta, Selected::all(stream)));
+            };
+            let total = match stream.size_hint() {
+                (lower, Some(upper)) if lower == upper => lower,
+                _ => anyhow::bail!("page selection is not supported by {}", Self::name()),
+            };
+            let indices = pages.resolve(total)?;
+            tracing::info!(
+                "[collector] select {} of {total} pages by {pages}",
+                indices.len()
+            );
+            Ok((meta, Selected::new(stream, indices)))
+        }
+    }
 }
 
-pub(crate) static URL_FROM_TEXT_RE: Lazy<Regex> = Lazy::new(|| {
-    Regex::new(r#"((https://exhentai\.org/g/\w+/[\w-]+)|(https://e-hentai\.org/g/\w+/[\w-]+)|(https://nhentai\.net/g/\d+)|(https://nhentai\.to/g/\d+))"#).unwrap()
-});
-pub(crate) static URL_FROM_URL_RE: Lazy<Regex> = Lazy::new(|| {
-    Regex::new(r#"^((https://exhentai\.org/g/\w+/[\w-]+)|(https://e-hentai\.org/g/\w+/[\w-]+)|(https://nhentai\.net/g/\d+)|(https://nhentai\.to/g/\d+))"#).unwrap()
-});
+/// Gallery urls of all the hosts. They are matched loosely, `canonicalize_url`
+/// normalizes them.
+fn gallery_url_pattern(hosts: &Hosts) -> String {
+    let mut sites = Vec::new();
+    for (site, with_token) in hosts.sites() {
+        let path = if with_token {
+            r"/g/\w+/[\w-]+"
+        } else {
+            r"/g/\d+"
+        };
+        sites.extend(
+            site.iter()
+                .map(|host| format!("{}{path}", regex::escape(host))),
+        );
+    }
+    sites.push(r"hitomi\.la/[\w-]+/[\w%-]*\d+\.html".to_string());
+    format!(
+        r"(https?://(?:www\.|m\.|g\.)?(?:{})/?(?:#pages=[\d,.=-]+)?)",
+        sites.join("|")
+    )
+}
 
 #[derive(Debug, Clone)]
 pub struct Registry {
     eh: EHCollector,
     nh: NHCollector,
     ex: EXCollector,
+    hitomi: HitomiCollector,
+    // of the collectors
+    hosts: Hosts,
+    // gallery urls anywhere in a text, and at the start of one
+    url_from_text: Regex,
+    url_from_url: Regex,
 }
 
 pub trait Param<T> {
@@ -86,12 +173,168 @@ impl Param<EXCollector> for Registry {
     }
 }
 
+impl Param<HitomiCollector> for Registry {
+    fn get(&self) -> &HitomiCollector {
+        &self.hitomi
+    }
+}
+
+/// Collectors built without the config, exhentai has empty cookies.
+impl Default for Registry {
+    fn default() -> Self {
+        Self::new(
+            EHCollector::new(None),
+            NHCollector::new(),
+            EXCollector::new(&exhentai::ExConfig::default(), None)
+                .expect("empty cookies are valid"),
+            HitomiCollector::new(),
+        )
+    }
+}
+
 impl Registry {
-    pub fn new_from_config() -> Self {
+    /// Gallery urls are matched by the hosts of the collectors.
+    pub fn new(eh: EHCollector, nh: NHCollector, ex: EXCollector, hitomi: HitomiCollector) -> Self {
+        let hosts = Hosts {
+            ehentai: eh.hosts().clone(),
+            exhentai: ex.hosts().clone(),
+            nhentai: nh.hosts().clone(),
+        };
+        let pattern = gallery_url_pattern(&hosts);
         Self {
-            eh: EHCollector::new_from_config().expect("unable to build e-hentai collector"),
-            nh: NHCollector::new_from_config().expect("unable to build nhentai collector"),
-            ex: EXCollector::new_from_config().expect("unable to build exhentai collector"),
+            eh,
+            nh,
+            ex,
+            hitomi,
+            hosts,
+            url_from_text: Regex::new(&pattern).unwrap(),
+            url_from_url: Regex::new(&format!("^{pattern}")).unwrap(),
         }
     }
+
+    /// Collectors of the config, with the hosts of `collectors`.
+    pub fn new_from_config() -> Self {
+        let hosts = Hosts::from_config().expect("unable to parse collector hosts");
+        Self::new(
+            EHCollector::new_from_config(&hosts).expect("unable to build e-hentai collector"),
+            NHCollector::new_from_config(&hosts).expect("unable to build nhentai collector"),
+            EXCollector::new_from_config(&hosts).expect("unable to build exhentai collector"),
+            HitomiCollector::new_from_config().expect("unable to build hitomi collector"),
+        )
+    }
+
+    pub fn hosts(&self) -> &Hosts {
+        &self.hosts
+    }
+
+    /// The first gallery url in the text.
+    pub fn match_url_from_text<'a>(&self, content: &'a str) -> Option<&'a str> {
+        match_first_group(&self.url_from_text, content)
+    }
+
+    /// All gallery urls in the text in order, without duplicates.
+    pub fn match_urls_from_text<'a>(&self, content: &'a str) -> Vec<&'a str> {
+        let mut urls = Vec::new();
+        for c in self.url_from_text.captures_iter(content) {
+            let url = c
+                .get(1)
+                .expect("regexp is matched but no group 1 found")
+                .as_str();
+            if !urls.contains(&url) {
+                urls.push(url);
+            }
+        }
+        urls
+    }
+
+    /// The gallery url the text starts with.
+    pub fn match_url_from_url<'a>(&self, content: &'a str) -> Option<&'a str> {
+        match_first_group(&self.url_from_url, content)
+    }
+
+    /// Share the proxied client with collectors which support it.
+    pub fn with_proxy(mut self, client: ProxiedClient) -> Self {
+        self.eh = self.eh.with_proxy(client.clone());
+        self.nh = self.nh.with_proxy(client.clone());
+        self.ex = self.ex.with_proxy(client.clone());
+        self.hitomi = self.hitomi.with_proxy(client);
+        self
+    }
+
+    /// Archives downloaded by e-hentai are given up beyond `max_bytes`.
+    pub fn with_archive_limit(mut self, max_bytes: Option<u64>) -> Self {
+        self.eh = self.eh.with_archive_limit(max_bytes);
+        self
+    }
+
+    /// Images downloaded from e-hentai, see `Resolution`.
+    pub fn with_resolution(mut self, resolution: e_hentai::Resolution) -> Self {
+        self.eh = self.eh.with_resolution(resolution);
+        self
+    }
+
+    /// Keep the downloaded images of all collectors in the cache.
+    pub fn with_image_cache(mut self, cache: ImageCache) -> Self {
+        self.eh = self.eh.with_image_cache(cache.clone());
+        self.nh = self.nh.with_image_cache(cache.clone());
+        self.ex = self.ex.with_image_cache(cache.clone());
+        self.hitomi = self.hitomi.with_image_cache(cache);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gallery_url_pattern() {
+        let config = "ehentai:\n  hosts: [e-hentai.example]\nnhentai:\n  hosts: [nh.example]";
+        let hosts = Hosts::from(serde_yaml::from_str::<hosts::CollectorsConfig>(config).unwrap());
+        let re = Regex::new(&gallery_url_pattern(&hosts)).unwrap();
+        let text = "see https://e-hentai.example/g/1/abc/#pages=1-3 and https://nh.example/g/2/";
+        let urls = re
+            .captures_iter(text)
+            .map(|c| c[1].to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            urls,
+            [
+                "https://e-hentai.example/g/1/abc/#pages=1-3",
+                "https://nh.example/g/2/"
+            ]
+        );
+        // the replaced hosts are no longer matched, the others still are
+        assert!(!re.is_match("https://e-hentai.org/g/1/abc/"));
+        assert!(!re.is_match("https://nhentai.net/g/1/"));
+        assert!(re.is_match("https://exhentai.org/g/1/abc/"));
+        assert!(re.is_match("https://hitomi.la/doujinshi/title-123.html"));
+    }
+
+    #[test]
+    fn test_match_urls_from_text() {
+        let registry = Registry::default();
+        let text = "https://nhentai.net/g/1 and https://e-hentai.org/g/2/abc#pages=1-3\n\
+            again https://nhentai.net/g/1 https://example.com/g/3 http://m.e-hentai.org/g/4/def/?p=1";
+        assert_eq!(
+            registry.match_urls_from_text(text),
+            [
+                "https://nhentai.net/g/1",
+                "https://e-hentai.org/g/2/abc#pages=1-3",
+                "http://m.e-hentai.org/g/4/def/"
+            ]
+        );
+        assert!(registry.match_urls_from_text("no links").is_empty());
+        assert_eq!(
+            registry.match_url_from_url("https://nhentai.net/g/1/ trailing"),
+            Some("https://nhentai.net/g/1/")
+        );
+        assert_eq!(
+            registry.match_url_from_url("see https://nhentai.net/g/1/"),
+            None
+        );
+
+        // by the hosts of the collectors
+        assert_eq!(registry.hosts(), &Hosts::default());
+    }
 }
@@ -1,23 +1,29 @@
 /// nhentai collector.
-/// Host matching: nhentai.to or nhentai.net
+/// Host matching: nhentai.to or nhentai.net, or the hosts of `collectors.nhentai.hosts`
 ///
-/// Since nhentai.net always enable CloudFlare Firewall, so we will
-/// use nhapi.cat42.uk(there will be some syncing latency).
+/// Gallery info is read from the JSON API of the first host through the shared `ProxiedClient`.
+/// Since nhentai.net always enable CloudFlare Firewall, the forwarding proxy or
+/// a mirror set by `nhentai.api` is needed in most cases.
 use again::RetryPolicy;
-use rand::seq::SliceRandom;
-use reqwest::Response;
+use rand::Rng;
 use serde::Deserialize;
 use std::time::Duration;
 
 use crate::{
-    http_client::{GhostClient, GhostClientBuilder},
+    config,
+    http_client::HttpRequestBuilder,
+    http_proxy::ProxiedClient,
+    storage::image_cache::{download_cached, ImageCache},
     stream::AsyncStream,
-    util::get_bytes,
 };
 
-use super::{AlbumMeta, Collector, ImageData, ImageMeta};
+use super::{
+    hosts::{Hosts, SiteHosts},
+    utils::fetch::{send_gallery_request, GALLERY_RETRY},
+    AlbumMeta, Collector, ImageData, ImageMeta,
+};
 
-const NHAPI: &str = "https://nhapi.cat42.uk/gallery/";
+const CONFIG_KEY: &str = "nhentai";
 
 lazy_static::lazy_static! {
     static ref RETRY_POLICY: RetryPolicy = RetryPolicy::fixed(Duration::from_millis(200))
@@ -25,41 +31,106 @@ lazy_static::lazy_static! {
         .with_jitter(true);
 }
 
-const DOMAIN_LIST: [&str; 0] = [];
-const NH_CDN_LIST: [&str; 4] = [
-    "https://i1.nhentai.net/galleries",
-    "https://i2.nhentai.net/galleries",
-    "https://i3.nhentai.net/galleries",
-    "https://i4.nhentai.net/galleries",
-];
+// images are served by i1-i4.nhentai.net
+const CDN_SHARDS: u8 = 4;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct NhConfig {
+    /// Base url of the gallery api, the gallery id is appended to it.
+    /// `https://{host}/api/gallery/` of the first nhentai host if not set.
+    api: Option<String>,
+}
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone)]
 pub struct NHCollector {
-    client: GhostClient,
+    client: ProxiedClient,
+    // of the galleries, `collectors.nhentai.hosts`
+    hosts: SiteHosts,
+    // of the gallery links
+    host: String,
+    api: String,
+    image_cache: Option<ImageCache>,
+}
+
+impl Default for NHCollector {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl NHCollector {
+    /// With the default hosts.
     pub fn new() -> Self {
+        Self::with_hosts(Hosts::default().nhentai)
+    }
+
+    fn with_hosts(hosts: SiteHosts) -> Self {
+        let host = hosts.primary().to_string();
         Self {
-            client: GhostClientBuilder::default()
-                .with_cf_resolve(&DOMAIN_LIST)
-                .build(None),
+            client: ProxiedClient::default(),
+            hosts,
+            host: String::new(),
+            api: String::new(),
+            image_cache: None,
         }
+        .with_host(host)
+    }
+
+    pub fn new_from_config(hosts: &Hosts) -> anyhow::Result<Self> {
+        let config: NhConfig = config::parse(CONFIG_KEY)?.unwrap_or_default();
+        let collector = Self::with_hosts(hosts.nhentai.clone());
+        Ok(Self {
+            api: config.api.unwrap_or(collector.api.clone()),
+            ..collector
+        })
     }
 
-    pub fn new_from_config() -> anyhow::Result<Self> {
-        Ok(Self::new())
+    /// Use `host` instead of the configured one, for the links and the api.
+    pub fn with_host(mut self, host: impl Into<String>) -> Self {
+        self.host = host.into();
+        self.api = format!("https://{}/api/gallery/", self.host);
+        self
+    }
+
+    /// Send all requests through the given client.
+    pub fn with_proxy(mut self, client: ProxiedClient) -> Self {
+        self.client = client;
+        self
+    }
+
+    pub fn with_image_cache(mut self, cache: ImageCache) -> Self {
+        self.image_cache = Some(cache);
+        self
+    }
+
+    /// The hosts galleries are matched by.
+    pub fn hosts(&self) -> &SiteHosts {
+        &self.hosts
+    }
+}
+
+/// Gallery id of paths like `/g/333678/`.
+fn parse_gallery_id(path: &str) -> anyhow::Result<u64> {
+    let mut parts = path.trim_matches('/').split('/');
+    match (
+        parts.next(),
+        parts.next().map(str::parse::<u64>),
+        parts.next(),
+    ) {
+        (Some("g"), Some(Ok(id)), None) => Ok(id),
+        _ => Err(anyhow::anyhow!(
+            "invalid input path({path}), gallery url is expected(like https://nhentai.net/g/333678)"
+        )),
     }
 }
 
 #[derive(Deserialize)]
 struct NhAlbum {
-    // id: u32,
     media_id: String,
     title: Title,
     images: Images,
-    // tags: Vec<Tag>,
-    // num_pages: usize,
+    #[serde(default)]
+    tags: Vec<Tag>,
 }
 
 #[derive(Deserialize)]
@@ -117,12 +188,53 @@ impl ImageType {
     }
 }
 
-// #[derive(Deserialize)]
-// struct Tag {
-//     #[serde(rename = "type")]
-//     typ: String,
-//     name: String,
-// }
+#[derive(Deserialize)]
+struct Tag {
+    #[serde(rename = "type")]
+    typ: String,
+    name: String,
+}
+
+impl NhAlbum {
+    fn into_parts(self, host: &str, album_id: u64) -> (AlbumMeta, Vec<ImageURL>) {
+        let name = self.title.title(|| format!("nhentai-{album_id}"));
+        let authors = self
+            .tags
+            .iter()
+            .filter(|t| t.typ == "artist")
+            .map(|t| t.name.clone())
+            .collect::<Vec<_>>();
+        let tags = self
+            .tags
+            .iter()
+            .map(|t| format!("{}:{}", t.typ, t.name))
+            .collect::<Vec<_>>();
+        let image_urls = self
+            .images
+            .pages
+            .iter()
+            .enumerate()
+            .map(|(idx, page)| ImageURL::new(self.media_id.clone(), idx + 1, page.t))
+            .collect();
+        let language = self
+            .tags
+            .iter()
+            .find(|t| t.typ == "language" && t.name != "translated")
+            .map(|t| t.name.clone());
+        let meta = AlbumMeta {
+            link: format!("https://{host}/g/{album_id}"),
+            name,
+            japanese_name: self.title.japanese.clone(),
+            class: None,
+            description: None,
+            authors: (!authors.is_empty()).then_some(authors),
+            language,
+            tags: (!tags.is_empty()).then_some(tags),
+            page_count: Some(self.images.pages.len()),
+        };
+        (meta, image_urls)
+    }
+}
 
 impl Collector for NHCollector {
     type FetchError = anyhow::Error;
@@ -134,61 +246,65 @@ impl Collector for NHCollector {
         "nhentai"
     }
 
+    fn matches(&self, url: &url::Url) -> bool {
+        self.hosts.matches_gallery(url)
+    }
+
     async fn fetch(
         &self,
         path: String,
     ) -> Result<(AlbumMeta, Self::ImageStream), Self::FetchError> {
-        // normalize url
-        let mut parts = path.trim_matches(|c| c == '/').split('/');
-        let g = parts.next();
-        let album_id = parts.next();
-        let album_id = match (g, album_id) {
-            (Some("g"), Some(album_id)) => album_id,
-            _ => {
-                return Err(anyhow::anyhow!("invalid input path({path}), gallery url is expected(like https://nhentai.net/g/333678)"));
-            }
-        };
-        // Note: Since nh enables CF firewall, we use nhentai.to instead.
-        let api_url = format!("{NHAPI}{album_id}");
-        let original_url = format!("https://nhentai.net/g/{album_id}");
-        tracing::info!("[nhentai] process {api_url}(original url {original_url})");
-
-        // clone client to force changing ip
-        let client = self.client.clone();
-        let album: NhAlbum = client
-            .get(&api_url)
-            .send()
-            .await
-            .and_then(Response::error_for_status)?
-            .json()
-            .await?;
-        let title = album.title.title(|| format!("nhentai-{album_id}"));
-        let image_urls = album
-            .images
-            .pages
-            .iter()
-            .enumerate()
-            .map(|(idx, page)| ImageURL::new(album.media_id.clone(), idx + 1, page.t))
-            .collect::<Vec<_>>()
-            .into_iter();
+        let album_id = parse_gallery_id(&path)?;
+        let api_url = format!("{}{album_id}", self.api);
+        tracing::info!("[nhentai] process {api_url}");
+
+        let album: NhAlbum = send_gallery_request(
+            &self.client,
+            self.client.get_builder(&api_url),
+            &GALLERY_RETRY,
+        )
+        .await?
+        .json()
+        .await?;
+        let (meta, image_urls) = album.into_parts(&self.host, album_id);
 
         Ok((
-            AlbumMeta {
-                link: original_url,
-                name: title,
-                class: None,
-                description: None,
-                authors: None,
-                tags: None,
+            meta,
+            NHImageStream {
+                client: self.client.clone(),
+                image_cache: self.image_cache.clone(),
+                image_urls: image_urls.into_iter(),
             },
-            NHImageStream { client, image_urls },
         ))
     }
 }
 
+/// Image host, `i{n}.nhentai.net` or `i.nhentai.net` without a shard.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct CdnHost {
+    shard: Option<u8>,
+}
+
+impl CdnHost {
+    const UNSHARDED: Self = Self { shard: None };
+
+    fn random() -> Self {
+        Self {
+            shard: Some(rand::thread_rng().gen_range(1..=CDN_SHARDS)),
+        }
+    }
+
+    fn link(&self, media: &str, id: usize, typ: ImageType) -> String {
+        let shard = self.shard.map(|n| n.to_string()).unwrap_or_default();
+        format!(
+            "https://i{shard}.nhentai.net/galleries/{media}/{id}{}",
+            typ.as_str()
+        )
+    }
+}
+
 #[derive(Debug)]
 struct ImageURL {
-    raw: String,
     media: String,
     id: usize,
     typ: ImageType,
@@ -196,41 +312,48 @@ struct ImageURL {
 
 impl ImageURL {
     fn new(media: String, id: usize, typ: ImageType) -> Self {
-        Self {
-            raw: Self::random_cdn_link(&media, id, typ),
-            media,
-            id,
-            typ,
-        }
+        Self { media, id, typ }
     }
 
-    fn raw(&self) -> &str {
-        &self.raw
+    /// Hosts to try in order: a random shard, then the unsharded host. Thumbnails
+    /// are never uploaded in place of a page, it fails if both hosts fail.
+    fn hosts() -> [CdnHost; 2] {
+        [CdnHost::random(), CdnHost::UNSHARDED]
     }
 
-    fn fallback(&self) -> String {
-        Self::random_cdn_link(&self.media, self.id, self.typ)
+    #[cfg(test)]
+    fn candidates(&self) -> [String; 2] {
+        Self::hosts().map(|host| self.link(host))
     }
 
-    fn random_cdn_link(media: &str, id: usize, typ: ImageType) -> String {
-        let cdn = NH_CDN_LIST
-            .choose(&mut rand::thread_rng())
-            .expect("empty CDN list");
-        format!("{cdn}/{media}/{id}{}", typ.as_str())
+    fn link(&self, host: CdnHost) -> String {
+        host.link(&self.media, self.id, self.typ)
     }
 }
 
 #[derive(Debug)]
 pub struct NHImageStream {
-    client: GhostClient,
+    client: ProxiedClient,
+    image_cache: Option<ImageCache>,
     image_urls: std::vec::IntoIter<ImageURL>,
 }
 
 impl NHImageStream {
-    async fn load_image(client: GhostClient, link: &str) -> anyhow::Result<(ImageMeta, ImageData)> {
-        let image_data = RETRY_POLICY
-            .retry(|| async { get_bytes(&client, link).await })
-            .await?;
+    async fn load_image(
+        client: &ProxiedClient,
+        image_cache: Option<&ImageCache>,
+        link: &str,
+        cache_key: &str,
+    ) -> anyhow::Result<(ImageMeta, ImageData)> {
+        let image_data = download_cached(
+            image_cache,
+            cache_key,
+            client,
+            link,
+            reqwest::header::HeaderMap::new(),
+            &RETRY_POLICY,
+        )
+        .await?;
 
         tracing::trace!(
             "download nhentai image with size {}, link: {link}",
@@ -253,14 +376,21 @@ impl AsyncStream for NHImageStream {
     fn next(&mut self) -> Option<Self::Future> {
         let link = self.image_urls.next()?;
         let client = self.client.clone();
+        let image_cache = self.image_cache.clone();
         Some(async move {
-            match Self::load_image(client.clone(), link.raw()).await {
-                Ok(r) => Ok(r),
-                Err(e) => {
-                    tracing::error!("fallback for nh image {link:?}: {e}");
-                    Self::load_image(client, &link.fallback()).await
+            let mut last_err = None;
+            for host in ImageURL::hosts() {
+                // cached by the unsharded link since the shard is picked at random
+                let (candidate, key) = (link.link(host), link.link(CdnHost::UNSHARDED));
+                match Self::load_image(&client, image_cache.as_ref(), &candidate, &key).await {
+                    Ok(r) => return Ok(r),
+                    Err(e) => {
+                        tracing::error!("fallback for nh image {candidate}: {e}");
+                        last_err = Some(e);
+                    }
                 }
             }
+            Err(last_err.expect("candidates must not be empty"))
         })
     }
 
@@ -269,3 +399,171 @@ impl AsyncStream for NHImageStream {
         self.image_urls.size_hint()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_gallery_id() {
+        assert_eq!(parse_gallery_id("/g/333678/").unwrap(), 333678);
+        assert_eq!(parse_gallery_id("g/333678").unwrap(), 333678);
+        assert!(parse_gallery_id("/g/abc/").is_err());
+        assert!(parse_gallery_id("/g/").is_err());
+        assert!(parse_gallery_id("/s/333678").is_err());
+        assert!(parse_gallery_id("/g/333678/1").is_err());
+    }
+
+    #[test]
+    fn test_cdn_link() {
+        assert_eq!(
+            CdnHost { shard: Some(2) }.link("987", 3, ImageType::Png),
+            "https://i2.nhentai.net/galleries/987/3.png"
+        );
+        assert_eq!(
+            CdnHost::UNSHARDED.link("987", 3, ImageType::Jpg),
+            "https://i.nhentai.net/galleries/987/3.jpg"
+        );
+        for _ in 0..16 {
+            let shard = CdnHost::random().shard.unwrap();
+            assert!((1..=CDN_SHARDS).contains(&shard));
+        }
+    }
+
+    #[test]
+    fn test_album_parts() {
+        let body = r#"{
+            "id": 1,
+            "media_id": "987",
+            "title": {"english": "Title", "japanese": null, "pretty": null},
+            "images": {"pages": [{"t": "j", "w": 1, "h": 1}, {"t": "p", "w": 1, "h": 1}]},
+            "tags": [
+                {"id": 1, "type": "artist", "name": "someone", "url": "/artist/someone/", "count": 1},
+                {"id": 2, "type": "language", "name": "english", "url": "/language/english/", "count": 1}
+            ]
+        }"#;
+        let album: NhAlbum = serde_json::from_str(body).unwrap();
+        let (meta, urls) = album.into_parts("nhentai.net", 1);
+        assert_eq!(meta.name, "Title");
+        assert_eq!(meta.link, "https://nhentai.net/g/1");
+        let collector = NHCollector::new().with_host("nh.example");
+        assert_eq!(collector.api, "https://nh.example/api/gallery/");
+        assert_eq!(meta.authors.unwrap(), ["someone"]);
+        assert_eq!(meta.tags.unwrap(), ["artist:someone", "language:english"]);
+        assert_eq!(meta.language.as_deref(), Some("english"));
+        assert_eq!(meta.page_count, Some(2));
+        assert_eq!(urls.len(), 2);
+        assert_eq!(
+            urls[1].candidates()[1],
+            "https://i.nhentai.net/galleries/987/2.png"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fetch_retry() {
+        use crate::{
+            collector::CollectorError,
+            mock_server::{MockResponse, MockServer},
+        };
+
+        let body = r#"{"media_id": "987", "title": {"pretty": "Title"}, "images": {"pages": [{"t": "j"}]}}"#;
+        let server = MockServer::start(move |idx, _| match idx {
+            0 => MockResponse::new(503, "unavailable"),
+            _ => MockResponse::new(200, body),
+        })
+        .await;
+        let collector = NHCollector {
+            api: server.url("/api/gallery/"),
+            ..NHCollector::new()
+        };
+        let (meta, stream) = collector.fetch("/g/1/".to_string()).await.unwrap();
+        assert_eq!(meta.name, "Title");
+        assert_eq!(stream.size_hint().0, 1);
+        assert_eq!(server.requests().len(), 2);
+        assert_eq!(server.requests()[1].path, "/api/gallery/1");
+
+        let server = MockServer::start(|_, _| MockResponse::new(404, "")).await;
+        let collector = NHCollector {
+            api: server.url("/api/gallery/"),
+            ..NHCollector::new()
+        };
+        let err = collector.fetch("/g/1/".to_string()).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<CollectorError>(),
+            Some(CollectorError::NotFound)
+        ));
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_no_thumbnail() {
+        use crate::mock_server::{MockResponse, MockServer};
+
+        let body = r#"{"media_id": "987", "title": {"pretty": "Title"}, "images": {"pages": [{"t": "j"}]}}"#;
+        let server = MockServer::start(move |_, req| {
+            match req.header("x-forwarded-for").unwrap_or_default() {
+                "https://nhentai.net/api/gallery/1" => MockResponse::new(200, body),
+                _ => MockResponse::new(404, ""),
+            }
+        })
+        .await;
+        let collector =
+            NHCollector::new().with_proxy(ProxiedClient::new(&server.url("/"), "token").unwrap());
+        let (_, mut stream) = collector.fetch("/g/1/".to_string()).await.unwrap();
+        assert!(stream.next().unwrap().await.is_err());
+        let requests = server.requests();
+        assert!(requests
+            .iter()
+            .filter_map(|r| r.header("x-forwarded-for"))
+            .all(|link| !link.contains("//t.nhentai.net")));
+    }
+
+    #[tokio::test]
+    async fn test_image_cache() {
+        use crate::mock_server::{MockResponse, MockServer};
+
+        let body = r#"{"media_id": "987", "title": {"pretty": "Title"}, "images": {"pages": [{"t": "j"}, {"t": "p"}]}}"#;
+        // all requests are proxied, images are answered with their links
+        let server = MockServer::start(move |_, req| {
+            match req.header("x-forwarded-for").unwrap_or_default() {
+                "https://nhentai.net/api/gallery/1" => MockResponse::new(200, body),
+                link => MockResponse::new(200, link),
+            }
+        })
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let collector = NHCollector::new()
+            .with_proxy(ProxiedClient::new(&server.url("/"), "token").unwrap())
+            .with_image_cache(ImageCache::open(dir.path(), None).unwrap());
+
+        let collect = || async {
+            let (_, mut stream) = collector.fetch("/g/1/".to_string()).await.unwrap();
+            let mut images = Vec::new();
+            while let Some(fut) = stream.next() {
+                images.push(fut.await.unwrap().1);
+            }
+            images
+        };
+        let first = collect().await;
+        assert_eq!(server.requests().len(), 3);
+        assert!(first[1].ends_with(b".nhentai.net/galleries/987/2.png"));
+        // hits even if other shards are picked, only the gallery is fetched again
+        let second = collect().await;
+        assert_eq!(first, second);
+        let requests = server.requests();
+        assert_eq!(requests.len(), 4);
+        assert_eq!(
+            requests[3].header("x-forwarded-for"),
+            Some("https://nhentai.net/api/gallery/1")
+        );
+    }
+
+    #[cfg_attr(not(feature = "network-tests"), ignore)]
+    #[tokio::test]
+    async fn test_fetch() {
+        let collector = NHCollector::new();
+        let (meta, stream) = collector.fetch("/g/177013/".to_string()).await.unwrap();
+        assert!(!meta.name.is_empty());
+        assert!(stream.size_hint().0 > 0);
+    }
+}
@@ -0,0 +1,149 @@
+//! Page range selection of a gallery.
+
+use std::{collections::BTreeSet, fmt, str::FromStr};
+
+/// 1-based page ranges like `10-40`, `10..=40`, `10..41`, `5-` or `1,3,5-7`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PageSelection {
+    // inclusive ranges, `None` end means to the last page
+    ranges: Vec<(usize, Option<usize>)>,
+}
+
+impl PageSelection {
+    /// Resolve to sorted 0-based indices of a gallery with `total` pages.
+    /// Range ends are clamped to the page count, and it fails if a range starts
+    /// beyond the last page.
+    pub fn resolve(&self, total: usize) -> anyhow::Result<Vec<usize>> {
+        let mut indices = BTreeSet::new();
+        for &(start, end) in &self.ranges {
+            if start > total {
+                anyhow::bail!("page {start} out of range, the gallery has {total} pages");
+            }
+            let end = end.unwrap_or(total).min(total);
+            indices.extend(start - 1..end);
+        }
+        Ok(indices.into_iter().collect())
+    }
+}
+
+impl FromStr for PageSelection {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let page = |p: &str| -> anyhow::Result<usize> {
+            match p.trim().parse::<usize>() {
+                Ok(0) => anyhow::bail!("page number starts from 1"),
+                Ok(n) => Ok(n),
+                Err(_) => anyhow::bail!("invalid page number {p:?}"),
+            }
+        };
+        let mut ranges = Vec::new();
+        for part in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (start, end) = if let Some((a, b)) = part.split_once("..=") {
+                (page(a)?, Some(page(b)?))
+            } else if let Some((a, b)) = part.split_once("..") {
+                let b = page(b)?;
+                if b < 2 {
+                    anyhow::bail!("empty page range {part:?}");
+                }
+                (page(a)?, Some(b - 1))
+            } else if let Some((a, b)) = part.split_once('-') {
+                let end = if b.trim().is_empty() {
+                    None
+                } else {
+                    Some(page(b)?)
+                };
+                (page(a)?, end)
+            } else {
+                let n = page(part)?;
+                (n, Some(n))
+            };
+            if matches!(end, Some(end) if end < start) {
+                anyhow::bail!("empty page range {part:?}");
+            }
+            ranges.push((start, end));
+        }
+        if ranges.is_empty() {
+            anyhow::bail!("no page selected");
+        }
+        Ok(Self { ranges })
+    }
+}
+
+impl fmt::Display for PageSelection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (idx, (start, end)) in self.ranges.iter().enumerate() {
+            if idx != 0 {
+                f.write_str(",")?;
+            }
+            match end {
+                Some(end) if end == start => write!(f, "{start}")?,
+                Some(end) => write!(f, "{start}-{end}")?,
+                None => write!(f, "{start}-")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::{AsyncStream, Selected};
+
+    #[test]
+    fn test_parse() {
+        let sel: PageSelection = "10-40".parse().unwrap();
+        assert_eq!(sel, "10..=40".parse().unwrap());
+        assert_eq!(sel, "10..41".parse().unwrap());
+        assert_eq!(sel.to_string(), "10-40");
+
+        let sel: PageSelection = " 1, 3,5-7 ,9-".parse().unwrap();
+        assert_eq!(sel.to_string(), "1,3,5-7,9-");
+
+        for bad in ["", ",", "0-3", "5-3", "a-3", "3..3", "1,,x"] {
+            assert!(bad.parse::<PageSelection>().is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn test_resolve() {
+        let sel: PageSelection = "1,3,5-7,6-8".parse().unwrap();
+        assert_eq!(sel.resolve(10).unwrap(), [0, 2, 4, 5, 6, 7]);
+        // clamped to the page count
+        let sel: PageSelection = "8-40".parse().unwrap();
+        assert_eq!(sel.resolve(10).unwrap(), [7, 8, 9]);
+        let sel: PageSelection = "9-".parse().unwrap();
+        assert_eq!(sel.resolve(10).unwrap(), [8, 9]);
+        // nothing in range
+        let sel: PageSelection = "11-40".parse().unwrap();
+        assert!(sel.resolve(10).is_err());
+    }
+
+    struct Pages(std::ops::Range<usize>);
+
+    impl AsyncStream for Pages {
+        type Item = usize;
+        type Future = std::future::Ready<usize>;
+
+        fn next(&mut self) -> Option<Self::Future> {
+            self.0.next().map(std::future::ready)
+        }
+
+        fn size_hint(&self) -> (usize, Option<usize>) {
+            self.0.size_hint()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_selected_stream() {
+        let sel: PageSelection = "2,4-5,9-".parse().unwrap();
+        let mut stream = Selected::new(Pages(0..10), sel.resolve(10).unwrap());
+        assert_eq!(stream.size_hint(), (5, Some(5)));
+        let mut out = Vec::new();
+        while let Some(f) = stream.next() {
+            out.push(f.await);
+        }
+        assert_eq!(out, [1, 3, 4, 8, 9]);
+    }
+}
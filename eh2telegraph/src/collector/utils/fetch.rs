@@ -0,0 +1,104 @@
+//! Gallery metadata and page list requests, which fail the whole sync if they fail.
+//! Timeouts and 5xx responses are retried, while a missing gallery or one requiring
+//! login fails fast with [`CollectorError`].
+
+use std::time::Duration;
+
+use once_cell::sync::Lazy;
+use reqwest::{RequestBuilder, Response, StatusCode};
+
+use crate::{
+    http_client::HttpRequestBuilder,
+    http_proxy::{send_with_retry, RetryPolicy},
+};
+
+use super::super::CollectorError;
+
+pub static GALLERY_RETRY: Lazy<RetryPolicy> = Lazy::new(|| {
+    RetryPolicy::new(3, Duration::from_millis(500)).with_retry_status(vec![
+        StatusCode::TOO_MANY_REQUESTS,
+        StatusCode::INTERNAL_SERVER_ERROR,
+        StatusCode::BAD_GATEWAY,
+        StatusCode::SERVICE_UNAVAILABLE,
+        StatusCode::GATEWAY_TIMEOUT,
+    ])
+});
+
+/// Send the request with the policy, and fail on non-successful upstream status.
+pub async fn send_gallery_request<C: HttpRequestBuilder>(
+    client: &C,
+    req: RequestBuilder,
+    policy: &RetryPolicy,
+) -> Result<Response, CollectorError> {
+    let resp = send_with_retry(req, policy, client.retry_budget(), |r| async {
+        Ok(client.send_request(r).await?)
+    })
+    .await?;
+    match resp.upstream_status() {
+        status if status.is_success() => Ok(resp.into_inner()),
+        StatusCode::NOT_FOUND | StatusCode::GONE => Err(CollectorError::NotFound),
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Err(CollectorError::RequiresAuth),
+        status => Err(CollectorError::Status(status)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mock_server::{MockResponse, MockServer};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_send_gallery_request() {
+        let client = reqwest::Client::new();
+        let policy = RetryPolicy {
+            base_delay: Duration::from_millis(10),
+            ..GALLERY_RETRY.clone()
+        };
+        let send = |url: String| {
+            let (client, policy) = (&client, &policy);
+            async move { send_gallery_request(client, client.get_builder(&url), policy).await }
+        };
+
+        let server = MockServer::start(|idx, _| match idx {
+            0 => MockResponse::new(503, "unavailable"),
+            _ => MockResponse::new(200, "gallery"),
+        })
+        .await;
+        let resp = send(server.url("/g/1")).await.unwrap();
+        assert_eq!(resp.text().await.unwrap(), "gallery");
+        assert_eq!(server.requests().len(), 2);
+
+        // permanent errors are not retried
+        for (status, permanent) in [(404, true), (403, true), (400, false)] {
+            let server = MockServer::start(move |_, _| MockResponse::new(status, "")).await;
+            let err = send(server.url("/g/1")).await.unwrap_err();
+            assert_eq!(err.is_permanent(), permanent, "{status}");
+            assert_eq!(server.requests().len(), 1, "{status}");
+        }
+
+        // the last status is reported when attempts are exhausted
+        let server = MockServer::start(|_, _| MockResponse::new(500, "")).await;
+        let err = send(server.url("/g/1")).await.unwrap_err();
+        assert!(matches!(
+            err,
+            CollectorError::Status(StatusCode::INTERNAL_SERVER_ERROR)
+        ));
+        assert_eq!(server.requests().len(), 3);
+
+        // the retry budget of the client is shared with its other requests
+        let client = crate::http_proxy::ProxiedClient::default().with_retry_budget(1, 0.0);
+        let server = MockServer::start(|_, _| MockResponse::new(503, "")).await;
+        for requests in [2, 3] {
+            let req = client.get_builder(&server.url("/g/1"));
+            let err = send_gallery_request(&client, req, &policy)
+                .await
+                .unwrap_err();
+            assert!(matches!(
+                err,
+                CollectorError::Status(StatusCode::SERVICE_UNAVAILABLE)
+            ));
+            assert_eq!(server.requests().len(), requests);
+        }
+    }
+}
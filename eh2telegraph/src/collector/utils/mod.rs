@@ -1 +1,3 @@
+pub mod fetch;
 pub mod paged;
+pub mod zip;
@@ -1,7 +1,10 @@
-use reqwest::Response;
-
 use crate::http_client::HttpRequestBuilder;
 
+use super::{
+    super::CollectorError,
+    fetch::{send_gallery_request, GALLERY_RETRY},
+};
+
 pub trait PageFormatter {
     fn format_n(&self, n: usize) -> String;
 }
@@ -14,6 +17,8 @@ pub trait PageIndicator {
 pub enum PagedError {
     #[error("reqwest error")]
     Reqwest(#[from] reqwest::Error),
+    #[error("unable to load the gallery page: {0}")]
+    Gallery(#[from] CollectorError),
 }
 
 pub struct Paged<T> {
@@ -40,7 +45,10 @@ where
     {
         let url = self.page_indicator.format_n(self.next_page);
 
-        let content = client.get_builder(&url).send().await?.text().await?;
+        let content = send_gallery_request(client, client.get_builder(&url), &GALLERY_RETRY)
+            .await?
+            .text()
+            .await?;
         self.next_page += 1;
         Ok(content)
     }
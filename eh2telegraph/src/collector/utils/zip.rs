@@ -0,0 +1,207 @@
+//! A minimal reader of zip archives like the ones of e-hentai galleries, only stored
+//! and deflated entries without zip64 are supported. Entries are read from the file
+//! one at a time, so the archive is never loaded into memory as a whole.
+
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+};
+
+use anyhow::{bail, ensure, Context};
+use bytes::Bytes;
+use parking_lot::Mutex;
+
+const EOCD_SIGNATURE: u32 = 0x0605_4b50;
+const CENTRAL_SIGNATURE: u32 = 0x0201_4b50;
+const LOCAL_SIGNATURE: u32 = 0x0403_4b50;
+const EOCD_LEN: usize = 22;
+const LOCAL_LEN: usize = 30;
+
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub name: String,
+    method: u16,
+    crc: u32,
+    compressed: u32,
+    size: u32,
+    // of the local header
+    local: u64,
+}
+
+#[derive(Debug)]
+pub struct Archive {
+    file: Mutex<File>,
+    entries: Vec<Entry>,
+}
+
+fn u16_at(data: &[u8], offset: usize) -> anyhow::Result<u16> {
+    data.get(offset..offset + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .context("truncated zip")
+}
+
+fn u32_at(data: &[u8], offset: usize) -> anyhow::Result<u32> {
+    data.get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .context("truncated zip")
+}
+
+fn read_at(file: &mut File, offset: u64, len: usize) -> anyhow::Result<Vec<u8>> {
+    let mut buf = vec![0; len];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut buf).context("truncated zip")?;
+    Ok(buf)
+}
+
+impl Archive {
+    /// Read the central directory, the files in it are the entries in its order.
+    /// Directories are skipped.
+    pub fn open(mut file: File) -> anyhow::Result<Self> {
+        let len = file.metadata()?.len();
+        // the end record is followed by a comment of at most u16::MAX bytes
+        let tail_len = len.min((EOCD_LEN + u16::MAX as usize) as u64) as usize;
+        let tail = read_at(&mut file, len - tail_len as u64, tail_len)?;
+        let eocd = (0..=tail.len().saturating_sub(EOCD_LEN))
+            .rev()
+            .find(|&i| u32_at(&tail, i).ok() == Some(EOCD_SIGNATURE))
+            .context("not a zip archive")?;
+        let count = u16_at(&tail, eocd + 10)? as usize;
+        let directory_len = u32_at(&tail, eocd + 12)? as u64;
+        let directory_offset = u32_at(&tail, eocd + 16)? as u64;
+        ensure!(directory_offset + directory_len <= len, "truncated zip");
+        let directory = read_at(&mut file, directory_offset, directory_len as usize)?;
+
+        let mut offset = 0;
+        let mut entries = Vec::with_capacity(count);
+        for _ in 0..count {
+            ensure!(
+                u32_at(&directory, offset)? == CENTRAL_SIGNATURE,
+                "invalid zip central directory"
+            );
+            let flags = u16_at(&directory, offset + 8)?;
+            let method = u16_at(&directory, offset + 10)?;
+            let crc = u32_at(&directory, offset + 16)?;
+            let compressed = u32_at(&directory, offset + 20)?;
+            let size = u32_at(&directory, offset + 24)?;
+            let name_len = u16_at(&directory, offset + 28)? as usize;
+            let extra_len = u16_at(&directory, offset + 30)? as usize;
+            let comment_len = u16_at(&directory, offset + 32)? as usize;
+            let local = u32_at(&directory, offset + 42)? as u64;
+            let name = directory
+                .get(offset + 46..offset + 46 + name_len)
+                .context("truncated zip")?;
+            let name = String::from_utf8_lossy(name).into_owned();
+            offset += 46 + name_len + extra_len + comment_len;
+            if name.ends_with('/') {
+                continue;
+            }
+            ensure!(flags & 1 == 0, "zip entry {name} is encrypted");
+            ensure!(
+                compressed != u32::MAX && size != u32::MAX,
+                "zip entry {name} is zip64 which is not supported"
+            );
+            entries.push(Entry {
+                name,
+                method,
+                crc,
+                compressed,
+                size,
+                local,
+            });
+        }
+        Ok(Self {
+            file: Mutex::new(file),
+            entries,
+        })
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    /// Seek the file to the content of `entry`.
+    fn seek_content(file: &mut File, entry: &Entry) -> anyhow::Result<()> {
+        let local = read_at(file, entry.local, LOCAL_LEN)?;
+        ensure!(
+            u32_at(&local, 0)? == LOCAL_SIGNATURE,
+            "invalid zip entry {}",
+            entry.name
+        );
+        let start = entry.local
+            + LOCAL_LEN as u64
+            + u16_at(&local, 26)? as u64
+            + u16_at(&local, 28)? as u64;
+        file.seek(SeekFrom::Start(start))?;
+        Ok(())
+    }
+
+    /// At most the first `limit` bytes of the content, like to sniff its type.
+    fn read_limited(&self, entry: &Entry, limit: u64) -> anyhow::Result<Vec<u8>> {
+        let mut file = self.file.lock();
+        Self::seek_content(&mut file, entry)?;
+        let compressed = (&mut *file).take(entry.compressed as u64);
+        let mut out = Vec::new();
+        match entry.method {
+            0 => compressed.take(limit).read_to_end(&mut out),
+            8 => flate2::read::DeflateDecoder::new(compressed)
+                .take(limit.min(entry.size as u64))
+                .read_to_end(&mut out),
+            m => bail!("zip entry {} has unsupported compression {m}", entry.name),
+        }
+        .with_context(|| format!("unable to read zip entry {}", entry.name))?;
+        Ok(out)
+    }
+
+    /// The head of the content, at most `len` bytes.
+    pub fn read_head(&self, entry: &Entry, len: usize) -> anyhow::Result<Vec<u8>> {
+        self.read_limited(entry, len as u64)
+    }
+
+    /// The whole content, checked against the size and CRC of the directory.
+    pub fn read(&self, entry: &Entry) -> anyhow::Result<Bytes> {
+        let content = self.read_limited(entry, entry.size as u64)?;
+        let mut check = flate2::Crc::new();
+        check.update(&content);
+        ensure!(
+            content.len() == entry.size as usize && check.sum() == entry.crc,
+            "zip entry {} is corrupted",
+            entry.name
+        );
+        Ok(content.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURE: &[u8] = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/fixtures/e_hentai_archive.zip"
+    ));
+
+    fn open(data: &[u8]) -> anyhow::Result<Archive> {
+        let mut file = tempfile::tempfile().unwrap();
+        std::io::Write::write_all(&mut file, data).unwrap();
+        Archive::open(file)
+    }
+
+    #[test]
+    fn test_read() {
+        let archive = open(FIXTURE).unwrap();
+        let entries = archive.entries();
+        let names = entries.iter().map(|e| e.name.as_str()).collect::<Vec<_>>();
+        // in the order of the archive, not sorted
+        assert_eq!(names, ["01.jpg", "02.png", "10.jpg", "03.txt"]);
+        assert_eq!(
+            &archive.read(&entries[0]).unwrap()[..],
+            b"\xFF\xD8\xFFstored"
+        );
+        let png = archive.read(&entries[1]).unwrap();
+        assert!(png.starts_with(b"\x89PNG") && png.len() > 1000);
+        assert_eq!(archive.read_head(&entries[1], 4).unwrap(), b"\x89PNG");
+
+        assert!(open(b"not a zip").is_err());
+        assert!(open(&FIXTURE[..FIXTURE.len() / 2]).is_err());
+    }
+}
@@ -1,8 +1,8 @@
-use std::{collections::HashMap, env};
+use std::{collections::HashMap, env, fmt, path::Path};
 
-use once_cell::sync::OnceCell;
+use serde::de::DeserializeOwned;
 
-use serde::Deserialize;
+use once_cell::sync::OnceCell;
 
 static CFG_PATH: OnceCell<String> = OnceCell::new();
 
@@ -10,21 +10,184 @@ static CFG_PATH: OnceCell<String> = OnceCell::new();
 pub struct WhitelistConfig {
     pub enabled: bool,
     pub ids: Vec<i64>,
+    /// Never allowed even if the whitelist is disabled, admins excepted.
+    #[serde(default)]
+    pub deny_ids: Vec<i64>,
 }
 
+const ENV_PREFIX: &str = "EH2TG__";
+const ENV_SEPARATOR: &str = "__";
+
+type Mapping = HashMap<String, serde_yaml::Value>;
+type ParseError = Box<dyn std::error::Error + Send + Sync>;
+
 lazy_static::lazy_static! {
-    static ref CONFIG_MAPPING: HashMap<String, serde_yaml::Value> = {
-        let file_path = CFG_PATH.get_or_init(get_config_path);
-        let file_content = std::fs::read_to_string(file_path).expect("config file not found");
-        serde_yaml::from_str(&file_content).expect("unable to parse config file")
+    static ref CONFIG: Config = {
+        let file_content = std::fs::read_to_string(path()).expect("config file not found");
+        let mut config = Config::load(ConfigFormat::from_path(path()), &file_content)
+            .expect("unable to parse config file");
+        apply_env_overrides(&mut config.0, env_vars());
+        config
     };
 }
 
+/// Sections of a loaded config, parsed by key on demand.
+#[derive(Debug, Clone, Default)]
+pub struct Config(Mapping);
+
+impl Config {
+    pub fn load(format: ConfigFormat, content: &str) -> Result<Self, ParseError> {
+        format.parse(content).map(Self)
+    }
+
+    pub fn parse<T: DeserializeOwned>(&self, key: &str) -> serde_yaml::Result<Option<T>> {
+        self.0
+            .get(key)
+            .cloned()
+            .map(|v| serde_yaml::from_value(v))
+            .transpose()
+    }
+
+    /// Parse the section for validation, a parse error is recorded as a problem.
+    pub fn section<T: DeserializeOwned>(&self, key: &str, errors: &mut ConfigErrors) -> Option<T> {
+        self.parse(key).unwrap_or_else(|e| {
+            errors.push(key, e);
+            None
+        })
+    }
+
+    /// Same as `section`, but a missing section is a problem too.
+    pub fn required<T: DeserializeOwned>(&self, key: &str, errors: &mut ConfigErrors) -> Option<T> {
+        if !self.0.contains_key(key) {
+            errors.push(key, "section is required but missing");
+        }
+        self.section(key, errors)
+    }
+
+    /// Check the sections used by this crate. All problems are collected instead of
+    /// stopping at the first one, so they can be fixed at once.
+    pub fn validate(&self) -> ConfigErrors {
+        let mut errors = ConfigErrors::default();
+        crate::http_proxy::validate_config(self, &mut errors);
+        crate::storage::validate_config(self, &mut errors);
+        self.section::<WhitelistConfig>("whitelist", &mut errors);
+        crate::collector::e_hentai::validate_config(self, &mut errors);
+        crate::collector::hosts::validate_config(self, &mut errors);
+        crate::storage::rate_limit::validate_config(self, &mut errors);
+        self.section::<crate::sync::SyncLimits>("limits", &mut errors);
+        crate::phash::validate_config(self, &mut errors);
+        errors
+    }
+}
+
+/// Problems found in the config, each one prefixed with the key of its section.
+#[derive(Debug, Default)]
+pub struct ConfigErrors(Vec<String>);
+
+impl ConfigErrors {
+    pub fn push(&mut self, key: &str, error: impl fmt::Display) {
+        self.0.push(format!("{key}: {error}"));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.0.iter().map(String::as_str)
+    }
+}
+
+impl fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} problem(s) found in the config:", self.0.len())?;
+        for e in &self.0 {
+            write!(f, "\n  - {e}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigErrors {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Yaml,
+    Toml,
+    Json,
+}
+
+impl ConfigFormat {
+    /// Detect the format by the extension, YAML if unknown.
+    pub fn from_path(path: impl AsRef<Path>) -> Self {
+        let ext = path
+            .as_ref()
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(str::to_ascii_lowercase);
+        match ext.as_deref() {
+            Some("toml") => Self::Toml,
+            Some("json") => Self::Json,
+            _ => Self::Yaml,
+        }
+    }
+
+    // all formats are loaded as YAML values, so `parse` works the same on them
+    fn parse(self, content: &str) -> Result<Mapping, ParseError> {
+        Ok(match self {
+            Self::Yaml => serde_yaml::from_str(content)?,
+            Self::Toml => toml::from_str(content)?,
+            Self::Json => serde_json::from_str(content)?,
+        })
+    }
+}
+
+fn env_vars() -> impl Iterator<Item = (String, String)> {
+    env::vars_os().filter_map(|(k, v)| Some((k.into_string().ok()?, v.into_string().ok()?)))
+}
+
+/// Override keys with env vars like `EH2TG__PROXY__ENDPOINT` for `proxy.endpoint`,
+/// so env takes precedence over the file. Missing keys are created, and values are
+/// parsed as YAML, so `8`, `true` and `[1, 2]` keep their types.
+fn apply_env_overrides(mapping: &mut Mapping, vars: impl IntoIterator<Item = (String, String)>) {
+    use serde_yaml::Value;
+
+    for (name, value) in vars {
+        let Some(path) = name.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+        let mut keys = path.split(ENV_SEPARATOR).map(str::to_ascii_lowercase);
+        let Some(root) = keys.next().filter(|k| !k.is_empty()) else {
+            continue;
+        };
+        let value = serde_yaml::from_str(&value).unwrap_or(Value::String(value));
+        let mut node = mapping.entry(root).or_insert(Value::Null);
+        for key in keys {
+            if !node.is_mapping() {
+                *node = Value::Mapping(Default::default());
+            }
+            node = node
+                .as_mapping_mut()
+                .expect("replaced with a mapping")
+                .entry(Value::String(key))
+                .or_insert(Value::Null);
+        }
+        *node = value;
+    }
+}
+
+/// Path of the config file.
+pub fn path() -> &'static str {
+    CFG_PATH.get_or_init(get_config_path)
+}
+
 fn get_config_path() -> String {
-    // read from env
-    if let Ok(p) = env::var("CONFIG_FILE") {
-        if !p.is_empty() {
-            return p;
+    // read from env, `CONFIG_FILE` is kept for compatibility
+    for key in ["EH2TG_CONFIG", "CONFIG_FILE"] {
+        if let Ok(p) = env::var(key) {
+            if !p.is_empty() {
+                return p;
+            }
         }
     }
 
@@ -37,17 +200,210 @@ pub fn init(config_path: Option<String>) {
     if let Some(p) = config_path {
         let _ = CFG_PATH.set(p);
     }
-    lazy_static::initialize(&CONFIG_MAPPING);
+    lazy_static::initialize(&CONFIG);
+}
+
+/// The global config, loaded on first use.
+pub fn global() -> &'static Config {
+    &CONFIG
 }
 
 /// Parse struct from global config.
 pub fn parse<T>(key: &str) -> serde_yaml::Result<Option<T>>
 where
-    T: serde::de::DeserializeOwned,
+    T: DeserializeOwned,
 {
-    CONFIG_MAPPING
-        .get(key)
-        .cloned()
-        .map(|v| serde_yaml::from_value(v))
+    CONFIG.parse(key)
+}
+
+/// Read the config file again and parse struct from it.
+/// The global config used by `parse` is not changed.
+pub fn reparse<T>(key: &str) -> std::io::Result<Option<T>>
+where
+    T: DeserializeOwned,
+{
+    let invalid = |e| std::io::Error::new(std::io::ErrorKind::InvalidData, e);
+    let file_content = std::fs::read_to_string(path())?;
+    let mut mapping = ConfigFormat::from_path(path())
+        .parse(&file_content)
+        .map_err(invalid)?;
+    apply_env_overrides(&mut mapping, env_vars());
+    mapping
+        .remove(key)
+        .map(|v| serde_yaml::from_value(v).map_err(|e| invalid(e.into())))
         .transpose()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, serde::Deserialize)]
+    struct TestConfig {
+        name: String,
+        ids: Vec<i64>,
+        ratio: f64,
+        enabled: bool,
+        nested: Option<Nested>,
+    }
+
+    #[derive(Debug, PartialEq, serde::Deserialize)]
+    struct Nested {
+        url: String,
+        pool_size: Option<usize>,
+    }
+
+    #[test]
+    fn test_env_overrides() {
+        let yaml = r#"
+proxy:
+  endpoint: https://old.example.com
+  authorization: token
+storage:
+  max_entries: 10
+"#;
+        let mut mapping = ConfigFormat::Yaml.parse(yaml).unwrap();
+        let vars = [
+            ("EH2TG__PROXY__ENDPOINT", "https://new.example.com"),
+            ("EH2TG__STORAGE__MAX_ENTRIES", "20"),
+            // missing keys are created
+            ("EH2TG__STORAGE__SQLITE__PATH", "/data/cache.db"),
+            ("EH2TG__WHITELIST__IDS", "[1, 2]"),
+            ("EH2TG__WHITELIST__ENABLED", "true"),
+            ("OTHER__PROXY__ENDPOINT", "ignored"),
+            ("EH2TG__", "ignored"),
+        ];
+        apply_env_overrides(
+            &mut mapping,
+            vars.map(|(k, v)| (k.to_string(), v.to_string())),
+        );
+
+        let get = |path: &[&str]| {
+            let mut node = &mapping[path[0]];
+            for key in &path[1..] {
+                node = &node[*key];
+            }
+            node.clone()
+        };
+        assert_eq!(get(&["proxy", "endpoint"]), "https://new.example.com");
+        // siblings are kept
+        assert_eq!(get(&["proxy", "authorization"]), "token");
+        assert_eq!(get(&["storage", "max_entries"]), 20);
+        assert_eq!(get(&["storage", "sqlite", "path"]), "/data/cache.db");
+        let whitelist: WhitelistConfig =
+            serde_yaml::from_value(mapping["whitelist"].clone()).unwrap();
+        assert!(whitelist.enabled);
+        assert_eq!(whitelist.ids, vec![1, 2]);
+        assert!(whitelist.deny_ids.is_empty());
+        assert_eq!(mapping.len(), 3);
+    }
+
+    #[test]
+    fn test_validate() {
+        let yaml = r#"
+proxy:
+  endpoint: ftp://proxy.example.com
+  authorization: token
+storage:
+  max_entries: many
+whitelist:
+  enabled: false
+rate_limit:
+  per_hour: 0
+ehentai:
+  ipb_member_id: "1"
+  ipb_pass_hash: abc
+  archive_fallback: 1.5
+"#;
+        let config = Config::load(ConfigFormat::Yaml, yaml).unwrap();
+        let errors = config.validate();
+        let errors = errors.iter().collect::<Vec<_>>();
+        assert_eq!(errors.len(), 5, "{errors:?}");
+        assert!(errors[0].starts_with("proxy: "), "{errors:?}");
+        assert!(errors[0].contains("http or https"), "{errors:?}");
+        assert!(errors[1].starts_with("storage: "), "{errors:?}");
+        assert!(errors[2].starts_with("whitelist: "), "{errors:?}");
+        assert_eq!(
+            errors[3],
+            "ehentai.archive_fallback: must be between 0 and 1"
+        );
+        assert!(errors[4].starts_with("rate_limit.per_hour: "), "{errors:?}");
+
+        let mut errors = ConfigErrors::default();
+        assert!(config
+            .required::<WhitelistConfig>("base", &mut errors)
+            .is_none());
+        assert_eq!(
+            errors.to_string(),
+            "1 problem(s) found in the config:\n  - base: section is required but missing"
+        );
+        assert!(Config::default().validate().is_empty());
+    }
+
+    #[test]
+    fn test_format_from_path() {
+        assert_eq!(ConfigFormat::from_path("config.yaml"), ConfigFormat::Yaml);
+        assert_eq!(ConfigFormat::from_path("/etc/bot.TOML"), ConfigFormat::Toml);
+        assert_eq!(ConfigFormat::from_path("./config.json"), ConfigFormat::Json);
+        assert_eq!(ConfigFormat::from_path("config"), ConfigFormat::Yaml);
+    }
+
+    #[test]
+    fn test_formats_parse_same() {
+        let yaml = r#"
+test:
+  name: bot
+  ids: [1, -100]
+  ratio: 0.5
+  enabled: true
+  nested:
+    url: redis://127.0.0.1
+empty: {}
+"#;
+        let toml = r#"
+[test]
+name = "bot"
+ids = [1, -100]
+ratio = 0.5
+enabled = true
+
+[test.nested]
+url = "redis://127.0.0.1"
+
+[empty]
+"#;
+        let json = r#"{
+    "test": {
+        "name": "bot",
+        "ids": [1, -100],
+        "ratio": 0.5,
+        "enabled": true,
+        "nested": {"url": "redis://127.0.0.1", "pool_size": null}
+    },
+    "empty": {}
+}"#;
+        let expected = TestConfig {
+            name: "bot".to_string(),
+            ids: vec![1, -100],
+            ratio: 0.5,
+            enabled: true,
+            nested: Some(Nested {
+                url: "redis://127.0.0.1".to_string(),
+                pool_size: None,
+            }),
+        };
+        for (format, content) in [
+            (ConfigFormat::Yaml, yaml),
+            (ConfigFormat::Toml, toml),
+            (ConfigFormat::Json, json),
+        ] {
+            let mut mapping = format.parse(content).unwrap();
+            let config: TestConfig =
+                serde_yaml::from_value(mapping.remove("test").unwrap()).unwrap();
+            assert_eq!(config, expected, "{format:?}");
+            let empty: HashMap<String, String> =
+                serde_yaml::from_value(mapping.remove("empty").unwrap()).unwrap();
+            assert!(empty.is_empty(), "{format:?}");
+        }
+    }
+}
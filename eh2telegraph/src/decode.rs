@@ -0,0 +1,172 @@
+//! Decode images for re-encoding. The image crate reads the common formats, AVIF and
+//! JPEG XL are decoded with the `avif` and `jxl` features, and fail without them.
+
+use std::io::Cursor;
+
+use image::{DynamicImage, ImageReader};
+
+use crate::sniff::ImageKind;
+
+/// Decode by the sniffed type.
+/// This is CPU bound, call it in a blocking thread.
+pub fn load(data: &[u8]) -> anyhow::Result<DynamicImage> {
+    match ImageKind::sniff(data) {
+        #[cfg(feature = "avif")]
+        ImageKind::Avif => avif::load(data),
+        #[cfg(feature = "jxl")]
+        ImageKind::Jxl => jxl::load(data),
+        kind if !kind.is_decodable() => {
+            anyhow::bail!("no decoder of {} images in this build", kind.extension())
+        }
+        _ => Ok(image::load_from_memory(data)?),
+    }
+}
+
+/// Width and height, only the image header is read. None if it can not be decoded.
+pub fn dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    match ImageKind::sniff(data) {
+        #[cfg(feature = "avif")]
+        ImageKind::Avif => avif::dimensions(data),
+        #[cfg(feature = "jxl")]
+        ImageKind::Jxl => jxl::dimensions(data),
+        kind if !kind.is_decodable() => None,
+        _ => ImageReader::new(Cursor::new(data))
+            .with_guessed_format()
+            .ok()?
+            .into_dimensions()
+            .ok(),
+    }
+}
+
+#[cfg(feature = "avif")]
+mod avif {
+    use std::io::Cursor;
+
+    use image::{DynamicImage, RgbImage};
+    use re_rav1d::dav1d::{
+        pixel::{MatrixCoefficients, YUVRange},
+        Decoder, Error, Picture, PixelLayout, PlanarImageComponent, Settings,
+    };
+
+    // rounds of the decoder to give a picture, a still image needs one or two
+    const MAX_ROUNDS: usize = 16;
+
+    /// The color channels, the alpha one is dropped since the output is JPEG.
+    pub(super) fn load(data: &[u8]) -> anyhow::Result<DynamicImage> {
+        let avif = avif_parse::read_avif(&mut Cursor::new(data))?;
+        let mut settings = Settings::new();
+        settings.set_n_threads(1);
+        settings.set_max_frame_delay(1);
+        let mut decoder = Decoder::with_settings(&settings)?;
+        match decoder.send_data(avif.primary_item.to_vec(), None, None, None) {
+            Ok(()) | Err(Error::Again) => (),
+            Err(e) => return Err(e.into()),
+        }
+        for _ in 0..MAX_ROUNDS {
+            match decoder.get_picture() {
+                Ok(picture) => return Ok(DynamicImage::ImageRgb8(to_rgb(&picture))),
+                Err(Error::Again) => match decoder.send_pending_data() {
+                    Ok(()) | Err(Error::Again) => (),
+                    Err(e) => return Err(e.into()),
+                },
+                Err(e) => return Err(e.into()),
+            }
+        }
+        anyhow::bail!("no picture decoded from the avif image")
+    }
+
+    pub(super) fn dimensions(data: &[u8]) -> Option<(u32, u32)> {
+        let meta = avif_parse::read_avif(&mut Cursor::new(data))
+            .ok()?
+            .primary_item_metadata()
+            .ok()?;
+        Some((meta.max_frame_width.get(), meta.max_frame_height.get()))
+    }
+
+    fn to_rgb(picture: &Picture) -> RgbImage {
+        let bits = picture.bits_per_component().map_or(8, |b| b.0 as u32);
+        let wide = picture.bit_depth() > 8;
+        let planes = [
+            PlanarImageComponent::Y,
+            PlanarImageComponent::U,
+            PlanarImageComponent::V,
+        ]
+        .map(|c| (picture.plane(c), picture.stride(c) as usize));
+        let layout = picture.pixel_layout();
+        let (shift_x, shift_y) = match layout {
+            PixelLayout::I420 => (1, 1),
+            PixelLayout::I422 => (1, 0),
+            PixelLayout::I400 | PixelLayout::I444 => (0, 0),
+        };
+        let sample = |idx: usize, x: u32, y: u32| {
+            let (plane, stride) = &planes[idx];
+            let pos = y as usize * stride;
+            match wide {
+                true => {
+                    let pos = pos + x as usize * 2;
+                    u16::from_le_bytes([plane[pos], plane[pos + 1]]) as f32
+                }
+                false => plane[pos + x as usize] as f32,
+            }
+        };
+
+        // to [0, 1] for luma and [-0.5, 0.5] for chroma
+        let unit = (1 << (bits - 8)) as f32;
+        let max = ((1 << bits) - 1) as f32;
+        let (offset, luma_scale, chroma_scale) = match picture.color_range() {
+            YUVRange::Limited => (16.0 * unit, 219.0 * unit, 224.0 * unit),
+            YUVRange::Full => (0.0, max, max),
+        };
+        let luma = |y: f32| (y - offset) / luma_scale;
+        let chroma = |c: f32| (c - 128.0 * unit) / chroma_scale;
+        let matrix = picture.matrix_coefficients();
+        // Kr and Kb, BT.601 if unspecified like libavif
+        let (kr, kb) = match matrix {
+            MatrixCoefficients::BT709 => (0.2126, 0.0722),
+            MatrixCoefficients::BT2020NonConstantLuminance
+            | MatrixCoefficients::BT2020ConstantLuminance => (0.2627, 0.0593),
+            MatrixCoefficients::ST240M => (0.212, 0.087),
+            _ => (0.299, 0.114),
+        };
+        let to_u8 = |v: f32| (v * 255.0).round().clamp(0.0, 255.0) as u8;
+
+        RgbImage::from_fn(picture.width(), picture.height(), |x, y| {
+            let luma = luma(sample(0, x, y));
+            let (cb, cr) = match layout {
+                PixelLayout::I400 => (0.0, 0.0),
+                _ => (
+                    chroma(sample(1, x >> shift_x, y >> shift_y)),
+                    chroma(sample(2, x >> shift_x, y >> shift_y)),
+                ),
+            };
+            if matrix == MatrixCoefficients::Identity {
+                // GBR in the Y, U and V planes
+                return image::Rgb([to_u8(cr + 0.5), to_u8(luma), to_u8(cb + 0.5)]);
+            }
+            let r = luma + 2.0 * (1.0 - kr) * cr;
+            let b = luma + 2.0 * (1.0 - kb) * cb;
+            let g = (luma - kr * r - kb * b) / (1.0 - kr - kb);
+            image::Rgb([to_u8(r), to_u8(g), to_u8(b)])
+        })
+    }
+}
+
+#[cfg(feature = "jxl")]
+mod jxl {
+    use std::io::Cursor;
+
+    use image::{DynamicImage, ImageDecoder};
+    use jxl_oxide::integration::JxlDecoder;
+
+    pub(super) fn load(data: &[u8]) -> anyhow::Result<DynamicImage> {
+        Ok(DynamicImage::from_decoder(JxlDecoder::new(Cursor::new(
+            data,
+        ))?)?)
+    }
+
+    pub(super) fn dimensions(data: &[u8]) -> Option<(u32, u32)> {
+        JxlDecoder::new(Cursor::new(data))
+            .ok()
+            .map(|d| d.dimensions())
+    }
+}
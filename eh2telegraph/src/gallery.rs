@@ -0,0 +1,600 @@
+//! Sync a gallery by its url, the programmatic equivalent of sending a link to the bot.
+//!
+//! Supported urls, all of them take an optional `#pages=10-40` suffix to sync only
+//! these pages:
+//! - `https://e-hentai.org/g/{id}/{token}/`
+//! - `https://exhentai.org/g/{id}/{token}/`, which needs the cookies of
+//!   [`ExConfig`](crate::collector::exhentai::ExConfig)
+//! - `https://nhentai.net/g/{id}/` and `https://nhentai.to/g/{id}/`
+//! - `https://hitomi.la/{type}/{title}-{id}.html`
+
+use std::fmt;
+
+use url::Url;
+
+use crate::{
+    collector::{
+        e_hentai::{EHCollector, Resolution},
+        exhentai::EXCollector,
+        hitomi::HitomiCollector,
+        nhentai::NHCollector,
+        selection::PageSelection,
+        Collector, Param, Registry,
+    },
+    http_proxy::ProxiedClient,
+    storage::{KVStorage, SimpleMemStorage},
+    sync::{DryRunReport, SharedError, Synchronizer, UploadOptions},
+    telegraph::Telegraph,
+    util::canonicalize_url,
+};
+
+// moved to `sync`, where it is shared by all of the syncs
+pub use crate::sync::SyncError;
+
+/// Sites galleries can be synced from, detected by `Collector::matches`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Site {
+    EHentai,
+    ExHentai,
+    NHentai,
+    Hitomi,
+}
+
+impl Site {
+    /// The site of the collector of `registry` matching the url.
+    pub fn from_url(url: &Url, registry: &Registry) -> Option<Self> {
+        [
+            (
+                Param::<EHCollector>::get(registry).matches(url),
+                Self::EHentai,
+            ),
+            (
+                Param::<EXCollector>::get(registry).matches(url),
+                Self::ExHentai,
+            ),
+            (
+                Param::<NHCollector>::get(registry).matches(url),
+                Self::NHentai,
+            ),
+            (
+                Param::<HitomiCollector>::get(registry).matches(url),
+                Self::Hitomi,
+            ),
+        ]
+        .into_iter()
+        .find_map(|(matches, site)| matches.then_some(site))
+    }
+}
+
+/// A gallery url split into the parts used for syncing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GalleryUrl {
+    pub site: Site,
+    pub path: String,
+    pub pages: Option<PageSelection>,
+}
+
+impl GalleryUrl {
+    /// Parse by the hosts of `registry`, the url is canonicalized first, see
+    /// `canonicalize_url`.
+    pub fn parse(s: &str, registry: &Registry) -> Result<Self, SyncError> {
+        let url = canonicalize_url(s, registry.hosts())
+            .map_err(|_| SyncError::InvalidUrl(s.to_string()))?;
+        let site = Site::from_url(&url, registry)
+            .ok_or_else(|| SyncError::UnsupportedUrl(url.to_string()))?;
+        let pages = match url.fragment().and_then(|f| f.strip_prefix("pages=")) {
+            Some(pages) => Some(
+                pages
+                    .parse()
+                    .map_err(|e| SyncError::Pages(SharedError::new(e)))?,
+            ),
+            None => None,
+        };
+        Ok(Self {
+            site,
+            path: url.path().to_string(),
+            pages,
+        })
+    }
+}
+
+impl<C> Synchronizer<C>
+where
+    C: KVStorage<String>,
+{
+    /// Sync with the collector of the site, the page selection of the url overrides
+    /// the one of `options`.
+    pub async fn sync_gallery(
+        &self,
+        gallery: &GalleryUrl,
+        mut options: UploadOptions,
+    ) -> Result<Vec<String>, SyncError> {
+        if gallery.pages.is_some() {
+            options.pages = gallery.pages.clone();
+        }
+        let path = gallery.path.clone();
+        tracing::info!("[registry] sync {:?} for path {path}", gallery.site);
+        match gallery.site {
+            Site::EHentai => self.sync_with_options::<EHCollector>(path, options).await,
+            Site::ExHentai => self.sync_with_options::<EXCollector>(path, options).await,
+            Site::NHentai => self.sync_with_options::<NHCollector>(path, options).await,
+            Site::Hitomi => {
+                self.sync_with_options::<HitomiCollector>(path, options)
+                    .await
+            }
+        }
+    }
+
+    /// Links of the gallery if synced before, without syncing it on miss.
+    pub async fn cached_gallery(
+        &self,
+        gallery: &GalleryUrl,
+    ) -> anyhow::Result<Option<Vec<String>>> {
+        let (path, pages) = (gallery.path.as_str(), gallery.pages.as_ref());
+        match gallery.site {
+            Site::EHentai => self.cached::<EHCollector>(path, pages).await,
+            Site::ExHentai => self.cached::<EXCollector>(path, pages).await,
+            Site::NHentai => self.cached::<NHCollector>(path, pages).await,
+            Site::Hitomi => self.cached::<HitomiCollector>(path, pages).await,
+        }
+    }
+
+    /// Links of a gallery with the same content synced before, see `cached_content`.
+    pub async fn cached_gallery_content(
+        &self,
+        gallery: &GalleryUrl,
+    ) -> anyhow::Result<Option<Vec<String>>> {
+        let (path, pages) = (gallery.path.as_str(), gallery.pages.as_ref());
+        match gallery.site {
+            Site::EHentai => self.cached_content::<EHCollector>(path, pages).await,
+            Site::ExHentai => self.cached_content::<EXCollector>(path, pages).await,
+            Site::NHentai => self.cached_content::<NHCollector>(path, pages).await,
+            Site::Hitomi => self.cached_content::<HitomiCollector>(path, pages).await,
+        }
+    }
+
+    /// Report what `sync_gallery` would upload, without uploading it.
+    pub async fn dry_run_gallery(&self, gallery: &GalleryUrl) -> anyhow::Result<DryRunReport> {
+        let (path, pages) = (gallery.path.clone(), gallery.pages.as_ref());
+        match gallery.site {
+            Site::EHentai => self.dry_run::<EHCollector>(path, pages).await,
+            Site::ExHentai => self.dry_run::<EXCollector>(path, pages).await,
+            Site::NHentai => self.dry_run::<NHCollector>(path, pages).await,
+            Site::Hitomi => self.dry_run::<HitomiCollector>(path, pages).await,
+        }
+    }
+}
+
+/// Options of `sync_url`. Unset parts fall back to a direct connection, collectors
+/// without exhentai cookies, and an in memory cache.
+#[derive(Debug)]
+pub struct SyncOptions<C = SimpleMemStorage<String>> {
+    tokens: Vec<String>,
+    proxy: Option<ProxiedClient>,
+    registry: Option<Registry>,
+    resolution: Option<Resolution>,
+    upload: UploadOptions,
+    dry_run: bool,
+    storage: C,
+}
+
+impl SyncOptions {
+    /// Each gallery is created with the least used of the telegraph access tokens,
+    /// see `TokenPool`.
+    pub fn new(tokens: Vec<String>) -> Self {
+        Self {
+            tokens,
+            proxy: None,
+            registry: None,
+            resolution: None,
+            upload: UploadOptions::default(),
+            dry_run: false,
+            storage: SimpleMemStorage::default(),
+        }
+    }
+}
+
+impl<C> SyncOptions<C> {
+    /// Used by telegraph and the collectors which support it.
+    pub fn with_proxy(mut self, proxy: ProxiedClient) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    pub fn with_registry(mut self, registry: Registry) -> Self {
+        self.registry = Some(registry);
+        self
+    }
+
+    /// Images downloaded from e-hentai, overriding the one of the registry.
+    pub fn with_resolution(mut self, resolution: Resolution) -> Self {
+        self.resolution = Some(resolution);
+        self
+    }
+
+    pub fn with_upload_options(mut self, upload: UploadOptions) -> Self {
+        self.upload = upload;
+        self
+    }
+
+    /// Collect and download the galleries without uploading them, so no token is
+    /// needed and no links are returned. The report of each one is logged, use
+    /// `dry_run_url` to get it.
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Cache of the synced galleries and the checkpoints of interrupted ones.
+    pub fn with_storage<S>(self, storage: S) -> SyncOptions<S> {
+        SyncOptions {
+            tokens: self.tokens,
+            proxy: self.proxy,
+            registry: self.registry,
+            resolution: self.resolution,
+            upload: self.upload,
+            dry_run: self.dry_run,
+            storage,
+        }
+    }
+}
+
+/// Sync the gallery of the url to telegraph and return the links of the created
+/// pages in order. See the module docs for the supported urls.
+pub async fn sync_url<C>(url: &str, opts: SyncOptions<C>) -> Result<Vec<Url>, SyncError>
+where
+    C: KVStorage<String>,
+{
+    let (upload, dry_run, no_token) = (opts.upload.clone(), opts.dry_run, opts.tokens.is_empty());
+    let sync = build_synchronizer(opts)?;
+    let gallery = GalleryUrl::parse(url, sync.registry())?;
+    if no_token && !dry_run {
+        return Err(SyncError::NoToken);
+    }
+    sync_or_dry_run(&sync, &gallery, upload, dry_run).await
+}
+
+/// Results of `sync_urls`, one per url in the order given.
+#[derive(Debug)]
+pub struct BatchReport {
+    pub results: Vec<(String, Result<Vec<Url>, SyncError>)>,
+}
+
+impl BatchReport {
+    pub fn succeeded(&self) -> impl Iterator<Item = (&str, &[Url])> {
+        self.results
+            .iter()
+            .filter_map(|(url, r)| r.as_deref().ok().map(|links| (url.as_str(), links)))
+    }
+
+    pub fn failed(&self) -> impl Iterator<Item = (&str, &SyncError)> {
+        self.results
+            .iter()
+            .filter_map(|(url, r)| r.as_ref().err().map(|e| (url.as_str(), e)))
+    }
+}
+
+impl fmt::Display for BatchReport {
+    /// The counts, then a line with the reason of each failed url.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let failed = self.failed().count();
+        write!(f, "{} synced, {failed} failed", self.results.len() - failed)?;
+        for (url, e) in self.failed() {
+            write!(f, "\n{url}: {e}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Sync the urls one after another like `sync_url`. A url failing, e.g. a gallery
+/// removed from the site, is recorded in the report and the others are still synced.
+/// Only fails if the synchronizer cannot be built, like without a token.
+pub async fn sync_urls<C, I>(urls: I, opts: SyncOptions<C>) -> Result<BatchReport, SyncError>
+where
+    C: KVStorage<String>,
+    I: IntoIterator,
+    I::Item: AsRef<str>,
+{
+    if opts.tokens.is_empty() && !opts.dry_run {
+        return Err(SyncError::NoToken);
+    }
+    let (upload, dry_run) = (opts.upload.clone(), opts.dry_run);
+    let sync = build_synchronizer(opts)?;
+    let mut results = Vec::new();
+    for url in urls {
+        let url = url.as_ref();
+        let result = match GalleryUrl::parse(url, sync.registry()) {
+            Ok(gallery) => sync_or_dry_run(&sync, &gallery, upload.clone(), dry_run).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = &result {
+            tracing::warn!("[batch] skip {url}: {e}");
+        }
+        results.push((url.to_string(), result));
+    }
+    Ok(BatchReport { results })
+}
+
+/// Sync the gallery, or in a dry run only log what would be uploaded.
+async fn sync_or_dry_run<C>(
+    sync: &Synchronizer<C>,
+    gallery: &GalleryUrl,
+    upload: UploadOptions,
+    dry_run: bool,
+) -> Result<Vec<Url>, SyncError>
+where
+    C: KVStorage<String>,
+{
+    if dry_run {
+        let report = sync
+            .dry_run_gallery(gallery)
+            .await
+            .map_err(SyncError::classify)?;
+        tracing::info!("[dry run] {:?}: {report:?}", gallery.path);
+        return Ok(Vec::new());
+    }
+    let links = sync.sync_gallery(gallery, upload).await?;
+    parse_links(&links)
+}
+
+fn parse_links(links: &[String]) -> Result<Vec<Url>, SyncError> {
+    links
+        .iter()
+        .map(|l| Url::parse(l).map_err(|e| SyncError::Other(SharedError::new(e))))
+        .collect()
+}
+
+/// Download the gallery of the url like `sync_url` and report what would be
+/// uploaded, nothing is sent to telegraph so no token is needed. The same as
+/// `sync_url` with `SyncOptions::with_dry_run`, which only logs the report.
+pub async fn dry_run_url<C>(url: &str, opts: SyncOptions<C>) -> Result<DryRunReport, SyncError>
+where
+    C: KVStorage<String>,
+{
+    let sync = build_synchronizer(opts)?;
+    let gallery = GalleryUrl::parse(url, sync.registry())?;
+    sync.dry_run_gallery(&gallery)
+        .await
+        .map_err(SyncError::classify)
+}
+
+fn build_synchronizer<C>(opts: SyncOptions<C>) -> Result<Synchronizer<C>, SyncError>
+where
+    C: KVStorage<String>,
+{
+    let proxy = opts.proxy.unwrap_or_default();
+    let mut registry = opts.registry.unwrap_or_default().with_proxy(proxy.clone());
+    if let Some(resolution) = opts.resolution {
+        registry = registry.with_resolution(resolution);
+    }
+    // only a dry run gets here without a token, and it sends nothing to telegraph
+    let tokens = match opts.tokens.is_empty() {
+        true => vec![String::new()],
+        false => opts.tokens,
+    };
+    let telegraph = Telegraph::new(tokens).with_proxy(proxy);
+    Ok(Synchronizer::new(telegraph, registry, opts.storage))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_server::{MockResponse, MockServer};
+
+    fn parse(url: &str) -> Result<GalleryUrl, SyncError> {
+        GalleryUrl::parse(url, &Registry::default())
+    }
+
+    fn synchronizer() -> Synchronizer<SimpleMemStorage<String>> {
+        let telegraph =
+            Telegraph::new(vec!["token".to_string()]).with_proxy(ProxiedClient::default());
+        Synchronizer::new(telegraph, Registry::default(), SimpleMemStorage::default())
+    }
+
+    #[test]
+    fn test_parse_gallery_url() {
+        let cases = [
+            ("https://e-hentai.org/g/1/abc/", Site::EHentai, "/g/1/abc/"),
+            ("https://exhentai.org/g/1/abc/", Site::ExHentai, "/g/1/abc/"),
+            ("https://nhentai.net/g/177013/", Site::NHentai, "/g/177013/"),
+            ("https://nhentai.to/g/177013", Site::NHentai, "/g/177013/"),
+            (
+                "https://m.e-hentai.org/g/1/abc?p=2",
+                Site::EHentai,
+                "/g/1/abc/",
+            ),
+            (
+                "https://hitomi.la/doujinshi/title-123.html",
+                Site::Hitomi,
+                "/doujinshi/title-123.html",
+            ),
+        ];
+        for (url, site, path) in cases {
+            let gallery = parse(url).unwrap();
+            assert_eq!(gallery.site, site, "{url}");
+            assert_eq!(gallery.path, path, "{url}");
+            assert!(gallery.pages.is_none(), "{url}");
+        }
+
+        let gallery = parse("https://nhentai.net/g/1/#pages=2-5").unwrap();
+        assert_eq!(gallery.pages, Some("2-5".parse().unwrap()));
+        assert!(matches!(
+            parse("https://pixiv.net/artworks/1"),
+            Err(SyncError::UnsupportedUrl(url)) if url == "https://pixiv.net/artworks/1"
+        ));
+        for url in [
+            "https://e-hentai.org/tag/artist:xxx",
+            "https://nhentai.net/search/?q=xxx",
+        ] {
+            assert!(
+                matches!(parse(url), Err(SyncError::UnsupportedUrl(_))),
+                "{url}"
+            );
+        }
+        assert!(matches!(parse("/g/1/"), Err(SyncError::InvalidUrl(_))));
+        assert!(matches!(
+            parse("https://nhentai.net/g/1/#pages=0"),
+            Err(SyncError::Pages(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_dispatch() {
+        let sync = synchronizer();
+        for (key, url) in [
+            ("e-hentai|/g/1/abc", "https://e-hentai.org/g/1/abc/"),
+            ("nhentai|/g/2", "https://nhentai.to/g/2/"),
+            (
+                "hitomi|/doujinshi/t-3.html",
+                "https://hitomi.la/doujinshi/t-3.html",
+            ),
+            (
+                "nhentai|/g/4#pages=1-3",
+                "https://nhentai.net/g/4/#pages=1-3",
+            ),
+        ] {
+            sync.cache()
+                .set(key.to_string(), format!("https://telegra.ph/{key}"), None)
+                .await
+                .unwrap();
+            let gallery = parse(url).unwrap();
+            let expected = vec![format!("https://telegra.ph/{key}")];
+            assert_eq!(
+                sync.cached_gallery(&gallery).await.unwrap(),
+                Some(expected.clone()),
+                "{url}"
+            );
+            // a cache hit without touching the site
+            let links = sync
+                .sync_gallery(&gallery, Default::default())
+                .await
+                .unwrap();
+            assert_eq!(links, expected, "{url}");
+        }
+
+        // exhentai shares the cache of e-hentai
+        let gallery = parse("https://exhentai.org/g/1/abc").unwrap();
+        assert!(sync.cached_gallery(&gallery).await.unwrap().is_some());
+        let gallery = parse("https://nhentai.net/g/4/").unwrap();
+        assert!(sync.cached_gallery(&gallery).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_sync_url_errors() {
+        let opts = || SyncOptions::new(vec!["token".to_string()]);
+        assert!(matches!(
+            sync_url("https://example.com/g/1", opts()).await,
+            Err(SyncError::UnsupportedUrl(_))
+        ));
+        assert!(matches!(
+            sync_url("https://nhentai.net/g/1", SyncOptions::new(vec![])).await,
+            Err(SyncError::NoToken)
+        ));
+        assert!(matches!(
+            dry_run_url("https://example.com/g/1", SyncOptions::new(vec![])).await,
+            Err(SyncError::UnsupportedUrl(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_sync_urls() {
+        // every request goes through the proxy, the removed gallery is a 404
+        let server = MockServer::start(|_, _| MockResponse::new(404, "")).await;
+        let storage = SimpleMemStorage::default();
+        storage
+            .set(
+                "nhentai|/g/1".to_string(),
+                "https://telegra.ph/synced".to_string(),
+                None,
+            )
+            .await
+            .unwrap();
+        let opts = SyncOptions::new(vec!["token".to_string()])
+            .with_proxy(ProxiedClient::new(&server.url("/"), "t").unwrap())
+            .with_storage(storage);
+        let report = sync_urls(
+            [
+                "https://nhentai.net/g/1/",
+                "https://nhentai.net/g/2/",
+                "https://example.com/g/3",
+            ],
+            opts,
+        )
+        .await
+        .unwrap();
+
+        let succeeded: Vec<_> = report.succeeded().collect();
+        assert_eq!(succeeded.len(), 1);
+        assert_eq!(succeeded[0].0, "https://nhentai.net/g/1/");
+        assert_eq!(succeeded[0].1[0].as_str(), "https://telegra.ph/synced");
+        let failed: Vec<_> = report.failed().collect();
+        assert!(matches!(
+            failed[0],
+            ("https://nhentai.net/g/2/", SyncError::NotFound)
+        ));
+        assert!(matches!(failed[1].1, SyncError::UnsupportedUrl(_)));
+        // removed galleries are not retried
+        assert_eq!(server.requests().len(), 1);
+        let summary = report.to_string();
+        assert!(summary.starts_with("1 synced, 2 failed\nhttps://nhentai.net/g/2/: "));
+        assert_eq!(summary.lines().count(), 3);
+
+        assert!(matches!(
+            sync_urls(["https://nhentai.net/g/1/"], SyncOptions::new(vec![])).await,
+            Err(SyncError::NoToken)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_sync_url_dry_run() {
+        let server = MockServer::start(|idx, req| {
+            match req.header("x-forwarded-for").unwrap_or_default() {
+                "https://nhentai.net/api/gallery/1" => MockResponse::new(
+                    200,
+                    r#"{"media_id": "9", "title": {"pretty": "t"}, "images": {"pages": [{"t": "j"}]}}"#,
+                ),
+                _ => MockResponse::new(200, format!("\u{FF}\u{D8}\u{FF}image-{idx}")),
+            }
+        })
+        .await;
+        let opts = || {
+            SyncOptions::new(vec![])
+                .with_proxy(ProxiedClient::new(&server.url("/"), "t").unwrap())
+                .with_dry_run(true)
+        };
+        let links = sync_url("https://nhentai.net/g/1/", opts()).await.unwrap();
+        assert!(links.is_empty());
+        let report = sync_urls(["https://nhentai.net/g/1/"], opts())
+            .await
+            .unwrap();
+        assert!(report.succeeded().all(|(_, links)| links.is_empty()));
+        assert_eq!(report.failed().count(), 0);
+
+        // the gallery and its image only, nothing is sent to telegraph
+        let targets = server
+            .requests()
+            .iter()
+            .map(|r| r.header("x-forwarded-for").unwrap_or_default().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(targets.len(), 4, "{targets:?}");
+        assert!(
+            targets.iter().all(|t| t.contains("nhentai.net")),
+            "{targets:?}"
+        );
+    }
+
+    /// Needs a telegraph access token in `TELEGRAPH_TOKEN`.
+    #[cfg_attr(not(feature = "network-tests"), ignore)]
+    #[tokio::test]
+    async fn test_sync_url() {
+        let token = std::env::var("TELEGRAPH_TOKEN").expect("TELEGRAPH_TOKEN is not set");
+        let links = sync_url(
+            "https://nhentai.net/g/177013/#pages=1-2",
+            SyncOptions::new(vec![token]),
+        )
+        .await
+        .unwrap();
+        assert_eq!(links.len(), 1);
+        assert_eq!(links[0].host_str(), Some("telegra.ph"));
+    }
+}
@@ -57,12 +57,15 @@ const CONFIG_KEY: &str = "http";
 const TIMTOUT: Duration = Duration::from_secs(30);
 
 use std::{
+    future::Future,
     net::{IpAddr, Ipv6Addr, SocketAddr},
     ops::{Deref, DerefMut},
     sync::Arc,
     time::Duration,
 };
 
+use bytes::Bytes;
+use futures::future::BoxFuture;
 use ipnet::Ipv6Net;
 use reqwest::header;
 use rustls::ClientConfig;
@@ -81,6 +84,28 @@ pub fn rand_ua() -> &'static str {
 pub trait HttpRequestBuilder {
     fn get_builder(&self, url: &str) -> reqwest::RequestBuilder;
     fn post_builder(&self, url: &str) -> reqwest::RequestBuilder;
+
+    /// GET with extra headers like `Referer` for the target.
+    fn get_builder_with_headers(
+        &self,
+        url: &str,
+        headers: reqwest::header::HeaderMap,
+    ) -> reqwest::RequestBuilder {
+        self.get_builder(url).headers(headers)
+    }
+
+    /// Send a request built by this client.
+    fn send_request(
+        &self,
+        req: reqwest::RequestBuilder,
+    ) -> impl Future<Output = reqwest::Result<reqwest::Response>> + Send {
+        req.send()
+    }
+
+    /// Retries shared by the requests of this client, unlimited if None.
+    fn retry_budget(&self) -> Option<&crate::http_proxy::RetryBudget> {
+        None
+    }
 }
 
 macro_rules! gen_impl {
@@ -101,9 +126,158 @@ macro_rules! gen_impl {
 }
 
 gen_impl!(reqwest::Client);
-gen_impl!(crate::http_proxy::ProxiedClient);
 gen_impl!(GhostClient);
 
+// the user agent pool of ProxiedClient takes precedence
+impl HttpRequestBuilder for crate::http_proxy::ProxiedClient {
+    #[inline]
+    fn get_builder(&self, url: &str) -> reqwest::RequestBuilder {
+        self.with_fallback_user_agent(self.get(url), rand_ua)
+    }
+
+    #[inline]
+    fn post_builder(&self, url: &str) -> reqwest::RequestBuilder {
+        self.with_fallback_user_agent(self.post(url), rand_ua)
+    }
+
+    // the proxy headers are kept
+    #[inline]
+    fn get_builder_with_headers(
+        &self,
+        url: &str,
+        headers: reqwest::header::HeaderMap,
+    ) -> reqwest::RequestBuilder {
+        self.with_fallback_user_agent(self.get_with_headers(url, headers), rand_ua)
+    }
+
+    // wait for the concurrency limit if set
+    #[inline]
+    fn send_request(
+        &self,
+        req: reqwest::RequestBuilder,
+    ) -> impl Future<Output = reqwest::Result<reqwest::Response>> + Send {
+        self.acquire_and_send(req)
+    }
+
+    #[inline]
+    fn retry_budget(&self) -> Option<&crate::http_proxy::RetryBudget> {
+        crate::http_proxy::ProxiedClient::retry_budget(self)
+    }
+}
+
+/// A buffered response returned by [`HttpFetcher`].
+#[derive(Debug, Clone)]
+pub struct FetchResponse {
+    pub status: reqwest::StatusCode,
+    pub headers: header::HeaderMap,
+    pub body: Bytes,
+}
+
+impl FetchResponse {
+    pub fn new(status: u16, body: impl Into<Bytes>) -> Self {
+        Self {
+            status: reqwest::StatusCode::from_u16(status).expect("invalid status code"),
+            headers: header::HeaderMap::new(),
+            body: body.into(),
+        }
+    }
+
+    pub fn text(&self) -> anyhow::Result<&str> {
+        Ok(std::str::from_utf8(&self.body)?)
+    }
+
+    pub fn json<T: serde::de::DeserializeOwned>(&self) -> anyhow::Result<T> {
+        Ok(serde_json::from_slice(&self.body)?)
+    }
+
+    pub fn error_for_status(self) -> anyhow::Result<Self> {
+        if self.status.is_client_error() || self.status.is_server_error() {
+            anyhow::bail!("unexpected status {}", self.status);
+        }
+        Ok(self)
+    }
+}
+
+/// Minimal http interface so code fetching over the network can be tested
+/// without a real client. See `testing::MockFetcher`.
+/// The futures are boxed, so it can be used as `Arc<dyn HttpFetcher>`.
+pub trait HttpFetcher: Send + Sync {
+    fn request<'a>(
+        &'a self,
+        method: reqwest::Method,
+        url: &'a str,
+        body: Option<Bytes>,
+    ) -> BoxFuture<'a, anyhow::Result<FetchResponse>>;
+
+    fn get<'a>(&'a self, url: &'a str) -> BoxFuture<'a, anyhow::Result<FetchResponse>> {
+        self.request(reqwest::Method::GET, url, None)
+    }
+
+    fn post<'a>(
+        &'a self,
+        url: &'a str,
+        body: Bytes,
+    ) -> BoxFuture<'a, anyhow::Result<FetchResponse>> {
+        self.request(reqwest::Method::POST, url, Some(body))
+    }
+}
+
+async fn buffer_response(resp: reqwest::Response) -> anyhow::Result<FetchResponse> {
+    let status = resp.status();
+    let headers = resp.headers().clone();
+    let body = resp.bytes().await?;
+    Ok(FetchResponse {
+        status,
+        headers,
+        body,
+    })
+}
+
+impl HttpFetcher for reqwest::Client {
+    fn request<'a>(
+        &'a self,
+        method: reqwest::Method,
+        url: &'a str,
+        body: Option<Bytes>,
+    ) -> BoxFuture<'a, anyhow::Result<FetchResponse>> {
+        let mut req = reqwest::Client::request(self, method, url)
+            .header(reqwest::header::USER_AGENT, rand_ua());
+        if let Some(body) = body {
+            req = req.body(body);
+        }
+        Box::pin(async move { buffer_response(req.send().await?).await })
+    }
+}
+
+impl HttpFetcher for crate::http_proxy::ProxiedClient {
+    fn request<'a>(
+        &'a self,
+        method: reqwest::Method,
+        url: &'a str,
+        body: Option<Bytes>,
+    ) -> BoxFuture<'a, anyhow::Result<FetchResponse>> {
+        let mut req = self.with_fallback_user_agent(
+            crate::http_proxy::ProxiedClient::request(self, method, url),
+            rand_ua,
+        );
+        if let Some(body) = body {
+            req = req.body(body);
+        }
+        Box::pin(async move { buffer_response(self.acquire_and_send(req).await?).await })
+    }
+}
+
+impl<T: HttpFetcher + ?Sized> HttpFetcher for Arc<T> {
+    fn request<'a>(
+        &'a self,
+        method: reqwest::Method,
+        url: &'a str,
+        body: Option<Bytes>,
+    ) -> BoxFuture<'a, anyhow::Result<FetchResponse>> {
+        (**self).request(method, url, body)
+    }
+}
+
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, derive_more::From, derive_more::Into)]
 pub struct Ipv6Net2(Ipv6Net);
 
@@ -120,6 +294,13 @@ impl<'de> serde::Deserialize<'de> for Ipv6Net2 {
     }
 }
 
+/// Turn off the decompression of response bodies. The reqwest features enabling it
+/// are shared by the whole build, so the clients not opting in with
+/// `ProxiedClient::with_compression` keep getting the bodies as they are sent.
+pub fn without_decompression(builder: reqwest::ClientBuilder) -> reqwest::ClientBuilder {
+    builder.no_gzip().no_brotli().no_deflate()
+}
+
 #[derive(serde::Deserialize, Clone, Debug, Default)]
 struct HTTPConfig {
     ipv6_prefix: Option<Ipv6Net2>,
@@ -127,7 +308,7 @@ struct HTTPConfig {
 
 #[derive(Debug, Default)]
 pub struct GhostClientBuilder {
-    mapping: Vec<(&'static str, SocketAddr)>,
+    mapping: Vec<(String, SocketAddr)>,
     headers: Option<header::HeaderMap>,
 }
 
@@ -139,10 +320,10 @@ impl GhostClientBuilder {
         }
     }
 
-    pub fn with_cf_resolve(mut self, domains: &[&'static str]) -> Self {
+    pub fn with_cf_resolve<'a>(mut self, domains: impl IntoIterator<Item = &'a str>) -> Self {
         let cf = SocketAddr::new(IpAddr::V6(CF_ADDR), 443);
-        for &domain in domains.iter() {
-            self.mapping.push((domain, cf));
+        for domain in domains {
+            self.mapping.push((domain.to_string(), cf));
         }
         self
     }
@@ -150,8 +331,8 @@ impl GhostClientBuilder {
     #[deprecated = "telegra.ph has fixed it and returns 501 when using ipv6"]
     pub fn with_tg_resolve(mut self) -> Self {
         let tg = SocketAddr::new(IpAddr::V6(TG_ADDR), 443);
-        self.mapping.push(("telegra.ph", tg));
-        self.mapping.push(("api.telegra.ph", tg));
+        self.mapping.push(("telegra.ph".to_string(), tg));
+        self.mapping.push(("api.telegra.ph".to_string(), tg));
         self
     }
 
@@ -175,7 +356,7 @@ impl GhostClientBuilder {
 #[derive(Debug, Default)]
 pub struct GhostClient {
     prefix: Option<Ipv6Net>,
-    mapping: Arc<Vec<(&'static str, SocketAddr)>>,
+    mapping: Arc<Vec<(String, SocketAddr)>>,
     headers: Option<header::HeaderMap>,
 
     inner: reqwest::Client,
@@ -216,10 +397,10 @@ impl DerefMut for GhostClient {
 impl GhostClient {
     fn build_raw(
         net: &Option<Ipv6Net>,
-        mapping: &[(&'static str, SocketAddr)],
+        mapping: &[(String, SocketAddr)],
         headers: Option<header::HeaderMap>,
     ) -> reqwest::Client {
-        let mut builder = reqwest::Client::builder().timeout(TIMTOUT);
+        let mut builder = without_decompression(reqwest::Client::builder()).timeout(TIMTOUT);
 
         if let Some(headers) = headers {
             builder = builder.default_headers(headers);
@@ -262,7 +443,52 @@ lazy_static::lazy_static! {
 
 #[cfg(test)]
 mod tests {
-    use super::TLS_CFG;
+    use super::{GhostClient, HttpFetcher, TLS_CFG};
+    use crate::{
+        http_proxy::ProxiedClient,
+        mock_server::{MockResponse, MockServer},
+    };
+
+    #[tokio::test]
+    async fn test_no_decompression() {
+        use std::io::Write;
+
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), Default::default());
+        encoder.write_all(b"hello").unwrap();
+        let body = encoder.finish().unwrap();
+        let raw = body.clone();
+        let server = MockServer::start(move |_, _| {
+            MockResponse::new(200, body.clone()).header("Content-Encoding", "gzip")
+        })
+        .await;
+
+        let client = GhostClient::builder().build(None);
+        let resp = client.get(server.url("/")).send().await.unwrap();
+        assert_eq!(resp.bytes().await.unwrap(), raw);
+        assert_eq!(server.requests()[0].header("accept-encoding"), None);
+    }
+
+    #[tokio::test]
+    async fn test_proxied_client_fetcher() {
+        let server = MockServer::start(|_, req| {
+            assert_eq!(req.header("x-forwarded-for"), Some("https://example.com/a"));
+            MockResponse::new(201, req.body.clone()).header("x-test", "1")
+        })
+        .await;
+        let client = ProxiedClient::new(&server.url("/"), "token").unwrap();
+        // inherent methods of ProxiedClient shadow the trait ones
+        let resp = HttpFetcher::post(
+            &client,
+            "https://example.com/a",
+            bytes::Bytes::from_static(b"hello"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.status, 201);
+        assert_eq!(resp.headers["x-test"], "1");
+        assert_eq!(resp.text().unwrap(), "hello");
+        assert!(server.requests()[0].header("user-agent").is_some());
+    }
 
     #[ignore]
     #[tokio::test]
@@ -0,0 +1,486 @@
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize},
+        Arc,
+    },
+    time::Duration,
+};
+
+use arc_swap::ArcSwap;
+use reqwest::{
+    cookie::Jar,
+    header::{HeaderMap, HeaderValue, ACCEPT_ENCODING},
+};
+
+use super::{
+    circuit::CircuitBreaker,
+    middleware::{Middlewares, RequestMiddleware},
+    rate_limit::RateLimiter,
+    ProxiedClient, Proxy, ProxyError, RetryBudget, DOWNLOAD_TIMEOUT, MAX_REDIRECTS, TIMEOUT,
+};
+
+/// Settings of the inner reqwest client.
+/// We keep them so the client can be rebuilt without losing any of them.
+#[derive(Debug, Clone)]
+pub(crate) struct ClientConfig {
+    pub(crate) timeout: Duration,
+    pub(crate) connect_timeout: Option<Duration>,
+    // applied by download_to_writer, which replaces `timeout` with them
+    pub(crate) read_timeout: Duration,
+    pub(crate) download_timeout: Duration,
+    pub(crate) headers: HeaderMap,
+    pub(crate) socks5: Option<reqwest::Proxy>,
+    // shared with clones and rebuilt clients
+    pub(crate) cookie_store: Option<Arc<Jar>>,
+    pub(crate) max_redirects: usize,
+    pub(crate) pool_idle_timeout: Option<Duration>,
+    pub(crate) pool_max_idle_per_host: Option<usize>,
+    pub(crate) http2_prior_knowledge: bool,
+    pub(crate) identity: Option<reqwest::Identity>,
+    pub(crate) root_certs: Vec<reqwest::Certificate>,
+    pub(crate) built_in_roots: bool,
+    pub(crate) accept_invalid_certs: bool,
+    pub(crate) resolve: Vec<(String, SocketAddr)>,
+    // (gzip, brotli, deflate)
+    pub(crate) compression: (bool, bool, bool),
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            timeout: TIMEOUT,
+            connect_timeout: None,
+            read_timeout: TIMEOUT,
+            download_timeout: DOWNLOAD_TIMEOUT,
+            headers: HeaderMap::new(),
+            socks5: None,
+            cookie_store: None,
+            max_redirects: MAX_REDIRECTS,
+            pool_idle_timeout: None,
+            pool_max_idle_per_host: None,
+            http2_prior_knowledge: false,
+            identity: None,
+            root_certs: Vec::new(),
+            built_in_roots: true,
+            accept_invalid_certs: false,
+            resolve: Vec::new(),
+            compression: (false, false, false),
+        }
+    }
+}
+
+impl ClientConfig {
+    pub(crate) fn build(&self) -> reqwest::Result<reqwest::Client> {
+        let redirect = match self.max_redirects {
+            0 => reqwest::redirect::Policy::none(),
+            n => reqwest::redirect::Policy::limited(n),
+        };
+        let (gzip, brotli, deflate) = self.compression;
+        let mut headers = self.headers.clone();
+        let encodings: Vec<_> = [(gzip, "gzip"), (brotli, "br"), (deflate, "deflate")]
+            .into_iter()
+            .filter_map(|(enabled, name)| enabled.then_some(name))
+            .collect();
+        if !encodings.is_empty() {
+            headers
+                .entry(ACCEPT_ENCODING)
+                .or_insert(HeaderValue::from_str(&encodings.join(", ")).expect("valid header"));
+        }
+        let mut builder = reqwest::Client::builder()
+            .timeout(self.timeout)
+            .redirect(redirect)
+            .gzip(gzip)
+            .brotli(brotli)
+            .deflate(deflate)
+            .default_headers(headers);
+        if let Some(socks5) = &self.socks5 {
+            builder = builder.proxy(socks5.clone());
+        }
+        if let Some(connect_timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(connect_timeout);
+        }
+        if let Some(jar) = &self.cookie_store {
+            builder = builder.cookie_provider(jar.clone());
+        }
+        if let Some(timeout) = self.pool_idle_timeout {
+            builder = builder.pool_idle_timeout(timeout);
+        }
+        if let Some(max) = self.pool_max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(max);
+        }
+        if self.http2_prior_knowledge {
+            builder = builder.http2_prior_knowledge();
+        }
+        if let Some(identity) = &self.identity {
+            builder = builder.identity(identity.clone());
+        }
+        for cert in self.root_certs.iter() {
+            builder = builder.add_root_certificate(cert.clone());
+        }
+        for (host, addr) in self.resolve.iter() {
+            builder = builder.resolve(host, *addr);
+        }
+        builder = builder
+            .tls_built_in_root_certs(self.built_in_roots)
+            .danger_accept_invalid_certs(self.accept_invalid_certs);
+        builder.build()
+    }
+}
+
+/// Accumulate all options and build the reqwest client exactly once.
+#[derive(Debug, Default)]
+pub struct ProxiedClientBuilder {
+    proxies: Vec<Proxy>,
+    config: ClientConfig,
+    health_check: Option<(Duration, usize)>,
+    user_agents: Vec<HeaderValue>,
+    no_proxy: Vec<String>,
+    middlewares: Middlewares,
+    max_concurrent: Option<usize>,
+    rate_limit: u64,
+    circuit_breaker: Option<(usize, Duration, Duration)>,
+    retry_budget: Option<(u32, f64)>,
+}
+
+impl ProxiedClientBuilder {
+    /// Add a forwarding proxy. Multiple proxies will be used in turn.
+    pub fn with_proxy(mut self, proxy: Proxy) -> Self {
+        self.proxies.push(proxy);
+        self
+    }
+
+    pub fn with_proxies<I: IntoIterator<Item = Proxy>>(mut self, proxies: I) -> Self {
+        self.proxies.extend(proxies);
+        self
+    }
+
+    /// Tunnel all requests through a SOCKS5 server.
+    pub fn with_socks5(mut self, proxy: reqwest::Proxy) -> Self {
+        self.config.socks5 = Some(proxy);
+        self
+    }
+
+    /// Merge headers into default headers, existing headers with the same name are replaced.
+    pub fn with_default_headers(mut self, headers: HeaderMap) -> Self {
+        self.config.headers.extend(headers);
+        self
+    }
+
+    /// Set the overall request timeout, including reading the body.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.config.timeout = timeout;
+        self
+    }
+
+    /// Set the timeout for establishing the TCP(and TLS) connection only.
+    pub fn with_connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.config.connect_timeout = Some(connect_timeout);
+        self
+    }
+
+    /// See `ProxiedClient::with_read_timeout`.
+    pub fn with_read_timeout(mut self, timeout: Duration) -> Self {
+        self.config.read_timeout = timeout;
+        self
+    }
+
+    /// See `ProxiedClient::with_download_timeout`.
+    pub fn with_download_timeout(mut self, timeout: Duration) -> Self {
+        self.config.download_timeout = timeout;
+        self
+    }
+
+    /// See `ProxiedClient::with_cookie_store`.
+    pub fn with_cookie_store(mut self, enabled: bool) -> Self {
+        if !enabled {
+            self.config.cookie_store = None;
+        } else if self.config.cookie_store.is_none() {
+            self.config.cookie_store = Some(Default::default());
+        }
+        self
+    }
+
+    /// See `ProxiedClient::with_cookies`.
+    pub fn with_cookies<'a, I>(mut self, url: &str, cookies: I) -> Result<Self, ProxyError>
+    where
+        I: IntoIterator<Item = (&'a String, &'a String)>,
+    {
+        let jar = self
+            .config
+            .cookie_store
+            .get_or_insert_with(Default::default);
+        add_cookies(jar, url, cookies)?;
+        Ok(self)
+    }
+
+    /// See `ProxiedClient::with_redirect_policy`.
+    pub fn with_redirect_policy(mut self, max: usize) -> Self {
+        self.config.max_redirects = max;
+        self
+    }
+
+    /// See `ProxiedClient::with_pool_idle_timeout`.
+    pub fn with_pool_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.config.pool_idle_timeout = Some(timeout);
+        self
+    }
+
+    /// See `ProxiedClient::with_pool_max_idle_per_host`.
+    pub fn with_pool_max_idle_per_host(mut self, max: usize) -> Self {
+        self.config.pool_max_idle_per_host = Some(max);
+        self
+    }
+
+    /// See `ProxiedClient::with_http2_prior_knowledge`.
+    pub fn with_http2_prior_knowledge(mut self, enabled: bool) -> Self {
+        self.config.http2_prior_knowledge = enabled;
+        self
+    }
+
+    /// See `ProxiedClient::with_client_identity`.
+    pub fn with_client_identity(
+        mut self,
+        pkcs12_der: &[u8],
+        password: &str,
+    ) -> Result<Self, ProxyError> {
+        self.config.identity = Some(pkcs12_identity(pkcs12_der, password)?);
+        Ok(self)
+    }
+
+    /// See `ProxiedClient::with_root_certificate`.
+    pub fn with_root_certificate(mut self, pem: &[u8]) -> Result<Self, ProxyError> {
+        self.config.root_certs.extend(certificates(pem)?);
+        Ok(self)
+    }
+
+    /// See `ProxiedClient::with_pinned_certificates`.
+    pub fn with_pinned_certificates(mut self, pems: &[&[u8]]) -> Result<Self, ProxyError> {
+        self.config.root_certs = pinned_certificates(pems)?;
+        self.config.built_in_roots = false;
+        Ok(self)
+    }
+
+    /// See `ProxiedClient::danger_accept_invalid_certs`.
+    pub fn danger_accept_invalid_certs(mut self, accept: bool) -> Self {
+        self.config.accept_invalid_certs = accept;
+        self
+    }
+
+    /// See `ProxiedClient::with_resolve`.
+    pub fn with_resolve(mut self, host: &str, addr: SocketAddr) -> Self {
+        self.config.resolve.push((host.to_string(), addr));
+        self
+    }
+
+    /// See `ProxiedClient::with_compression`.
+    pub fn with_compression(mut self, gzip: bool, brotli: bool, deflate: bool) -> Self {
+        self.config.compression = (gzip, brotli, deflate);
+        self
+    }
+
+    /// See `ProxiedClient::with_health_check`.
+    pub fn with_health_check(mut self, interval: Duration, failure_threshold: usize) -> Self {
+        self.health_check = Some((interval, failure_threshold));
+        self
+    }
+
+    /// See `ProxiedClient::with_user_agent_pool`.
+    pub fn with_user_agent_pool(mut self, agents: Vec<String>) -> Self {
+        self.user_agents = user_agent_pool(agents);
+        self
+    }
+
+    /// See `ProxiedClient::with_no_proxy`.
+    pub fn with_no_proxy(mut self, hosts: Vec<String>) -> Self {
+        self.no_proxy = no_proxy_patterns(hosts);
+        self
+    }
+
+    /// See `ProxiedClient::with_middleware`.
+    pub fn with_middleware(mut self, middleware: impl RequestMiddleware + 'static) -> Self {
+        self.middlewares.push(Arc::new(middleware));
+        self
+    }
+
+    /// See `ProxiedClient::with_max_concurrent`.
+    pub fn with_max_concurrent(mut self, max_concurrent: usize) -> Self {
+        self.max_concurrent = Some(max_concurrent.max(1));
+        self
+    }
+
+    /// See `ProxiedClient::with_rate_limit`.
+    pub fn with_rate_limit(mut self, bytes_per_sec: u64) -> Self {
+        self.rate_limit = bytes_per_sec;
+        self
+    }
+
+    /// See `ProxiedClient::with_circuit_breaker`.
+    pub fn with_circuit_breaker(
+        mut self,
+        failure_threshold: usize,
+        window: Duration,
+        cooldown: Duration,
+    ) -> Self {
+        self.circuit_breaker = Some((failure_threshold, window, cooldown));
+        self
+    }
+
+    /// See `ProxiedClient::with_retry_budget`.
+    pub fn with_retry_budget(mut self, size: u32, refill_per_sec: f64) -> Self {
+        self.retry_budget = Some((size, refill_per_sec));
+        self
+    }
+
+    /// Build the client.
+    /// Note: If health check is set, this must be called inside a tokio runtime.
+    pub fn build(self) -> Result<ProxiedClient, ProxyError> {
+        let client = ProxiedClient {
+            inner: self.config.build()?,
+            proxies: Arc::new(ArcSwap::from_pointee(self.proxies)),
+            next_proxy: Arc::new(AtomicUsize::new(0)),
+            healthy: Arc::new(AtomicBool::new(true)),
+            user_agents: Arc::new(self.user_agents),
+            no_proxy: Arc::new(self.no_proxy),
+            middlewares: self.middlewares,
+            metrics: Default::default(),
+            limiter: self
+                .max_concurrent
+                .map(|n| Arc::new(tokio::sync::Semaphore::new(n))),
+            rate_limit: rate_limiter(self.rate_limit),
+            circuit: self
+                .circuit_breaker
+                .map(|(n, window, cooldown)| Arc::new(CircuitBreaker::new(n, window, cooldown))),
+            retry_budget: self
+                .retry_budget
+                .map(|(size, refill)| Arc::new(RetryBudget::new(size, refill))),
+            config: self.config,
+        };
+        Ok(match self.health_check {
+            Some((interval, failure_threshold)) => {
+                client.with_health_check(interval, failure_threshold)
+            }
+            None => client,
+        })
+    }
+}
+
+/// Parse all certificates in a PEM bundle.
+pub(crate) fn certificates(pem: &[u8]) -> Result<Vec<reqwest::Certificate>, ProxyError> {
+    let certs = reqwest::Certificate::from_pem_bundle(pem)
+        .map_err(|e| ProxyError::Certificate(e.to_string()))?;
+    if certs.is_empty() {
+        return Err(ProxyError::Certificate("no certificate found".to_string()));
+    }
+    Ok(certs)
+}
+
+pub(crate) fn pinned_certificates(pems: &[&[u8]]) -> Result<Vec<reqwest::Certificate>, ProxyError> {
+    if pems.is_empty() {
+        return Err(ProxyError::Certificate("no pinned certificate".to_string()));
+    }
+    let mut certs = Vec::new();
+    for pem in pems {
+        certs.extend(certificates(pem)?);
+    }
+    Ok(certs)
+}
+
+/// Load a PKCS#12 archive as a rustls identity, which only accepts PEM.
+pub(crate) fn pkcs12_identity(der: &[u8], password: &str) -> Result<reqwest::Identity, ProxyError> {
+    use base64::Engine;
+
+    let store = p12_keystore::KeyStore::from_pkcs12(der, password)
+        .map_err(|e| ProxyError::ClientIdentity(e.to_string()))?;
+    let (_, chain) = store
+        .private_key_chain()
+        .ok_or_else(|| ProxyError::ClientIdentity("no private key found".to_string()))?;
+
+    let mut pem = String::new();
+    let mut push = |label: &str, der: &[u8]| {
+        let encoded = base64::engine::general_purpose::STANDARD.encode(der);
+        pem.push_str(&format!("-----BEGIN {label}-----\n"));
+        for line in encoded.as_bytes().chunks(64) {
+            pem.push_str(std::str::from_utf8(line).unwrap_or_default());
+            pem.push('\n');
+        }
+        pem.push_str(&format!("-----END {label}-----\n"));
+    };
+    push("PRIVATE KEY", chain.key());
+    for cert in chain.chain() {
+        push("CERTIFICATE", cert.as_der());
+    }
+    Ok(reqwest::Identity::from_pem(pem.as_bytes())?)
+}
+
+pub(crate) fn add_cookies<'a, I>(jar: &Jar, url: &str, cookies: I) -> Result<(), ProxyError>
+where
+    I: IntoIterator<Item = (&'a String, &'a String)>,
+{
+    let url = url.parse().map_err(ProxyError::CookieUrl)?;
+    for (name, value) in cookies {
+        jar.add_cookie_str(&format!("{name}={value}"), &url);
+    }
+    Ok(())
+}
+
+pub(crate) fn rate_limiter(bytes_per_sec: u64) -> Option<Arc<RateLimiter>> {
+    (bytes_per_sec > 0).then(|| Arc::new(RateLimiter::new(bytes_per_sec)))
+}
+
+pub(crate) fn user_agent_pool(agents: Vec<String>) -> Vec<HeaderValue> {
+    agents
+        .into_iter()
+        .filter_map(|ua| match HeaderValue::try_from(ua) {
+            Ok(v) => Some(v),
+            Err(e) => {
+                tracing::warn!("[proxy] skip invalid user agent: {e}");
+                None
+            }
+        })
+        .collect()
+}
+
+/// Lowercase host patterns, `*.example.com` is taken as `.example.com`.
+pub(crate) fn no_proxy_patterns(hosts: Vec<String>) -> Vec<String> {
+    hosts
+        .into_iter()
+        .map(|h| {
+            let h = h.trim().to_ascii_lowercase();
+            match h.strip_prefix('*') {
+                Some(suffix) if suffix.starts_with('.') => suffix.to_string(),
+                _ => h,
+            }
+        })
+        .filter(|h| !h.is_empty() && h != ".")
+        .collect()
+}
+
+/// Whether `host` is one of the patterns, `.example.com` matches `example.com` and
+/// all its subdomains.
+pub(crate) fn matches_no_proxy(patterns: &[String], host: &str) -> bool {
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    patterns.iter().any(|p| match p.strip_prefix('.') {
+        Some(domain) => host == domain || host.ends_with(p.as_str()),
+        None => host == *p,
+    })
+}
+
+/// Build a SOCKS5 proxy from `host:port` or a full url like `socks5h://host:port`.
+pub(crate) fn socks5_proxy(
+    addr: &str,
+    credentials: Option<(&str, &str)>,
+) -> Result<reqwest::Proxy, ProxyError> {
+    let mut url: reqwest::Url = if addr.contains("://") {
+        addr.parse()?
+    } else {
+        format!("socks5://{addr}").parse()?
+    };
+    if let Some((username, password)) = credentials {
+        // username and password can only be rejected when url has no host.
+        url.set_username(username)
+            .and_then(|_| url.set_password(Some(password)))
+            .map_err(|_| url::ParseError::EmptyHost)?;
+    }
+    Ok(reqwest::Proxy::all(url)?)
+}
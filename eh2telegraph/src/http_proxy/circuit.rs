@@ -0,0 +1,123 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Requests are sent normally.
+    Closed,
+    /// Requests fail fast until the cooldown ends.
+    Open,
+    /// One request is let through to test whether the proxy recovered.
+    HalfOpen,
+}
+
+/// Opens after `failure_threshold` failures within `window`, and lets one probe
+/// request through after `cooldown`. The probe closes it on success or opens it again.
+#[derive(Debug)]
+pub(crate) struct CircuitBreaker {
+    failure_threshold: usize,
+    window: Duration,
+    cooldown: Duration,
+    state: Mutex<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    state: CircuitState,
+    failures: VecDeque<Instant>,
+    opened_at: Instant,
+    probing: bool,
+}
+
+impl CircuitBreaker {
+    pub(crate) fn new(failure_threshold: usize, window: Duration, cooldown: Duration) -> Self {
+        Self {
+            failure_threshold: failure_threshold.max(1),
+            window,
+            cooldown,
+            state: Mutex::new(Inner {
+                state: CircuitState::Closed,
+                failures: VecDeque::new(),
+                opened_at: Instant::now(),
+                probing: false,
+            }),
+        }
+    }
+
+    pub(crate) fn state(&self) -> CircuitState {
+        let mut inner = self.state.lock();
+        self.refresh(&mut inner);
+        inner.state
+    }
+
+    /// Whether a request may be sent now.
+    pub(crate) fn try_acquire(&self) -> bool {
+        let mut inner = self.state.lock();
+        self.refresh(&mut inner);
+        match inner.state {
+            CircuitState::Closed => true,
+            CircuitState::Open => false,
+            CircuitState::HalfOpen if inner.probing => false,
+            CircuitState::HalfOpen => {
+                inner.probing = true;
+                inner.opened_at = Instant::now();
+                true
+            }
+        }
+    }
+
+    pub(crate) fn record_success(&self) {
+        let mut inner = self.state.lock();
+        if inner.state == CircuitState::HalfOpen {
+            tracing::info!("[proxy] circuit closed");
+        }
+        inner.state = CircuitState::Closed;
+        inner.failures.clear();
+        inner.probing = false;
+    }
+
+    pub(crate) fn record_failure(&self) {
+        let mut inner = self.state.lock();
+        let now = Instant::now();
+        match inner.state {
+            CircuitState::HalfOpen => self.open(&mut inner, now),
+            CircuitState::Open => (),
+            CircuitState::Closed => {
+                inner.failures.push_back(now);
+                while let Some(first) = inner.failures.front() {
+                    if now.duration_since(*first) <= self.window {
+                        break;
+                    }
+                    inner.failures.pop_front();
+                }
+                if inner.failures.len() >= self.failure_threshold {
+                    self.open(&mut inner, now);
+                }
+            }
+        }
+    }
+
+    fn open(&self, inner: &mut Inner, now: Instant) {
+        tracing::warn!("[proxy] circuit opened for {:?}", self.cooldown);
+        inner.state = CircuitState::Open;
+        inner.opened_at = now;
+        inner.failures.clear();
+        inner.probing = false;
+    }
+
+    fn refresh(&self, inner: &mut Inner) {
+        if inner.opened_at.elapsed() < self.cooldown {
+            return;
+        }
+        match inner.state {
+            CircuitState::Open => inner.state = CircuitState::HalfOpen,
+            // the probe was dropped without reporting, let another one through
+            CircuitState::HalfOpen => inner.probing = false,
+            CircuitState::Closed => (),
+        }
+    }
+}
@@ -0,0 +1,43 @@
+use reqwest::header::{InvalidHeaderName, InvalidHeaderValue};
+
+#[derive(thiserror::Error, Debug)]
+pub enum ProxyError {
+    #[error("invalid proxy endpoint {0}")]
+    Endpoint(#[from] url::ParseError),
+    #[error("proxy endpoint {0} must be an http or https url")]
+    InvalidScheme(String),
+    #[error("no proxy endpoint given")]
+    NoEndpoint,
+    #[error(
+        "proxy config incomplete (endpoint set: {endpoint_set}, authorization set: {auth_set})"
+    )]
+    IncompleteConfig { endpoint_set: bool, auth_set: bool },
+    #[error("invalid proxy authorization {0}")]
+    Authorization(#[from] InvalidHeaderValue),
+    #[error("basic proxy authorization must be like user:pass")]
+    BasicAuth,
+    #[error("invalid resolve address {addr} for {host}")]
+    Resolve { host: String, addr: String },
+    #[error("invalid certificate {0}")]
+    Certificate(String),
+    #[error("invalid client identity {0}")]
+    ClientIdentity(String),
+    #[error("invalid cookie url {0}")]
+    CookieUrl(url::ParseError),
+    #[error("proxy authorization env {0} is not set")]
+    MissingEnv(String),
+    #[error("invalid proxy header name {0}")]
+    HeaderName(#[from] InvalidHeaderName),
+    #[error("unable to parse proxy config {0}")]
+    Config(#[from] serde_yaml::Error),
+    #[error("no data received for {0:?}")]
+    Stalled(std::time::Duration),
+    #[error("proxy circuit is open")]
+    CircuitOpen,
+    #[error("empty range {0:?}")]
+    EmptyRange(std::ops::Range<u64>),
+    #[error("io error {0}")]
+    Io(#[from] std::io::Error),
+    #[error("reqwest error {0}")]
+    Reqwest(#[from] reqwest::Error),
+}
@@ -0,0 +1,134 @@
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Weak,
+    },
+    time::Duration,
+};
+
+use arc_swap::ArcSwap;
+
+use super::Proxy;
+
+/// Probe proxies periodically and mark them unhealthy after `failure_threshold`
+/// consecutive failed rounds. A round succeeds if any endpoint responds without
+/// a connection error or a 5xx status, or if there is no endpoint at all.
+/// Proxies are loaded every round so reloaded ones are probed too.
+/// The task exits when all clients sharing `healthy` are dropped.
+pub(crate) fn spawn_health_check(
+    client: reqwest::Client,
+    proxies: Arc<ArcSwap<Vec<Proxy>>>,
+    healthy: Weak<AtomicBool>,
+    interval: Duration,
+    failure_threshold: usize,
+) {
+    tokio::spawn(async move {
+        let mut failures = 0;
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            let alive = probe(&client, &proxies.load_full()).await;
+            let healthy: Arc<AtomicBool> = match healthy.upgrade() {
+                Some(h) => h,
+                None => return,
+            };
+            if alive {
+                failures = 0;
+                if !healthy.swap(true, Ordering::Relaxed) {
+                    tracing::info!("[proxy] proxy recovered, routing requests through proxy");
+                }
+                continue;
+            }
+            failures += 1;
+            if failures >= failure_threshold && healthy.swap(false, Ordering::Relaxed) {
+                tracing::warn!(
+                    "[proxy] proxy probe failed {failures} times, using direct connection"
+                );
+            }
+        }
+    });
+}
+
+async fn probe(client: &reqwest::Client, proxies: &[Proxy]) -> bool {
+    if proxies.is_empty() {
+        return true;
+    }
+    for p in proxies {
+        match client.head(p.endpoint.clone()).send().await {
+            Ok(resp) if !resp.status().is_server_error() => return true,
+            Ok(resp) => {
+                tracing::debug!("[proxy] probe {} got {}", p.endpoint, resp.status());
+            }
+            Err(e) => {
+                tracing::debug!("[proxy] probe {} failed: {e}", p.endpoint);
+            }
+        }
+    }
+    false
+}
+
+/// How a request is sent, see `ProxiedClient::probe`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProbeRoute {
+    /// Through the forwarding proxy of this endpoint.
+    Proxy(String),
+    /// Through the SOCKS5 proxy of the client.
+    Socks5,
+    Direct,
+}
+
+/// Result of `ProxiedClient::probe`.
+#[derive(Debug, Clone)]
+pub struct ProbeReport {
+    pub url: String,
+    pub route: ProbeRoute,
+    pub status: Option<reqwest::StatusCode>,
+    pub elapsed: Duration,
+    /// With the causes, if no response is received.
+    pub error: Option<String>,
+}
+
+impl ProbeReport {
+    /// A response is received and it is not an error.
+    pub fn is_ok(&self) -> bool {
+        self.status
+            .is_some_and(|s| !s.is_client_error() && !s.is_server_error())
+    }
+}
+
+impl fmt::Display for ProbeReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "GET {} ", self.url)?;
+        match &self.route {
+            ProbeRoute::Proxy(endpoint) => write!(f, "through the proxy {endpoint}")?,
+            ProbeRoute::Socks5 => write!(f, "through the socks5 proxy")?,
+            ProbeRoute::Direct => write!(f, "directly")?,
+        }
+        match (self.status, &self.error) {
+            (Some(status), _) => write!(f, ": {status} in {}ms", self.elapsed.as_millis())?,
+            (None, Some(error)) => {
+                write!(f, ": failed in {}ms, {error}", self.elapsed.as_millis())?
+            }
+            (None, None) => write!(f, ": no response")?,
+        }
+        let rejected = matches!(self.status.map(|s| s.as_u16()), Some(401 | 403 | 407));
+        if rejected && matches!(self.route, ProbeRoute::Proxy(_)) {
+            write!(f, " (is the authorization of the proxy right?)")?;
+        }
+        Ok(())
+    }
+}
+
+/// The error with its causes, reqwest only shows the outermost one.
+pub(crate) fn error_chain(e: &dyn std::error::Error) -> String {
+    let mut message = e.to_string();
+    let mut source = e.source();
+    while let Some(e) = source {
+        message.push_str(": ");
+        message.push_str(&e.to_string());
+        source = e.source();
+    }
+    message
+}
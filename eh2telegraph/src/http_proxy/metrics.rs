@@ -0,0 +1,39 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counters shared by all clones of a client.
+#[derive(Debug, Default)]
+pub(crate) struct Metrics {
+    total_requests: AtomicU64,
+    proxy_errors: AtomicU64,
+    direct_requests: AtomicU64,
+}
+
+/// A snapshot of the client counters.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProxyMetrics {
+    /// Requests sent through `ProxiedClient::send`, including direct ones.
+    pub total_requests: u64,
+    /// Requests via the forwarding proxy which failed without a response.
+    pub proxy_errors: u64,
+    /// Requests sent to the target directly.
+    pub direct_requests: u64,
+}
+
+impl Metrics {
+    pub(crate) fn record(&self, proxied: bool, failed: bool) {
+        self.total_requests.fetch_add(1, Ordering::Relaxed);
+        if !proxied {
+            self.direct_requests.fetch_add(1, Ordering::Relaxed);
+        } else if failed {
+            self.proxy_errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn snapshot(&self) -> ProxyMetrics {
+        ProxyMetrics {
+            total_requests: self.total_requests.load(Ordering::Relaxed),
+            proxy_errors: self.proxy_errors.load(Ordering::Relaxed),
+            direct_requests: self.direct_requests.load(Ordering::Relaxed),
+        }
+    }
+}
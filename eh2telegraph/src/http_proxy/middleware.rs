@@ -0,0 +1,60 @@
+//! Mutations of every request the client builds, like extra headers, signing or
+//! logging, composed in order instead of tweaking the builder at each call site.
+
+use std::{fmt, sync::Arc};
+
+use reqwest::{
+    header::{HeaderName, HeaderValue},
+    RequestBuilder,
+};
+
+/// Applied to requests with the proxy, authorization and user agent headers already
+/// set, so it sees the endpoint rather than the target url of forwarded requests.
+/// It should not replace the proxy headers.
+pub trait RequestMiddleware: Send + Sync {
+    fn apply(&self, req: RequestBuilder) -> RequestBuilder;
+}
+
+impl<F> RequestMiddleware for F
+where
+    F: Fn(RequestBuilder) -> RequestBuilder + Send + Sync,
+{
+    fn apply(&self, req: RequestBuilder) -> RequestBuilder {
+        self(req)
+    }
+}
+
+/// Set a header on every request.
+#[derive(Debug, Clone)]
+pub struct StaticHeader {
+    pub name: HeaderName,
+    pub value: HeaderValue,
+}
+
+impl RequestMiddleware for StaticHeader {
+    fn apply(&self, req: RequestBuilder) -> RequestBuilder {
+        req.header(&self.name, self.value.clone())
+    }
+}
+
+/// The middlewares of a client in the order they are applied, shared with clones.
+#[derive(Clone, Default)]
+pub(crate) struct Middlewares(Arc<Vec<Arc<dyn RequestMiddleware>>>);
+
+impl Middlewares {
+    pub(crate) fn push(&mut self, middleware: Arc<dyn RequestMiddleware>) {
+        let mut all = self.0.as_ref().clone();
+        all.push(middleware);
+        self.0 = Arc::new(all);
+    }
+
+    pub(crate) fn apply(&self, req: RequestBuilder) -> RequestBuilder {
+        self.0.iter().fold(req, |req, m| m.apply(req))
+    }
+}
+
+impl fmt::Debug for Middlewares {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Middlewares({})", self.0.len())
+    }
+}
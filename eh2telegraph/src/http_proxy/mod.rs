@@ -0,0 +1,2906 @@
+pub use builder::ProxiedClientBuilder;
+pub use circuit::CircuitState;
+pub use error::ProxyError;
+pub use health::{ProbeReport, ProbeRoute};
+pub use metrics::ProxyMetrics;
+pub use middleware::{RequestMiddleware, StaticHeader};
+pub use response::ProxiedResponse;
+pub use retry::{RetryBudget, RetryPolicy};
+
+pub(crate) use retry::{retry_after, send_with_retry};
+
+mod builder;
+mod circuit;
+mod error;
+mod health;
+mod metrics;
+mod middleware;
+mod rate_limit;
+mod response;
+mod retry;
+#[cfg(feature = "hot-reload")]
+mod watch;
+
+#[cfg(feature = "hot-reload")]
+pub use watch::spawn_config_watcher;
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use arc_swap::ArcSwap;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tracing::Instrument;
+
+use crate::config;
+
+use self::{
+    builder::{socks5_proxy, ClientConfig},
+    middleware::Middlewares,
+};
+
+const CONFIG_KEY: &str = "proxy";
+const TIMEOUT: Duration = Duration::from_secs(30);
+// a large image may take long, as long as it keeps coming
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(600);
+// same as reqwest
+const MAX_REDIRECTS: usize = 10;
+// HeaderName::from_static requires lowercase
+const FORWARD_HEADER: &str = "x-forwarded-for";
+const AUTH_HEADER: &str = "x-authorization";
+/// Sent with every request, so one can be found in the logs of the proxy too.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+tokio::task_local! {
+    static REQUEST_ID: Arc<str>;
+    static DOWNLOAD_PROGRESS: DownloadProgress;
+}
+
+/// A random request id, a UUID v4.
+pub fn new_request_id() -> String {
+    uuid::Uuid::new_v4().to_string()
+}
+
+/// Run `fut` with its requests carrying `id` instead of generated ones, like the id
+/// of a gallery task so all of its requests share it. Must be a valid header value,
+/// otherwise generated ones are used.
+/// Tasks spawned by `fut` do not inherit it unless wrapped by `propagate_scope`.
+pub async fn with_request_id<F: Future>(id: impl Into<Arc<str>>, fut: F) -> F::Output {
+    REQUEST_ID.scope(id.into(), fut).await
+}
+
+/// Told the size of each chunk of the response bodies received by `util::get_bytes*`
+/// and the `download_*` methods, within `with_download_progress`.
+#[derive(Clone)]
+pub struct DownloadProgress(Arc<dyn Fn(u64) + Send + Sync>);
+
+impl DownloadProgress {
+    pub fn new(f: impl Fn(u64) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(f))
+    }
+
+    /// Run `f` with the downloads of the futures created by it reporting to `self`.
+    pub fn sync_scope<R>(&self, f: impl FnOnce() -> R) -> R {
+        DOWNLOAD_PROGRESS.sync_scope(self.clone(), f)
+    }
+}
+
+impl std::fmt::Debug for DownloadProgress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("DownloadProgress")
+    }
+}
+
+/// Run `fut` with its downloads reporting to `progress`. Like the request id, tasks
+/// spawned by `fut` do not inherit it unless wrapped by `propagate_scope`.
+pub async fn with_download_progress<F: Future>(progress: DownloadProgress, fut: F) -> F::Output {
+    DOWNLOAD_PROGRESS.scope(progress, fut).await
+}
+
+pub(crate) fn report_downloaded(len: u64) {
+    let _ = DOWNLOAD_PROGRESS.try_with(|p| (p.0)(len));
+}
+
+/// Wrap `fut` to be spawned, keeping the request id and the download progress of the
+/// current scope if any.
+pub(crate) fn propagate_scope<F: Future>(fut: F) -> impl Future<Output = F::Output> {
+    let id = REQUEST_ID.try_with(Arc::clone).ok();
+    let progress = DOWNLOAD_PROGRESS.try_with(Clone::clone).ok();
+    async move {
+        let fut = async move {
+            match progress {
+                Some(progress) => DOWNLOAD_PROGRESS.scope(progress, fut).await,
+                None => fut.await,
+            }
+        };
+        match id {
+            Some(id) => REQUEST_ID.scope(id, fut).await,
+            None => fut.await,
+        }
+    }
+}
+
+/// The id of the request, the one already set on it, the one of the scope or a new one.
+fn attach_request_id(request: &mut reqwest::Request) -> String {
+    if let Some(id) = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+    {
+        return id.to_string();
+    }
+    let (id, value) = REQUEST_ID
+        .try_with(|id| Some((id.to_string(), HeaderValue::from_str(id).ok()?)))
+        .ok()
+        .flatten()
+        .unwrap_or_else(|| {
+            let id = new_request_id();
+            let value = HeaderValue::from_str(&id).expect("uuid is a valid header value");
+            (id, value)
+        });
+    request
+        .headers_mut()
+        .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+    id
+}
+
+#[derive(serde::Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum ProxyKindName {
+    #[default]
+    HttpForward,
+    Socks5,
+}
+
+/// How the authorization is sent to the forwarding proxy.
+#[derive(serde::Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuthScheme {
+    /// As is in the auth header, `X-Authorization` by default.
+    #[default]
+    CustomHeader,
+    /// `Authorization: Bearer <authorization>`.
+    Bearer,
+    /// `Authorization: Basic <base64>`, the authorization is `user:pass`.
+    Basic,
+}
+
+#[derive(serde::Deserialize, Clone, Debug, Default)]
+struct ProxyConfig {
+    #[serde(default)]
+    kind: ProxyKindName,
+    /// One endpoint or a list of endpoints which will be used in turn.
+    #[serde(default, deserialize_with = "one_or_many")]
+    endpoint: Vec<String>,
+    /// Literal value, or `${ENV_VAR}` to read it from the environment.
+    #[serde(default)]
+    authorization: String,
+    /// Read the authorization from this environment variable, overrides `authorization`.
+    #[serde(default)]
+    authorization_env: Option<String>,
+    /// Header carrying the target url, `X-Forwarded-For` by default.
+    #[serde(default)]
+    forward_header: Option<String>,
+    /// Header carrying the authorization, `X-Authorization` by default.
+    /// Only used by the `custom-header` scheme.
+    #[serde(default)]
+    auth_header: Option<String>,
+    #[serde(default)]
+    auth_scheme: AuthScheme,
+    /// SOCKS5 server address, like `127.0.0.1:1080` or `socks5h://127.0.0.1:1080`.
+    #[serde(default)]
+    addr: String,
+    #[serde(default)]
+    username: Option<String>,
+    #[serde(default)]
+    password: Option<String>,
+    /// Overall request timeout in seconds, 30 by default.
+    #[serde(default)]
+    timeout: Option<u64>,
+    /// Connect timeout in seconds, no limit by default.
+    #[serde(default)]
+    connect_timeout: Option<u64>,
+    /// Seconds without any data before a download is given up, 30 by default.
+    #[serde(default)]
+    read_timeout: Option<u64>,
+    /// Ceiling of a whole download in seconds, 600 by default.
+    #[serde(default)]
+    download_timeout: Option<u64>,
+    /// PKCS#12 client certificate for mutual TLS with the proxy endpoint.
+    #[serde(default)]
+    client_cert_path: Option<String>,
+    #[serde(default)]
+    client_cert_password: String,
+    /// PEM certificates which become the only accepted roots, for pinning the endpoint.
+    #[serde(default)]
+    root_cert_paths: Vec<String>,
+    /// Fixed addresses for hosts, like `proxy.internal: 10.0.0.2:443`, skipping DNS.
+    #[serde(default)]
+    resolve: HashMap<String, String>,
+    /// Max redirects to follow, 0 means returning 3xx responses as is.
+    #[serde(default = "default_max_redirects")]
+    max_redirects: usize,
+    /// Probe interval in seconds, health check is disabled if not set.
+    #[serde(default)]
+    health_check_interval: Option<u64>,
+    /// Consecutive probe failures before falling back to direct connection.
+    #[serde(default = "default_failure_threshold")]
+    failure_threshold: usize,
+    /// Max requests in flight for `acquire_and_send`, no limit by default.
+    #[serde(default)]
+    max_concurrent: Option<usize>,
+    /// Download bandwidth cap shared by all requests, 0 means unlimited.
+    #[serde(default)]
+    rate_limit_bytes_per_sec: u64,
+    /// Fail fast in `send_with_retry` after sustained failures, disabled if not set.
+    #[serde(default)]
+    circuit_breaker: Option<CircuitBreakerConfig>,
+    /// Retries shared by all requests of `send_with_retry`, unlimited if not set.
+    #[serde(default)]
+    retry_budget: Option<RetryBudgetConfig>,
+    /// Keep the cookies set by responses, enabled if `cookies` is not empty.
+    #[serde(default)]
+    cookie_store: bool,
+    /// Cookies to seed, url to name and value, like `https://exhentai.org: {igneous: xxx}`.
+    #[serde(default)]
+    cookies: HashMap<String, HashMap<String, String>>,
+    /// User-Agent strings picked randomly for every request.
+    #[serde(default)]
+    user_agents: Vec<String>,
+    /// Hosts sent directly instead of through the forwarding proxy, like `telegra.ph`,
+    /// or `.telegra.ph` for it and all its subdomains.
+    #[serde(default)]
+    no_proxy: Vec<String>,
+}
+
+/// Check the proxy config by building a client from it.
+pub(crate) fn validate_config(config: &config::Config, errors: &mut config::ConfigErrors) {
+    if let Some(cfg) = config.section::<ProxyConfig>(CONFIG_KEY, errors) {
+        if let Err(e) = ProxiedClient::from_proxy_config(cfg) {
+            errors.push(CONFIG_KEY, e);
+        }
+    }
+}
+
+const fn default_failure_threshold() -> usize {
+    3
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
+struct CircuitBreakerConfig {
+    /// Failures within the window to open the circuit.
+    #[serde(default = "default_circuit_failure_threshold")]
+    failure_threshold: usize,
+    /// Sliding window in seconds.
+    #[serde(default = "default_circuit_window")]
+    window: u64,
+    /// Seconds before letting a probe request through.
+    #[serde(default = "default_circuit_cooldown")]
+    cooldown: u64,
+}
+
+const fn default_circuit_failure_threshold() -> usize {
+    5
+}
+
+const fn default_circuit_window() -> u64 {
+    60
+}
+
+const fn default_circuit_cooldown() -> u64 {
+    30
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
+struct RetryBudgetConfig {
+    /// Max retries saved up.
+    #[serde(default = "default_retry_budget_size")]
+    size: u32,
+    /// Retries added per second.
+    #[serde(default = "default_retry_budget_refill")]
+    refill_per_sec: f64,
+}
+
+const fn default_retry_budget_size() -> u32 {
+    100
+}
+
+const fn default_retry_budget_refill() -> f64 {
+    1.0
+}
+
+const fn default_max_redirects() -> usize {
+    MAX_REDIRECTS
+}
+
+impl ProxyConfig {
+    /// Forwarding proxies, empty if neither endpoint nor authorization is set.
+    /// The authorization must be resolved already.
+    fn forward_proxies(&self) -> Result<Vec<Proxy>, ProxyError> {
+        if self.endpoint.is_empty() != self.authorization.is_empty() {
+            return Err(ProxyError::IncompleteConfig {
+                endpoint_set: !self.endpoint.is_empty(),
+                auth_set: !self.authorization.is_empty(),
+            });
+        }
+        let forward_header = self.forward_header.as_deref().unwrap_or(FORWARD_HEADER);
+        let auth_header = self.auth_header.as_deref().unwrap_or(AUTH_HEADER);
+        self.endpoint
+            .iter()
+            .map(|endpoint| {
+                Proxy::new(endpoint, &self.authorization)?
+                    .with_header_names(forward_header, auth_header)?
+                    .with_auth_scheme(self.auth_scheme)
+            })
+            .collect()
+    }
+
+    /// Resolve the authorization, reading it from the environment if required.
+    fn authorization(&self) -> Result<String, ProxyError> {
+        let var = match self.authorization_env.as_deref() {
+            Some(var) => var,
+            None => match self
+                .authorization
+                .strip_prefix("${")
+                .and_then(|s| s.strip_suffix('}'))
+            {
+                Some(var) => var,
+                None => return Ok(self.authorization.clone()),
+            },
+        };
+        std::env::var(var).map_err(|_| ProxyError::MissingEnv(var.to_string()))
+    }
+}
+
+fn one_or_many<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    let v = match serde::Deserialize::deserialize(deserializer)? {
+        OneOrMany::One(s) => vec![s],
+        OneOrMany::Many(v) => v,
+    };
+    Ok(v.into_iter().filter(|s| !s.is_empty()).collect())
+}
+
+/// RequestBuilder helps create a Request with proxy.
+/// Note: Users should not replace headers, add them with `request_with_headers`.
+///
+/// A clone is a handle to the same client: the connection pool, proxies, limits and
+/// counters are all behind `Arc`s. Build one at startup and give clones of it to the
+/// collectors, telegraph and the searcher, so the limits and metrics cover them all.
+/// The gallery and image pages of e-hentai and exhentai are not fetched with it, but
+/// with the `GhostClient`s of the collectors, which pick a random address of the
+/// IPv6 prefix and keep the cookies.
+#[derive(Debug, Clone)]
+pub struct ProxiedClient {
+    // swapped by reload_from_config and shared with clones, requests load a snapshot
+    proxies: Arc<ArcSwap<Vec<Proxy>>>,
+    // shared with clones so rotation is global
+    next_proxy: Arc<AtomicUsize>,
+    // updated by the health check task, shared with clones
+    healthy: Arc<AtomicBool>,
+    // picked randomly per request, empty means using the default headers
+    user_agents: Arc<Vec<HeaderValue>>,
+    // hosts bypassing the forwarding proxy
+    no_proxy: Arc<Vec<String>>,
+    // applied last to every request built
+    middlewares: Middlewares,
+    metrics: Arc<metrics::Metrics>,
+    // shared with clones so the limit is global
+    limiter: Option<Arc<tokio::sync::Semaphore>>,
+    // consulted by download_to_writer, shared with clones
+    rate_limit: Option<Arc<rate_limit::RateLimiter>>,
+    // used by send_with_retry, shared with clones
+    circuit: Option<Arc<circuit::CircuitBreaker>>,
+    // used by send_with_retry, shared with clones
+    retry_budget: Option<Arc<RetryBudget>>,
+    config: ClientConfig,
+    inner: reqwest::Client,
+}
+
+#[derive(Debug, Clone)]
+pub struct Proxy {
+    endpoint: reqwest::Url,
+    authorization: HeaderValue,
+    forward_header: HeaderName,
+    auth_header: HeaderName,
+}
+
+impl Proxy {
+    /// The endpoint must be an http or https url. Since nothing is appended to it, a
+    /// warning is logged if it has a path other than `/`.
+    pub fn new(endpoint: &str, authorization: &str) -> Result<Self, ProxyError> {
+        let url: reqwest::Url = match endpoint.parse() {
+            Ok(url) => url,
+            Err(url::ParseError::RelativeUrlWithoutBase) => {
+                return Err(ProxyError::InvalidScheme(endpoint.to_string()))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(ProxyError::InvalidScheme(endpoint.to_string()));
+        }
+        if url.path() != "/" {
+            tracing::warn!("[proxy] endpoint {url} has a path, requests are sent to it as is");
+        }
+        Ok(Self {
+            endpoint: url,
+            authorization: authorization.parse()?,
+            forward_header: HeaderName::from_static(FORWARD_HEADER),
+            auth_header: HeaderName::from_static(AUTH_HEADER),
+        })
+    }
+
+    /// Use custom header names for the target url and the authorization.
+    pub fn with_header_names(
+        mut self,
+        forward_header: &str,
+        auth_header: &str,
+    ) -> Result<Self, ProxyError> {
+        self.forward_header = forward_header.parse()?;
+        self.auth_header = auth_header.parse()?;
+        Ok(self)
+    }
+
+    /// Send the authorization in the standard `Authorization` header with the scheme,
+    /// the custom auth header is kept by `AuthScheme::CustomHeader`.
+    pub fn with_auth_scheme(mut self, scheme: AuthScheme) -> Result<Self, ProxyError> {
+        use base64::Engine;
+        let token = self.authorization.to_str().unwrap_or_default();
+        let value = match scheme {
+            AuthScheme::CustomHeader => return Ok(self),
+            AuthScheme::Bearer => format!("Bearer {token}"),
+            AuthScheme::Basic => {
+                if !token.contains(':') {
+                    return Err(ProxyError::BasicAuth);
+                }
+                let encoded = base64::engine::general_purpose::STANDARD.encode(token);
+                format!("Basic {encoded}")
+            }
+        };
+        self.authorization = value.parse()?;
+        self.auth_header = reqwest::header::AUTHORIZATION;
+        Ok(self)
+    }
+}
+
+/// How requests reach the target.
+/// `HttpForward` rewrites every request to the endpoint and carries the real url in
+/// headers, while `Socks5` tunnels requests to the real url through a SOCKS5 server.
+#[derive(Debug, Clone)]
+pub enum ProxyKind {
+    HttpForward {
+        endpoint: String,
+        authorization: String,
+    },
+    Socks5 {
+        addr: String,
+        credentials: Option<(String, String)>,
+    },
+}
+
+impl Default for ProxiedClient {
+    fn default() -> Self {
+        ProxiedClientBuilder::default()
+            .build()
+            .expect("unable to build reqwest client")
+    }
+}
+
+impl ProxiedClient {
+    pub fn builder() -> ProxiedClientBuilder {
+        ProxiedClientBuilder::default()
+    }
+
+    pub fn new(endpoint: &str, authorization: &str) -> Result<Self, ProxyError> {
+        Self::new_round_robin(&[endpoint], authorization)
+    }
+
+    /// Create a client which rotates all endpoints for every request.
+    pub fn new_round_robin<S: AsRef<str>>(
+        endpoints: &[S],
+        authorization: &str,
+    ) -> Result<Self, ProxyError> {
+        Self::forward_builder(endpoints, authorization)?.build()
+    }
+
+    pub fn new_socks5(addr: &str, credentials: Option<(&str, &str)>) -> Result<Self, ProxyError> {
+        Self::builder()
+            .with_socks5(socks5_proxy(addr, credentials)?)
+            .build()
+    }
+
+    pub fn from_kind(kind: &ProxyKind) -> Result<Self, ProxyError> {
+        match kind {
+            ProxyKind::HttpForward {
+                endpoint,
+                authorization,
+            } => Self::new(endpoint, authorization),
+            ProxyKind::Socks5 { addr, credentials } => Self::new_socks5(
+                addr,
+                credentials.as_ref().map(|(u, p)| (u.as_str(), p.as_str())),
+            ),
+        }
+    }
+
+    /// Same as `new`, but panics on invalid endpoint or authorization.
+    pub fn new_unchecked(endpoint: &str, authorization: &str) -> Self {
+        Self::new(endpoint, authorization).expect("unable to build proxied client")
+    }
+
+    /// Build from the config, falling back to direct connection with a warning if the
+    /// config is missing or incomplete.
+    /// Panics on other errors, so a broken proxy config never exposes the server address
+    /// to the target sites.
+    pub fn new_from_config() -> Self {
+        Self::or_direct(Self::try_from_config())
+    }
+
+    fn or_direct(built: Result<Option<Self>, ProxyError>) -> Self {
+        match built {
+            Ok(Some(client)) => client,
+            Ok(None) => {
+                tracing::warn!("no proxy config found, using direct connection");
+                Self::default()
+            }
+            Err(e @ ProxyError::IncompleteConfig { .. }) => {
+                tracing::warn!("{e}, using direct connection");
+                Self::default()
+            }
+            Err(e) => panic!("unable to build proxied client: {e}"),
+        }
+    }
+
+    /// Build from the config. Returns `Ok(None)` if there is no proxy config, and
+    /// `ProxyError::IncompleteConfig` if only one of endpoint and authorization is set.
+    /// If neither is set, the client connects directly with other options applied.
+    pub fn try_from_config() -> Result<Option<Self>, ProxyError> {
+        match config::parse::<ProxyConfig>(CONFIG_KEY)? {
+            Some(cfg) => Self::from_proxy_config(cfg).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn from_proxy_config(mut cfg: ProxyConfig) -> Result<Self, ProxyError> {
+        cfg.authorization = cfg.authorization()?;
+        let mut builder = if cfg.kind == ProxyKindName::Socks5 {
+            if cfg.addr.is_empty() {
+                tracing::warn!("socks5 proxy addr is empty, using direct connection");
+                Self::builder()
+            } else {
+                let credentials = cfg.username.as_deref().zip(cfg.password.as_deref());
+                Self::builder().with_socks5(socks5_proxy(&cfg.addr, credentials)?)
+            }
+        } else {
+            Self::builder().with_proxies(cfg.forward_proxies()?)
+        };
+        if let Some(t) = cfg.timeout {
+            builder = builder.with_timeout(Duration::from_secs(t));
+        }
+        if let Some(t) = cfg.connect_timeout {
+            builder = builder.with_connect_timeout(Duration::from_secs(t));
+        }
+        if let Some(t) = cfg.read_timeout {
+            builder = builder.with_read_timeout(Duration::from_secs(t));
+        }
+        if let Some(t) = cfg.download_timeout {
+            builder = builder.with_download_timeout(Duration::from_secs(t));
+        }
+        builder = builder.with_redirect_policy(cfg.max_redirects);
+        for (host, addr) in cfg.resolve.iter() {
+            let addr = addr.parse().map_err(|_| ProxyError::Resolve {
+                host: host.clone(),
+                addr: addr.clone(),
+            })?;
+            builder = builder.with_resolve(host, addr);
+        }
+        if let Some(path) = cfg.client_cert_path.as_deref() {
+            let der = std::fs::read(path)?;
+            builder = builder.with_client_identity(&der, &cfg.client_cert_password)?;
+        }
+        if !cfg.root_cert_paths.is_empty() {
+            let pems = cfg
+                .root_cert_paths
+                .iter()
+                .map(std::fs::read)
+                .collect::<Result<Vec<_>, _>>()?;
+            let pems: Vec<&[u8]> = pems.iter().map(Vec::as_slice).collect();
+            builder = builder.with_pinned_certificates(&pems)?;
+        }
+        if let Some(t) = cfg.health_check_interval {
+            builder = builder.with_health_check(Duration::from_secs(t), cfg.failure_threshold);
+        }
+        if let Some(c) = cfg.circuit_breaker {
+            builder = builder.with_circuit_breaker(
+                c.failure_threshold,
+                Duration::from_secs(c.window),
+                Duration::from_secs(c.cooldown),
+            );
+        }
+        if let Some(b) = cfg.retry_budget {
+            builder = builder.with_retry_budget(b.size, b.refill_per_sec);
+        }
+        if cfg.cookie_store {
+            builder = builder.with_cookie_store(true);
+        }
+        for (url, cookies) in cfg.cookies.iter() {
+            builder = builder.with_cookies(url, cookies)?;
+        }
+        if cfg.rate_limit_bytes_per_sec > 0 {
+            builder = builder.with_rate_limit(cfg.rate_limit_bytes_per_sec);
+        }
+        if let Some(n) = cfg.max_concurrent {
+            builder = builder.with_max_concurrent(n);
+        }
+        if !cfg.user_agents.is_empty() {
+            builder = builder.with_user_agent_pool(cfg.user_agents);
+        }
+        if !cfg.no_proxy.is_empty() {
+            builder = builder.with_no_proxy(cfg.no_proxy);
+        }
+        builder.build()
+    }
+
+    fn forward_builder<S: AsRef<str>>(
+        endpoints: &[S],
+        authorization: &str,
+    ) -> Result<ProxiedClientBuilder, ProxyError> {
+        if endpoints.is_empty() {
+            return Err(ProxyError::NoEndpoint);
+        }
+        let proxies = endpoints
+            .iter()
+            .map(|endpoint| Proxy::new(endpoint.as_ref(), authorization))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self::builder().with_proxies(proxies))
+    }
+
+    /// Merge headers into default headers.
+    /// Note: This rebuilds the inner client, prefer `ProxiedClientBuilder` when possible.
+    pub fn with_default_headers(mut self, headers: HeaderMap) -> Self {
+        self.config.headers.extend(headers);
+        self.rebuild()
+    }
+
+    /// Set the overall request timeout, including reading the body.
+    /// Note: This rebuilds the inner client, prefer `ProxiedClientBuilder` when possible.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.config.timeout = timeout;
+        self.rebuild()
+    }
+
+    /// Set the timeout for establishing the TCP(and TLS) connection only.
+    /// Note: This rebuilds the inner client, prefer `ProxiedClientBuilder` when possible.
+    pub fn with_connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.config.connect_timeout = Some(connect_timeout);
+        self.rebuild()
+    }
+
+    /// Give up a `download_to_writer` once no data is received for `timeout`, 30s by default.
+    pub fn with_read_timeout(mut self, timeout: Duration) -> Self {
+        self.config.read_timeout = timeout;
+        self
+    }
+
+    /// Ceiling of a whole `download_to_writer`, 600s by default. It replaces the
+    /// overall request timeout, which would abort large downloads still in progress.
+    pub fn with_download_timeout(mut self, timeout: Duration) -> Self {
+        self.config.download_timeout = timeout;
+        self
+    }
+
+    /// Pick a random User-Agent from `agents` for every request.
+    /// Invalid header values are skipped, and an empty pool keeps the default headers.
+    pub fn with_user_agent_pool(mut self, agents: Vec<String>) -> Self {
+        self.user_agents = Arc::new(builder::user_agent_pool(agents));
+        self
+    }
+
+    /// Send requests to the `hosts` directly even if a forwarding proxy is configured,
+    /// as `request_direct` does. A host like `.telegra.ph` matches its subdomains too.
+    /// Note: SOCKS5 proxy is part of the inner client, so it is NOT bypassed.
+    pub fn with_no_proxy(mut self, hosts: Vec<String>) -> Self {
+        self.no_proxy = Arc::new(builder::no_proxy_patterns(hosts));
+        self
+    }
+
+    /// Apply the middleware to every request built from now on, after the ones added
+    /// before. Clones created afterwards share it.
+    pub fn with_middleware(mut self, middleware: impl RequestMiddleware + 'static) -> Self {
+        self.middlewares.push(Arc::new(middleware));
+        self
+    }
+
+    /// Limit requests sent through `acquire_and_send` to `max_concurrent` at the same time.
+    /// The limit replaces the previous one and is shared with clones created afterwards.
+    pub fn with_max_concurrent(mut self, max_concurrent: usize) -> Self {
+        self.limiter = Some(Arc::new(tokio::sync::Semaphore::new(max_concurrent.max(1))));
+        self
+    }
+
+    /// Follow at most `max` redirects, 10 by default. With 0 redirects are not followed
+    /// and the 3xx response is returned, so callers can read the `Location` header.
+    /// Note: This rebuilds the inner client, prefer `ProxiedClientBuilder` when possible.
+    pub fn with_redirect_policy(mut self, max: usize) -> Self {
+        self.config.max_redirects = max;
+        self.rebuild()
+    }
+
+    /// Close idle connections in the pool after `timeout`, 90s by default.
+    /// Note: This rebuilds the inner client, prefer `ProxiedClientBuilder` when possible.
+    pub fn with_pool_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.config.pool_idle_timeout = Some(timeout);
+        self.rebuild()
+    }
+
+    /// Keep at most `max` idle connections per host, no limit by default.
+    /// Note: This rebuilds the inner client, prefer `ProxiedClientBuilder` when possible.
+    pub fn with_pool_max_idle_per_host(mut self, max: usize) -> Self {
+        self.config.pool_max_idle_per_host = Some(max);
+        self.rebuild()
+    }
+
+    /// Speak HTTP/2 without negotiation. This only makes sense when all requests go to
+    /// a proxy endpoint known to serve h2, otherwise requests to HTTP/1 hosts will fail.
+    /// Note: This rebuilds the inner client, prefer `ProxiedClientBuilder` when possible.
+    pub fn with_http2_prior_knowledge(mut self, enabled: bool) -> Self {
+        self.config.http2_prior_knowledge = enabled;
+        self.rebuild()
+    }
+
+    /// Connect to `addr` for `host` instead of resolving it with DNS. This applies to all
+    /// requests, not only the proxy endpoint.
+    /// Note: The port of `addr` is ignored by reqwest, put the port in the url instead.
+    /// Note: This rebuilds the inner client, prefer `ProxiedClientBuilder` when possible.
+    pub fn with_resolve(mut self, host: &str, addr: SocketAddr) -> Self {
+        self.config.resolve.push((host.to_string(), addr));
+        self.rebuild()
+    }
+
+    /// Decompress response bodies with the enabled encodings, all disabled by default.
+    /// `Accept-Encoding` is set in the default headers to the enabled ones unless already
+    /// there. A per-request `Accept-Encoding` takes precedence, and bodies in encodings
+    /// not enabled here are returned as is.
+    /// Note: `Content-Length` of decompressed responses is unknown, so the total passed to
+    /// the `download_to_writer` progress is None.
+    /// Note: This rebuilds the inner client, prefer `ProxiedClientBuilder` when possible.
+    pub fn with_compression(mut self, gzip: bool, brotli: bool, deflate: bool) -> Self {
+        self.config.compression = (gzip, brotli, deflate);
+        self.rebuild()
+    }
+
+    /// Authenticate to the proxy endpoint with a PKCS#12 client certificate.
+    /// Note: This rebuilds the inner client, prefer `ProxiedClientBuilder` when possible.
+    pub fn with_client_identity(
+        mut self,
+        pkcs12_der: &[u8],
+        password: &str,
+    ) -> Result<Self, ProxyError> {
+        self.config.identity = Some(builder::pkcs12_identity(pkcs12_der, password)?);
+        Ok(self.rebuild())
+    }
+
+    /// Trust the certificates in the PEM bundle besides the built-in roots.
+    /// Note: This rebuilds the inner client, prefer `ProxiedClientBuilder` when possible.
+    pub fn with_root_certificate(mut self, pem: &[u8]) -> Result<Self, ProxyError> {
+        self.config.root_certs.extend(builder::certificates(pem)?);
+        Ok(self.rebuild())
+    }
+
+    /// Trust only the given PEM certificates, the built-in roots are disabled.
+    /// Note: This applies to direct requests too, so pin only if all requests go to the proxy.
+    /// Note: This rebuilds the inner client, prefer `ProxiedClientBuilder` when possible.
+    pub fn with_pinned_certificates(mut self, pems: &[&[u8]]) -> Result<Self, ProxyError> {
+        self.config.root_certs = builder::pinned_certificates(pems)?;
+        self.config.built_in_roots = false;
+        Ok(self.rebuild())
+    }
+
+    /// Accept any certificate, including expired and self-signed ones. Never use it
+    /// outside of testing since it defeats TLS entirely.
+    /// Note: This rebuilds the inner client, prefer `ProxiedClientBuilder` when possible.
+    pub fn danger_accept_invalid_certs(mut self, accept: bool) -> Self {
+        self.config.accept_invalid_certs = accept;
+        self.rebuild()
+    }
+
+    /// Enable or disable the cookie jar, cookies set by responses are replayed on later
+    /// requests. Clones share the same jar.
+    /// When using the forwarding proxy, cookies of the target url are sent in the `Cookie`
+    /// header to the endpoint, so the proxy must forward them. Cookies set by proxied
+    /// responses are stored under the endpoint host, so seed the target cookies with
+    /// `with_cookies` instead.
+    /// Note: This rebuilds the inner client, prefer `ProxiedClientBuilder` when possible.
+    pub fn with_cookie_store(mut self, enabled: bool) -> Self {
+        if !enabled {
+            self.config.cookie_store = None;
+        } else if self.config.cookie_store.is_none() {
+            self.config.cookie_store = Some(Default::default());
+        }
+        self.rebuild()
+    }
+
+    /// Seed cookies for `url`, the cookie jar is enabled if it is not.
+    pub fn with_cookies<'a, I>(self, url: &str, cookies: I) -> Result<Self, ProxyError>
+    where
+        I: IntoIterator<Item = (&'a String, &'a String)>,
+    {
+        let this = match self.config.cookie_store {
+            Some(_) => self,
+            None => self.with_cookie_store(true),
+        };
+        if let Some(jar) = &this.config.cookie_store {
+            builder::add_cookies(jar, url, cookies)?;
+        }
+        Ok(this)
+    }
+
+    /// Open the circuit after `failure_threshold` failed requests(errors or 5xx) within
+    /// `window`, so `send_with_retry` fails fast with `ProxyError::CircuitOpen`. After
+    /// `cooldown` one request is let through, closing the circuit if it succeeds.
+    pub fn with_circuit_breaker(
+        mut self,
+        failure_threshold: usize,
+        window: Duration,
+        cooldown: Duration,
+    ) -> Self {
+        self.circuit = Some(Arc::new(circuit::CircuitBreaker::new(
+            failure_threshold,
+            window,
+            cooldown,
+        )));
+        self
+    }
+
+    /// State of the circuit breaker, None if not enabled.
+    pub fn circuit_state(&self) -> Option<CircuitState> {
+        self.circuit.as_ref().map(|c| c.state())
+    }
+
+    /// Share at most `size` retries among all `send_with_retry` calls, refilled by
+    /// `refill_per_sec`. A retryable failure is returned at once when no retry is left.
+    /// It also applies to the gallery requests of collectors using this client.
+    pub fn with_retry_budget(mut self, size: u32, refill_per_sec: f64) -> Self {
+        self.retry_budget = Some(Arc::new(RetryBudget::new(size, refill_per_sec)));
+        self
+    }
+
+    /// A clone whose retries take from no budget, like for requests which must not
+    /// starve the others.
+    pub fn without_retry_budget(mut self) -> Self {
+        self.retry_budget = None;
+        self
+    }
+
+    /// The retry budget, None if not enabled.
+    pub fn retry_budget(&self) -> Option<&RetryBudget> {
+        self.retry_budget.as_deref()
+    }
+
+    /// Cap the total download speed of `download_to_writer` to `bytes_per_sec`,
+    /// shared by all concurrent downloads. Zero means unlimited.
+    pub fn with_rate_limit(mut self, bytes_per_sec: u64) -> Self {
+        self.rate_limit = builder::rate_limiter(bytes_per_sec);
+        self
+    }
+
+    /// Probe the proxy endpoints with HEAD requests every `interval`, and route
+    /// requests directly after `failure_threshold` consecutive failures until the
+    /// proxy recovers. Clones share the same health status.
+    /// Note: This spawns a task so it must be called inside a tokio runtime.
+    pub fn with_health_check(self, interval: Duration, failure_threshold: usize) -> Self {
+        health::spawn_health_check(
+            self.inner.clone(),
+            self.proxies.clone(),
+            Arc::downgrade(&self.healthy),
+            interval,
+            failure_threshold.max(1),
+        );
+        self
+    }
+
+    /// Read the proxy config from the config file again and swap the forwarding proxies
+    /// for all clones. Requests already built keep using the proxy they picked.
+    /// Only endpoints, authorization and header names are reloaded, other options
+    /// including SOCKS5 require a restart.
+    pub fn reload_from_config(&self) -> Result<(), ProxyError> {
+        let proxies = match config::reparse::<ProxyConfig>(CONFIG_KEY)? {
+            Some(cfg) => Self::reload_proxies(cfg)?,
+            None => Vec::new(),
+        };
+        tracing::info!("[proxy] reloaded {} proxy endpoints", proxies.len());
+        self.set_proxies(proxies);
+        Ok(())
+    }
+
+    fn reload_proxies(mut cfg: ProxyConfig) -> Result<Vec<Proxy>, ProxyError> {
+        if cfg.kind == ProxyKindName::Socks5 {
+            tracing::warn!("[proxy] socks5 proxy can not be reloaded, restart to apply it");
+            return Ok(Vec::new());
+        }
+        cfg.authorization = cfg.authorization()?;
+        cfg.forward_proxies()
+    }
+
+    /// Replace the forwarding proxies for all clones, empty means direct connection.
+    pub fn set_proxies(&self, proxies: Vec<Proxy>) {
+        self.proxies.store(Arc::new(proxies));
+    }
+
+    /// Whether the proxy is healthy. Always true if health check is not enabled.
+    pub fn is_proxy_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    /// Counters of requests sent through `send`, shared with clones.
+    pub fn metrics(&self) -> ProxyMetrics {
+        self.metrics.snapshot()
+    }
+
+    /// GET `url` the way syncs do and report how it went, to check the proxy config.
+    pub async fn probe(&self, url: &str) -> ProbeReport {
+        let start = Instant::now();
+        let (client, request) = self.get(url).build_split();
+        let request = match request {
+            Ok(request) => request,
+            Err(e) => {
+                return ProbeReport {
+                    url: url.to_string(),
+                    route: ProbeRoute::Direct,
+                    status: None,
+                    elapsed: start.elapsed(),
+                    error: Some(health::error_chain(&e)),
+                }
+            }
+        };
+        let proxies = self.proxies.load_full();
+        let route = match forwarding_proxy(&proxies, &request) {
+            Some(p) => ProbeRoute::Proxy(p.endpoint.to_string()),
+            None if self.config.socks5.is_some() => ProbeRoute::Socks5,
+            None => ProbeRoute::Direct,
+        };
+        let result = self
+            .send(reqwest::RequestBuilder::from_parts(client, request))
+            .await;
+        ProbeReport {
+            url: url.to_string(),
+            route,
+            status: result.as_ref().ok().map(reqwest::Response::status),
+            elapsed: start.elapsed(),
+            error: result.err().map(|e| health::error_chain(&e)),
+        }
+    }
+
+    /// Pick the next proxy in turn. Returns None when the proxy is unhealthy.
+    fn proxy(&self) -> Option<Proxy> {
+        if !self.is_proxy_healthy() {
+            return None;
+        }
+        let proxies = self.proxies.load();
+        match proxies.len() {
+            0 => None,
+            1 => proxies.first().cloned(),
+            n => {
+                let idx = self.next_proxy.fetch_add(1, Ordering::Relaxed) % n;
+                proxies.get(idx).cloned()
+            }
+        }
+    }
+
+    /// The proxy for `url`, None if it is sent directly.
+    fn proxy_for(&self, url: &str) -> Option<Proxy> {
+        if !self.no_proxy.is_empty() {
+            let host = reqwest::Url::parse(url)
+                .ok()
+                .and_then(|u| u.host_str().map(str::to_owned));
+            if host.is_some_and(|h| builder::matches_no_proxy(&self.no_proxy, &h)) {
+                return None;
+            }
+        }
+        self.proxy()
+    }
+
+    fn user_agent(&self) -> Option<HeaderValue> {
+        use rand::seq::SliceRandom;
+        self.user_agents.choose(&mut rand::thread_rng()).cloned()
+    }
+
+    fn rebuild(self) -> Self {
+        let inner = self.config.build().expect("unable to build reqwest client");
+        Self { inner, ..self }
+    }
+}
+
+fn validator_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".validator");
+    PathBuf::from(name)
+}
+
+/// `ETag`, or `Last-Modified` if there is none.
+fn validator(headers: &HeaderMap) -> Option<&HeaderValue> {
+    headers
+        .get(reqwest::header::ETAG)
+        .or_else(|| headers.get(reqwest::header::LAST_MODIFIED))
+}
+
+/// The first byte of `Content-Range: bytes 100-199/200`.
+fn content_range_start(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(reqwest::header::CONTENT_RANGE)?
+        .to_str()
+        .ok()?
+        .strip_prefix("bytes ")?
+        .split_once('-')?
+        .0
+        .parse()
+        .ok()
+}
+
+async fn remove_if_exists(path: &Path) -> std::io::Result<()> {
+    match tokio::fs::remove_file(path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// The forwarding proxy the request is built for, None if it is sent directly.
+fn forwarding_proxy<'a>(proxies: &'a [Proxy], request: &reqwest::Request) -> Option<&'a Proxy> {
+    proxies
+        .iter()
+        .find(|p| p.endpoint == *request.url() && request.headers().contains_key(&p.forward_header))
+}
+
+// For SOCKS5 the proxy is applied by the inner client, so `proxies` is empty and
+// requests go to the real url directly.
+macro_rules! impl_method {
+    ($method: ident, $m: ident) => {
+        pub fn $method(&self, url: &str) -> reqwest::RequestBuilder {
+            self.request(reqwest::Method::$m, url)
+        }
+    };
+}
+
+macro_rules! impl_direct_method {
+    ($method: ident, $m: ident) => {
+        /// Bypass the forwarding proxy, see `request_direct`.
+        pub fn $method(&self, url: &str) -> reqwest::RequestBuilder {
+            self.request_direct(reqwest::Method::$m, url)
+        }
+    };
+}
+
+impl ProxiedClient {
+    impl_method!(get, GET);
+    impl_method!(post, POST);
+    impl_method!(head, HEAD);
+    impl_method!(put, PUT);
+    impl_method!(delete, DELETE);
+    impl_method!(patch, PATCH);
+
+    impl_direct_method!(get_direct, GET);
+    impl_direct_method!(post_direct, POST);
+    impl_direct_method!(head_direct, HEAD);
+    impl_direct_method!(put_direct, PUT);
+    impl_direct_method!(delete_direct, DELETE);
+    impl_direct_method!(patch_direct, PATCH);
+
+    /// Send the request built by this client in a tracing span recording the target url,
+    /// the path taken, the status and the elapsed time, and update the metrics.
+    /// The request carries `X-Request-Id`, see `with_request_id`, also recorded in the span.
+    pub async fn send(&self, req: reqwest::RequestBuilder) -> reqwest::Result<reqwest::Response> {
+        let (client, request) = req.build_split();
+        let mut request = request?;
+        let request_id = attach_request_id(&mut request);
+        let proxies = self.proxies.load_full();
+        let proxy = forwarding_proxy(&proxies, &request);
+        let target = match proxy {
+            Some(p) => request.headers()[&p.forward_header]
+                .to_str()
+                .unwrap_or_default()
+                .to_string(),
+            None => request.url().to_string(),
+        };
+        let span = tracing::debug_span!(
+            "proxied_request",
+            url = %target,
+            path = if proxy.is_some() { "proxy" } else { "direct" },
+            request_id = %request_id,
+            status = tracing::field::Empty,
+            elapsed_ms = tracing::field::Empty,
+        );
+
+        let start = Instant::now();
+        let result = client.execute(request).instrument(span.clone()).await;
+        span.record("elapsed_ms", start.elapsed().as_millis() as u64);
+        span.in_scope(|| match &result {
+            Ok(resp) => {
+                span.record("status", resp.status().as_u16());
+                tracing::debug!("[proxy] request finished");
+            }
+            Err(e) => tracing::debug!("[proxy] request failed: {e}"),
+        });
+        self.metrics.record(proxy.is_some(), result.is_err());
+        result
+    }
+
+    /// Same as `send`, but waits for a permit first if `max_concurrent` is set.
+    /// The permit is released once the response headers are received or the request fails.
+    pub async fn acquire_and_send(
+        &self,
+        req: reqwest::RequestBuilder,
+    ) -> reqwest::Result<reqwest::Response> {
+        let _permit = match &self.limiter {
+            Some(limiter) => Some(
+                limiter
+                    .acquire()
+                    .await
+                    .expect("concurrency limiter should never be closed"),
+            ),
+            None => None,
+        };
+        self.send(req).await
+    }
+
+    /// GET `url` and stream the body into `writer` chunk by chunk, calling `progress`
+    /// with the bytes written so far and the `Content-Length` if known.
+    /// The speed is limited if `with_rate_limit` is set. It is given up with
+    /// `ProxyError::Stalled` if nothing is received within the read timeout.
+    /// Non-success status is returned as an error. Returns the total bytes written.
+    pub async fn download_to_writer<W>(
+        &self,
+        url: &str,
+        mut writer: W,
+        mut progress: impl FnMut(u64, Option<u64>),
+    ) -> Result<u64, ProxyError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let mut resp = self
+            .start_download(self.get(url))
+            .await?
+            .error_for_status()?;
+        let total = resp.content_length();
+        self.write_body(&mut resp, &mut writer, 0, total, &mut progress)
+            .await
+    }
+
+    /// Like `download_to_writer`, but into the file at `path`, resuming the partial
+    /// file left by an interrupted download with `Range`. The `ETag` or
+    /// `Last-Modified` of the partial file is kept in `{path}.validator` and sent with
+    /// `If-Range`, so the file is downloaded again in full if it changed, or if the
+    /// server ignores the range. `progress` counts the resumed bytes too.
+    /// The file is complete only when Ok is returned, with its size.
+    pub async fn download_resumable(
+        &self,
+        url: &str,
+        path: &Path,
+        progress: impl FnMut(u64, Option<u64>),
+    ) -> Result<u64, ProxyError> {
+        self.download_resumable_with_headers(url, HeaderMap::new(), path, progress)
+            .await
+    }
+
+    /// `download_resumable` with extra headers, like the `Referer` some image hosts need.
+    pub async fn download_resumable_with_headers(
+        &self,
+        url: &str,
+        extra: HeaderMap,
+        path: &Path,
+        mut progress: impl FnMut(u64, Option<u64>),
+    ) -> Result<u64, ProxyError> {
+        let validator_path = validator_path(path);
+        // without a validator the partial file can not be told apart from another version
+        let stored = tokio::fs::read_to_string(&validator_path)
+            .await
+            .ok()
+            .and_then(|v| HeaderValue::from_str(&v).ok());
+        let mut offset = match &stored {
+            Some(_) => tokio::fs::metadata(path).await.map_or(0, |m| m.len()),
+            None => 0,
+        };
+        let resp = loop {
+            let mut headers = extra.clone();
+            if offset > 0 {
+                let range = HeaderValue::from_str(&format!("bytes={offset}-"))
+                    .expect("range is a valid header value");
+                headers.insert(reqwest::header::RANGE, range);
+                if let Some(validator) = &stored {
+                    headers.insert(reqwest::header::IF_RANGE, validator.clone());
+                }
+            }
+            let resp = self
+                .start_download(self.get_with_headers(url, headers))
+                .await?;
+            if offset == 0 {
+                break resp;
+            }
+            match resp.status() {
+                reqwest::StatusCode::PARTIAL_CONTENT
+                    if content_range_start(resp.headers()) == Some(offset)
+                        && validator(resp.headers()).is_none_or(|v| Some(v) == stored.as_ref()) =>
+                {
+                    break resp
+                }
+                // the range of another version, or past its end
+                reqwest::StatusCode::PARTIAL_CONTENT
+                | reqwest::StatusCode::RANGE_NOT_SATISFIABLE => {
+                    tracing::debug!("[proxy] unable to resume {url} at {offset}, download again");
+                    offset = 0;
+                }
+                _ => break resp.error_for_status()?,
+            }
+        };
+        let mut resp = resp.error_for_status()?;
+        let resumed = resp.status() == reqwest::StatusCode::PARTIAL_CONTENT;
+        if !resumed {
+            offset = 0;
+            match validator(resp.headers()) {
+                Some(v) => tokio::fs::write(&validator_path, v.as_bytes()).await?,
+                None => remove_if_exists(&validator_path).await?,
+            }
+        }
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(resumed)
+            .truncate(!resumed)
+            .open(path)
+            .await?;
+        let total = resp.content_length().map(|n| n + offset);
+        let written = match self
+            .write_body(&mut resp, &mut file, offset, total, &mut progress)
+            .await
+        {
+            Ok(n) => n,
+            Err(e) => {
+                // keep what is received for the next attempt
+                let _ = file.flush().await;
+                return Err(e);
+            }
+        };
+        remove_if_exists(&validator_path).await?;
+        Ok(written)
+    }
+
+    /// Send a download request, waiting at most the read timeout for the headers.
+    async fn start_download(
+        &self,
+        request: reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response, ProxyError> {
+        let read_timeout = self.config.read_timeout;
+        let request = request.timeout(self.config.download_timeout);
+        Ok(tokio::time::timeout(read_timeout, self.send(request))
+            .await
+            .map_err(|_| ProxyError::Stalled(read_timeout))??)
+    }
+
+    /// Stream the body into `writer` after the `written` bytes, see `download_to_writer`.
+    async fn write_body<W: AsyncWrite + Unpin>(
+        &self,
+        resp: &mut reqwest::Response,
+        writer: &mut W,
+        mut written: u64,
+        total: Option<u64>,
+        progress: &mut impl FnMut(u64, Option<u64>),
+    ) -> Result<u64, ProxyError> {
+        let read_timeout = self.config.read_timeout;
+        while let Some(chunk) = tokio::time::timeout(read_timeout, resp.chunk())
+            .await
+            .map_err(|_| ProxyError::Stalled(read_timeout))??
+        {
+            if let Some(limiter) = &self.rate_limit {
+                limiter.acquire(chunk.len() as u64).await;
+            }
+            writer.write_all(&chunk).await?;
+            written += chunk.len() as u64;
+            report_downloaded(chunk.len() as u64);
+            progress(written, total);
+        }
+        writer.flush().await?;
+        Ok(written)
+    }
+
+    /// HEAD `url` the way `get` would send it and return the status and headers, like
+    /// `Content-Length` before deciding whether to download it. When proxied they are
+    /// the ones the proxy answers with, so this helps to troubleshoot it too.
+    /// Non-success status is returned as is.
+    pub async fn head_status_and_headers(
+        &self,
+        url: &str,
+    ) -> Result<(reqwest::StatusCode, HeaderMap), ProxyError> {
+        let resp = self.send(self.head(url)).await?;
+        Ok((resp.status(), resp.headers().clone()))
+    }
+
+    /// Send the request built by this client, retrying on connection errors, timeouts
+    /// and retryable status codes with exponential backoff.
+    /// If the circuit breaker is open, `ProxyError::CircuitOpen` is returned without
+    /// sending the request. Retries are taken from the retry budget if set.
+    pub async fn send_with_retry(
+        &self,
+        req: reqwest::RequestBuilder,
+        policy: RetryPolicy,
+    ) -> Result<ProxiedResponse, ProxyError> {
+        retry::send_with_retry(req, &policy, self.retry_budget(), |r| {
+            self.send_with_circuit(r)
+        })
+        .await
+    }
+
+    async fn send_with_circuit(
+        &self,
+        req: reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response, ProxyError> {
+        let circuit = match &self.circuit {
+            Some(c) => c,
+            None => return Ok(self.send(req).await?),
+        };
+        if !circuit.try_acquire() {
+            return Err(ProxyError::CircuitOpen);
+        }
+        let result = self.send(req).await;
+        match &result {
+            Ok(resp) if !resp.status().is_server_error() => circuit.record_success(),
+            _ => circuit.record_failure(),
+        }
+        Ok(result?)
+    }
+
+    pub fn request(&self, method: reqwest::Method, url: &str) -> reqwest::RequestBuilder {
+        self.middlewares
+            .apply(self.request_via(self.proxy_for(url), method, url))
+    }
+
+    /// GET with extra headers, see `request_with_headers`.
+    pub fn get_with_headers(&self, url: &str, extra: HeaderMap) -> reqwest::RequestBuilder {
+        self.request_with_headers(reqwest::Method::GET, url, extra)
+    }
+
+    /// GET the bytes `range` of the url, like the head of an image to sniff its type.
+    /// Servers ignoring `Range` answer 200 with the full body instead of 206, so the
+    /// caller should check the status. An empty `range` has no header form and fails.
+    pub fn get_range(
+        &self,
+        url: &str,
+        range: std::ops::Range<u64>,
+    ) -> Result<reqwest::RequestBuilder, ProxyError> {
+        if range.is_empty() {
+            return Err(ProxyError::EmptyRange(range));
+        }
+        let mut headers = HeaderMap::new();
+        let value = format!("bytes={}-{}", range.start, range.end.saturating_sub(1));
+        headers.insert(
+            reqwest::header::RANGE,
+            HeaderValue::from_str(&value).expect("range is a valid header value"),
+        );
+        Ok(self.get_with_headers(url, headers))
+    }
+
+    /// Same as `request`, with extra headers like `Referer` sent to the target.
+    /// The forwarding and authorization headers of the proxy take precedence, extras
+    /// with the same names are dropped. Others like `User-Agent` replace the defaults.
+    pub fn request_with_headers(
+        &self,
+        method: reqwest::Method,
+        url: &str,
+        mut extra: HeaderMap,
+    ) -> reqwest::RequestBuilder {
+        let proxy = self.proxy_for(url);
+        if let Some(p) = &proxy {
+            extra.remove(&p.forward_header);
+            extra.remove(&p.auth_header);
+        }
+        self.middlewares
+            .apply(self.request_via(proxy, method, url).headers(extra))
+    }
+
+    fn request_via(
+        &self,
+        proxy: Option<Proxy>,
+        method: reqwest::Method,
+        url: &str,
+    ) -> reqwest::RequestBuilder {
+        let builder = match proxy {
+            Some(p) => {
+                let builder = self
+                    .inner
+                    .request(method, p.endpoint.clone())
+                    .header(&p.forward_header, url)
+                    .header(&p.auth_header, p.authorization.clone());
+                match self.target_cookies(url) {
+                    Some(cookies) => builder.header(reqwest::header::COOKIE, cookies),
+                    None => builder,
+                }
+            }
+            None => self.inner.request(method, url),
+        };
+        self.with_user_agent(builder)
+    }
+
+    /// Cookies in the jar for the target url, reqwest only attaches those of the endpoint.
+    fn target_cookies(&self, url: &str) -> Option<HeaderValue> {
+        use reqwest::cookie::CookieStore;
+        let jar = self.config.cookie_store.as_ref()?;
+        jar.cookies(&url.parse().ok()?)
+    }
+
+    /// Send the request to `url` directly even if a forwarding proxy is configured.
+    /// Both the endpoint rewrite and the authorization header injection are skipped,
+    /// while default headers and timeouts still apply.
+    /// Note: SOCKS5 proxy is part of the inner client, so it is NOT bypassed.
+    pub fn request_direct(&self, method: reqwest::Method, url: &str) -> reqwest::RequestBuilder {
+        self.middlewares
+            .apply(self.with_user_agent(self.inner.request(method, url)))
+    }
+
+    /// Set the User-Agent from `ua` if the user agent pool is empty.
+    pub(crate) fn with_fallback_user_agent(
+        &self,
+        builder: reqwest::RequestBuilder,
+        ua: impl FnOnce() -> &'static str,
+    ) -> reqwest::RequestBuilder {
+        match self.user_agents.is_empty() {
+            true => builder.header(reqwest::header::USER_AGENT, ua()),
+            false => builder,
+        }
+    }
+
+    fn with_user_agent(&self, builder: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        match self.user_agent() {
+            Some(ua) => builder.header(reqwest::header::USER_AGENT, ua),
+            None => builder,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_proxy_config_parsing() {
+        // Test parsing empty proxy config
+        let yaml = "endpoint: \"\"\nauthorization: \"\"";
+        let cfg: ProxyConfig = serde_yaml::from_str(yaml).unwrap();
+        assert!(cfg.endpoint.is_empty());
+        assert_eq!(cfg.authorization, "");
+
+        // Test parsing missing fields (should use default)
+        let yaml = "";
+        let cfg: ProxyConfig = serde_yaml::from_str(yaml).unwrap();
+        assert!(cfg.endpoint.is_empty());
+        assert_eq!(cfg.authorization, "");
+
+        // Test parsing valid proxy config
+        let yaml = "endpoint: \"https://proxy.example.com/\"\nauthorization: \"test-key\"";
+        let cfg: ProxyConfig = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(cfg.endpoint, vec!["https://proxy.example.com/"]);
+        assert_eq!(cfg.authorization, "test-key");
+
+        // Test parsing endpoint list
+        let yaml = "endpoint:\n  - \"https://a.example.com/\"\n  - \"https://b.example.com/\"";
+        let cfg: ProxyConfig = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(
+            cfg.endpoint,
+            vec!["https://a.example.com/", "https://b.example.com/"]
+        );
+    }
+
+    #[test]
+    fn test_proxied_client_new() {
+        let client = ProxiedClient::new("https://proxy.example.com/", "test-key").unwrap();
+        assert_eq!(client.proxies.load().len(), 1);
+
+        // Invalid endpoint
+        let err = ProxiedClient::new("https://[::1/", "test-key").unwrap_err();
+        assert!(matches!(err, ProxyError::Endpoint(_)));
+
+        // Authorization with illegal header bytes
+        let err = ProxiedClient::new("https://proxy.example.com/", "bad\nkey").unwrap_err();
+        assert!(matches!(err, ProxyError::Authorization(_)));
+    }
+
+    #[test]
+    fn test_proxied_client_timeout() {
+        let yaml = "timeout: 120\nconnect_timeout: 5";
+        let cfg: ProxyConfig = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(cfg.timeout, Some(120));
+        assert_eq!(cfg.connect_timeout, Some(5));
+
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Test", HeaderValue::from_static("1"));
+        let client = ProxiedClient::new("https://proxy.example.com/", "test-key")
+            .unwrap()
+            .with_default_headers(headers)
+            .with_timeout(Duration::from_secs(120))
+            .with_connect_timeout(Duration::from_secs(5));
+        assert_eq!(client.config.timeout, Duration::from_secs(120));
+        assert_eq!(client.config.connect_timeout, Some(Duration::from_secs(5)));
+        assert!(client.config.headers.contains_key("X-Test"));
+        assert_eq!(client.proxies.load().len(), 1);
+    }
+
+    #[test]
+    fn test_socks5_proxy() {
+        let yaml = "kind: socks5\naddr: \"127.0.0.1:1080\"\nusername: user\npassword: pass";
+        let cfg: ProxyConfig = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(cfg.kind, ProxyKindName::Socks5);
+        assert_eq!(cfg.addr, "127.0.0.1:1080");
+
+        // default kind is http forward
+        let cfg: ProxyConfig = serde_yaml::from_str("").unwrap();
+        assert_eq!(cfg.kind, ProxyKindName::HttpForward);
+
+        let client = ProxiedClient::from_kind(&ProxyKind::Socks5 {
+            addr: "127.0.0.1:1080".to_string(),
+            credentials: Some(("user".to_string(), "pass".to_string())),
+        })
+        .unwrap();
+        assert!(client.proxies.load().is_empty());
+        assert!(client.config.socks5.is_some());
+
+        // socks5 requests are sent to the real url
+        let req = client.get("https://e-hentai.org/g/1/2").build().unwrap();
+        assert_eq!(req.url().as_str(), "https://e-hentai.org/g/1/2");
+        assert!(req.headers().get("X-Forwarded-For").is_none());
+
+        // http forward requests are rewritten to the endpoint
+        let client = ProxiedClient::new("https://proxy.example.com/", "test-key").unwrap();
+        let req = client.get("https://e-hentai.org/g/1/2").build().unwrap();
+        assert_eq!(req.url().as_str(), "https://proxy.example.com/");
+        assert_eq!(
+            req.headers().get("X-Forwarded-For").unwrap(),
+            "https://e-hentai.org/g/1/2"
+        );
+    }
+
+    #[test]
+    fn test_proxied_client_default() {
+        // Test that default ProxiedClient has no proxy
+        let client = ProxiedClient::default();
+        assert!(client.proxies.load().is_empty());
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let endpoints = [
+            "https://a.example.com/",
+            "https://b.example.com/",
+            "https://c.example.com/",
+        ];
+        let client = ProxiedClient::new_round_robin(&endpoints, "test-key").unwrap();
+        let cloned = client.clone();
+
+        let mut hits = std::collections::HashMap::new();
+        for i in 0..9 {
+            // clones share the same rotation
+            let c = if i % 2 == 0 { &client } else { &cloned };
+            let req = c.get("https://e-hentai.org/").build().unwrap();
+            *hits.entry(req.url().to_string()).or_insert(0) += 1;
+        }
+        assert_eq!(hits.len(), 3);
+        for endpoint in endpoints {
+            assert_eq!(hits[endpoint], 3);
+        }
+
+        let err = ProxiedClient::new_round_robin::<&str>(&[], "test-key").unwrap_err();
+        assert!(matches!(err, ProxyError::NoEndpoint));
+    }
+
+    #[tokio::test]
+    async fn test_send_with_retry() {
+        use crate::mock_server::{MockResponse, MockServer};
+
+        let server = MockServer::start(|idx, _| match idx {
+            0 | 1 => MockResponse::new(503, "unavailable"),
+            _ => MockResponse::new(200, "ok"),
+        })
+        .await;
+        let client = ProxiedClient::default();
+        let policy = RetryPolicy::new(5, Duration::from_millis(10));
+        let resp = client
+            .send_with_retry(client.get(&server.url("/")), policy)
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.into_inner().text().await.unwrap(), "ok");
+        assert_eq!(server.requests().len(), 3);
+
+        // Retry-After overrides the computed delay
+        let server = MockServer::start(|idx, _| match idx {
+            0 => MockResponse::new(429, "").header("Retry-After", "0"),
+            _ => MockResponse::new(200, "ok"),
+        })
+        .await;
+        let policy = RetryPolicy::new(2, Duration::from_secs(60));
+        let resp = tokio::time::timeout(
+            Duration::from_secs(5),
+            client.send_with_retry(client.get(&server.url("/")), policy),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(resp.status(), 200);
+
+        // The last response is returned when attempts are exhausted
+        let server = MockServer::start(|_, _| MockResponse::new(502, "")).await;
+        let policy = RetryPolicy::new(2, Duration::from_millis(10));
+        let resp = client
+            .send_with_retry(client.get(&server.url("/")), policy)
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 502);
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_upstream_status() {
+        use crate::mock_server::{MockResponse, MockServer};
+
+        // the target site returned 503 through the proxy
+        let server = MockServer::start(|idx, _| match idx {
+            0 => MockResponse::new(200, "").header("X-Upstream-Status", "503"),
+            _ => MockResponse::new(200, "ok"),
+        })
+        .await;
+        let client = ProxiedClient::default();
+        let policy = RetryPolicy::new(3, Duration::from_millis(10));
+        let resp = client
+            .send_with_retry(client.get(&server.url("/")), policy.clone())
+            .await
+            .unwrap();
+        assert_eq!(resp.upstream_status(), 200);
+        assert_eq!(server.requests().len(), 2);
+
+        // the proxy itself answered 503 but the target site returned 404
+        let server =
+            MockServer::start(|_, _| MockResponse::new(503, "").header("X-Upstream-Status", "404"))
+                .await;
+        let resp = client
+            .send_with_retry(client.get(&server.url("/")), policy.clone())
+            .await
+            .unwrap();
+        assert_eq!(resp.proxy_status(), 503);
+        assert_eq!(resp.upstream_status(), 404);
+        assert!(resp.has_upstream_status());
+        assert_eq!(server.requests().len(), 1);
+
+        // falls back to the http status
+        let server = MockServer::start(|_, _| MockResponse::new(403, "")).await;
+        let resp = client
+            .send_with_retry(client.get(&server.url("/")), policy)
+            .await
+            .unwrap();
+        assert_eq!(resp.upstream_status(), 403);
+        assert!(!resp.has_upstream_status());
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        let policy = RetryPolicy::new(5, Duration::from_millis(100))
+            .with_jitter(0.0)
+            .with_max_delay(Duration::from_millis(300));
+        assert_eq!(policy.delay(0), Duration::from_millis(100));
+        assert_eq!(policy.delay(1), Duration::from_millis(200));
+        assert_eq!(policy.delay(2), Duration::from_millis(300));
+    }
+
+    #[tokio::test]
+    async fn test_health_check() {
+        use crate::mock_server::{MockResponse, MockServer};
+
+        // an address nobody listens on
+        let dead = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://{}/", listener.local_addr().unwrap())
+        };
+        let client = ProxiedClient::new(&dead, "test-key")
+            .unwrap()
+            .with_health_check(Duration::from_millis(20), 2);
+        let cloned = client.clone();
+        assert!(client.is_proxy_healthy());
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!client.is_proxy_healthy());
+        assert!(!cloned.is_proxy_healthy());
+        let req = cloned.get("https://e-hentai.org/").build().unwrap();
+        assert_eq!(req.url().as_str(), "https://e-hentai.org/");
+
+        let server = MockServer::start(|_, _| MockResponse::new(403, "")).await;
+        let client = ProxiedClient::new(&server.url("/"), "test-key")
+            .unwrap()
+            .with_health_check(Duration::from_millis(20), 1);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(client.is_proxy_healthy());
+        assert!(server.requests().iter().all(|r| r.method == "HEAD"));
+        let req = client.get("https://e-hentai.org/").build().unwrap();
+        assert_eq!(req.url().as_str(), server.url("/"));
+    }
+
+    #[test]
+    fn test_builder() {
+        let mut first = HeaderMap::new();
+        first.insert("X-First", HeaderValue::from_static("1"));
+        first.insert("X-Replaced", HeaderValue::from_static("old"));
+        let mut second = HeaderMap::new();
+        second.insert("X-Replaced", HeaderValue::from_static("new"));
+
+        let client = ProxiedClient::builder()
+            .with_proxy(Proxy::new("https://proxy.example.com/", "test-key").unwrap())
+            .with_timeout(Duration::from_secs(60))
+            .with_default_headers(first)
+            .with_default_headers(second)
+            .build()
+            .unwrap();
+        assert_eq!(client.config.timeout, Duration::from_secs(60));
+        assert_eq!(client.config.headers["X-First"], "1");
+        assert_eq!(client.config.headers["X-Replaced"], "new");
+
+        // headers set after construction are merged and timeout is kept
+        let mut third = HeaderMap::new();
+        third.insert("X-Third", HeaderValue::from_static("3"));
+        let client = client.with_default_headers(third);
+        assert_eq!(client.config.timeout, Duration::from_secs(60));
+        assert_eq!(client.config.headers.len(), 3);
+        let req = client.get("https://e-hentai.org/").build().unwrap();
+        assert_eq!(req.url().as_str(), "https://proxy.example.com/");
+    }
+
+    #[test]
+    fn test_auth_scheme() {
+        let cfg: ProxyConfig = serde_yaml::from_str("auth_scheme: bearer").unwrap();
+        assert_eq!(cfg.auth_scheme, AuthScheme::Bearer);
+        let cfg: ProxyConfig = serde_yaml::from_str("endpoint: x").unwrap();
+        assert_eq!(cfg.auth_scheme, AuthScheme::CustomHeader);
+
+        for (scheme, authorization, expected) in [
+            (
+                AuthScheme::CustomHeader,
+                "test-key",
+                (AUTH_HEADER, "test-key"),
+            ),
+            (
+                AuthScheme::Bearer,
+                "test-key",
+                ("authorization", "Bearer test-key"),
+            ),
+            // base64 of user:pass
+            (
+                AuthScheme::Basic,
+                "user:pass",
+                ("authorization", "Basic dXNlcjpwYXNz"),
+            ),
+        ] {
+            let proxy = Proxy::new("https://proxy.example.com/", authorization)
+                .unwrap()
+                .with_auth_scheme(scheme)
+                .unwrap();
+            let client = ProxiedClient::builder().with_proxy(proxy).build().unwrap();
+            let req = client.get("https://e-hentai.org/").build().unwrap();
+            let (header, value) = expected;
+            assert_eq!(req.headers()[header], value, "{scheme:?}");
+            assert_eq!(req.headers()[FORWARD_HEADER], "https://e-hentai.org/");
+            if scheme != AuthScheme::CustomHeader {
+                assert!(req.headers().get(AUTH_HEADER).is_none());
+            }
+        }
+
+        let yaml =
+            "endpoint: https://proxy.example.com/\nauthorization: test-key\nauth_scheme: basic";
+        let err =
+            ProxiedClient::from_proxy_config(serde_yaml::from_str(yaml).unwrap()).unwrap_err();
+        assert!(matches!(err, ProxyError::BasicAuth));
+    }
+
+    #[test]
+    fn test_custom_header_names() {
+        let yaml = "forward_header: X-Target-Url\nauth_header: X-Proxy-Key";
+        let cfg: ProxyConfig = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(cfg.forward_header.as_deref(), Some("X-Target-Url"));
+        assert_eq!(cfg.auth_header.as_deref(), Some("X-Proxy-Key"));
+
+        let proxy = Proxy::new("https://proxy.example.com/", "test-key")
+            .unwrap()
+            .with_header_names("X-Target-Url", "X-Proxy-Key")
+            .unwrap();
+        let client = ProxiedClient::builder().with_proxy(proxy).build().unwrap();
+        for req in [
+            client.get("https://e-hentai.org/"),
+            client.post("https://e-hentai.org/"),
+            client.request(reqwest::Method::PUT, "https://e-hentai.org/"),
+        ] {
+            let req = req.build().unwrap();
+            assert_eq!(req.headers()["X-Target-Url"], "https://e-hentai.org/");
+            assert_eq!(req.headers()["X-Proxy-Key"], "test-key");
+            assert!(req.headers().get(FORWARD_HEADER).is_none());
+            assert!(req.headers().get(AUTH_HEADER).is_none());
+        }
+
+        let err = Proxy::new("https://proxy.example.com/", "test-key")
+            .unwrap()
+            .with_header_names("bad header", "X-Proxy-Key")
+            .unwrap_err();
+        assert!(matches!(err, ProxyError::HeaderName(_)));
+    }
+
+    #[tokio::test]
+    async fn test_request_with_headers() {
+        use crate::mock_server::{MockResponse, MockServer};
+
+        let server = MockServer::start(|_, _| MockResponse::new(200, "ok")).await;
+        let client = ProxiedClient::new(&server.url("/"), "test-key").unwrap();
+        let mut extra = HeaderMap::new();
+        extra.insert(
+            reqwest::header::REFERER,
+            HeaderValue::from_static("https://e-hentai.org/g/1/x/"),
+        );
+        // not able to redirect or unauthorize the request
+        extra.insert(
+            FORWARD_HEADER,
+            HeaderValue::from_static("https://evil.com/"),
+        );
+        extra.insert(AUTH_HEADER, HeaderValue::from_static("other-key"));
+        let resp = client
+            .send(client.get_with_headers("https://ehgt.org/1.jpg", extra))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 200);
+
+        let requests = server.requests();
+        let req = &requests[0];
+        assert_eq!(req.header(FORWARD_HEADER), Some("https://ehgt.org/1.jpg"));
+        assert_eq!(req.header(AUTH_HEADER), Some("test-key"));
+        assert_eq!(req.header("referer"), Some("https://e-hentai.org/g/1/x/"));
+        let forwarded = req.headers.iter().filter(|(k, _)| k == FORWARD_HEADER);
+        assert_eq!(forwarded.count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_get_range() {
+        use crate::mock_server::{MockResponse, MockServer};
+
+        const BODY: &[u8] = b"0123456789";
+        let server = MockServer::start(|_, req| {
+            let range = req.header("range").and_then(|r| r.strip_prefix("bytes="));
+            let Some((start, end)) = range.and_then(|r| r.split_once('-')) else {
+                return MockResponse::new(200, BODY);
+            };
+            let (start, end): (usize, usize) = (start.parse().unwrap(), end.parse().unwrap());
+            MockResponse::new(206, &BODY[start..=end]).header(
+                "content-range",
+                &format!("bytes {start}-{end}/{}", BODY.len()),
+            )
+        })
+        .await;
+        let ignoring = MockServer::start(|_, _| MockResponse::new(200, BODY)).await;
+
+        // directly and through the proxy
+        let direct = ProxiedClient::default();
+        let proxy = ProxiedClient::new(&server.url("/"), "token").unwrap();
+        for resp in [
+            direct.send(direct.get_range(&server.url("/a.jpg"), 2..6).unwrap()),
+            proxy.send(proxy.get_range("https://ehgt.org/a.jpg", 2..6).unwrap()),
+        ] {
+            let resp = resp.await.unwrap();
+            assert_eq!(resp.status(), 206);
+            assert_eq!(resp.headers()["content-range"], "bytes 2-5/10");
+            assert_eq!(&resp.bytes().await.unwrap()[..], b"2345");
+        }
+        let requests = server.requests();
+        assert_eq!(requests[1].header("range"), Some("bytes=2-5"));
+        assert_eq!(
+            requests[1].header("x-forwarded-for"),
+            Some("https://ehgt.org/a.jpg")
+        );
+
+        let resp = direct
+            .send(direct.get_range(&ignoring.url("/a.jpg"), 0..4).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 200);
+        assert_eq!(&resp.bytes().await.unwrap()[..], BODY);
+
+        // no bogus request for an empty one
+        assert!(matches!(
+            direct.get_range(&ignoring.url("/a.jpg"), 4..4),
+            Err(ProxyError::EmptyRange(r)) if r == (4..4)
+        ));
+    }
+
+    #[test]
+    fn test_direct_request() {
+        let client = ProxiedClient::new("https://proxy.example.com/", "test-key").unwrap();
+        for req in [
+            client.get_direct("https://telegra.ph/"),
+            client.post_direct("https://telegra.ph/"),
+            client.request_direct(reqwest::Method::HEAD, "https://telegra.ph/"),
+        ] {
+            let req = req.build().unwrap();
+            assert_eq!(req.url().as_str(), "https://telegra.ph/");
+            assert!(req.headers().get(FORWARD_HEADER).is_none());
+            assert!(req.headers().get(AUTH_HEADER).is_none());
+        }
+    }
+
+    #[test]
+    fn test_no_proxy() {
+        let yaml = "endpoint: https://proxy.example.com/\nauthorization: test-key\nno_proxy:\n  - Telegra.ph\n  - \"*.telegram.org\"";
+        let cfg: ProxyConfig = serde_yaml::from_str(yaml).unwrap();
+        let client = ProxiedClient::from_proxy_config(cfg).unwrap();
+        assert_eq!(*client.no_proxy, ["telegra.ph", ".telegram.org"]);
+        for url in [
+            "https://telegra.ph/upload",
+            "https://TELEGRA.PH./file/a.jpg",
+            "https://api.telegram.org/bot",
+            "https://telegram.org/",
+        ] {
+            let req = client.post(url).build().unwrap();
+            assert_eq!(
+                req.url().as_str(),
+                reqwest::Url::parse(url).unwrap().as_str()
+            );
+            assert!(req.headers().get(FORWARD_HEADER).is_none());
+            assert!(req.headers().get(AUTH_HEADER).is_none());
+        }
+        // exact hosts do not match subdomains, suffixes match on labels only
+        for url in [
+            "https://e-hentai.org/g/1/abc/",
+            "https://img.telegra.ph/a.jpg",
+            "https://nottelegram.org/",
+        ] {
+            let req = client
+                .get_with_headers(url, HeaderMap::new())
+                .build()
+                .unwrap();
+            assert_eq!(req.url().as_str(), "https://proxy.example.com/");
+            assert_eq!(req.headers()[FORWARD_HEADER], url);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_middleware() {
+        use crate::mock_server::{MockResponse, MockServer};
+
+        let server = MockServer::start(|_, _| MockResponse::new(200, "")).await;
+        let client = ProxiedClient::new(&server.url("/"), "token")
+            .unwrap()
+            .with_middleware(StaticHeader {
+                name: HeaderName::from_static("x-deployment"),
+                value: HeaderValue::from_static("eu-1"),
+            })
+            // sees the headers set before it
+            .with_middleware(|req: reqwest::RequestBuilder| {
+                let (client, req) = req.build_split();
+                let req = req.unwrap();
+                let signed = req.headers().contains_key("x-deployment")
+                    && req.headers().contains_key(AUTH_HEADER);
+                reqwest::RequestBuilder::from_parts(client, req)
+                    .header("x-signed", signed.to_string())
+            });
+        let clone = client.clone();
+        let mut referer = HeaderMap::new();
+        referer.insert(
+            reqwest::header::REFERER,
+            HeaderValue::from_static("https://e-hentai.org/"),
+        );
+        for req in [
+            client.get("https://e-hentai.org/g/1/x/"),
+            client.get_with_headers("https://e-hentai.org/g/1/x/", referer),
+            clone.post(&server.url("/upload")),
+        ] {
+            req.send().await.unwrap();
+        }
+        let direct = client.get_direct(&server.url("/direct"));
+        direct.send().await.unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 4);
+        for req in &requests {
+            assert_eq!(req.header("x-deployment"), Some("eu-1"), "{}", req.path);
+        }
+        let signed = requests
+            .iter()
+            .map(|r| r.header("x-signed").unwrap())
+            .collect::<Vec<_>>();
+        // no proxy authorization on the direct one
+        assert_eq!(signed, ["true", "true", "true", "false"]);
+        assert_eq!(requests[1].header("referer"), Some("https://e-hentai.org/"));
+    }
+
+    #[test]
+    fn test_user_agent_pool() {
+        let yaml = "user_agents:\n  - \"UA-1\"\n  - \"UA-2\"";
+        let cfg: ProxyConfig = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(cfg.user_agents, vec!["UA-1", "UA-2"]);
+
+        // no pool, no User-Agent set per request
+        let client = ProxiedClient::new("https://proxy.example.com/", "test-key").unwrap();
+        let req = client.get("https://e-hentai.org/").build().unwrap();
+        assert!(req.headers().get(reqwest::header::USER_AGENT).is_none());
+
+        let agents = vec![
+            "UA-1".to_string(),
+            "UA-2".to_string(),
+            "bad\nUA".to_string(),
+        ];
+        let client = client.with_user_agent_pool(agents);
+        assert_eq!(client.user_agents.len(), 2);
+        let mut seen = std::collections::HashSet::new();
+        for _ in 0..64 {
+            let req = client.get("https://e-hentai.org/").build().unwrap();
+            seen.insert(req.headers()[reqwest::header::USER_AGENT].clone());
+        }
+        assert_eq!(seen.len(), 2);
+
+        let client = ProxiedClient::builder()
+            .with_user_agent_pool(vec!["UA-1".to_string()])
+            .build()
+            .unwrap();
+        let req = client.get_direct("https://e-hentai.org/").build().unwrap();
+        assert_eq!(req.headers()[reqwest::header::USER_AGENT], "UA-1");
+
+        // the pool is not overridden by HttpRequestBuilder
+        use crate::http_client::HttpRequestBuilder;
+        let req = client.get_builder("https://e-hentai.org/").build().unwrap();
+        let uas: Vec<_> = req
+            .headers()
+            .get_all(reqwest::header::USER_AGENT)
+            .iter()
+            .collect();
+        assert_eq!(uas, ["UA-1"]);
+        let req = ProxiedClient::default()
+            .get_builder("https://e-hentai.org/")
+            .build()
+            .unwrap();
+        assert!(req.headers().contains_key(reqwest::header::USER_AGENT));
+    }
+
+    #[tokio::test]
+    async fn test_metrics() {
+        use crate::mock_server::{MockResponse, MockServer};
+
+        let server = MockServer::start(|_, _| MockResponse::new(200, "ok")).await;
+        let dead = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://{}/", listener.local_addr().unwrap())
+        };
+
+        let client = ProxiedClient::new(&dead, "test-key").unwrap();
+        let cloned = client.clone();
+        assert!(client.send(client.get(&server.url("/"))).await.is_err());
+        let resp = cloned
+            .send(cloned.get_direct(&server.url("/")))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 200);
+
+        let proxy = ProxiedClient::new(&server.url("/"), "test-key").unwrap();
+        proxy
+            .send(proxy.get("https://e-hentai.org/"))
+            .await
+            .unwrap();
+        assert_eq!(
+            server.requests()[1].header(FORWARD_HEADER),
+            Some("https://e-hentai.org/")
+        );
+
+        assert_eq!(
+            client.metrics(),
+            ProxyMetrics {
+                total_requests: 2,
+                proxy_errors: 1,
+                direct_requests: 1,
+            }
+        );
+        assert_eq!(proxy.metrics().total_requests, 1);
+        assert_eq!(proxy.metrics().direct_requests, 0);
+    }
+
+    #[test]
+    fn test_authorization_env() {
+        let cfg: ProxyConfig = serde_yaml::from_str("authorization: test-key").unwrap();
+        assert_eq!(cfg.authorization().unwrap(), "test-key");
+
+        std::env::set_var("EH2TG_TEST_PROXY_AUTH", "env-key");
+        let cfg: ProxyConfig =
+            serde_yaml::from_str("authorization: \"${EH2TG_TEST_PROXY_AUTH}\"").unwrap();
+        assert_eq!(cfg.authorization().unwrap(), "env-key");
+        let cfg: ProxyConfig = serde_yaml::from_str(
+            "authorization: ignored\nauthorization_env: EH2TG_TEST_PROXY_AUTH",
+        )
+        .unwrap();
+        assert_eq!(cfg.authorization().unwrap(), "env-key");
+
+        let cfg: ProxyConfig =
+            serde_yaml::from_str("authorization: \"${EH2TG_TEST_PROXY_AUTH_MISSING}\"").unwrap();
+        let err = cfg.authorization().unwrap_err();
+        assert!(
+            matches!(err, ProxyError::MissingEnv(ref var) if var == "EH2TG_TEST_PROXY_AUTH_MISSING")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_max_concurrent() {
+        use crate::mock_server::{MockResponse, MockServer};
+
+        let cfg: ProxyConfig = serde_yaml::from_str("max_concurrent: 2").unwrap();
+        assert_eq!(cfg.max_concurrent, Some(2));
+
+        let server =
+            MockServer::start(|_, _| MockResponse::new(200, "ok").delay(Duration::from_millis(50)))
+                .await;
+        let client = ProxiedClient::builder()
+            .with_max_concurrent(2)
+            .build()
+            .unwrap();
+        let url = server.url("/");
+        let tasks: Vec<_> = (0..8)
+            .map(|_| {
+                let client = client.clone();
+                let url = url.clone();
+                tokio::spawn(async move { client.acquire_and_send(client.get(&url)).await })
+            })
+            .collect();
+        for task in futures::future::join_all(tasks).await {
+            assert_eq!(task.unwrap().unwrap().status(), 200);
+        }
+        assert_eq!(server.requests().len(), 8);
+        assert_eq!(server.max_in_flight(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_download_progress() {
+        use crate::mock_server::{MockResponse, MockServer};
+
+        let server = MockServer::start(|_, _| {
+            MockResponse::new(200, vec![1; 4096]).chunked(1024, Duration::from_millis(2))
+        })
+        .await;
+        let client = ProxiedClient::default();
+        let chunks = Arc::new(parking_lot::Mutex::new(Vec::new()));
+        let progress = DownloadProgress::new({
+            let chunks = chunks.clone();
+            move |len| chunks.lock().push(len)
+        });
+        with_download_progress(progress, async {
+            crate::util::get_bytes(&client, &server.url("/a"))
+                .await
+                .unwrap();
+            client
+                .download_to_writer(&server.url("/b"), Vec::new(), |_, _| {})
+                .await
+                .unwrap();
+            // spawned ones report only when propagated
+            let (c, url) = (client.clone(), server.url("/c"));
+            tokio::spawn(propagate_scope(async move {
+                crate::util::get_bytes(&c, &url).await.unwrap();
+            }))
+            .await
+            .unwrap();
+            let (c, url) = (client.clone(), server.url("/d"));
+            tokio::spawn(async move { crate::util::get_bytes(&c, &url).await.unwrap() })
+                .await
+                .unwrap();
+        })
+        .await;
+        crate::util::get_bytes(&client, &server.url("/e"))
+            .await
+            .unwrap();
+
+        let chunks = chunks.lock();
+        assert_eq!(chunks.iter().sum::<u64>(), 3 * 4096);
+        assert!(chunks.len() > 3);
+    }
+
+    #[tokio::test]
+    async fn test_download_to_writer() {
+        use crate::mock_server::{MockResponse, MockServer};
+
+        let body: Vec<u8> = (0..64 * 1024).map(|i| i as u8).collect();
+        let expected = body.clone();
+        let server = MockServer::start(move |_, req| match req.path.as_str() {
+            "/missing" => MockResponse::new(404, ""),
+            _ => MockResponse::new(200, body.clone()),
+        })
+        .await;
+
+        let direct = ProxiedClient::default();
+        let proxy = ProxiedClient::new(&server.url("/"), "test-key").unwrap();
+        for (client, url) in [
+            (&direct, server.url("/image.jpg")),
+            (&proxy, "https://e-hentai.org/image.jpg".to_string()),
+        ] {
+            let mut out = Vec::new();
+            let mut calls = Vec::new();
+            let n = client
+                .download_to_writer(&url, &mut out, |done, total| calls.push((done, total)))
+                .await
+                .unwrap();
+            assert_eq!(n, expected.len() as u64);
+            assert_eq!(out, expected);
+            assert_eq!(calls.last(), Some(&(n, Some(n))));
+            assert!(calls.windows(2).all(|w| w[0].0 < w[1].0));
+        }
+
+        let err = direct
+            .download_to_writer(&server.url("/missing"), Vec::new(), |_, _| {})
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ProxyError::Reqwest(_)));
+    }
+
+    #[tokio::test]
+    async fn test_download_resumable() {
+        use crate::mock_server::{MockResponse, MockServer};
+
+        let body: Vec<u8> = (0..64 * 1024).map(|i| i as u8).collect();
+        let expected = body.clone();
+        let server = MockServer::start(move |idx, req| {
+            let etag = if idx < 2 { "\"v1\"" } else { "\"v2\"" };
+            let body = match idx {
+                0..=1 => body.clone(),
+                _ => body.iter().rev().copied().collect(),
+            };
+            let range = req.header("range").and_then(|r| r.strip_prefix("bytes="));
+            let start = range.and_then(|r| r.trim_end_matches('-').parse::<usize>().ok());
+            match (idx, start) {
+                // fails halfway
+                (0, _) => MockResponse::new(200, body).header("etag", etag).cut(40000),
+                (1 | 2, Some(start)) => {
+                    let end = body.len() - 1;
+                    MockResponse::new(206, body[start..].to_vec())
+                        .header("etag", etag)
+                        .header(
+                            "content-range",
+                            &format!("bytes {start}-{end}/{}", body.len()),
+                        )
+                }
+                _ => MockResponse::new(200, body).header("etag", etag),
+            }
+        })
+        .await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("image.jpg");
+        let client = ProxiedClient::default();
+        let url = server.url("/image.jpg");
+        assert!(client
+            .download_resumable(&url, &path, |_, _| {})
+            .await
+            .is_err());
+        let partial = std::fs::metadata(&path).unwrap().len();
+        assert!(partial > 0 && partial < expected.len() as u64);
+
+        let mut calls = Vec::new();
+        let n = client
+            .download_resumable(&url, &path, |done, total| calls.push((done, total)))
+            .await
+            .unwrap();
+        assert_eq!(n, expected.len() as u64);
+        assert_eq!(std::fs::read(&path).unwrap(), expected);
+        assert!(calls[0].0 > partial);
+        assert_eq!(calls.last(), Some(&(n, Some(n))));
+        assert!(!validator_path(&path).exists());
+        let resumed = &server.requests()[1];
+        assert_eq!(
+            resumed.header("range"),
+            Some(format!("bytes={partial}-").as_str())
+        );
+        assert_eq!(resumed.header("if-range"), Some("\"v1\""));
+
+        // the file changed, so the range of the new one is not appended
+        std::fs::write(&path, &expected[..1000]).unwrap();
+        std::fs::write(validator_path(&path), "\"v1\"").unwrap();
+        client
+            .download_resumable(&url, &path, |_, _| {})
+            .await
+            .unwrap();
+        let changed: Vec<u8> = expected.iter().rev().copied().collect();
+        assert_eq!(std::fs::read(&path).unwrap(), changed);
+        assert_eq!(server.requests()[3].header("range"), None);
+    }
+
+    #[tokio::test]
+    async fn test_head_status_and_headers() {
+        use crate::mock_server::{MockResponse, MockServer};
+
+        let server = MockServer::start(|_, req| match req.path.as_str() {
+            "/missing" => MockResponse::new(404, ""),
+            _ => MockResponse::new(200, "hello").header("content-type", "image/jpeg"),
+        })
+        .await;
+        let direct = ProxiedClient::default();
+        let proxy = ProxiedClient::new(&server.url("/"), "test-key").unwrap();
+        for (client, url) in [
+            (&direct, server.url("/image.jpg")),
+            (&proxy, "https://e-hentai.org/image.jpg".to_string()),
+        ] {
+            let (status, headers) = client.head_status_and_headers(&url).await.unwrap();
+            assert_eq!(status, reqwest::StatusCode::OK);
+            assert_eq!(headers[reqwest::header::CONTENT_TYPE], "image/jpeg");
+            assert_eq!(headers[reqwest::header::CONTENT_LENGTH], "5");
+        }
+        let (status, _) = direct
+            .head_status_and_headers(&server.url("/missing"))
+            .await
+            .unwrap();
+        assert_eq!(status, reqwest::StatusCode::NOT_FOUND);
+
+        let requests = server.requests();
+        assert!(requests.iter().all(|r| r.method == "HEAD"));
+        assert_eq!(
+            requests[1].header("x-forwarded-for"),
+            Some("https://e-hentai.org/image.jpg")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_timeout() {
+        use crate::mock_server::{MockResponse, MockServer};
+
+        let cfg: ProxyConfig =
+            serde_yaml::from_str("read_timeout: 5\ndownload_timeout: 60").unwrap();
+        let client = ProxiedClient::from_proxy_config(cfg).unwrap();
+        assert_eq!(client.config.read_timeout, Duration::from_secs(5));
+        assert_eq!(client.config.download_timeout, Duration::from_secs(60));
+
+        let server = MockServer::start(|_, req| match req.path.as_str() {
+            "/steady" => {
+                MockResponse::new(200, vec![1; 1000]).chunked(100, Duration::from_millis(50))
+            }
+            "/stalled" => {
+                MockResponse::new(200, vec![1; 1000]).chunked(500, Duration::from_secs(5))
+            }
+            _ => MockResponse::new(200, vec![1; 1000]).delay(Duration::from_secs(5)),
+        })
+        .await;
+        // the whole download takes longer than the overall timeout
+        let client = ProxiedClient::builder()
+            .with_timeout(Duration::from_millis(200))
+            .with_read_timeout(Duration::from_millis(300))
+            .build()
+            .unwrap();
+        let n = client
+            .download_to_writer(&server.url("/steady"), Vec::new(), |_, _| {})
+            .await
+            .unwrap();
+        assert_eq!(n, 1000);
+
+        for path in ["/stalled", "/headers"] {
+            let start = std::time::Instant::now();
+            let err = client
+                .download_to_writer(&server.url(path), Vec::new(), |_, _| {})
+                .await
+                .unwrap_err();
+            assert!(matches!(err, ProxyError::Stalled(_)), "{err}");
+            assert!(start.elapsed() < Duration::from_secs(2));
+        }
+
+        // the ceiling still applies to a steady download
+        let client = ProxiedClient::builder()
+            .with_read_timeout(Duration::from_secs(1))
+            .with_download_timeout(Duration::from_millis(200))
+            .build()
+            .unwrap();
+        let err = client
+            .download_to_writer(&server.url("/steady"), Vec::new(), |_, _| {})
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, ProxyError::Reqwest(ref e) if e.is_timeout()),
+            "{err}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        use crate::mock_server::{MockResponse, MockServer};
+
+        let cfg: ProxyConfig = serde_yaml::from_str("rate_limit_bytes_per_sec: 1024").unwrap();
+        assert_eq!(cfg.rate_limit_bytes_per_sec, 1024);
+        assert!(ProxiedClient::default()
+            .with_rate_limit(0)
+            .rate_limit
+            .is_none());
+
+        let server = MockServer::start(|_, _| MockResponse::new(200, vec![0; 20_000])).await;
+        let client = ProxiedClient::builder()
+            .with_rate_limit(20_000)
+            .build()
+            .unwrap();
+        let (url, cloned) = (server.url("/"), client.clone());
+        // the first second is a burst, the second one is limited
+        let start = std::time::Instant::now();
+        let (a, b) = tokio::join!(
+            client.download_to_writer(&url, Vec::new(), |_, _| {}),
+            cloned.download_to_writer(&url, Vec::new(), |_, _| {})
+        );
+        assert_eq!(a.unwrap() + b.unwrap(), 40_000);
+        assert!(start.elapsed() >= Duration::from_millis(800));
+    }
+
+    #[tokio::test]
+    async fn test_cookie_store() {
+        use crate::mock_server::{MockResponse, MockServer};
+
+        let yaml = "cookies:\n  \"https://exhentai.org\":\n    igneous: abc";
+        let cfg: ProxyConfig = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(cfg.cookies["https://exhentai.org"]["igneous"], "abc");
+
+        let server = MockServer::start(|idx, _| match idx {
+            0 => MockResponse::new(200, "").header("Set-Cookie", "session=1; Path=/"),
+            _ => MockResponse::new(200, ""),
+        })
+        .await;
+        let client = ProxiedClient::default().with_cookie_store(true);
+        client
+            .send(client.get(&server.url("/login")))
+            .await
+            .unwrap();
+        client.send(client.get(&server.url("/g/1"))).await.unwrap();
+        let requests = server.requests();
+        assert_eq!(requests[0].header("cookie"), None);
+        assert_eq!(requests[1].header("cookie"), Some("session=1"));
+
+        // seeded cookies of the target are sent to the proxy endpoint
+        let cookies = HashMap::from([("igneous".to_string(), "abc".to_string())]);
+        let proxy = ProxiedClient::new("https://proxy.example.com/", "test-key")
+            .unwrap()
+            .with_cookies("https://exhentai.org", &cookies)
+            .unwrap();
+        let req = proxy.get("https://exhentai.org/g/1/2").build().unwrap();
+        assert_eq!(req.headers()[reqwest::header::COOKIE], "igneous=abc");
+        let req = proxy.get("https://e-hentai.org/g/1/2").build().unwrap();
+        assert!(req.headers().get(reqwest::header::COOKIE).is_none());
+
+        let err = ProxiedClient::builder()
+            .with_cookies("not a url", &cookies)
+            .unwrap_err();
+        assert!(matches!(err, ProxyError::CookieUrl(_)));
+    }
+
+    #[tokio::test]
+    async fn test_redirect_policy() {
+        use crate::mock_server::{MockResponse, MockServer};
+
+        let cfg: ProxyConfig = serde_yaml::from_str("").unwrap();
+        assert_eq!(cfg.max_redirects, 10);
+        let cfg: ProxyConfig = serde_yaml::from_str("max_redirects: 0").unwrap();
+        assert_eq!(cfg.max_redirects, 0);
+
+        let server = MockServer::start(|_, req| match req.path.as_str() {
+            "/g/1" => MockResponse::new(301, "").header("Location", "/login"),
+            _ => MockResponse::new(200, "login"),
+        })
+        .await;
+
+        let client = ProxiedClient::default();
+        let resp = client.send(client.get(&server.url("/g/1"))).await.unwrap();
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.text().await.unwrap(), "login");
+
+        let client = client.with_redirect_policy(0);
+        let resp = client.send(client.get(&server.url("/g/1"))).await.unwrap();
+        assert_eq!(resp.status(), 301);
+        assert_eq!(resp.headers()[reqwest::header::LOCATION], "/login");
+    }
+
+    #[test]
+    fn test_pool_options() {
+        let client = ProxiedClient::builder()
+            .with_pool_idle_timeout(Duration::from_secs(30))
+            .with_pool_max_idle_per_host(4)
+            .with_http2_prior_knowledge(true)
+            .build()
+            .unwrap();
+        assert_eq!(
+            client.config.pool_idle_timeout,
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(client.config.pool_max_idle_per_host, Some(4));
+        assert!(client.config.http2_prior_knowledge);
+
+        // kept after rebuilding
+        let client = client
+            .with_timeout(Duration::from_secs(10))
+            .with_http2_prior_knowledge(false);
+        assert_eq!(client.config.pool_max_idle_per_host, Some(4));
+        assert!(!client.config.http2_prior_knowledge);
+    }
+
+    #[test]
+    fn test_or_direct() {
+        let build = |yaml: &str| {
+            ProxiedClient::from_proxy_config(serde_yaml::from_str(yaml).unwrap()).map(Some)
+        };
+        let direct = ProxiedClient::or_direct(build("endpoint: https://proxy.example.com/"));
+        assert!(direct.proxies.load().is_empty());
+        assert!(ProxiedClient::or_direct(Ok(None)).proxies.load().is_empty());
+        let invalid = std::panic::catch_unwind(|| {
+            ProxiedClient::or_direct(build(
+                "endpoint: ftp://proxy.example.com/\nauthorization: xxx",
+            ))
+        });
+        assert!(invalid.is_err());
+    }
+
+    #[test]
+    fn test_from_proxy_config() {
+        let parse =
+            |yaml: &str| ProxiedClient::from_proxy_config(serde_yaml::from_str(yaml).unwrap());
+
+        let client =
+            parse("endpoint: \"https://proxy.example.com/\"\nauthorization: test-key").unwrap();
+        assert_eq!(client.proxies.load().len(), 1);
+
+        // neither is set, connect directly with other options
+        let client = parse("timeout: 60").unwrap();
+        assert!(client.proxies.load().is_empty());
+        assert_eq!(client.config.timeout, Duration::from_secs(60));
+
+        let err = parse("endpoint: \"https://proxy.example.com/\"").unwrap_err();
+        assert!(matches!(
+            err,
+            ProxyError::IncompleteConfig {
+                endpoint_set: true,
+                auth_set: false
+            }
+        ));
+        let err = parse("authorization: test-key").unwrap_err();
+        assert!(matches!(
+            err,
+            ProxyError::IncompleteConfig {
+                endpoint_set: false,
+                auth_set: true
+            }
+        ));
+    }
+
+    #[test]
+    fn test_endpoint_scheme() {
+        for endpoint in [
+            "ftp://proxy.example.com/",
+            "proxy.example.com",
+            "proxy.example.com:8080",
+        ] {
+            let err = Proxy::new(endpoint, "test-key").unwrap_err();
+            assert!(matches!(err, ProxyError::InvalidScheme(ref e) if e == endpoint));
+        }
+        let proxy = Proxy::new("https://proxy.example.com", "test-key").unwrap();
+        assert_eq!(proxy.endpoint.as_str(), "https://proxy.example.com/");
+        assert!(Proxy::new("http://127.0.0.1:8080/forward", "test-key").is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_circuit_breaker() {
+        use crate::mock_server::{MockResponse, MockServer};
+
+        let yaml = "circuit_breaker:\n  failure_threshold: 2";
+        let cfg: ProxyConfig = serde_yaml::from_str(yaml).unwrap();
+        let c = cfg.circuit_breaker.unwrap();
+        assert_eq!((c.failure_threshold, c.window, c.cooldown), (2, 60, 30));
+
+        let server = MockServer::start(|idx, _| match idx {
+            0 | 1 => MockResponse::new(503, ""),
+            _ => MockResponse::new(200, "ok"),
+        })
+        .await;
+        let client = ProxiedClient::default().with_circuit_breaker(
+            2,
+            Duration::from_secs(10),
+            Duration::from_millis(100),
+        );
+        let url = server.url("/");
+        let once = || RetryPolicy::new(1, Duration::ZERO);
+        assert_eq!(client.circuit_state(), Some(CircuitState::Closed));
+        for _ in 0..2 {
+            let resp = client.send_with_retry(client.get(&url), once()).await;
+            assert_eq!(resp.unwrap().status(), 503);
+        }
+        assert_eq!(client.circuit_state(), Some(CircuitState::Open));
+        let err = client
+            .send_with_retry(client.get(&url), RetryPolicy::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ProxyError::CircuitOpen));
+        assert_eq!(server.requests().len(), 2);
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(client.circuit_state(), Some(CircuitState::HalfOpen));
+        let resp = client.send_with_retry(client.get(&url), once()).await;
+        assert_eq!(resp.unwrap().status(), 200);
+        assert_eq!(client.circuit_state(), Some(CircuitState::Closed));
+    }
+
+    #[tokio::test]
+    async fn test_retry_budget() {
+        use crate::mock_server::{MockResponse, MockServer};
+
+        let cfg: ProxyConfig = serde_yaml::from_str("retry_budget:\n  size: 5").unwrap();
+        let b = cfg.retry_budget.unwrap();
+        assert_eq!((b.size, b.refill_per_sec), (5, 1.0));
+
+        let server = MockServer::start(|_, _| MockResponse::new(503, "")).await;
+        let client = ProxiedClient::default().with_retry_budget(2, 0.0);
+        let url = server.url("/");
+        let policy = RetryPolicy::new(5, Duration::ZERO);
+        let resp = client
+            .send_with_retry(client.get(&url), policy.clone())
+            .await;
+        assert_eq!(resp.unwrap().status(), 503);
+        assert_eq!(server.requests().len(), 3);
+        assert_eq!(client.retry_budget().unwrap().remaining(), 0);
+        assert!(client
+            .clone()
+            .without_retry_budget()
+            .retry_budget()
+            .is_none());
+        assert!(client.retry_budget().is_some());
+
+        // drained, so the last failure is returned at once, also by clones
+        let clone = client.clone();
+        let resp = clone.send_with_retry(clone.get(&url), policy).await;
+        assert_eq!(resp.unwrap().status(), 503);
+        assert_eq!(server.requests().len(), 4);
+
+        let budget = RetryBudget::new(1, 50.0);
+        assert!(budget.try_acquire());
+        assert!(!budget.try_acquire());
+        tokio::time::sleep(Duration::from_millis(40)).await;
+        assert_eq!(budget.remaining(), 1);
+        assert!(!RetryBudget::new(0, 1.0).try_acquire());
+    }
+
+    #[test]
+    fn test_client_identity() {
+        let der = include_bytes!("testdata/client.p12");
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Test", HeaderValue::from_static("1"));
+        let client = ProxiedClient::new("https://proxy.example.com/", "test-key")
+            .unwrap()
+            .with_timeout(Duration::from_secs(60))
+            .with_default_headers(headers)
+            .with_client_identity(der, "test")
+            .unwrap();
+        assert!(client.config.identity.is_some());
+        assert_eq!(client.config.timeout, Duration::from_secs(60));
+        assert!(client.config.headers.contains_key("X-Test"));
+
+        let err = ProxiedClient::builder()
+            .with_client_identity(der, "wrong")
+            .unwrap_err();
+        assert!(matches!(err, ProxyError::ClientIdentity(_)));
+
+        let cfg: ProxyConfig = serde_yaml::from_str("client_cert_path: /not/exist.p12").unwrap();
+        let err = ProxiedClient::from_proxy_config(cfg).unwrap_err();
+        assert!(matches!(err, ProxyError::Io(_)));
+    }
+
+    #[test]
+    fn test_root_certificates() {
+        let pem = include_bytes!("testdata/cert.pem");
+        let client = ProxiedClient::default().with_root_certificate(pem).unwrap();
+        assert_eq!(client.config.root_certs.len(), 1);
+        assert!(client.config.built_in_roots);
+
+        let client = ProxiedClient::builder()
+            .with_pinned_certificates(&[pem, pem])
+            .unwrap()
+            .danger_accept_invalid_certs(true)
+            .build()
+            .unwrap();
+        assert_eq!(client.config.root_certs.len(), 2);
+        assert!(!client.config.built_in_roots);
+        assert!(client.config.accept_invalid_certs);
+
+        let err = ProxiedClient::default()
+            .with_root_certificate(b"not a certificate")
+            .unwrap_err();
+        assert!(matches!(err, ProxyError::Certificate(_)));
+        let err = ProxiedClient::builder()
+            .with_pinned_certificates(&[])
+            .unwrap_err();
+        assert!(matches!(err, ProxyError::Certificate(_)));
+
+        let cfg: ProxyConfig =
+            serde_yaml::from_str("root_cert_paths:\n  - /not/exist.pem").unwrap();
+        let err = ProxiedClient::from_proxy_config(cfg).unwrap_err();
+        assert!(matches!(err, ProxyError::Io(_)));
+    }
+
+    #[test]
+    fn test_reload_proxies() {
+        let client = ProxiedClient::new("https://a.example.com/", "key-a").unwrap();
+        let cloned = client.clone();
+        let before = client.get("https://e-hentai.org/");
+
+        let yaml = "endpoint: \"https://b.example.com/\"\nauthorization: key-b";
+        let proxies = ProxiedClient::reload_proxies(serde_yaml::from_str(yaml).unwrap()).unwrap();
+        client.set_proxies(proxies);
+
+        // built requests keep their snapshot, clones see the new proxy
+        let req = before.build().unwrap();
+        assert_eq!(req.url().as_str(), "https://a.example.com/");
+        let req = cloned.get("https://e-hentai.org/").build().unwrap();
+        assert_eq!(req.url().as_str(), "https://b.example.com/");
+        assert_eq!(req.headers()[AUTH_HEADER], "key-b");
+
+        let err = ProxiedClient::reload_proxies(serde_yaml::from_str("authorization: x").unwrap())
+            .unwrap_err();
+        assert!(matches!(err, ProxyError::IncompleteConfig { .. }));
+        let proxies = ProxiedClient::reload_proxies(serde_yaml::from_str("").unwrap()).unwrap();
+        client.set_proxies(proxies);
+        let req = cloned.get("https://e-hentai.org/").build().unwrap();
+        assert_eq!(req.url().as_str(), "https://e-hentai.org/");
+    }
+
+    #[tokio::test]
+    async fn test_resolve() {
+        use crate::mock_server::{MockResponse, MockServer};
+
+        let server = MockServer::start(|_, _| MockResponse::new(200, "ok")).await;
+        let host = format!("proxy.internal:{}", server.addr().port());
+        let client = ProxiedClient::new(&format!("http://{host}/"), "test-key")
+            .unwrap()
+            .with_resolve("proxy.internal", server.addr());
+        let resp = client
+            .send(client.get("https://e-hentai.org/"))
+            .await
+            .unwrap();
+        assert_eq!(resp.text().await.unwrap(), "ok");
+        assert_eq!(server.requests()[0].header("host"), Some(host.as_str()));
+
+        let cfg: ProxyConfig = serde_yaml::from_str("resolve:\n  proxy.internal: bad").unwrap();
+        let err = ProxiedClient::from_proxy_config(cfg).unwrap_err();
+        assert!(matches!(err, ProxyError::Resolve { ref host, .. } if host == "proxy.internal"));
+    }
+
+    #[tokio::test]
+    async fn test_compression() {
+        use std::io::Write;
+
+        use crate::mock_server::{MockResponse, MockServer};
+
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), Default::default());
+        encoder.write_all(b"hello").unwrap();
+        let body = encoder.finish().unwrap();
+        let raw = body.clone();
+        let server = MockServer::start(move |_, _| {
+            MockResponse::new(200, body.clone()).header("Content-Encoding", "gzip")
+        })
+        .await;
+
+        let client = ProxiedClient::default();
+        let resp = client.send(client.get(&server.url("/"))).await.unwrap();
+        assert_eq!(resp.bytes().await.unwrap(), raw);
+        assert_eq!(server.requests()[0].header("accept-encoding"), None);
+
+        let client = client.with_compression(true, false, true);
+        let resp = client.send(client.get(&server.url("/"))).await.unwrap();
+        assert_eq!(resp.text().await.unwrap(), "hello");
+        assert_eq!(
+            server.requests()[1].header("accept-encoding"),
+            Some("gzip, deflate")
+        );
+    }
+
+    /// Collects the `request_id` fields of the spans.
+    #[derive(Clone, Default)]
+    struct RequestIds(Arc<parking_lot::Mutex<Vec<String>>>);
+
+    impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for RequestIds {
+        fn on_new_span(
+            &self,
+            attrs: &tracing::span::Attributes<'_>,
+            _: &tracing::span::Id,
+            _: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            struct Visitor<'a>(&'a RequestIds);
+            impl tracing::field::Visit for Visitor<'_> {
+                fn record_debug(
+                    &mut self,
+                    field: &tracing::field::Field,
+                    value: &dyn std::fmt::Debug,
+                ) {
+                    if field.name() == "request_id" {
+                        self.0 .0.lock().push(format!("{value:?}"));
+                    }
+                }
+            }
+            attrs.record(&mut Visitor(self));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_request_id() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        use crate::mock_server::{MockResponse, MockServer};
+
+        let ids = RequestIds::default();
+        let _guard =
+            tracing::subscriber::set_default(tracing_subscriber::registry().with(ids.clone()));
+        let server = MockServer::start(|_, _| MockResponse::new(200, "")).await;
+        let direct = ProxiedClient::default();
+        let proxied = ProxiedClient::new(&server.url("/"), "test-key").unwrap();
+        direct.send(direct.get(&server.url("/a"))).await.unwrap();
+        proxied
+            .send(proxied.get("https://e-hentai.org/"))
+            .await
+            .unwrap();
+        with_request_id("gallery-1", async {
+            direct.send(direct.get(&server.url("/b"))).await.unwrap();
+            // spawned ones keep it only when propagated
+            let client = direct.clone();
+            let url = server.url("/c");
+            tokio::spawn(propagate_scope(async move {
+                client.send(client.get(&url)).await.unwrap();
+            }))
+            .await
+            .unwrap();
+        })
+        .await;
+
+        let sent = server
+            .requests()
+            .iter()
+            .map(|r| r.header(REQUEST_ID_HEADER).unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(*ids.0.lock(), sent);
+        assert_ne!(sent[0], sent[1]);
+        assert!(uuid::Uuid::parse_str(&sent[0]).is_ok());
+        assert_eq!(sent[2..], ["gallery-1", "gallery-1"]);
+    }
+
+    #[tokio::test]
+    async fn test_probe() {
+        use crate::mock_server::{MockResponse, MockServer};
+
+        let server = MockServer::start(|_, req| match req.header("x-authorization") {
+            Some("token") => MockResponse::new(200, ""),
+            _ => MockResponse::new(401, ""),
+        })
+        .await;
+        let client = ProxiedClient::new(&server.url("/"), "token").unwrap();
+        let report = client.probe("https://e-hentai.org/").await;
+        assert_eq!(report.route, ProbeRoute::Proxy(server.url("/")));
+        assert_eq!(report.status, Some(reqwest::StatusCode::OK));
+        assert!(report.is_ok() && report.error.is_none());
+        assert_eq!(
+            server.requests()[0].header("x-forwarded-for"),
+            Some("https://e-hentai.org/")
+        );
+
+        let client = ProxiedClient::new(&server.url("/"), "wrong").unwrap();
+        let report = client.probe("https://e-hentai.org/").await;
+        assert!(!report.is_ok());
+        assert!(
+            report.to_string().contains("authorization of the proxy"),
+            "{report}"
+        );
+
+        let client = ProxiedClient::default().with_connect_timeout(Duration::from_secs(1));
+        let report = client.probe("http://127.0.0.1:1/").await;
+        assert_eq!(report.route, ProbeRoute::Direct);
+        assert!(report.status.is_none() && !report.is_ok());
+        let error = report.error.unwrap();
+        assert!(error.contains(": "), "{error}");
+    }
+}
@@ -0,0 +1,41 @@
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+/// Token bucket limiting bytes per second, bursts up to one second of traffic.
+/// Tokens may go negative, so concurrent callers queue up behind the debt.
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    bytes_per_sec: f64,
+    // (tokens, last refill)
+    state: Mutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(bytes_per_sec: u64) -> Self {
+        let bytes_per_sec = bytes_per_sec as f64;
+        Self {
+            bytes_per_sec,
+            state: Mutex::new((bytes_per_sec, Instant::now())),
+        }
+    }
+
+    /// Take `bytes` tokens, sleeping until they are paid off.
+    pub(crate) async fn acquire(&self, bytes: u64) {
+        let wait = {
+            let mut state = self.state.lock();
+            let now = Instant::now();
+            let refill = now.duration_since(state.1).as_secs_f64() * self.bytes_per_sec;
+            state.0 = (state.0 + refill).min(self.bytes_per_sec) - bytes as f64;
+            state.1 = now;
+            if state.0 < 0.0 {
+                Duration::from_secs_f64(-state.0 / self.bytes_per_sec)
+            } else {
+                Duration::ZERO
+            }
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
@@ -0,0 +1,62 @@
+use std::ops::Deref;
+
+use reqwest::{Response, StatusCode};
+
+/// Set by the forwarding proxy to the status returned by the target site.
+pub(crate) const UPSTREAM_STATUS_HEADER: &str = "x-upstream-status";
+
+/// A response which tells apart the status of the proxy and of the target site.
+/// Without `X-Upstream-Status`, the HTTP status is taken as the upstream status.
+#[derive(Debug)]
+pub struct ProxiedResponse {
+    inner: Response,
+    upstream_status: Option<StatusCode>,
+}
+
+impl ProxiedResponse {
+    pub(crate) fn new(inner: Response) -> Self {
+        let upstream_status = inner
+            .headers()
+            .get(UPSTREAM_STATUS_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse::<u16>().ok())
+            .and_then(|v| StatusCode::from_u16(v).ok());
+        Self {
+            inner,
+            upstream_status,
+        }
+    }
+
+    /// Status of the response as received, which may come from the proxy itself.
+    pub fn proxy_status(&self) -> StatusCode {
+        self.inner.status()
+    }
+
+    /// Status returned by the target site.
+    pub fn upstream_status(&self) -> StatusCode {
+        self.upstream_status.unwrap_or(self.inner.status())
+    }
+
+    /// Whether the proxy reported the upstream status explicitly.
+    pub fn has_upstream_status(&self) -> bool {
+        self.upstream_status.is_some()
+    }
+
+    pub fn into_inner(self) -> Response {
+        self.inner
+    }
+}
+
+impl Deref for ProxiedResponse {
+    type Target = Response;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl From<ProxiedResponse> for Response {
+    fn from(resp: ProxiedResponse) -> Self {
+        resp.inner
+    }
+}
@@ -0,0 +1,199 @@
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+use reqwest::{header, RequestBuilder, Response, StatusCode};
+
+use super::{ProxiedResponse, ProxyError};
+
+const DEFAULT_RETRY_STATUS: [StatusCode; 4] = [
+    StatusCode::TOO_MANY_REQUESTS,
+    StatusCode::BAD_GATEWAY,
+    StatusCode::SERVICE_UNAVAILABLE,
+    StatusCode::GATEWAY_TIMEOUT,
+];
+
+/// Retry policy with exponential backoff.
+/// The n-th retry(starting from 0) sleeps `base_delay * 2^n`, randomly scaled by
+/// `1 ± jitter`, and never longer than `max_delay`.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Max attempts including the first one.
+    pub max_attempts: usize,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    /// Jitter factor in [0, 1].
+    pub jitter: f64,
+    /// Response status which should be retried.
+    pub retry_status: Vec<StatusCode>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            jitter: 0.2,
+            retry_status: DEFAULT_RETRY_STATUS.to_vec(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn new(max_attempts: usize, base_delay: Duration) -> Self {
+        Self {
+            max_attempts,
+            base_delay,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = max_delay;
+        self
+    }
+
+    pub fn with_jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    pub fn with_retry_status(mut self, status: Vec<StatusCode>) -> Self {
+        self.retry_status = status;
+        self
+    }
+
+    /// Delay before the n-th retry(starting from 0).
+    pub fn delay(&self, retry: usize) -> Duration {
+        let exp = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(retry.min(31) as u32));
+        let factor = if self.jitter > 0.0 {
+            1.0 + rand::Rng::gen_range(&mut rand::thread_rng(), -self.jitter..=self.jitter)
+        } else {
+            1.0
+        };
+        exp.mul_f64(factor).min(self.max_delay)
+    }
+
+    pub(crate) fn should_retry_status(&self, status: StatusCode) -> bool {
+        self.retry_status.contains(&status)
+    }
+
+    pub(crate) fn should_retry_error(e: &ProxyError) -> bool {
+        match e {
+            ProxyError::Reqwest(e) => e.is_connect() || e.is_timeout() || e.is_request(),
+            _ => false,
+        }
+    }
+}
+
+/// Token bucket of retries shared by all requests of a client, so a systematically
+/// failing proxy makes `send_with_retry` fail fast instead of every request retrying
+/// up to its own `max_attempts`. Holds at most `size` retries, refilled continuously.
+#[derive(Debug)]
+pub struct RetryBudget {
+    size: f64,
+    refill_per_sec: f64,
+    // (retries left, last refill)
+    state: Mutex<(f64, Instant)>,
+}
+
+impl RetryBudget {
+    /// Zero `size` disables retries, zero `refill_per_sec` never refills.
+    pub fn new(size: u32, refill_per_sec: f64) -> Self {
+        Self {
+            size: size as f64,
+            refill_per_sec: refill_per_sec.max(0.0),
+            state: Mutex::new((size as f64, Instant::now())),
+        }
+    }
+
+    /// Take one retry, false if the budget is exhausted.
+    pub fn try_acquire(&self) -> bool {
+        let mut state = self.refill();
+        if state.0 < 1.0 {
+            return false;
+        }
+        state.0 -= 1.0;
+        true
+    }
+
+    /// Whole retries left.
+    pub fn remaining(&self) -> u32 {
+        self.refill().0 as u32
+    }
+
+    fn refill(&self) -> parking_lot::MutexGuard<'_, (f64, Instant)> {
+        let mut state = self.state.lock();
+        let now = Instant::now();
+        let refill = now.duration_since(state.1).as_secs_f64() * self.refill_per_sec;
+        state.0 = (state.0 + refill).min(self.size);
+        state.1 = now;
+        state
+    }
+}
+
+/// Parse `Retry-After` in seconds. HTTP-date format is not supported.
+pub(crate) fn retry_after(resp: &Response) -> Option<Duration> {
+    resp.headers()
+        .get(header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+/// Send the request with `send`, retrying as the policy says.
+/// If the request can not be cloned(streaming body), it will be sent only once.
+/// The decision is made on the upstream status, so an error of the target site relayed
+/// by the proxy is not mistaken for a proxy failure and vice versa.
+/// When retries are exhausted on a retryable status, the last response is returned.
+/// The same goes for the last response or error when `budget` has no retry left.
+pub(crate) async fn send_with_retry<F, Fut>(
+    req: RequestBuilder,
+    policy: &RetryPolicy,
+    budget: Option<&RetryBudget>,
+    send: F,
+) -> Result<ProxiedResponse, ProxyError>
+where
+    F: Fn(RequestBuilder) -> Fut,
+    Fut: Future<Output = Result<Response, ProxyError>>,
+{
+    let mut retry = 0;
+    loop {
+        let attempt = match req.try_clone() {
+            Some(r) if retry + 1 < policy.max_attempts => r,
+            _ => return send(req).await.map(ProxiedResponse::new),
+        };
+        let result = send(attempt).await.map(ProxiedResponse::new);
+        let delay = match &result {
+            Ok(resp) if policy.should_retry_status(resp.upstream_status()) => {
+                tracing::debug!(
+                    "[retry] got status {}(proxy {}), will retry",
+                    resp.upstream_status(),
+                    resp.proxy_status()
+                );
+                retry_after(resp)
+                    .map(|d| d.min(policy.max_delay))
+                    .unwrap_or_else(|| policy.delay(retry))
+            }
+            Err(e) if RetryPolicy::should_retry_error(e) => {
+                tracing::debug!("[retry] request failed: {e}, will retry");
+                policy.delay(retry)
+            }
+            _ => return result,
+        };
+        if budget.is_some_and(|b| !b.try_acquire()) {
+            tracing::warn!("[retry] retry budget exhausted, not retrying");
+            return result;
+        }
+        tokio::time::sleep(delay).await;
+        retry += 1;
+    }
+}
@@ -0,0 +1,32 @@
+use std::time::{Duration, SystemTime};
+
+use crate::config;
+
+use super::ProxiedClient;
+
+/// Check the mtime of the config file every `interval`, and call
+/// `reload_from_config` on the client when it changes. All clones observe the reload.
+/// Note: This spawns a task so it must be called inside a tokio runtime.
+pub fn spawn_config_watcher(
+    client: ProxiedClient,
+    interval: Duration,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mtime =
+            || -> Option<SystemTime> { std::fs::metadata(config::path()).ok()?.modified().ok() };
+        let mut last = mtime();
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            let current = mtime();
+            if current == last {
+                continue;
+            }
+            last = current;
+            if let Err(e) = client.reload_from_config() {
+                tracing::error!("[proxy] unable to reload proxy config: {e}");
+            }
+        }
+    })
+}
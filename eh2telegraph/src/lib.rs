@@ -1,4 +1,3 @@
-#![feature(type_alias_impl_trait)]
 #![feature(impl_trait_in_assoc_type)]
 
 #[macro_use]
@@ -7,12 +6,28 @@ pub mod telegraph;
 pub mod buffer;
 pub mod collector;
 pub mod config;
+pub mod decode;
+pub mod gallery;
 pub mod http_client;
 pub mod http_proxy;
 pub mod indexer;
+pub mod metrics;
+pub mod phash;
+pub mod reencode;
 pub mod searcher;
+pub mod slicer;
+pub mod sniff;
 pub mod storage;
 pub mod stream;
 pub mod sync;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod title;
 pub mod tls;
 pub mod util;
+
+#[cfg(any(test, feature = "testing"))]
+pub mod mock_server;
+
+pub use gallery::{dry_run_url, sync_url, sync_urls, BatchReport, SyncOptions};
+pub use sync::SyncError;
@@ -0,0 +1,75 @@
+//! Counters of a `Synchronizer`, aggregated across all syncs.
+//! Requests of the proxy are counted by `ProxiedClient::metrics`.
+
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+#[derive(Debug, Default)]
+pub(crate) struct Metrics {
+    galleries_synced: AtomicU64,
+    galleries_failed: AtomicU64,
+    cache_hits: AtomicU64,
+    images_uploaded: AtomicU64,
+    bytes_downloaded: AtomicU64,
+    sync_millis: AtomicU64,
+}
+
+/// A snapshot of the sync counters.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SyncMetrics {
+    /// Galleries uploaded, cache hits are not included.
+    pub galleries_synced: u64,
+    pub galleries_failed: u64,
+    pub cache_hits: u64,
+    pub images_uploaded: u64,
+    /// Size of the downloaded images before re-encoding.
+    pub bytes_downloaded: u64,
+    /// Time spent in the uploaded galleries.
+    pub sync_time: Duration,
+}
+
+impl SyncMetrics {
+    pub fn average_sync_time(&self) -> Option<Duration> {
+        let synced = u32::try_from(self.galleries_synced).ok()?;
+        (synced > 0).then(|| self.sync_time / synced)
+    }
+}
+
+impl Metrics {
+    pub(crate) fn record_cache_hit(&self) {
+        self.cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_sync(&self, succeeded: bool, elapsed: Duration) {
+        if succeeded {
+            self.galleries_synced.fetch_add(1, Ordering::Relaxed);
+            self.sync_millis
+                .fetch_add(elapsed.as_millis() as u64, Ordering::Relaxed);
+        } else {
+            self.galleries_failed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn record_download(&self, bytes: usize) {
+        self.bytes_downloaded
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_upload(&self, images: usize) {
+        self.images_uploaded
+            .fetch_add(images as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> SyncMetrics {
+        SyncMetrics {
+            galleries_synced: self.galleries_synced.load(Ordering::Relaxed),
+            galleries_failed: self.galleries_failed.load(Ordering::Relaxed),
+            cache_hits: self.cache_hits.load(Ordering::Relaxed),
+            images_uploaded: self.images_uploaded.load(Ordering::Relaxed),
+            bytes_downloaded: self.bytes_downloaded.load(Ordering::Relaxed),
+            sync_time: Duration::from_millis(self.sync_millis.load(Ordering::Relaxed)),
+        }
+    }
+}
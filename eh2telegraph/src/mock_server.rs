@@ -0,0 +1,237 @@
+//! A tiny HTTP/1.1 server for tests.
+//! Every connection serves exactly one request and is closed after the response.
+#![allow(dead_code)]
+
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use parking_lot::Mutex;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+#[derive(Debug, Clone)]
+pub struct MockRequest {
+    pub method: String,
+    pub path: String,
+    /// Header names are lowercased.
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl MockRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        let name = name.to_ascii_lowercase();
+        self.headers
+            .iter()
+            .find(|(k, _)| *k == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MockResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// Sleep before writing the response.
+    pub delay: Option<Duration>,
+    /// Write the body in chunks of this size, sleeping before each one.
+    pub chunks: Option<(usize, Duration)>,
+    /// Close the connection after this many bytes of the body, like a dropped download.
+    pub cut: Option<usize>,
+}
+
+impl MockResponse {
+    pub fn new(status: u16, body: impl Into<Vec<u8>>) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: body.into(),
+            delay: None,
+            chunks: None,
+            cut: None,
+        }
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay = Some(delay);
+        self
+    }
+
+    pub fn chunked(mut self, size: usize, interval: Duration) -> Self {
+        self.chunks = Some((size.max(1), interval));
+        self
+    }
+
+    pub fn cut(mut self, bytes: usize) -> Self {
+        self.cut = Some(bytes);
+        self
+    }
+}
+
+type MockHandler = dyn Fn(usize, &MockRequest) -> MockResponse + Send + Sync;
+
+pub struct MockServer {
+    addr: SocketAddr,
+    requests: Arc<Mutex<Vec<MockRequest>>>,
+    in_flight: Arc<InFlight>,
+}
+
+/// Requests received but not yet responded.
+#[derive(Default)]
+struct InFlight {
+    current: AtomicUsize,
+    max: AtomicUsize,
+}
+
+impl MockServer {
+    /// Start a server, the handler receives the index of the request(starting from 0).
+    pub async fn start<F>(handler: F) -> Self
+    where
+        F: Fn(usize, &MockRequest) -> MockResponse + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let handler: Arc<MockHandler> = Arc::new(handler);
+        let counter = Arc::new(AtomicUsize::new(0));
+        let in_flight = Arc::new(InFlight::default());
+
+        let reqs = requests.clone();
+        let flight = in_flight.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let handler = handler.clone();
+                let reqs = reqs.clone();
+                let counter = counter.clone();
+                let flight = flight.clone();
+                tokio::spawn(async move {
+                    let _ = serve(stream, handler, reqs, counter, flight).await;
+                });
+            }
+        });
+        Self {
+            addr,
+            requests,
+            in_flight,
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{path}", self.addr)
+    }
+
+    pub fn requests(&self) -> Vec<MockRequest> {
+        self.requests.lock().clone()
+    }
+
+    /// The max number of requests being handled at the same time.
+    pub fn max_in_flight(&self) -> usize {
+        self.in_flight.max.load(Ordering::SeqCst)
+    }
+}
+
+async fn serve(
+    mut stream: TcpStream,
+    handler: Arc<MockHandler>,
+    requests: Arc<Mutex<Vec<MockRequest>>>,
+    counter: Arc<AtomicUsize>,
+    in_flight: Arc<InFlight>,
+) -> std::io::Result<()> {
+    let mut buf = Vec::new();
+    let mut chunk = [0; 4096];
+    let header_end = loop {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos;
+        }
+    };
+
+    let head = String::from_utf8_lossy(&buf[..header_end]).to_string();
+    let mut lines = head.split("\r\n");
+    let mut first = lines.next().unwrap_or_default().split(' ');
+    let method = first.next().unwrap_or_default().to_string();
+    let path = first.next().unwrap_or_default().to_string();
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|l| l.split_once(':'))
+        .map(|(k, v)| (k.trim().to_ascii_lowercase(), v.trim().to_string()))
+        .collect();
+    let content_length = headers
+        .iter()
+        .find(|(k, _)| k == "content-length")
+        .and_then(|(_, v)| v.parse::<usize>().ok())
+        .unwrap_or(0);
+    let mut body = buf[header_end + 4..].to_vec();
+    while body.len() < content_length {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        body.extend_from_slice(&chunk[..n]);
+    }
+
+    let request = MockRequest {
+        method,
+        path,
+        headers,
+        body,
+    };
+    let request_method = request.method.clone();
+    let current = in_flight.current.fetch_add(1, Ordering::SeqCst) + 1;
+    in_flight.max.fetch_max(current, Ordering::SeqCst);
+    let idx = counter.fetch_add(1, Ordering::SeqCst);
+    let response = handler(idx, &request);
+    requests.lock().push(request);
+
+    if let Some(delay) = response.delay {
+        tokio::time::sleep(delay).await;
+    }
+    // before writing, so the client can not send the next request in the meantime
+    in_flight.current.fetch_sub(1, Ordering::SeqCst);
+    let mut out = format!("HTTP/1.1 {} Mock\r\n", response.status);
+    for (k, v) in response.headers.iter() {
+        out.push_str(&format!("{k}: {v}\r\n"));
+    }
+    out.push_str(&format!(
+        "content-length: {}\r\nconnection: close\r\n\r\n",
+        response.body.len()
+    ));
+    stream.write_all(out.as_bytes()).await?;
+    let body = match response.cut {
+        Some(cut) => &response.body[..cut.min(response.body.len())],
+        None => &response.body[..],
+    };
+    match response.chunks {
+        _ if request_method == "HEAD" => (),
+        Some((size, interval)) => {
+            for chunk in body.chunks(size) {
+                tokio::time::sleep(interval).await;
+                stream.write_all(chunk).await?;
+                stream.flush().await?;
+            }
+        }
+        None => stream.write_all(body).await?,
+    }
+    stream.shutdown().await
+}
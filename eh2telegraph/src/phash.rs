@@ -0,0 +1,220 @@
+//! Perceptual hashes of gallery pages, so a gallery reuploaded after re-encoding,
+//! resizing or slight cropping is found even though its bytes differ.
+//!
+//! The hash is a dHash: the image is shrunk to 9x8 grayscale and each bit tells
+//! whether a pixel is brighter than its right neighbour. Similar images have hashes
+//! within a small Hamming distance.
+
+use image::imageops::FilterType;
+use serde::Deserialize;
+
+use crate::{config, decode};
+
+const CONFIG_KEY: &str = "near_duplicate";
+
+/// Hashes within it are taken as the same image.
+pub const DEFAULT_THRESHOLD: u32 = 10;
+/// Leading pages hashed of a gallery.
+pub const DEFAULT_SAMPLE: usize = 5;
+/// Pages of a gallery which must match, so one shared page is not enough.
+pub const MIN_MATCHING: usize = 2;
+// set or unset in a hash at least, see `is_informative`
+const MIN_BITS: u32 = 8;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NearDuplicateAction {
+    /// Upload anyway and tell the user about the one synced before.
+    #[default]
+    Warn,
+    /// Reuse the pages of the one synced before.
+    Skip,
+}
+
+/// How galleries similar to a synced one are detected and handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct NearDuplicate {
+    #[serde(default = "default_threshold")]
+    pub threshold: u32,
+    /// Leading pages hashed, they must be in the first upload batch.
+    #[serde(default = "default_sample")]
+    pub sample: usize,
+    #[serde(default)]
+    pub action: NearDuplicateAction,
+}
+
+impl Default for NearDuplicate {
+    fn default() -> Self {
+        Self {
+            threshold: DEFAULT_THRESHOLD,
+            sample: DEFAULT_SAMPLE,
+            action: NearDuplicateAction::default(),
+        }
+    }
+}
+
+const fn default_threshold() -> u32 {
+    DEFAULT_THRESHOLD
+}
+
+const fn default_sample() -> usize {
+    DEFAULT_SAMPLE
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct NearDuplicateConfig {
+    #[serde(default)]
+    enabled: bool,
+    #[serde(flatten)]
+    settings: NearDuplicate,
+}
+
+impl NearDuplicate {
+    /// The settings of the `near_duplicate` config, None if it is missing or disabled.
+    pub fn from_config() -> anyhow::Result<Option<Self>> {
+        Ok(config::parse::<NearDuplicateConfig>(CONFIG_KEY)?
+            .filter(|c| c.enabled)
+            .map(|c| c.settings))
+    }
+}
+
+pub(crate) fn validate_config(config: &config::Config, errors: &mut config::ConfigErrors) {
+    let Some(c) = config.section::<NearDuplicateConfig>(CONFIG_KEY, errors) else {
+        return;
+    };
+    if c.settings.threshold >= 64 {
+        errors.push(&format!("{CONFIG_KEY}.threshold"), "must be less than 64");
+    }
+    if c.settings.sample == 0 {
+        errors.push(&format!("{CONFIG_KEY}.sample"), "must be positive");
+    }
+}
+
+/// The dHash of an image, None if it can not be decoded.
+/// This is CPU bound, call it in a blocking thread.
+pub fn dhash(data: &[u8]) -> Option<u64> {
+    let img = decode::load(data).ok()?;
+    let small = img.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            let brighter = small.get_pixel(x, y)[0] > small.get_pixel(x + 1, y)[0];
+            hash = (hash << 1) | brighter as u64;
+        }
+    }
+    Some(hash)
+}
+
+/// Hamming distance of two hashes.
+pub fn distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+/// Whether the hash tells pages apart. Blank pages and plain ones like credits have
+/// hashes of almost no or almost all bits set, which are close to many others.
+pub fn is_informative(hash: u64) -> bool {
+    (MIN_BITS..=64 - MIN_BITS).contains(&hash.count_ones())
+}
+
+/// Whether at least half of the informative hashes of `sample`, and no less than
+/// `MIN_MATCHING`, are within `threshold` of some hash in `other`. So a few pages
+/// added or replaced still match, but a shared blank or credit page does not.
+pub fn matches(sample: &[u64], other: &[u64], threshold: u32) -> bool {
+    let sample = sample
+        .iter()
+        .filter(|h| is_informative(**h))
+        .collect::<Vec<_>>();
+    let close = sample
+        .iter()
+        .filter(|a| {
+            other
+                .iter()
+                .any(|b| is_informative(*b) && distance(***a, *b) <= threshold)
+        })
+        .count();
+    close >= MIN_MATCHING && close * 2 >= sample.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{codecs::jpeg::JpegEncoder, DynamicImage, RgbImage};
+
+    use super::*;
+
+    // smooth shapes, like a scanned page
+    fn page(seed: u32, width: u32, height: u32) -> RgbImage {
+        RgbImage::from_fn(width, height, |x, y| {
+            let (fx, fy) = (x as f32 / width as f32, y as f32 / height as f32);
+            let s = seed as f32;
+            let v = ((fx * (3.0 + s) + s).sin() * (fy * (2.0 + s * 0.7)).cos() + 1.0) * 127.0;
+            image::Rgb([v as u8, (255.0 - v) as u8, (v * fy) as u8])
+        })
+    }
+
+    fn png(img: &RgbImage) -> Vec<u8> {
+        let mut out = Cursor::new(Vec::new());
+        img.write_to(&mut out, image::ImageFormat::Png).unwrap();
+        out.into_inner()
+    }
+
+    fn jpeg(img: &DynamicImage, quality: u8) -> Vec<u8> {
+        let mut out = Cursor::new(Vec::new());
+        JpegEncoder::new_with_quality(&mut out, quality)
+            .encode_image(img)
+            .unwrap();
+        out.into_inner()
+    }
+
+    #[test]
+    fn test_stable() {
+        let original = page(1, 400, 600);
+        let hash = dhash(&png(&original)).unwrap();
+        assert_eq!(dhash(&png(&original)), Some(hash));
+
+        let img = DynamicImage::ImageRgb8(original);
+        let reencoded = jpeg(&img, 40);
+        let resized = jpeg(&img.resize(200, 300, FilterType::Lanczos3), 85);
+        let cropped = jpeg(&img.crop_imm(8, 12, 384, 576), 85);
+        for altered in [reencoded, resized, cropped] {
+            let d = distance(hash, dhash(&altered).unwrap());
+            assert!(d <= DEFAULT_THRESHOLD, "distance {d}");
+        }
+
+        let other = dhash(&png(&page(4, 400, 600))).unwrap();
+        assert!(distance(hash, other) > DEFAULT_THRESHOLD);
+        assert_eq!(dhash(b"not an image"), None);
+    }
+
+    #[test]
+    fn test_matches() {
+        let stored = [0xffff_0000, 0xaaaa_aaaa, 0xf0f0_f0f0_0000];
+        // one bit off, and a page replaced
+        assert!(matches(
+            &[0xffff_0001, 0xaaaa_aaaa, 0x1234_5678_9abc],
+            &stored,
+            2
+        ));
+        assert!(!matches(
+            &[0xffff_0001, 0x1234_5678_9abc, 1 << 40],
+            &stored,
+            2
+        ));
+        // one matching page is not enough
+        assert!(!matches(&[0xffff_0001], &stored, 2));
+        assert!(!matches(&[], &stored, 64));
+
+        // blank and plain pages, like a shared credit page, are ignored
+        let blank = dhash(&png(&RgbImage::new(400, 600))).unwrap();
+        assert!(!is_informative(blank));
+        assert!(!is_informative(u64::MAX) && !is_informative(1 << 40));
+        assert!(!matches(
+            &[blank, blank, 0xffff_0000],
+            &[blank, 0xffff_0000],
+            0
+        ));
+        // the other pages of a gallery padded with blank ones still match
+        assert!(matches(&[0xffff_0000, 0xaaaa_aaaa, 0, 0, 0], &stored, 0));
+    }
+}
@@ -0,0 +1,187 @@
+//! Re-encode images exceeding the size limit of the upload target, or in a format it
+//! does not accept. AVIF and JPEG XL are decoded with the `avif` and `jxl` features,
+//! and fail without them.
+
+use std::io::Cursor;
+
+use bytes::Bytes;
+use image::{codecs::jpeg::JpegEncoder, imageops::FilterType, DynamicImage};
+
+use crate::{decode, sniff::ImageKind, telegraph::MAX_SINGLE_FILE_SIZE};
+
+pub const DEFAULT_QUALITY: u8 = 85;
+// give up after this many downscales
+const MAX_ROUNDS: usize = 8;
+
+#[derive(Debug, Clone, Copy)]
+pub struct ImageReencoder {
+    /// Images larger than this will be re-encoded to fit in it.
+    pub threshold: usize,
+    /// JPEG quality in [1, 100].
+    pub quality: u8,
+}
+
+impl Default for ImageReencoder {
+    fn default() -> Self {
+        Self {
+            // leave some room since the check on upload is exclusive
+            threshold: MAX_SINGLE_FILE_SIZE - 1024,
+            quality: DEFAULT_QUALITY,
+        }
+    }
+}
+
+impl ImageReencoder {
+    pub fn new(threshold: usize, quality: u8) -> Self {
+        Self {
+            threshold,
+            quality: quality.clamp(1, 100),
+        }
+    }
+
+    /// Whether `reencode` would change the data.
+    pub fn should_reencode(&self, data: &[u8]) -> bool {
+        data.len() > self.threshold || !ImageKind::sniff(data).is_accepted()
+    }
+
+    /// Return the data as is if it is within the threshold and accepted by the
+    /// upload target, otherwise re-encode it as JPEG, downscaling with the aspect
+    /// ratio kept until it fits.
+    /// This is CPU bound, call it in a blocking thread.
+    pub fn reencode(&self, data: Bytes) -> anyhow::Result<Bytes> {
+        if !self.should_reencode(&data) {
+            return Ok(data);
+        }
+        self.transcode(data)
+    }
+
+    /// Re-encode as JPEG even if the data is within the threshold and accepted,
+    /// downscaling like `reencode`.
+    /// This is CPU bound, call it in a blocking thread.
+    pub fn transcode(&self, data: Bytes) -> anyhow::Result<Bytes> {
+        let mut img = DynamicImage::ImageRgb8(decode::load(&data)?.to_rgb8());
+        for _ in 0..MAX_ROUNDS {
+            let encoded = self.encode(&img)?;
+            if encoded.len() <= self.threshold {
+                tracing::debug!(
+                    "[reencode] {} bytes -> {} bytes ({}x{})",
+                    data.len(),
+                    encoded.len(),
+                    img.width(),
+                    img.height()
+                );
+                return Ok(encoded.into());
+            }
+            // the size is roughly linear to the pixel count
+            let scale = (self.threshold as f64 / encoded.len() as f64).sqrt() * 0.9;
+            let width = ((img.width() as f64 * scale) as u32).max(1);
+            let height = ((img.height() as f64 * scale) as u32).max(1);
+            img = img.resize(width, height, FilterType::Triangle);
+        }
+        anyhow::bail!("unable to re-encode image within {} bytes", self.threshold)
+    }
+
+    fn encode(&self, img: &DynamicImage) -> anyhow::Result<Vec<u8>> {
+        let mut out = Cursor::new(Vec::new());
+        JpegEncoder::new_with_quality(&mut out, self.quality).encode_image(img)?;
+        Ok(out.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn noise_png(width: u32, height: u32) -> Bytes {
+        use rand::Rng;
+        let mut rng = rand::thread_rng();
+        let img = image::RgbImage::from_fn(width, height, |_, _| image::Rgb(rng.gen()));
+        let mut out = Cursor::new(Vec::new());
+        img.write_to(&mut out, image::ImageFormat::Png).unwrap();
+        out.into_inner().into()
+    }
+
+    #[test]
+    fn test_reencode() {
+        let reencoder = ImageReencoder::new(512 * 1024, 80);
+
+        let small = noise_png(64, 32);
+        let out = reencoder.reencode(small.clone()).unwrap();
+        assert_eq!(out, small);
+
+        let large = noise_png(1200, 600);
+        assert!(large.len() > 512 * 1024);
+        let out = reencoder.reencode(large).unwrap();
+        assert!(out.len() <= 512 * 1024);
+        let img = image::load_from_memory(&out).unwrap();
+        assert_eq!(image::guess_format(&out).unwrap(), image::ImageFormat::Jpeg);
+        let ratio = img.width() as f64 / img.height() as f64;
+        assert!((ratio - 2.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_transcode_unaccepted() {
+        let img = image::RgbImage::from_pixel(8, 4, image::Rgb([200, 0, 0]));
+        let mut bmp = Cursor::new(Vec::new());
+        img.write_to(&mut bmp, image::ImageFormat::Bmp).unwrap();
+        let bmp = Bytes::from(bmp.into_inner());
+
+        // small, but not accepted by the upload target
+        let reencoder = ImageReencoder::default();
+        assert!(reencoder.should_reencode(&bmp));
+        let out = reencoder.reencode(bmp).unwrap();
+        assert_eq!(ImageKind::sniff(&out), ImageKind::Jpeg);
+        let img = image::load_from_memory(&out).unwrap();
+        assert_eq!((img.width(), img.height()), (8, 4));
+
+        // no decoder for AVIF
+        #[cfg(not(feature = "avif"))]
+        {
+            let avif = Bytes::from_static(b"\x00\x00\x00\x1cftypavif\x00\x00\x00\x00");
+            let err = reencoder.reencode(avif).unwrap_err();
+            assert_eq!(err.to_string(), "no decoder of avif images in this build");
+        }
+    }
+
+    /// The gradient of the fixtures, red by x and green by y with blue at 160.
+    #[cfg(any(feature = "avif", feature = "jxl"))]
+    fn assert_gradient(out: &[u8], width: u32, height: u32) {
+        assert_eq!(ImageKind::sniff(out), ImageKind::Jpeg);
+        assert!(out.len() <= MAX_SINGLE_FILE_SIZE);
+        let img = image::load_from_memory(out).unwrap().to_rgb8();
+        assert_eq!(img.dimensions(), (width, height));
+        let expected = |x: u32, y: u32| [x * 256 / width, y * 240 / height, 160];
+        for (x, y) in [(4, 4), (width / 2, height / 2), (width - 4, height - 4)] {
+            let pixel = img.get_pixel(x, y).0;
+            for (got, expected) in pixel.into_iter().zip(expected(x, y)) {
+                assert!(got.abs_diff(expected as u8) < 16, "{pixel:?} at {x}x{y}");
+            }
+        }
+    }
+
+    #[cfg(feature = "avif")]
+    #[test]
+    fn test_transcode_avif() {
+        let avif = Bytes::from_static(include_bytes!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/fixtures/gradient.avif"
+        )));
+        assert_eq!(ImageKind::sniff(&avif), ImageKind::Avif);
+        let reencoder = ImageReencoder::default();
+        assert!(reencoder.should_reencode(&avif));
+        let out = reencoder.reencode(avif).unwrap();
+        assert_gradient(&out, 64, 48);
+    }
+
+    #[cfg(feature = "jxl")]
+    #[test]
+    fn test_transcode_jxl() {
+        let jxl = Bytes::from_static(include_bytes!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/fixtures/gradient.jxl"
+        )));
+        assert_eq!(ImageKind::sniff(&jxl), ImageKind::Jxl);
+        let out = ImageReencoder::default().reencode(jxl).unwrap();
+        assert_gradient(&out, 32, 24);
+    }
+}
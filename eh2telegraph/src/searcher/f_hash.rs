@@ -1,10 +1,13 @@
+use std::sync::Arc;
+
 use ipnet::Ipv6Net;
 use regex::Regex;
 
 use crate::{
-    collector::exhentai::EXCollector,
-    http_client::{GhostClient, GhostClientBuilder},
-    util::{get_string, match_first_group},
+    collector::{exhentai::EXCollector, hosts::Hosts},
+    http_client::{GhostClientBuilder, HttpFetcher},
+    http_proxy::ProxiedClient,
+    util::match_first_group,
 };
 
 lazy_static::lazy_static! {
@@ -13,32 +16,36 @@ lazy_static::lazy_static! {
 /// FHashConverter can convert f-hash(usually comes from a search result) to the first gallery url.
 /// Works for both e-hentai and ex-hentai.
 pub struct FHashConvertor {
-    client: GhostClient,
-    raw_client: reqwest::Client,
+    client: Arc<dyn HttpFetcher>,
+    // with the exhentai cookies
+    raw_client: Arc<dyn HttpFetcher>,
 }
 
 impl FHashConvertor {
     pub fn new(prefix: Option<Ipv6Net>) -> Self {
-        Self {
-            client: GhostClientBuilder::default()
-                .with_cf_resolve(&["e-hentai.org"])
-                .build(prefix),
-            raw_client: EXCollector::new_from_config()
-                .expect("unable to build ex-client")
-                .get_client(),
-        }
+        let client = GhostClientBuilder::default()
+            .with_cf_resolve(["e-hentai.org"])
+            .build(prefix);
+        Self::with_fetchers(
+            Arc::new(reqwest::Client::clone(&client)),
+            Arc::new(ex_client()),
+        )
     }
 
     pub fn new_from_config() -> Self {
-        Self {
-            client: GhostClientBuilder::default()
-                .with_cf_resolve(&["e-hentai.org"])
-                .build_from_config()
-                .expect("unable to build client for f-hash convertor"),
-            raw_client: EXCollector::new_from_config()
-                .expect("unable to build ex-client")
-                .get_client(),
-        }
+        let client = GhostClientBuilder::default()
+            .with_cf_resolve(["e-hentai.org"])
+            .build_from_config()
+            .expect("unable to build client for f-hash convertor");
+        Self::with_fetchers(
+            Arc::new(reqwest::Client::clone(&client)),
+            Arc::new(ex_client()),
+        )
+    }
+
+    /// Search e-hentai with `client` and exhentai with `raw_client`.
+    pub fn with_fetchers(client: Arc<dyn HttpFetcher>, raw_client: Arc<dyn HttpFetcher>) -> Self {
+        Self { client, raw_client }
     }
 
     // TODO: impl a trait?
@@ -46,7 +53,7 @@ impl FHashConvertor {
         tracing::info!("[f-hash] converting hash {f_hash}");
         // find in e-hentai
         let url = format!("https://e-hentai.org/?f_shash={f_hash}&f_sh=on&f_sname=on&f_stags=on&f_sh=on&f_spf=&f_spt=&f_sfl=on&f_sfu=on&f_sft=on");
-        let text = get_string(&self.client, &url).await?;
+        let text = get_text(self.client.as_ref(), &url).await?;
 
         if let Some(url) = match_first_group(&EHENTAI_URL_RE, &text) {
             tracing::info!("[f-hash] hash {f_hash} -> {url}");
@@ -55,7 +62,7 @@ impl FHashConvertor {
 
         // find in exhentai
         let url = format!("https://exhentai.org/?f_shash={f_hash}&f_sh=on&f_sname=on&f_stags=on&f_sh=on&f_spf=&f_spt=&f_sfl=on&f_sfu=on&f_sft=on");
-        let text = get_string(&self.raw_client, &url).await?;
+        let text = get_text(self.raw_client.as_ref(), &url).await?;
 
         if let Some(url) = match_first_group(&EHENTAI_URL_RE, &text) {
             tracing::info!("[f-hash] hash {f_hash} -> {url}");
@@ -66,3 +73,43 @@ impl FHashConvertor {
         Err(anyhow::anyhow!("not found in e-hentai or exhentai"))
     }
 }
+
+fn ex_client() -> ProxiedClient {
+    let hosts = Hosts::from_config().expect("unable to parse collector hosts");
+    EXCollector::new_from_config(&hosts)
+        .expect("unable to build ex-client")
+        .get_client()
+}
+
+async fn get_text(client: &dyn HttpFetcher, url: &str) -> anyhow::Result<String> {
+    let resp = client.get(url).await?.error_for_status()?;
+    Ok(resp.text()?.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockFetcher;
+
+    #[tokio::test]
+    async fn test_convert_to_gallery() {
+        let eh = Arc::new(MockFetcher::new().with_body("e-hentai.org", 200, "no hits"));
+        let ex = Arc::new(MockFetcher::new().with_body(
+            "f_shash=abc",
+            200,
+            r#"<a href="https://exhentai.org/g/1/0123abcd/">title</a>"#,
+        ));
+        let convertor = FHashConvertor::with_fetchers(eh.clone(), ex.clone());
+        assert_eq!(
+            convertor.convert_to_gallery("abc").await.unwrap(),
+            "https://exhentai.org/g/1/0123abcd"
+        );
+        assert!(eh.requests()[0].url.contains("f_shash=abc"));
+        assert!(convertor.convert_to_gallery("other").await.is_err());
+
+        // the search fails on errors of e-hentai
+        let eh = Arc::new(MockFetcher::new().with_body("e-hentai.org", 503, ""));
+        let convertor = FHashConvertor::with_fetchers(eh, ex);
+        assert!(convertor.convert_to_gallery("abc").await.is_err());
+    }
+}
@@ -17,7 +17,7 @@ mod tests {
     #[tokio::test]
     async fn demo() {
         let data = std::fs::read("./image.png").unwrap();
-        let searcher = saucenao::SaucenaoSearcher::new(None);
+        let searcher = saucenao::SaucenaoSearcher::new(Default::default());
         let r = searcher.search(data).await;
         println!("result: {r:?}");
     }
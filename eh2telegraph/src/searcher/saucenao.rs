@@ -1,22 +1,28 @@
 use std::{borrow::Cow, str::FromStr};
 
 use futures::Future;
-use ipnet::Ipv6Net;
 use regex::Regex;
 use reqwest::{
     multipart::{self, Part},
     Response,
 };
+use serde::Deserialize;
 
-use crate::http_client::{GhostClient, HttpRequestBuilder};
+use crate::{config, http_client::HttpRequestBuilder, http_proxy::ProxiedClient};
 
 use super::ImageSearcher;
 
+const CONFIG_KEY: &str = "saucenao";
+const SEARCH_URL: &str = "https://saucenao.com/search.php";
+const DEFAULT_MIN_SIMILARITY: u8 = 70;
+const DEFAULT_MIN_SIMILARITY_PRIVATE: u8 = 50;
+
 lazy_static::lazy_static! {
     static ref SEARCH_ELEMENT_RE: Regex = Regex::new(r#"<tr><td class="resulttableimage">(.*?)</tr>"#).unwrap();
     static ref S_URL_RE: Regex = Regex::new(r#"src="(https://.*?)""#).unwrap();
     static ref TITLE_RE: Regex = Regex::new(r#"<div class="resulttitle"><strong>(.*?)</strong>"#).unwrap();
     static ref SIM_RE: Regex = Regex::new(r#"<div class="resultsimilarityinfo">(\d+)\.?\d*%</div>"#).unwrap();
+    static ref NH_GALLERY_RE: Regex = Regex::new(r#"nhentai\.(?:net|to)/g/(\d+)"#).unwrap();
     static ref SITE_PARSE_RE: Regex = Regex::new(r#"saucenao\.com/(res/pixiv(_historical)?/\d+/manga/(?P<pixiv_id>\d+)_)|(ehentai/\w+/\w+/(?P<ehentai_fhash>\w+))|(res/nhentai/(?P<nhentai_id>\d+))"#).unwrap();
 }
 
@@ -42,39 +48,76 @@ macro_rules! extract_first_opt {
     };
 }
 
+#[derive(Debug, Clone, Default, Deserialize)]
+struct SaucenaoConfig {
+    /// Use the JSON API instead of the web page when set.
+    api_key: Option<String>,
+    min_similarity: Option<u8>,
+    min_similarity_private: Option<u8>,
+}
+
 /// Saucenao searcher.
-/// Note: even saucenao resolves to an ipv6 address, we still use force resolving.
+/// Without an api key the web page is scraped, otherwise the JSON API is used.
+/// Both are requested through the shared `ProxiedClient`.
 #[derive(Debug, Clone)]
 pub struct SaucenaoSearcher {
-    client: GhostClient,
+    client: ProxiedClient,
+    api_key: Option<String>,
+    min_similarity: u8,
+    min_similarity_private: u8,
 }
 
 impl SaucenaoSearcher {
-    pub fn new(prefix: Option<Ipv6Net>) -> Self {
+    /// Requests are sent through `client`, the one shared by the app.
+    pub fn new(client: ProxiedClient) -> Self {
         Self {
-            client: GhostClient::builder()
-                .with_cf_resolve(&["saucenao.com", "e-hentai.org"])
-                .build(prefix),
+            client,
+            api_key: None,
+            min_similarity: DEFAULT_MIN_SIMILARITY,
+            min_similarity_private: DEFAULT_MIN_SIMILARITY_PRIVATE,
         }
     }
 
-    pub fn new_from_config() -> Self {
-        Self {
-            client: GhostClient::builder()
-                .with_cf_resolve(&["saucenao.com", "e-hentai.org"])
-                .build_from_config()
-                .expect("unable to build client for saucenao"),
+    pub fn new_from_config(client: ProxiedClient) -> Self {
+        let config: SaucenaoConfig = config::parse(CONFIG_KEY)
+            .expect("unable to parse saucenao config")
+            .unwrap_or_default();
+        let mut searcher = Self::new(client).with_api_key(config.api_key);
+        if let Some(sim) = config.min_similarity {
+            searcher.min_similarity = sim;
         }
+        if let Some(sim) = config.min_similarity_private {
+            searcher.min_similarity_private = sim;
+        }
+        searcher
     }
 
-    async fn do_search<C: HttpRequestBuilder>(
-        client: &C,
-        file: Part,
-    ) -> anyhow::Result<SaucenaoOutput> {
+    /// Send the requests through the given client.
+    pub fn with_proxy(mut self, client: ProxiedClient) -> Self {
+        self.client = client;
+        self
+    }
+
+    pub fn with_api_key(mut self, api_key: Option<String>) -> Self {
+        self.api_key = api_key;
+        self
+    }
+
+    /// Results below it are not confident matches, private chats use a lower one.
+    pub fn min_similarity(&self, private: bool) -> u8 {
+        if private {
+            self.min_similarity_private
+        } else {
+            self.min_similarity
+        }
+    }
+
+    async fn do_search(client: &ProxiedClient, file: Part) -> anyhow::Result<SaucenaoOutput> {
+        let request = client
+            .post_builder(SEARCH_URL)
+            .multipart(multipart::Form::new().part("file", file));
         let response = client
-            .post_builder("https://saucenao.com/search.php")
-            .multipart(multipart::Form::new().part("file", file))
-            .send()
+            .send_request(request)
             .await
             .and_then(Response::error_for_status)?
             .text()
@@ -85,6 +128,28 @@ impl SaucenaoSearcher {
         }
         SaucenaoOutput::from_str(&response)
     }
+
+    async fn do_api_search(
+        client: &ProxiedClient,
+        api_key: &str,
+        file: Part,
+    ) -> anyhow::Result<SaucenaoOutput> {
+        // in the url, a query added to the request would go to the proxy instead
+        let url = reqwest::Url::parse_with_params(
+            SEARCH_URL,
+            [("output_type", "2"), ("db", "999"), ("api_key", api_key)],
+        )?;
+        let request = client
+            .post_builder(url.as_str())
+            .multipart(multipart::Form::new().part("file", file));
+        let response = client
+            .send_request(request)
+            .await
+            .and_then(Response::error_for_status)?
+            .text()
+            .await?;
+        SaucenaoOutput::from_api_json(&response)
+    }
 }
 
 #[non_exhaustive]
@@ -129,7 +194,13 @@ where
     fn search(&self, data: T) -> Self::FetchFuture {
         let file_part = Part::bytes(data).file_name("image.jpg");
         let client = self.client.clone();
-        async move { Self::do_search(&client, file_part).await }
+        let api_key = self.api_key.clone();
+        async move {
+            match api_key {
+                Some(key) => Self::do_api_search(&client, &key, file_part).await,
+                None => Self::do_search(&client, file_part).await,
+            }
+        }
     }
 }
 
@@ -149,7 +220,7 @@ impl FromStr for SaucenaoOutput {
             data.push(element);
         }
         // sort
-        data.sort_unstable_by(|a, b| b.similarity.cmp(&a.similarity));
+        data.sort_unstable_by_key(|e| std::cmp::Reverse(e.similarity));
 
         Ok(Self { data })
     }
@@ -169,21 +240,7 @@ impl FromStr for SaucenaoOuputElement {
         let similarity =
             extract_first!(SIM_RE, s, "unable to parse saucenao result similarity").parse()?;
 
-        let parsed = SITE_PARSE_RE
-            .captures(&raw_url)
-            .and_then(|cap| {
-                if let Some(pixiv) = cap.name("pixiv_id") {
-                    return Some(SaucenaoParsed::Pixiv(pixiv.as_str().to_string()));
-                }
-                if let Some(eh) = cap.name("ehentai_fhash") {
-                    return Some(SaucenaoParsed::EHentai(eh.as_str().to_string()));
-                }
-                if let Some(nh) = cap.name("nhentai_id") {
-                    return Some(SaucenaoParsed::NHentai(nh.as_str().to_string()));
-                }
-                None
-            })
-            .unwrap_or(SaucenaoParsed::Other);
+        let parsed = parse_site(&raw_url).unwrap_or(SaucenaoParsed::Other);
 
         Ok(Self {
             raw_url,
@@ -193,3 +250,209 @@ impl FromStr for SaucenaoOuputElement {
         })
     }
 }
+
+/// Site of the result by its thumbnail url.
+fn parse_site(raw_url: &str) -> Option<SaucenaoParsed> {
+    let cap = SITE_PARSE_RE.captures(raw_url)?;
+    if let Some(pixiv) = cap.name("pixiv_id") {
+        return Some(SaucenaoParsed::Pixiv(pixiv.as_str().to_string()));
+    }
+    if let Some(eh) = cap.name("ehentai_fhash") {
+        return Some(SaucenaoParsed::EHentai(eh.as_str().to_string()));
+    }
+    if let Some(nh) = cap.name("nhentai_id") {
+        return Some(SaucenaoParsed::NHentai(nh.as_str().to_string()));
+    }
+    None
+}
+
+// https://saucenao.com/user.php?page=search-api
+#[derive(Deserialize)]
+struct ApiResponse {
+    header: ApiHeader,
+    #[serde(default)]
+    results: Vec<ApiResult>,
+}
+
+#[derive(Deserialize)]
+struct ApiHeader {
+    /// Positive for server side errors and negative for client side ones.
+    status: i32,
+    message: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ApiResult {
+    header: ApiResultHeader,
+    #[serde(default)]
+    data: ApiResultData,
+}
+
+#[derive(Deserialize)]
+struct ApiResultHeader {
+    similarity: String,
+    thumbnail: String,
+}
+
+#[derive(Default, Deserialize)]
+struct ApiResultData {
+    #[serde(default)]
+    ext_urls: Vec<String>,
+    title: Option<String>,
+    eng_name: Option<String>,
+    jp_name: Option<String>,
+    source: Option<String>,
+}
+
+impl SaucenaoOutput {
+    /// Parse a response of the JSON API(`output_type=2`).
+    pub fn from_api_json(s: &str) -> anyhow::Result<Self> {
+        let response: ApiResponse = serde_json::from_str(s)?;
+        // some indexes may fail while others still return results
+        if response.header.status != 0 && response.results.is_empty() {
+            return Err(anyhow::anyhow!(
+                "saucenao api error({}): {}",
+                response.header.status,
+                response.header.message.unwrap_or_default()
+            ));
+        }
+
+        let mut data = Vec::with_capacity(response.results.len());
+        for result in response.results {
+            let similarity = result.header.similarity.parse::<f32>()? as u8;
+            let parsed = parse_site(&result.header.thumbnail)
+                .or_else(|| {
+                    result.data.ext_urls.iter().find_map(|u| {
+                        NH_GALLERY_RE
+                            .captures(u)
+                            .map(|cap| SaucenaoParsed::NHentai(cap[1].to_string()))
+                    })
+                })
+                .unwrap_or(SaucenaoParsed::Other);
+            let ApiResultData {
+                title,
+                eng_name,
+                jp_name,
+                source,
+                ..
+            } = result.data;
+            data.push(SaucenaoOuputElement {
+                raw_url: result.header.thumbnail,
+                name: eng_name
+                    .or(title)
+                    .or(jp_name)
+                    .or(source)
+                    .unwrap_or_else(|| "NO TITLE".to_string()),
+                similarity,
+                parsed,
+            });
+        }
+        data.sort_unstable_by_key(|e| std::cmp::Reverse(e.similarity));
+
+        Ok(Self { data })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_api_response() {
+        let body = r#"{
+            "header": {"user_id": "1", "status": 0, "results_requested": 3, "results_returned": 3},
+            "results": [
+                {
+                    "header": {
+                        "similarity": "45.10",
+                        "thumbnail": "https://img1.saucenao.com/res/pixiv/7594/manga/75943246_p1.jpg?auth=x",
+                        "index_id": 5
+                    },
+                    "data": {"ext_urls": ["https://www.pixiv.net/member_illust.php?mode=medium&illust_id=75943246"], "title": "pixiv"}
+                },
+                {
+                    "header": {
+                        "similarity": "93.25",
+                        "thumbnail": "https://img3.saucenao.com/ehentai/c5/17/c517710f0654ea883df1e0fea7117c671fb03bc1.jpg?auth=x",
+                        "index_id": 38
+                    },
+                    "data": {"source": "Some Doujin", "creator": ["someone"], "eng_name": "[Someone] Title", "jp_name": "タイトル"}
+                },
+                {
+                    "header": {
+                        "similarity": "88.4",
+                        "thumbnail": "https://img1.saucenao.com/x.jpg",
+                        "index_id": 18
+                    },
+                    "data": {"ext_urls": ["https://nhentai.net/g/177013/"], "jp_name": "タイトル"}
+                }
+            ]
+        }"#;
+        let output = SaucenaoOutput::from_api_json(body).unwrap();
+        assert_eq!(
+            output.data.iter().map(|e| e.similarity).collect::<Vec<_>>(),
+            [93, 88, 45]
+        );
+        assert_eq!(output.data[0].name, "[Someone] Title");
+        assert!(matches!(
+            &output.data[0].parsed,
+            SaucenaoParsed::EHentai(h) if h == "c517710f0654ea883df1e0fea7117c671fb03bc1"
+        ));
+        assert_eq!(output.data[1].name, "タイトル");
+        assert!(matches!(&output.data[1].parsed, SaucenaoParsed::NHentai(id) if id == "177013"));
+        assert!(matches!(&output.data[2].parsed, SaucenaoParsed::Pixiv(id) if id == "75943246"));
+    }
+
+    #[tokio::test]
+    async fn test_search() {
+        use crate::mock_server::{MockResponse, MockServer};
+
+        let server = MockServer::start(|_, req| {
+            match req.header("x-forwarded-for").unwrap_or_default() {
+                t if t.contains("api_key=key") => MockResponse::new(
+                    200,
+                    r#"{"header": {"status": 0}, "results": [{"header": {"similarity": "80.0", "thumbnail": "https://img1.saucenao.com/res/nhentai/1234/5.jpg"}}]}"#,
+                ),
+                _ => MockResponse::new(
+                    200,
+                    r#"<title>Sauce Found?</title><tr><td class="resulttableimage"><img src="https://img1.saucenao.com/res/nhentai/5678/1.jpg"></td><td><div class="resultsimilarityinfo">91.5%</div><div class="resulttitle"><strong>Title</strong></div></td></tr>"#,
+                ),
+            }
+        })
+        .await;
+        let proxy = ProxiedClient::new(&server.url("/"), "token").unwrap();
+        // both the web page and the api go through the proxy
+        let searcher = SaucenaoSearcher::new(proxy);
+        let output = searcher.search(vec![0xFF, 0xD8]).await.unwrap();
+        assert_eq!(
+            (output.data[0].similarity, output.data[0].name.as_str()),
+            (91, "Title")
+        );
+        assert!(matches!(&output.data[0].parsed, SaucenaoParsed::NHentai(id) if id == "5678"));
+        let searcher = searcher.with_api_key(Some("key".to_string()));
+        let output = searcher.search(vec![0xFF, 0xD8]).await.unwrap();
+        assert!(matches!(&output.data[0].parsed, SaucenaoParsed::NHentai(id) if id == "1234"));
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(requests
+            .iter()
+            .all(|r| r.header("x-forwarded-for").unwrap().starts_with(SEARCH_URL)));
+    }
+
+    #[test]
+    fn test_parse_api_error() {
+        let body =
+            r#"{"header": {"status": -2, "message": "Search Rate Too High."}, "results": []}"#;
+        let err = SaucenaoOutput::from_api_json(body).unwrap_err();
+        assert!(err.to_string().contains("Search Rate Too High."));
+
+        // failed indexes do not drop the results of others
+        let body = r#"{
+            "header": {"status": 1, "message": "some index failed"},
+            "results": [{"header": {"similarity": "80.0", "thumbnail": "https://img1.saucenao.com/res/nhentai/1234/5.jpg"}}]
+        }"#;
+        let output = SaucenaoOutput::from_api_json(body).unwrap();
+        assert!(matches!(&output.data[0].parsed, SaucenaoParsed::NHentai(id) if id == "1234"));
+    }
+}
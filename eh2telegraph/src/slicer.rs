@@ -0,0 +1,149 @@
+//! Fit images in the dimensions Telegraph displays well. It mangles very tall or wide
+//! images like long webtoon strips, so they are downscaled to the max width, then
+//! sliced top to bottom into segments of at most the max height.
+
+use std::io::Cursor;
+
+use bytes::Bytes;
+use image::{codecs::jpeg::JpegEncoder, imageops::FilterType, DynamicImage};
+
+use crate::{decode, reencode::DEFAULT_QUALITY};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageSlicer {
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+    /// Slice images taller than `max_height`, otherwise they are downscaled to fit it.
+    pub split_tall: bool,
+    /// JPEG quality of the segments in [1, 100], like `ImageReencoder::quality`.
+    pub quality: u8,
+}
+
+impl ImageSlicer {
+    pub fn new(max_width: Option<u32>, max_height: Option<u32>, split_tall: bool) -> Self {
+        Self {
+            max_width: max_width.map(|w| w.max(1)),
+            max_height: max_height.map(|h| h.max(1)),
+            split_tall,
+            quality: DEFAULT_QUALITY,
+        }
+    }
+
+    pub fn with_quality(mut self, quality: u8) -> Self {
+        self.quality = quality.clamp(1, 100);
+        self
+    }
+
+    /// Whether `slice` would change the data, only the image header is read.
+    /// Images which can not be decoded are left as they are.
+    pub fn should_slice(&self, data: &[u8]) -> bool {
+        let Some((width, height)) = decode::dimensions(data) else {
+            return false;
+        };
+        self.max_width.is_some_and(|max| width > max)
+            || self.max_height.is_some_and(|max| height > max)
+    }
+
+    /// Return the data as is if it fits, otherwise the segments of the image in
+    /// reading order, each re-encoded as JPEG. Segments of one image have about the
+    /// same height.
+    /// This is CPU bound, call it in a blocking thread.
+    pub fn slice(&self, data: Bytes) -> anyhow::Result<Vec<Bytes>> {
+        if !self.should_slice(&data) {
+            return Ok(vec![data]);
+        }
+        let mut img = DynamicImage::ImageRgb8(decode::load(&data)?.to_rgb8());
+        if let Some(max) = self.max_width.filter(|max| img.width() > *max) {
+            img = img.resize(max, u32::MAX, FilterType::Triangle);
+        }
+        let Some(max) = self.max_height.filter(|max| img.height() > *max) else {
+            return Ok(vec![self.encode(&img)?]);
+        };
+        if !self.split_tall {
+            let img = img.resize(u32::MAX, max, FilterType::Triangle);
+            return Ok(vec![self.encode(&img)?]);
+        }
+
+        let count = img.height().div_ceil(max);
+        let segment = img.height().div_ceil(count);
+        let segments = (0..count)
+            .map(|i| {
+                let top = i * segment;
+                let height = segment.min(img.height() - top);
+                self.encode(&img.crop_imm(0, top, img.width(), height))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        tracing::debug!(
+            "[slicer] {}x{} sliced into {count} segments",
+            img.width(),
+            img.height()
+        );
+        Ok(segments)
+    }
+
+    fn encode(&self, img: &DynamicImage) -> anyhow::Result<Bytes> {
+        let mut out = Cursor::new(Vec::new());
+        JpegEncoder::new_with_quality(&mut out, self.quality).encode_image(img)?;
+        Ok(out.into_inner().into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Red at the top, blue at the bottom.
+    fn strip_png(width: u32, height: u32) -> Bytes {
+        let img = image::RgbImage::from_fn(width, height, |_, y| {
+            let blue = (y * 255 / (height - 1)) as u8;
+            image::Rgb([255 - blue, 0, blue])
+        });
+        let mut out = Cursor::new(Vec::new());
+        img.write_to(&mut out, image::ImageFormat::Png).unwrap();
+        out.into_inner().into()
+    }
+
+    fn dimensions(data: &[u8]) -> (u32, u32) {
+        let img = image::load_from_memory(data).unwrap();
+        (img.width(), img.height())
+    }
+
+    #[test]
+    fn test_split_tall() {
+        let strip = strip_png(100, 1000);
+        let slicer = ImageSlicer::new(None, Some(300), true);
+        assert!(slicer.should_slice(&strip));
+        let segments = slicer.slice(strip).unwrap();
+        assert_eq!(segments.len(), 4);
+        assert!(segments.iter().all(|s| dimensions(s) == (100, 250)));
+        // in reading order
+        let first = image::load_from_memory(&segments[0]).unwrap().to_rgb8();
+        let last = image::load_from_memory(&segments[3]).unwrap().to_rgb8();
+        assert!(first.get_pixel(50, 0)[0] > 200);
+        assert!(last.get_pixel(50, 249)[2] > 200);
+
+        // downscaled to the max width first, so it is shorter too
+        let slicer = ImageSlicer::new(Some(50), Some(300), true);
+        let segments = slicer.slice(strip_png(100, 1000)).unwrap();
+        assert_eq!(segments.len(), 2);
+        assert!(segments.iter().all(|s| dimensions(s) == (50, 250)));
+    }
+
+    #[test]
+    fn test_downscale() {
+        let small = strip_png(100, 200);
+        let slicer = ImageSlicer::new(Some(100), Some(300), false);
+        assert!(!slicer.should_slice(&small));
+        assert_eq!(slicer.slice(small.clone()).unwrap(), [small]);
+
+        let segments = slicer.slice(strip_png(100, 1000)).unwrap();
+        assert_eq!(segments.len(), 1);
+        assert_eq!(dimensions(&segments[0]), (30, 300));
+        let low = slicer.with_quality(10).slice(strip_png(100, 1000)).unwrap();
+        assert!(low[0].len() < segments[0].len());
+
+        // not decodable, left as is
+        let avif = Bytes::from_static(b"\x00\x00\x00\x1cftypavif\x00\x00\x00\x00");
+        assert!(!slicer.should_slice(&avif));
+    }
+}
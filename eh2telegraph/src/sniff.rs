@@ -0,0 +1,174 @@
+//! Detect the image type by the magic bytes, since some image hosts serve WebP or
+//! AVIF under a `.jpg` url.
+
+use std::collections::BTreeSet;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ImageKind {
+    Jpeg,
+    Png,
+    Gif,
+    Webp,
+    Avif,
+    Jxl,
+    Bmp,
+    Unknown,
+}
+
+impl ImageKind {
+    pub fn sniff(data: &[u8]) -> Self {
+        match data {
+            [0xFF, 0xD8, 0xFF, ..] => Self::Jpeg,
+            [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A, ..] => Self::Png,
+            [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => Self::Gif,
+            [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Self::Webp,
+            // ISO BMFF with an AVIF major brand
+            [_, _, _, _, b'f', b't', b'y', b'p', b'a', b'v', b'i', b'f' | b's', ..] => Self::Avif,
+            // the bare codestream, or the ISO BMFF container
+            [0xFF, 0x0A, ..]
+            | [0, 0, 0, 0x0C, b'J', b'X', b'L', b' ', 0x0D, 0x0A, 0x87, 0x0A, ..] => Self::Jxl,
+            [b'B', b'M', ..] => Self::Bmp,
+            _ => Self::Unknown,
+        }
+    }
+
+    /// Extension of the uploaded file name. Unknown ones are uploaded as JPEG like
+    /// before sniffing.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Jpeg | Self::Unknown => "jpg",
+            Self::Png => "png",
+            Self::Gif => "gif",
+            Self::Webp => "webp",
+            Self::Avif => "avif",
+            Self::Jxl => "jxl",
+            Self::Bmp => "bmp",
+        }
+    }
+
+    pub fn mime(self) -> &'static str {
+        match self {
+            Self::Jpeg | Self::Unknown => "image/jpeg",
+            Self::Png => "image/png",
+            Self::Gif => "image/gif",
+            Self::Webp => "image/webp",
+            Self::Avif => "image/avif",
+            Self::Jxl => "image/jxl",
+            Self::Bmp => "image/bmp",
+        }
+    }
+
+    /// The type of a MIME type, None for non image types.
+    pub fn from_mime(mime: &str) -> Option<Self> {
+        [
+            Self::Jpeg,
+            Self::Png,
+            Self::Gif,
+            Self::Webp,
+            Self::Avif,
+            Self::Jxl,
+            Self::Bmp,
+        ]
+        .into_iter()
+        .find(|k| k.mime().eq_ignore_ascii_case(mime.trim()))
+    }
+
+    /// Whether the upload target takes it, others must be transcoded.
+    pub fn is_accepted(self) -> bool {
+        !matches!(self, Self::Avif | Self::Jxl | Self::Bmp)
+    }
+
+    /// Whether it can be decoded for re-encoding, AVIF and JPEG XL only with the
+    /// `avif` and `jxl` features.
+    pub fn is_decodable(self) -> bool {
+        !matches!(
+            (self, cfg!(feature = "avif"), cfg!(feature = "jxl")),
+            (Self::Avif, false, _) | (Self::Jxl, _, false)
+        )
+    }
+}
+
+/// Image types allowed to be uploaded, checked against the sniffed type so a body
+/// which is not an image, like an error page served with 200, is never uploaded.
+/// `Unknown` is never allowed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TypeAllowlist(BTreeSet<ImageKind>);
+
+impl Default for TypeAllowlist {
+    fn default() -> Self {
+        Self(
+            [
+                ImageKind::Jpeg,
+                ImageKind::Png,
+                ImageKind::Gif,
+                ImageKind::Webp,
+            ]
+            .into_iter()
+            .collect(),
+        )
+    }
+}
+
+impl TypeAllowlist {
+    /// Fail with the first MIME type which is not an image type.
+    pub fn from_mimes<S: AsRef<str>>(mimes: &[S]) -> Result<Self, String> {
+        mimes
+            .iter()
+            .map(|m| ImageKind::from_mime(m.as_ref()).ok_or_else(|| m.as_ref().to_string()))
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+
+    pub fn allows(&self, kind: ImageKind) -> bool {
+        self.0.contains(&kind)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sniff() {
+        // all served as .jpg
+        let cases: [(&[u8], ImageKind); 10] = [
+            (b"\xFF\xD8\xFF\xE0\x00\x10JFIF", ImageKind::Jpeg),
+            (b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR", ImageKind::Png),
+            (b"GIF89a\x01\x00\x01\x00", ImageKind::Gif),
+            (b"RIFF\x24\x00\x00\x00WEBPVP8 ", ImageKind::Webp),
+            (b"\x00\x00\x00\x1cftypavif\x00\x00\x00\x00", ImageKind::Avif),
+            (b"\x00\x00\x00\x20ftypavis\x00\x00\x00\x00", ImageKind::Avif),
+            (b"\xFF\x0A\xFA\x7F", ImageKind::Jxl),
+            (b"\x00\x00\x00\x0CJXL \x0D\x0A\x87\x0A", ImageKind::Jxl),
+            (b"BM\x3a\x00\x00\x00", ImageKind::Bmp),
+            (b"<html>blocked</html>", ImageKind::Unknown),
+        ];
+        for (data, kind) in cases {
+            assert_eq!(ImageKind::sniff(data), kind, "{data:?}");
+        }
+        assert_eq!(ImageKind::sniff(b""), ImageKind::Unknown);
+        assert_eq!(
+            ImageKind::sniff(b"RIFF\x24\x00\x00\x00WAVE"),
+            ImageKind::Unknown
+        );
+        assert!(!ImageKind::Avif.is_accepted() && !ImageKind::Jxl.is_accepted());
+        assert_eq!(ImageKind::Avif.is_decodable(), cfg!(feature = "avif"));
+        assert_eq!(ImageKind::Jxl.is_decodable(), cfg!(feature = "jxl"));
+        assert!(ImageKind::Webp.is_decodable());
+        assert_eq!(ImageKind::Webp.extension(), "webp");
+    }
+
+    #[test]
+    fn test_allowlist() {
+        let allowlist = TypeAllowlist::default();
+        assert!(allowlist.allows(ImageKind::Webp));
+        assert!(!allowlist.allows(ImageKind::Bmp) && !allowlist.allows(ImageKind::Unknown));
+
+        let allowlist = TypeAllowlist::from_mimes(&["image/png", " IMAGE/JPEG"]).unwrap();
+        assert!(allowlist.allows(ImageKind::Jpeg) && !allowlist.allows(ImageKind::Gif));
+        assert_eq!(
+            TypeAllowlist::from_mimes(&["image/png", "text/html"]),
+            Err("text/html".to_string())
+        );
+    }
+}
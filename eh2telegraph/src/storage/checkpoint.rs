@@ -0,0 +1,82 @@
+//! Progress of unfinished syncs, so an interrupted sync can skip the uploaded images.
+
+use std::collections::BTreeSet;
+
+use serde::{Deserialize, Serialize};
+
+use super::KVStorage;
+
+const KEY_PREFIX: &str = "checkpoint|";
+// an unfinished sync is unlikely to be resumed after a week
+const CHECKPOINT_TTL: usize = 3600 * 24 * 7;
+
+/// An image uploaded to Telegraph.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CheckpointImage {
+    /// Position of the image in the gallery stream.
+    pub index: usize,
+    pub id: String,
+    pub url: String,
+    pub description: Option<String>,
+    /// Telegraph url of the image.
+    pub src: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub images: Vec<CheckpointImage>,
+    /// Content fingerprint of the gallery, taken before the first upload.
+    #[serde(default)]
+    pub fingerprint: Option<String>,
+}
+
+impl Checkpoint {
+    pub fn uploaded(&self) -> BTreeSet<usize> {
+        self.images.iter().map(|i| i.index).collect()
+    }
+}
+
+/// Stores checkpoints as JSON in any string `KVStorage`, keyed by gallery.
+#[derive(Debug)]
+pub struct CheckpointStore<'a, S> {
+    storage: &'a S,
+}
+
+impl<S> Clone for CheckpointStore<'_, S> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<S> Copy for CheckpointStore<'_, S> {}
+
+impl<'a, S> CheckpointStore<'a, S>
+where
+    S: KVStorage<String>,
+{
+    pub fn new(storage: &'a S) -> Self {
+        Self { storage }
+    }
+
+    /// Load the checkpoint of the gallery, an empty one if there is none.
+    pub async fn load(&self, key: &str) -> anyhow::Result<Checkpoint> {
+        match self.storage.get(&format!("{KEY_PREFIX}{key}")).await? {
+            Some(v) => Ok(serde_json::from_str(&v)?),
+            None => Ok(Checkpoint::default()),
+        }
+    }
+
+    pub async fn save(&self, key: &str, checkpoint: &Checkpoint) -> anyhow::Result<()> {
+        self.storage
+            .set(
+                format!("{KEY_PREFIX}{key}"),
+                serde_json::to_string(checkpoint)?,
+                Some(CHECKPOINT_TTL),
+            )
+            .await
+    }
+
+    pub async fn clear(&self, key: &str) -> anyhow::Result<()> {
+        self.storage.delete(&format!("{KEY_PREFIX}{key}")).await
+    }
+}
@@ -46,6 +46,15 @@ impl CFStorage {
     }
 }
 
+pub(crate) fn validate_config(config: &config::Config, errors: &mut config::ConfigErrors) {
+    if let Some(c) = config.section::<CFConfig>(CONFIG_KEY, errors) {
+        let expire = Duration::from_secs(c.expire_sec);
+        if let Err(e) = CFStorage::new(c.endpoint, c.token, c.cache_size, expire) {
+            errors.push(CONFIG_KEY, e);
+        }
+    }
+}
+
 impl<T> KVStorage<T> for CFStorage
 where
     T: DeserializeOwned + Serialize + Send + Sync,
@@ -73,7 +82,7 @@ pub enum CFOrMemStorage<T> {
     CF(CFStorage),
 }
 
-impl<T> CFOrMemStorage<T> {
+impl<T: Send + Sync + 'static> CFOrMemStorage<T> {
     pub fn new_from_config() -> Self {
         match CFStorage::new_from_config() {
             Ok(s) => CFOrMemStorage::CF(s),
@@ -81,7 +90,7 @@ impl<T> CFOrMemStorage<T> {
                 tracing::error!(
                     "unable to read cloudflare cache settings, will use in memory cache: {e:?}"
                 );
-                CFOrMemStorage::Mem(SimpleMemStorage::<T>::default())
+                CFOrMemStorage::Mem(SimpleMemStorage::<T>::new_from_config())
             }
         }
     }
@@ -0,0 +1,207 @@
+//! Pages of synced galleries keyed by content, so a gallery posted under another url
+//! is not uploaded again.
+//!
+//! The content is the page count and the dHashes of the leading images, compared
+//! within a small distance so a re-encoded copy still matches. Galleries of a page
+//! count and upload options share one entry list.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{phash, telegraph::types::Page};
+
+use super::KVStorage;
+
+const KEY_PREFIX: &str = "dedup|";
+/// At most this many leading images are hashed.
+pub const FINGERPRINT_IMAGES: usize = 4;
+/// Hashes of the same image re-encoded are within it.
+pub const MAX_DISTANCE: u32 = 4;
+/// Galleries kept of a page count and options, the oldest are dropped beyond it.
+const MAX_ENTRIES: usize = 64;
+
+/// The page count and a hash of each leading image, like `12:0f3c..,a871..`.
+/// Images which can not be decoded are hashed by their bytes instead.
+/// This is CPU bound, call it in a blocking thread.
+pub fn fingerprint<'a>(total: usize, images: impl IntoIterator<Item = &'a [u8]>) -> String {
+    let hashes = images
+        .into_iter()
+        .take(FINGERPRINT_IMAGES)
+        .map(|image| {
+            let hash = phash::dhash(image).unwrap_or_else(|| {
+                let digest = Sha256::digest(image);
+                u64::from_le_bytes(digest[..8].try_into().expect("8 bytes of the digest"))
+            });
+            format!("{hash:016x}")
+        })
+        .collect::<Vec<_>>();
+    format!("{total}:{}", hashes.join(","))
+}
+
+fn parse(fingerprint: &str) -> anyhow::Result<(usize, Vec<u64>)> {
+    let (total, hashes) = fingerprint
+        .split_once(':')
+        .ok_or_else(|| anyhow::anyhow!("invalid fingerprint {fingerprint}"))?;
+    let hashes = hashes
+        .split(',')
+        .filter(|h| !h.is_empty())
+        .map(|h| u64::from_str_radix(h, 16))
+        .collect::<Result<_, _>>()?;
+    Ok((total.parse()?, hashes))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    hashes: Vec<u64>,
+    pages: Vec<Page>,
+}
+
+impl Entry {
+    fn matches(&self, hashes: &[u64]) -> bool {
+        self.hashes.len() == hashes.len()
+            && self
+                .hashes
+                .iter()
+                .zip(hashes)
+                .all(|(a, b)| phash::distance(*a, *b) <= MAX_DISTANCE)
+    }
+}
+
+/// Stores created pages as JSON in any string `KVStorage`, found by fingerprint.
+/// `variant` tells apart the pages of the same content created with other options.
+/// Note the update is not atomic, entries of concurrent syncs may be lost.
+#[derive(Debug)]
+pub struct DedupStore<'a, S> {
+    storage: &'a S,
+}
+
+impl<S> Clone for DedupStore<'_, S> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<S> Copy for DedupStore<'_, S> {}
+
+impl<'a, S> DedupStore<'a, S>
+where
+    S: KVStorage<String>,
+{
+    pub fn new(storage: &'a S) -> Self {
+        Self { storage }
+    }
+
+    async fn entries(&self, key: &str) -> anyhow::Result<Vec<Entry>> {
+        match self.storage.get(key).await? {
+            Some(v) => Ok(serde_json::from_str(&v)?),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    pub async fn get(&self, fingerprint: &str, variant: &str) -> anyhow::Result<Option<Vec<Page>>> {
+        let (total, hashes) = parse(fingerprint)?;
+        let entries = self
+            .entries(&format!("{KEY_PREFIX}{variant}|{total}"))
+            .await?;
+        Ok(entries
+            .into_iter()
+            .rev()
+            .find(|e| e.matches(&hashes))
+            .map(|e| e.pages))
+    }
+
+    pub async fn set(
+        &self,
+        fingerprint: &str,
+        variant: &str,
+        pages: &[Page],
+        expire_ttl: Option<usize>,
+    ) -> anyhow::Result<()> {
+        let (total, hashes) = parse(fingerprint)?;
+        let key = format!("{KEY_PREFIX}{variant}|{total}");
+        let mut entries = self.entries(&key).await?;
+        entries.retain(|e| !e.matches(&hashes));
+        entries.push(Entry {
+            hashes,
+            pages: pages.to_vec(),
+        });
+        let extra = entries.len().saturating_sub(MAX_ENTRIES);
+        entries.drain(..extra);
+        self.storage
+            .set(key, serde_json::to_string(&entries)?, expire_ttl)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{codecs::jpeg::JpegEncoder, RgbImage};
+
+    use super::*;
+    use crate::storage::SimpleMemStorage;
+
+    fn jpeg(seed: u32, quality: u8) -> Vec<u8> {
+        let img = RgbImage::from_fn(96, 128, |x, y| {
+            let v = ((x as f32 / 9.0 + seed as f32).sin() * (y as f32 / 13.0).cos() + 1.0) * 127.0;
+            image::Rgb([v as u8, (255.0 - v) as u8, 128])
+        });
+        let mut out = Cursor::new(Vec::new());
+        JpegEncoder::new_with_quality(&mut out, quality)
+            .encode_image(&img)
+            .unwrap();
+        out.into_inner()
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let images: [&[u8]; 5] = [b"a", b"b", b"c", b"d", b"e"];
+        let fp = fingerprint(10, images);
+        assert!(fp.starts_with("10:"));
+        assert_eq!(parse(&fp).unwrap().1.len(), FINGERPRINT_IMAGES);
+        assert_eq!(fp, fingerprint(10, images));
+        // only the leading images count
+        assert_eq!(
+            fp,
+            fingerprint(10, [b"a", b"b", b"c", b"d", b"x"].map(|i| &i[..]))
+        );
+        assert_ne!(fp, fingerprint(11, images));
+        assert_ne!(
+            fp,
+            fingerprint(10, [b"b", b"a", b"c", b"d"].map(|i| &i[..]))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reencoded() {
+        let storage = SimpleMemStorage::<String>::default();
+        let store = DedupStore::new(&storage);
+        let fp = |quality| {
+            let images = [jpeg(1, quality), jpeg(2, quality)];
+            fingerprint(20, images.iter().map(Vec::as_slice))
+        };
+        let page = Page {
+            path: "p".to_string(),
+            url: "https://telegra.ph/p".to_string(),
+            title: "t".to_string(),
+            description: String::new(),
+            author_name: None,
+            author_url: None,
+            image_url: None,
+            content: None,
+            views: 0,
+            can_edit: None,
+        };
+        store.set(&fp(90), "v", &[page], None).await.unwrap();
+
+        // the same pages re-encoded
+        let pages = store.get(&fp(60), "v").await.unwrap().unwrap();
+        assert_eq!(pages[0].url, "https://telegra.ph/p");
+        // created with other options
+        assert!(store.get(&fp(60), "w").await.unwrap().is_none());
+        let other = [jpeg(3, 90), jpeg(4, 90)];
+        let other = fingerprint(20, other.iter().map(Vec::as_slice));
+        assert!(store.get(&other, "v").await.unwrap().is_none());
+    }
+}
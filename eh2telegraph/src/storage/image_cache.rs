@@ -0,0 +1,442 @@
+//! Downloaded images on disk, so syncing a gallery again does not download them again.
+//!
+//! Files are named by the SHA-256 of the source url. The least recently used ones are
+//! pruned beyond the size limit, the order of use is kept in the file mtime so it
+//! survives restarts. Images downloaded with `download_cached` are received into
+//! `{name}.part` first, so a download interrupted by a failure or a restart is resumed.
+
+use std::{
+    collections::HashSet,
+    fs,
+    future::Future,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
+
+use again::RetryPolicy;
+use bytes::Bytes;
+use hashlink::LinkedHashMap;
+use parking_lot::Mutex;
+use reqwest::header::HeaderMap;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::{config, http_proxy::ProxiedClient, util::get_bytes_with_headers};
+
+const CONFIG_KEY: &str = "storage";
+
+#[derive(Debug, Deserialize)]
+struct StorageConfig {
+    image_cache: Option<ImageCacheConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ImageCacheConfig {
+    /// Directory of the images, created if not exists.
+    pub dir: String,
+    /// Prune the least recently used images beyond it, unbounded if not set.
+    pub max_bytes: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+struct Index {
+    /// File name to size, in the order of use.
+    files: LinkedHashMap<String, u64>,
+    total: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct ImageCache {
+    dir: Arc<PathBuf>,
+    max_bytes: Option<u64>,
+    index: Arc<Mutex<Index>>,
+    // names of the `.part` files being written
+    downloading: Arc<Mutex<HashSet<String>>>,
+}
+
+/// Releases the `.part` file of a download when dropped.
+struct PartGuard<'a> {
+    downloading: &'a Mutex<HashSet<String>>,
+    name: String,
+}
+
+impl Drop for PartGuard<'_> {
+    fn drop(&mut self) {
+        self.downloading.lock().remove(&self.name);
+    }
+}
+
+fn file_name(url: &str) -> String {
+    Sha256::digest(url.as_bytes())
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+impl ImageCache {
+    /// Open the directory and index the images already in it.
+    pub fn open(dir: impl AsRef<Path>, max_bytes: Option<u64>) -> anyhow::Result<Self> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        let mut files = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let meta = entry.metadata()?;
+            let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+                continue;
+            };
+            // left by a put interrupted by a restart
+            if meta.is_file() && name.ends_with(".tmp") {
+                if let Err(e) = fs::remove_file(entry.path()) {
+                    tracing::warn!("[image cache] unable to remove {name}: {e}");
+                }
+                continue;
+            }
+            // partial downloads are kept for resuming
+            if !meta.is_file() || [".part", ".validator"].iter().any(|e| name.ends_with(e)) {
+                continue;
+            }
+            files.push((meta.modified()?, name, meta.len()));
+        }
+        files.sort();
+
+        let mut index = Index::default();
+        for (_, name, len) in files {
+            index.total += len;
+            index.files.insert(name, len);
+        }
+        tracing::info!(
+            "[image cache] opened {} with {} images of {} bytes",
+            dir.display(),
+            index.files.len(),
+            index.total
+        );
+        let cache = Self {
+            dir: Arc::new(dir.to_path_buf()),
+            max_bytes,
+            index: Arc::new(Mutex::new(index)),
+            downloading: Default::default(),
+        };
+        let pruned = cache.prune();
+        remove_files(&cache.dir, &pruned);
+        Ok(cache)
+    }
+
+    /// `None` if there is no image cache in the config.
+    pub fn new_from_config() -> anyhow::Result<Option<Self>> {
+        let Some(config) = config::parse::<StorageConfig>(CONFIG_KEY)?.and_then(|c| c.image_cache)
+        else {
+            return Ok(None);
+        };
+        Self::open(&config.dir, config.max_bytes).map(Some)
+    }
+
+    pub async fn get(&self, url: &str) -> Option<Bytes> {
+        let name = file_name(url);
+        self.index.lock().files.to_back(&name)?;
+        let path = self.dir.join(&name);
+        let read = tokio::task::spawn_blocking(move || {
+            let data = fs::read(&path)?;
+            // keep the order of use for the next open
+            fs::File::options()
+                .append(true)
+                .open(&path)?
+                .set_modified(SystemTime::now())?;
+            std::io::Result::Ok(data)
+        })
+        .await;
+        match read {
+            Ok(Ok(data)) => Some(data.into()),
+            Ok(Err(e)) => {
+                tracing::warn!("[image cache] unable to read the image of {url}: {e}");
+                let mut index = self.index.lock();
+                if let Some(len) = index.files.remove(&name) {
+                    index.total -= len;
+                }
+                None
+            }
+            Err(_) => None,
+        }
+    }
+
+    pub async fn put(&self, url: &str, data: Bytes) -> anyhow::Result<()> {
+        let name = file_name(url);
+        let dir = self.dir.clone();
+        let len = data.len() as u64;
+        tokio::task::spawn_blocking({
+            let name = name.clone();
+            move || {
+                // renamed in place so a reader never sees a partial file, unique since
+                // the same image may be put by concurrent syncs
+                let tmp = dir.join(format!("{name}.{}.tmp", uuid::Uuid::new_v4().simple()));
+                fs::write(&tmp, &data)?;
+                fs::rename(&tmp, dir.join(&name))
+            }
+        })
+        .await??;
+        self.insert(name, len).await
+    }
+
+    /// Index the file `name` which is just written.
+    async fn insert(&self, name: String, len: u64) -> anyhow::Result<()> {
+        {
+            let mut index = self.index.lock();
+            if let Some(old) = index.files.insert(name, len) {
+                index.total -= old;
+            }
+            index.total += len;
+        }
+        let pruned = self.prune();
+        if !pruned.is_empty() {
+            let dir = self.dir.clone();
+            tokio::task::spawn_blocking(move || remove_files(&dir, &pruned)).await?;
+        }
+        Ok(())
+    }
+
+    /// Drop the image of `url`, like a downloaded one which turns out not to be an image.
+    pub async fn remove(&self, url: &str) {
+        let name = file_name(url);
+        {
+            let mut index = self.index.lock();
+            let Some(len) = index.files.remove(&name) else {
+                return;
+            };
+            index.total -= len;
+        }
+        let dir = self.dir.clone();
+        let _ = tokio::task::spawn_blocking(move || remove_files(&dir, &[name])).await;
+    }
+
+    /// The cached image of `url`, or the one downloaded from `link` with `client` which
+    /// is cached then. Every attempt of `policy` resumes from the bytes received by the
+    /// last one, they are kept in `{name}.part` until the download is complete.
+    pub async fn get_or_download(
+        &self,
+        url: &str,
+        client: &ProxiedClient,
+        link: &str,
+        headers: HeaderMap,
+        policy: &RetryPolicy,
+    ) -> anyhow::Result<Bytes> {
+        if let Some(data) = self.get(url).await {
+            tracing::trace!("[image cache] hit {url}");
+            return Ok(data);
+        }
+        let name = file_name(url);
+        // the same image downloaded by another sync would write the same file
+        if !self.downloading.lock().insert(name.clone()) {
+            let download = policy.retry(|| get_bytes_with_headers(client, link, headers.clone()));
+            return Ok(self.get_or_fetch(url, download).await?);
+        }
+        let _guard = PartGuard {
+            downloading: &self.downloading,
+            name: name.clone(),
+        };
+        let part = self.dir.join(format!("{name}.part"));
+        let len = policy
+            .retry(|| {
+                client.download_resumable_with_headers(link, headers.clone(), &part, |_, _| {})
+            })
+            .await?;
+        let data = tokio::fs::read(&part).await?;
+        match tokio::fs::rename(&part, self.dir.join(&name)).await {
+            Ok(()) => self.insert(name, len).await?,
+            Err(e) => tracing::warn!("[image cache] unable to store the image of {url}: {e}"),
+        }
+        Ok(data.into())
+    }
+
+    /// The cached image of `url`, or the one from `fetch` which is cached then.
+    /// Failures of the cache are logged, the image is downloaded as if it is not cached.
+    pub async fn get_or_fetch<F, E>(&self, url: &str, fetch: F) -> Result<Bytes, E>
+    where
+        F: Future<Output = Result<Bytes, E>>,
+    {
+        if let Some(data) = self.get(url).await {
+            tracing::trace!("[image cache] hit {url}");
+            return Ok(data);
+        }
+        let data = fetch.await?;
+        if let Err(e) = self.put(url, data.clone()).await {
+            tracing::warn!("[image cache] unable to store the image of {url}: {e}");
+        }
+        Ok(data)
+    }
+
+    /// Drop the least recently used images from the index until it fits, the last
+    /// image is kept even if it is larger than the limit. The dropped names are returned.
+    fn prune(&self) -> Vec<String> {
+        let Some(max) = self.max_bytes else {
+            return Vec::new();
+        };
+        let mut index = self.index.lock();
+        let mut pruned = Vec::new();
+        while index.total > max && index.files.len() > 1 {
+            let Some((name, len)) = index.files.pop_front() else {
+                break;
+            };
+            index.total -= len;
+            pruned.push(name);
+        }
+        pruned
+    }
+}
+
+fn remove_files(dir: &Path, names: &[String]) {
+    for name in names {
+        if let Err(e) = fs::remove_file(dir.join(name)) {
+            tracing::warn!("[image cache] unable to remove {name}: {e}");
+        }
+    }
+}
+
+/// Download with `fetch` through the cache if there is one.
+pub async fn fetch_cached<F, E>(cache: Option<&ImageCache>, url: &str, fetch: F) -> Result<Bytes, E>
+where
+    F: Future<Output = Result<Bytes, E>>,
+{
+    match cache {
+        Some(cache) => cache.get_or_fetch(url, fetch).await,
+        None => fetch.await,
+    }
+}
+
+/// Download `link` with `client` through the cache if there is one, resumed by the
+/// retries of `policy`, see `ImageCache::get_or_download`.
+pub async fn download_cached(
+    cache: Option<&ImageCache>,
+    url: &str,
+    client: &ProxiedClient,
+    link: &str,
+    headers: HeaderMap,
+    policy: &RetryPolicy,
+) -> anyhow::Result<Bytes> {
+    match cache {
+        Some(cache) => {
+            cache
+                .get_or_download(url, client, link, headers, policy)
+                .await
+        }
+        None => Ok(policy
+            .retry(|| get_bytes_with_headers(client, link, headers.clone()))
+            .await?),
+    }
+}
+
+/// The directory is created on open, so only its parent must exist.
+pub(crate) fn validate_config(config: &config::Config, errors: &mut config::ConfigErrors) {
+    let Some(cache) = config
+        .section::<StorageConfig>(CONFIG_KEY, errors)
+        .and_then(|c| c.image_cache)
+    else {
+        return;
+    };
+    let dir = Path::new(&cache.dir);
+    match dir.parent().filter(|p| !p.as_os_str().is_empty()) {
+        Some(parent) if !parent.is_dir() => {
+            errors.push(
+                "storage.image_cache",
+                format!("directory {} does not exist", parent.display()),
+            );
+        }
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_prune() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = ImageCache::open(dir.path(), Some(10)).unwrap();
+        cache.put("a", Bytes::from_static(b"aaaa")).await.unwrap();
+        cache.put("b", Bytes::from_static(b"bbbb")).await.unwrap();
+        // a is used later than b, so b is pruned for c
+        assert_eq!(cache.get("a").await.unwrap(), &b"aaaa"[..]);
+        cache.put("c", Bytes::from_static(b"cccc")).await.unwrap();
+        assert!(cache.get("b").await.is_none());
+        assert!(!dir.path().join(file_name("b")).exists());
+        assert!(cache.get("a").await.is_some() && cache.get("c").await.is_some());
+
+        // the index is rebuilt from the files
+        let cache = ImageCache::open(dir.path(), Some(10)).unwrap();
+        assert_eq!(cache.get("c").await.unwrap(), &b"cccc"[..]);
+        let cache = ImageCache::open(dir.path(), Some(4)).unwrap();
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+        assert!(cache.get("c").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_put() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = ImageCache::open(dir.path(), None).unwrap();
+        let puts = (0..8).map(|_| cache.put("a", Bytes::from_static(b"aaaa")));
+        for put in futures::future::join_all(puts).await {
+            put.unwrap();
+        }
+        assert_eq!(cache.get("a").await.unwrap(), &b"aaaa"[..]);
+        assert_eq!(cache.index.lock().total, 4);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        // the ones of an interrupted put are removed on open
+        fs::write(dir.path().join(format!("{}.x.tmp", file_name("b"))), b"b").unwrap();
+        ImageCache::open(dir.path(), None).unwrap();
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_get_or_download() {
+        use crate::mock_server::{MockResponse, MockServer};
+        use std::time::Duration;
+
+        let body: Vec<u8> = (0..64 * 1024).map(|i| i as u8).collect();
+        let expected = body.clone();
+        let server = MockServer::start(move |idx, req| {
+            let start = req
+                .header("range")
+                .and_then(|r| r.strip_prefix("bytes="))
+                .and_then(|r| r.trim_end_matches('-').parse::<usize>().ok());
+            match (idx, start) {
+                (0, _) => MockResponse::new(200, body.clone())
+                    .header("etag", "\"v1\"")
+                    .cut(40000),
+                (_, Some(start)) => MockResponse::new(206, body[start..].to_vec())
+                    .header("etag", "\"v1\"")
+                    .header(
+                        "content-range",
+                        &format!("bytes {start}-{}/{}", body.len() - 1, body.len()),
+                    ),
+                _ => MockResponse::new(200, body.clone()),
+            }
+        })
+        .await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let cache = ImageCache::open(dir.path(), None).unwrap();
+        let client = ProxiedClient::default();
+        let policy = RetryPolicy::fixed(Duration::from_millis(1)).with_max_retries(2);
+        let link = server.url("/image.jpg");
+        let data = cache
+            .get_or_download("page", &client, &link, HeaderMap::new(), &policy)
+            .await
+            .unwrap();
+        assert_eq!(data, expected);
+        // the retry resumed the first attempt
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[1].header("range").is_some());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        // hit without a request
+        let data = cache
+            .get_or_download("page", &client, &link, HeaderMap::new(), &policy)
+            .await
+            .unwrap();
+        assert_eq!(data, expected);
+        assert_eq!(server.requests().len(), 2);
+    }
+}
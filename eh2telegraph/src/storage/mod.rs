@@ -1,9 +1,24 @@
 use futures::Future;
-use parking_lot::RwLock;
-use std::{collections::HashMap, sync::Arc};
+use hashlink::LinkedHashMap;
+use parking_lot::Mutex;
+use serde::Deserialize;
+use std::{sync::Arc, time::Duration};
+use tokio::time::Instant;
 
+use crate::config;
+
+pub mod checkpoint;
 pub mod cloudflare_kv;
+pub mod dedup;
+pub mod image_cache;
 pub mod lru;
+pub mod near_dup;
+pub mod queue;
+pub mod rate_limit;
+#[cfg(feature = "redis")]
+pub mod redis;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
 
 pub trait KVStorage<V> {
     fn get(&self, key: &str) -> impl Future<Output = anyhow::Result<Option<V>>> + Send;
@@ -16,18 +31,140 @@ pub trait KVStorage<V> {
     fn delete(&self, key: &str) -> impl Future<Output = anyhow::Result<()>> + Send;
 }
 
+const CONFIG_KEY: &str = "storage";
+const DEFAULT_SWEEP_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Check the settings of all storage backends, used or not.
+pub(crate) fn validate_config(config: &config::Config, errors: &mut config::ConfigErrors) {
+    config.section::<MemConfig>(CONFIG_KEY, errors);
+    #[cfg(feature = "redis")]
+    redis::validate_config(config, errors);
+    #[cfg(feature = "sqlite")]
+    sqlite::validate_config(config, errors);
+    cloudflare_kv::validate_config(config, errors);
+    image_cache::validate_config(config, errors);
+    queue::validate_config(config, errors);
+}
+
+/// Settings of the in memory storage, other backends have their own sub keys.
+#[derive(Debug, Default, Deserialize)]
+struct MemConfig {
+    /// Evict the least recently used entries beyond it, unbounded if not set.
+    max_entries: Option<usize>,
+    /// TTL of entries set without one, never expire if not set.
+    default_ttl_sec: Option<u64>,
+    sweep_interval_sec: Option<u64>,
+}
+
+#[derive(Debug)]
+struct Entry<T> {
+    value: T,
+    expire_at: Option<Instant>,
+}
+
+impl<T> Entry<T> {
+    fn is_expired(&self, now: Instant) -> bool {
+        matches!(self.expire_at, Some(at) if at <= now)
+    }
+}
+
+/// In memory storage with optional expiry and size bound.
+/// Expired entries are treated as absent and removed on access, or by
+/// the sweeper started with `spawn_sweeper`.
+/// Entries are kept in the order of use, so the least recently used one is
+/// evicted in O(1) when `max_entries` is exceeded.
 #[derive(Clone, Debug)]
-pub struct SimpleMemStorage<T>(Arc<RwLock<HashMap<String, T>>>);
+pub struct SimpleMemStorage<T> {
+    entries: Arc<Mutex<LinkedHashMap<String, Entry<T>>>>,
+    default_ttl: Option<Duration>,
+    max_entries: Option<usize>,
+}
 
 impl<T> Default for SimpleMemStorage<T> {
     fn default() -> Self {
-        Self(Arc::new(RwLock::new(HashMap::new())))
+        Self::with_capacity(0)
     }
 }
 
 impl<T> SimpleMemStorage<T> {
     pub fn with_capacity(capacity: usize) -> Self {
-        Self(Arc::new(RwLock::new(HashMap::with_capacity(capacity))))
+        Self {
+            entries: Arc::new(Mutex::new(LinkedHashMap::with_capacity(capacity))),
+            default_ttl: None,
+            max_entries: None,
+        }
+    }
+
+    /// Evict the least recently used entries beyond `max`.
+    pub fn with_max_entries(mut self, max: Option<usize>) -> Self {
+        self.max_entries = max;
+        self
+    }
+
+    /// TTL of entries set without one.
+    pub fn with_default_ttl(mut self, ttl: Option<Duration>) -> Self {
+        self.default_ttl = ttl;
+        self
+    }
+
+    /// Build with the settings of `storage` and start the sweeper.
+    /// Must be called in a tokio runtime.
+    pub fn new_from_config() -> Self
+    where
+        T: Send + Sync + 'static,
+    {
+        let config: MemConfig = config::parse(CONFIG_KEY)
+            .unwrap_or_else(|e| {
+                tracing::error!("unable to parse storage config, use the default: {e}");
+                None
+            })
+            .unwrap_or_default();
+        let storage = Self::default()
+            .with_default_ttl(config.default_ttl_sec.map(Duration::from_secs))
+            .with_max_entries(config.max_entries);
+        storage.spawn_sweeper(
+            config
+                .sweep_interval_sec
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_SWEEP_INTERVAL),
+        );
+        storage
+    }
+
+    /// Purge expired entries periodically. The task exits once all clones
+    /// of the storage are dropped.
+    pub fn spawn_sweeper(&self, interval: Duration) -> tokio::task::JoinHandle<()>
+    where
+        T: Send + Sync + 'static,
+    {
+        let entries = Arc::downgrade(&self.entries);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            // the first tick completes immediately
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let Some(entries) = entries.upgrade() else {
+                    return;
+                };
+                let now = Instant::now();
+                let mut entries = entries.lock();
+                let before = entries.len();
+                entries.retain(|_, e| !e.is_expired(now));
+                if entries.len() != before {
+                    tracing::debug!("[mem cache] purge {} entries", before - entries.len());
+                }
+            }
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.lock().is_empty()
     }
 }
 
@@ -36,17 +173,123 @@ where
     T: Clone + Send + Sync,
 {
     async fn get(&self, key: &str) -> anyhow::Result<Option<T>> {
-        let v = self.0.read().get(key).cloned();
-        Ok(v)
+        let now = Instant::now();
+        let mut entries = self.entries.lock();
+        // move to the back as the most recently used
+        match entries.to_back(key) {
+            Some(e) if !e.is_expired(now) => Ok(Some(e.value.clone())),
+            Some(_) => {
+                entries.remove(key);
+                Ok(None)
+            }
+            None => Ok(None),
+        }
     }
 
-    async fn set(&self, key: String, value: T, _expire_ttl: Option<usize>) -> anyhow::Result<()> {
-        self.0.write().insert(key, value);
+    async fn set(&self, key: String, value: T, expire_ttl: Option<usize>) -> anyhow::Result<()> {
+        let ttl = expire_ttl
+            .map(|s| Duration::from_secs(s as u64))
+            .or(self.default_ttl);
+        let entry = Entry {
+            value,
+            expire_at: ttl.map(|ttl| Instant::now() + ttl),
+        };
+        let mut entries = self.entries.lock();
+        entries.insert(key, entry);
+        if let Some(max) = self.max_entries {
+            while entries.len() > max {
+                entries.pop_front();
+            }
+        }
         Ok(())
     }
 
     async fn delete(&self, key: &str) -> anyhow::Result<()> {
-        self.0.write().remove(key);
+        self.entries.lock().remove(key);
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_mem_expire() {
+        let storage =
+            SimpleMemStorage::<String>::default().with_default_ttl(Some(Duration::from_secs(60)));
+        storage
+            .set("ttl".to_string(), "a".to_string(), Some(10))
+            .await
+            .unwrap();
+        storage
+            .set("default".to_string(), "b".to_string(), None)
+            .await
+            .unwrap();
+        let forever = SimpleMemStorage::<String>::default();
+        forever
+            .set("k".to_string(), "c".to_string(), None)
+            .await
+            .unwrap();
+
+        tokio::time::advance(Duration::from_secs(11)).await;
+        assert!(storage.get("ttl").await.unwrap().is_none());
+        // lazily evicted
+        assert_eq!(storage.len(), 1);
+        assert_eq!(storage.get("default").await.unwrap().as_deref(), Some("b"));
+
+        tokio::time::advance(Duration::from_secs(50)).await;
+        assert!(storage.get("default").await.unwrap().is_none());
+        assert_eq!(forever.get("k").await.unwrap().as_deref(), Some("c"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_mem_sweep() {
+        let storage = SimpleMemStorage::<String>::default();
+        let sweeper = storage.spawn_sweeper(Duration::from_secs(30));
+        for i in 0..10 {
+            storage
+                .set(format!("k{i}"), "v".to_string(), Some(10))
+                .await
+                .unwrap();
+        }
+        storage
+            .set("kept".to_string(), "v".to_string(), None)
+            .await
+            .unwrap();
+        assert_eq!(storage.len(), 11);
+
+        tokio::time::sleep(Duration::from_secs(31)).await;
+        assert_eq!(storage.len(), 1);
+
+        // stops with the storage
+        drop(storage);
+        tokio::time::sleep(Duration::from_secs(31)).await;
+        assert!(sweeper.is_finished());
+    }
+
+    #[tokio::test]
+    async fn test_mem_lru() {
+        let storage = SimpleMemStorage::<usize>::default().with_max_entries(Some(3));
+        for i in 0..3 {
+            storage.set(format!("k{i}"), i, None).await.unwrap();
+        }
+        // the oldest one is evicted
+        storage.set("k3".to_string(), 3, None).await.unwrap();
+        assert_eq!(storage.len(), 3);
+        assert!(storage.get("k0").await.unwrap().is_none());
+
+        // reads protect k1, so k2 is the least recently used
+        assert_eq!(storage.get("k1").await.unwrap(), Some(1));
+        storage.set("k4".to_string(), 4, None).await.unwrap();
+        assert!(storage.get("k2").await.unwrap().is_none());
+        assert_eq!(storage.get("k1").await.unwrap(), Some(1));
+
+        // overwriting counts as a use
+        storage.set("k3".to_string(), 30, None).await.unwrap();
+        storage.set("k5".to_string(), 5, None).await.unwrap();
+        assert!(storage.get("k4").await.unwrap().is_none());
+        assert_eq!(storage.get("k3").await.unwrap(), Some(30));
+        assert_eq!(storage.len(), 3);
+    }
+}
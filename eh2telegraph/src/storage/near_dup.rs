@@ -0,0 +1,167 @@
+//! Perceptual hashes of synced galleries with their pages, so a gallery similar to
+//! one synced before can be found, see `crate::phash`.
+//!
+//! Hashes are compared one by one and any string `KVStorage` has no scan, so all
+//! entries are one JSON value. It is loaded once and then kept in memory, adds are
+//! serialized so none of them is lost. Only the newest entries are kept. Other
+//! instances sharing the storage only see the entries added before they loaded it.
+
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::{phash, telegraph::types::Page};
+
+use super::KVStorage;
+
+const KEY: &str = "near_dup|index";
+/// Entries beyond it are dropped, oldest first.
+pub const MAX_ENTRIES: usize = 1000;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    hashes: Vec<u64>,
+    pages: Vec<Page>,
+}
+
+/// The index shared by the clones, None until loaded.
+#[derive(Debug, Clone, Default)]
+pub struct NearDupStore {
+    entries: Arc<Mutex<Option<Vec<Entry>>>>,
+}
+
+impl NearDupStore {
+    /// Pages of the newest gallery matching the hashes, see `phash::matches`.
+    pub async fn find<S: KVStorage<String>>(
+        &self,
+        storage: &S,
+        hashes: &[u64],
+        threshold: u32,
+    ) -> anyhow::Result<Option<Vec<Page>>> {
+        let mut entries = self.entries.lock().await;
+        Ok(Self::load(&mut entries, storage)
+            .await?
+            .iter()
+            .rev()
+            .find(|e| phash::matches(hashes, &e.hashes, threshold))
+            .map(|e| e.pages.clone()))
+    }
+
+    /// Record the hashes of a synced gallery, the content of the pages is not kept.
+    /// Hashes too plain to tell galleries apart are dropped, see `phash::is_informative`.
+    pub async fn add<S: KVStorage<String>>(
+        &self,
+        storage: &S,
+        mut hashes: Vec<u64>,
+        pages: &[Page],
+    ) -> anyhow::Result<()> {
+        hashes.retain(|h| phash::is_informative(*h));
+        if hashes.is_empty() {
+            return Ok(());
+        }
+        let pages = pages
+            .iter()
+            .map(|p| Page {
+                content: None,
+                ..p.clone()
+            })
+            .collect();
+        // held until stored, so adds at the same time do not overwrite each other
+        let mut entries = self.entries.lock().await;
+        let entries = Self::load(&mut entries, storage).await?;
+        entries.push(Entry { hashes, pages });
+        if entries.len() > MAX_ENTRIES {
+            entries.drain(..entries.len() - MAX_ENTRIES);
+        }
+        storage
+            .set(KEY.to_string(), serde_json::to_string(&entries)?, None)
+            .await
+    }
+
+    async fn load<'e, S: KVStorage<String>>(
+        entries: &'e mut Option<Vec<Entry>>,
+        storage: &S,
+    ) -> anyhow::Result<&'e mut Vec<Entry>> {
+        if entries.is_none() {
+            *entries = Some(match storage.get(KEY).await? {
+                Some(v) => serde_json::from_str(&v)?,
+                None => Vec::new(),
+            });
+        }
+        Ok(entries.get_or_insert_with(Vec::new))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::SimpleMemStorage;
+
+    fn page(url: &str) -> Page {
+        serde_json::from_value(serde_json::json!({
+            "path": url,
+            "url": format!("https://telegra.ph/{url}"),
+            "title": "t",
+            "description": "",
+            "content": [],
+            "views": 0,
+        }))
+        .unwrap()
+    }
+
+    const A: [u64; 2] = [0xff00_ff00, 0x0ff0_0ff0];
+    const B: [u64; 2] = [0xff01_ff00, 0xf0f0_f0f0_0000];
+
+    #[tokio::test]
+    async fn test_find() {
+        let storage = SimpleMemStorage::default();
+        let store = NearDupStore::default();
+        assert!(store.find(&storage, &A, 10).await.unwrap().is_none());
+
+        store.add(&storage, A.to_vec(), &[page("a")]).await.unwrap();
+        store.add(&storage, B.to_vec(), &[page("b")]).await.unwrap();
+        // the newest match
+        let found = store.find(&storage, &[A[0], B[1]], 1).await.unwrap();
+        let found = found.unwrap();
+        assert_eq!(found[0].url, "https://telegra.ph/b");
+        assert!(found[0].content.is_none());
+        let found = store.find(&storage, &[0x0ff0_0ff1, A[0]], 1).await.unwrap();
+        assert_eq!(found.unwrap()[0].url, "https://telegra.ph/a");
+        // a single page is not enough
+        assert!(store.find(&storage, &A[..1], 4).await.unwrap().is_none());
+
+        // blank pages are not recorded
+        store
+            .add(&storage, vec![0, u64::MAX], &[page("c")])
+            .await
+            .unwrap();
+        assert!(store
+            .find(&storage, &[0, 0, u64::MAX], 0)
+            .await
+            .unwrap()
+            .is_none());
+
+        // loaded from the storage by a new one
+        let store = NearDupStore::default();
+        let found = store.find(&storage, &A, 0).await.unwrap();
+        assert_eq!(found.unwrap()[0].url, "https://telegra.ph/a");
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_add() {
+        let storage = SimpleMemStorage::default();
+        let store = NearDupStore::default();
+        let adds = (0..8u64).map(|i| {
+            let hashes = vec![0xff00_ff00 << i, 0x0ff0_0ff0 << i];
+            let (store, storage) = (&store, &storage);
+            async move { store.add(storage, hashes, &[page("a")]).await }
+        });
+        for add in futures::future::join_all(adds).await {
+            add.unwrap();
+        }
+        let stored: Vec<Entry> =
+            serde_json::from_str(&storage.get(KEY).await.unwrap().unwrap()).unwrap();
+        assert_eq!(stored.len(), 8);
+    }
+}
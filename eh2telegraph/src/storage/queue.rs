@@ -0,0 +1,585 @@
+//! Jobs waiting for a worker, one JSON file per job in a directory so they survive
+//! restarts. A file is replaced by renaming, so a crash never leaves a partial job.
+//!
+//! A job of the owner with the fewest running jobs, then the one served least
+//! recently, is taken first, so a batch of one user does not starve the others.
+//! Failed jobs wait with an exponential backoff before they are taken again, and
+//! the ones left running by a restart or a crash are pending again when the queue is
+//! opened. Finished jobs are deleted, the recent ones are kept in memory for
+//! `snapshot`.
+
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::sync::{Mutex, Notify};
+
+use crate::config;
+
+const CONFIG_KEY: &str = "storage";
+pub const DEFAULT_DIR: &str = "./queue";
+/// Attempts of a job before it is given up by `retry`.
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+/// Unfinished jobs kept at most, `push` fails beyond it.
+pub const DEFAULT_MAX_JOBS: usize = 1000;
+/// Delay before the first retry of a failed job, doubled on every retry.
+pub const DEFAULT_BACKOFF: Duration = Duration::from_secs(30);
+// finished jobs kept for `snapshot`
+const MAX_FINISHED: usize = 20;
+
+#[derive(Debug, Deserialize)]
+struct StorageConfig {
+    queue: Option<QueueConfig>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct QueueConfig {
+    /// Directory of the jobs, created if not exists, `./queue` if not set.
+    pub dir: Option<String>,
+    /// Unfinished jobs kept at most, 1000 if not set.
+    pub max_jobs: Option<usize>,
+    /// Seconds before the first retry of a failed job, 30 if not set.
+    pub retry_backoff_sec: Option<u64>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum QueueError {
+    #[error("the queue is full with {0} jobs")]
+    Full(usize),
+    #[error("unable to save the job: {0}")]
+    Save(#[from] anyhow::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Pending,
+    Running,
+    Done,
+    Failed,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Job<T> {
+    /// Increasing in the order of push.
+    pub id: u64,
+    pub status: JobStatus,
+    /// Times the job has been taken by `next`.
+    pub attempts: u32,
+    /// Unix time in milliseconds, a failed job is not taken again before it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_at: Option<u64>,
+    pub payload: T,
+}
+
+#[derive(Debug)]
+struct State<T> {
+    next_id: u64,
+    /// Unfinished jobs by id.
+    jobs: BTreeMap<u64, Job<T>>,
+    /// Recently finished jobs, the latest last.
+    finished: VecDeque<Job<T>>,
+    /// Owners of the unfinished jobs to the sequence number of their last take.
+    served: HashMap<i64, u64>,
+    taken: u64,
+}
+
+impl<T> Default for State<T> {
+    fn default() -> Self {
+        Self {
+            next_id: 1,
+            jobs: BTreeMap::new(),
+            finished: VecDeque::new(),
+            served: HashMap::new(),
+            taken: 0,
+        }
+    }
+}
+
+impl<T> State<T> {
+    fn pending(&self) -> usize {
+        self.jobs
+            .values()
+            .filter(|j| j.status == JobStatus::Pending)
+            .count()
+    }
+
+    /// The id of the job to take next, or the time until a job waiting for its retry
+    /// is ready. None if no job is pending.
+    fn pick(&self, now: u64, owner: fn(&T) -> i64) -> Result<u64, Option<Duration>> {
+        let mut running = HashMap::<i64, usize>::new();
+        for job in self.jobs.values() {
+            if job.status == JobStatus::Running {
+                *running.entry(owner(&job.payload)).or_default() += 1;
+            }
+        }
+        let mut wait = None;
+        let mut best = None;
+        for job in self.jobs.values() {
+            if job.status != JobStatus::Pending {
+                continue;
+            }
+            if let Some(at) = job.retry_at.filter(|&at| at > now) {
+                let left = Duration::from_millis(at - now);
+                wait = Some(wait.map_or(left, |w: Duration| w.min(left)));
+                continue;
+            }
+            let who = owner(&job.payload);
+            let rank = (
+                running.get(&who).copied().unwrap_or_default(),
+                self.served.get(&who).copied().unwrap_or_default(),
+                job.id,
+            );
+            if best.is_none_or(|(r, _)| rank < r) {
+                best = Some((rank, job.id));
+            }
+        }
+        best.map(|(_, id)| id).ok_or(wait)
+    }
+
+    fn finish(&mut self, id: u64, status: JobStatus, owner: fn(&T) -> i64) -> bool {
+        let Some(mut job) = self.jobs.remove(&id) else {
+            return false;
+        };
+        job.status = status;
+        job.retry_at = None;
+        let who = owner(&job.payload);
+        if !self.jobs.values().any(|j| owner(&j.payload) == who) {
+            self.served.remove(&who);
+        }
+        self.finished.push_back(job);
+        if self.finished.len() > MAX_FINISHED {
+            self.finished.pop_front();
+        }
+        true
+    }
+}
+
+#[derive(Debug)]
+pub struct JobQueue<T> {
+    state: Mutex<State<T>>,
+    notify: Notify,
+    /// None keeps the jobs only in memory.
+    dir: Option<Arc<PathBuf>>,
+    max_attempts: u32,
+    max_jobs: usize,
+    backoff: Duration,
+    owner: fn(&T) -> i64,
+}
+
+impl<T> Default for JobQueue<T> {
+    /// A queue in memory, all jobs have the same owner.
+    fn default() -> Self {
+        Self {
+            state: Mutex::new(State::default()),
+            notify: Notify::new(),
+            dir: None,
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            max_jobs: DEFAULT_MAX_JOBS,
+            backoff: DEFAULT_BACKOFF,
+            owner: |_| 0,
+        }
+    }
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+fn job_file(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{id}.json"))
+}
+
+impl<T> JobQueue<T>
+where
+    T: Clone + Serialize + DeserializeOwned + Send + 'static,
+{
+    /// Load the jobs in the directory, the ones left running are pending again.
+    pub fn open(dir: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        let mut state = State::default();
+        let mut recovered = 0;
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            match path.extension().and_then(|e| e.to_str()) {
+                Some("json") => (),
+                // left by a crash before the rename
+                Some("tmp") => {
+                    let _ = fs::remove_file(&path);
+                    continue;
+                }
+                _ => continue,
+            }
+            let mut job: Job<T> = match serde_json::from_slice(&fs::read(&path)?) {
+                Ok(job) => job,
+                Err(e) => {
+                    tracing::warn!("[queue] skip unreadable job {}: {e}", path.display());
+                    continue;
+                }
+            };
+            if job.status == JobStatus::Running {
+                job.status = JobStatus::Pending;
+                write_job(dir, &job)?;
+                recovered += 1;
+            }
+            state.next_id = state.next_id.max(job.id + 1);
+            state.jobs.insert(job.id, job);
+        }
+        tracing::info!(
+            "[queue] opened {} with {} pending jobs, {recovered} of them interrupted",
+            dir.display(),
+            state.pending()
+        );
+        Ok(Self {
+            state: Mutex::new(state),
+            dir: Some(Arc::new(dir.to_path_buf())),
+            ..Self::default()
+        })
+    }
+
+    /// Open the queue of the `storage.queue` config.
+    pub fn new_from_config() -> anyhow::Result<Self> {
+        let config = config::parse::<StorageConfig>(CONFIG_KEY)?
+            .and_then(|c| c.queue)
+            .unwrap_or_default();
+        let mut queue = Self::open(config.dir.as_deref().unwrap_or(DEFAULT_DIR))?;
+        if let Some(max_jobs) = config.max_jobs {
+            queue = queue.with_max_jobs(max_jobs);
+        }
+        if let Some(secs) = config.retry_backoff_sec {
+            queue = queue.with_backoff(Duration::from_secs(secs));
+        }
+        Ok(queue)
+    }
+
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    pub fn with_max_jobs(mut self, max_jobs: usize) -> Self {
+        self.max_jobs = max_jobs.max(1);
+        self
+    }
+
+    pub fn with_backoff(mut self, backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Jobs of different owners are taken in turn.
+    pub fn with_owner(mut self, owner: fn(&T) -> i64) -> Self {
+        self.owner = owner;
+        self
+    }
+
+    /// Add a pending job and return its id.
+    pub async fn push(&self, payload: T) -> Result<u64, QueueError> {
+        let mut state = self.state.lock().await;
+        if state.jobs.len() >= self.max_jobs {
+            return Err(QueueError::Full(state.jobs.len()));
+        }
+        let id = state.next_id;
+        let job = Job {
+            id,
+            status: JobStatus::Pending,
+            attempts: 0,
+            retry_at: None,
+            payload,
+        };
+        self.save(&job).await?;
+        state.next_id += 1;
+        state.jobs.insert(id, job);
+        drop(state);
+        self.notify.notify_one();
+        Ok(id)
+    }
+
+    /// Wait for the next pending job and mark it running.
+    /// It stays running if the change can not be saved, so a restart takes it again.
+    pub async fn next(&self) -> Job<T> {
+        loop {
+            let notified = self.notify.notified();
+            let wait = {
+                let mut state = self.state.lock().await;
+                match state.pick(unix_millis(), self.owner) {
+                    Ok(id) => {
+                        state.taken += 1;
+                        let taken = state.taken;
+                        let job = state.jobs.get_mut(&id).expect("picked from the jobs");
+                        job.status = JobStatus::Running;
+                        job.attempts += 1;
+                        job.retry_at = None;
+                        let job = job.clone();
+                        state.served.insert((self.owner)(&job.payload), taken);
+                        self.save_or_warn(&job).await;
+                        return job;
+                    }
+                    Err(wait) => wait,
+                }
+            };
+            match wait {
+                Some(wait) => {
+                    let _ = tokio::time::timeout(wait, notified).await;
+                }
+                None => notified.await,
+            }
+        }
+    }
+
+    /// Mark a running job done or failed.
+    pub async fn finish(&self, id: u64, succeeded: bool) {
+        let status = match succeeded {
+            true => JobStatus::Done,
+            false => JobStatus::Failed,
+        };
+        let mut state = self.state.lock().await;
+        if state.finish(id, status, self.owner) {
+            self.remove_or_warn(id).await;
+        }
+    }
+
+    /// Make a failed job pending again after the backoff. Return false and mark it
+    /// failed if it has been attempted `max_attempts` times.
+    pub async fn retry(&self, id: u64) -> bool {
+        let mut state = self.state.lock().await;
+        let Some(job) = state.jobs.get_mut(&id) else {
+            return false;
+        };
+        if job.attempts >= self.max_attempts {
+            state.finish(id, JobStatus::Failed, self.owner);
+            self.remove_or_warn(id).await;
+            return false;
+        }
+        let backoff = self.backoff * 2u32.pow(job.attempts.saturating_sub(1).min(10));
+        job.status = JobStatus::Pending;
+        job.retry_at = Some(unix_millis() + backoff.as_millis() as u64);
+        let job = job.clone();
+        self.save_or_warn(&job).await;
+        drop(state);
+        self.notify.notify_one();
+        true
+    }
+
+    /// Drop the pending jobs matching `f` and return them.
+    pub async fn remove_pending<F>(&self, f: F) -> Vec<Job<T>>
+    where
+        F: Fn(&T) -> bool,
+    {
+        let mut state = self.state.lock().await;
+        let ids = state
+            .jobs
+            .values()
+            .filter(|j| j.status == JobStatus::Pending && f(&j.payload))
+            .map(|j| j.id)
+            .collect::<Vec<_>>();
+        let mut removed = Vec::with_capacity(ids.len());
+        for id in ids {
+            if let Some(job) = state.jobs.remove(&id) {
+                self.remove_or_warn(id).await;
+                removed.push(job);
+            }
+        }
+        let owner = self.owner;
+        let State { jobs, served, .. } = &mut *state;
+        served.retain(|who, _| jobs.values().any(|j| owner(&j.payload) == *who));
+        removed
+    }
+
+    /// Unfinished jobs by id, then the recently finished ones.
+    pub async fn snapshot(&self) -> Vec<Job<T>> {
+        let state = self.state.lock().await;
+        state
+            .jobs
+            .values()
+            .chain(state.finished.iter())
+            .cloned()
+            .collect()
+    }
+
+    async fn save(&self, job: &Job<T>) -> anyhow::Result<()> {
+        let Some(dir) = self.dir.clone() else {
+            return Ok(());
+        };
+        let job = job.clone();
+        tokio::task::spawn_blocking(move || write_job(&dir, &job)).await?
+    }
+
+    async fn save_or_warn(&self, job: &Job<T>) {
+        if let Err(e) = self.save(job).await {
+            tracing::warn!("[queue] unable to save job {}: {e}", job.id);
+        }
+    }
+
+    async fn remove_or_warn(&self, id: u64) {
+        let Some(dir) = self.dir.clone() else {
+            return;
+        };
+        match tokio::task::spawn_blocking(move || fs::remove_file(job_file(&dir, id))).await {
+            Ok(Ok(())) => (),
+            Ok(Err(e)) => tracing::warn!("[queue] unable to remove job {id}: {e}"),
+            Err(e) => tracing::warn!("[queue] unable to remove job {id}: {e}"),
+        }
+    }
+}
+
+/// Written to a temporary file renamed in place, so a reader never sees a partial job.
+fn write_job<T: Serialize>(dir: &Path, job: &Job<T>) -> anyhow::Result<()> {
+    let path = job_file(dir, job.id);
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, serde_json::to_vec(job)?)?;
+    fs::rename(&tmp, &path)?;
+    Ok(())
+}
+
+/// The directory is created on open, so only its parent must exist.
+pub(crate) fn validate_config(config: &config::Config, errors: &mut config::ConfigErrors) {
+    let Some(queue) = config
+        .section::<StorageConfig>(CONFIG_KEY, errors)
+        .and_then(|c| c.queue)
+    else {
+        return;
+    };
+    if queue.max_jobs == Some(0) {
+        errors.push("storage.queue.max_jobs", "must be positive");
+    }
+    let Some(dir) = queue.dir.as_deref().map(Path::new) else {
+        return;
+    };
+    match dir.parent().filter(|p| !p.as_os_str().is_empty()) {
+        Some(parent) if !parent.is_dir() => {
+            errors.push(
+                "storage.queue.dir",
+                format!("directory {} does not exist", parent.display()),
+            );
+        }
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn statuses(jobs: &[Job<String>]) -> Vec<(&str, JobStatus)> {
+        jobs.iter()
+            .map(|j| (j.payload.as_str(), j.status))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_order() {
+        let queue = JobQueue::default().with_backoff(Duration::from_millis(100));
+        for url in ["a", "b", "c"] {
+            queue.push(url.to_string()).await.unwrap();
+        }
+        let a = queue.next().await;
+        let b = queue.next().await;
+        assert_eq!((a.payload.as_str(), b.payload.as_str()), ("a", "b"));
+        assert_eq!((a.status, a.attempts), (JobStatus::Running, 1));
+
+        // a failed job waits for the backoff
+        assert!(queue.retry(a.id).await);
+        queue.finish(b.id, true).await;
+        assert_eq!(
+            statuses(&queue.snapshot().await),
+            [
+                ("a", JobStatus::Pending),
+                ("c", JobStatus::Pending),
+                ("b", JobStatus::Done)
+            ]
+        );
+        assert_eq!(queue.next().await.payload, "c");
+        let next = queue.next();
+        tokio::pin!(next);
+        assert!(tokio::time::timeout(Duration::from_millis(20), &mut next)
+            .await
+            .is_err());
+        let a = next.await;
+        assert_eq!((a.payload.as_str(), a.attempts), ("a", 2));
+
+        // waits for a push
+        let next = queue.next();
+        tokio::pin!(next);
+        assert!(tokio::time::timeout(Duration::from_millis(20), &mut next)
+            .await
+            .is_err());
+        queue.push("d".to_string()).await.unwrap();
+        assert_eq!(next.await.payload, "d");
+
+        let removed = queue.remove_pending(|_| true).await;
+        assert!(removed.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_fairness() {
+        let queue = JobQueue::default()
+            .with_owner(|url: &String| url.starts_with('b') as i64)
+            .with_max_jobs(5);
+        for url in ["a1", "a2", "a3", "b1", "b2"] {
+            queue.push(url.to_string()).await.unwrap();
+        }
+        assert!(matches!(
+            queue.push("a4".to_string()).await,
+            Err(QueueError::Full(5))
+        ));
+
+        // b is taken while a is running, and in turn with a after that
+        let a1 = queue.next().await;
+        assert_eq!(queue.next().await.payload, "b1");
+        queue.finish(a1.id, true).await;
+        let taken = [
+            queue.next().await.payload,
+            queue.next().await.payload,
+            queue.next().await.payload,
+        ];
+        assert_eq!(taken, ["a2", "b2", "a3"]);
+        queue.push("a4".to_string()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_recover() {
+        let dir = tempfile::tempdir().unwrap();
+        let queue = JobQueue::open(dir.path()).unwrap().with_max_attempts(2);
+        for url in ["a", "b", "c"] {
+            queue.push(url.to_string()).await.unwrap();
+        }
+        let a = queue.next().await;
+        queue.finish(a.id, false).await;
+        let b = queue.next().await;
+        assert_eq!(b.payload, "b");
+        // one file per unfinished job
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+        // restarted with b running
+        drop(queue);
+
+        let queue = JobQueue::<String>::open(dir.path())
+            .unwrap()
+            .with_max_attempts(2)
+            .with_backoff(Duration::ZERO);
+        assert_eq!(
+            statuses(&queue.snapshot().await),
+            [("b", JobStatus::Pending), ("c", JobStatus::Pending)]
+        );
+        let b = queue.next().await;
+        assert_eq!((b.payload.as_str(), b.attempts), ("b", 2));
+        // given up after the attempts
+        assert!(!queue.retry(b.id).await);
+        let removed = queue.remove_pending(|url| url == "c").await;
+        assert_eq!(statuses(&removed), [("c", JobStatus::Pending)]);
+        assert_eq!(
+            statuses(&queue.snapshot().await),
+            [("b", JobStatus::Failed)]
+        );
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+}
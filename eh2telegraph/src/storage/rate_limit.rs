@@ -0,0 +1,172 @@
+//! Per user token buckets, kept in the storage so limits hold across restarts
+//! and instances sharing it.
+//! Note the update is not atomic, concurrent requests of one user may slip through.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use super::KVStorage;
+use crate::config;
+
+const CONFIG_KEY: &str = "rate_limit";
+const KEY_PREFIX: &str = "ratelimit|";
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct RateLimit {
+    /// Tokens refilled per hour, one is taken by each sync. Must be positive, leave
+    /// the section out to disable the limit.
+    pub per_hour: u32,
+    /// Max tokens a bucket holds, `per_hour` if not set.
+    pub burst: Option<u32>,
+}
+
+pub(crate) fn validate_config(config: &config::Config, errors: &mut config::ConfigErrors) {
+    let Some(limit) = config.section::<RateLimit>(CONFIG_KEY, errors) else {
+        return;
+    };
+    if limit.per_hour == 0 {
+        errors.push(
+            &format!("{CONFIG_KEY}.per_hour"),
+            "must be positive, remove the section to disable the limit",
+        );
+    }
+    if limit.burst == Some(0) {
+        errors.push(&format!("{CONFIG_KEY}.burst"), "must be positive");
+    }
+}
+
+impl RateLimit {
+    fn capacity(&self) -> f64 {
+        self.burst.unwrap_or(self.per_hour) as f64
+    }
+
+    fn refill_per_sec(&self) -> f64 {
+        self.per_hour as f64 / 3600.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct TokenBucket {
+    pub tokens: f64,
+    /// Unix time of the last refill.
+    pub updated_at: u64,
+}
+
+impl TokenBucket {
+    pub fn full(limit: &RateLimit, now: u64) -> Self {
+        Self {
+            tokens: limit.capacity(),
+            updated_at: now,
+        }
+    }
+
+    fn refill(&mut self, limit: &RateLimit, now: u64) {
+        let elapsed = now.saturating_sub(self.updated_at) as f64;
+        self.tokens = (self.tokens + elapsed * limit.refill_per_sec()).min(limit.capacity());
+        self.updated_at = now.max(self.updated_at);
+    }
+
+    /// Take a token, or return the time to wait for the next one.
+    pub fn take(&mut self, limit: &RateLimit, now: u64) -> Result<(), Duration> {
+        self.refill(limit, now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+        let wait = (1.0 - self.tokens) / limit.refill_per_sec();
+        Err(Duration::from_secs(wait.ceil() as u64))
+    }
+}
+
+pub struct RateLimiter<'a, S> {
+    storage: &'a S,
+    limit: RateLimit,
+}
+
+impl<'a, S> RateLimiter<'a, S>
+where
+    S: KVStorage<String>,
+{
+    pub fn new(storage: &'a S, limit: RateLimit) -> Self {
+        Self { storage, limit }
+    }
+
+    /// Take a token of the user, or return the time to wait if limited.
+    pub async fn acquire(&self, user: i64) -> anyhow::Result<Result<(), Duration>> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        self.acquire_at(user, now).await
+    }
+
+    async fn acquire_at(&self, user: i64, now: u64) -> anyhow::Result<Result<(), Duration>> {
+        let key = format!("{KEY_PREFIX}{user}");
+        let mut bucket = match self.storage.get(&key).await? {
+            Some(v) => serde_json::from_str(&v)?,
+            None => TokenBucket::full(&self.limit, now),
+        };
+        let r = bucket.take(&self.limit, now);
+        // a bucket refilled to full is the same as a missing one
+        let ttl = (self.limit.capacity() * 3600.0 / self.limit.per_hour as f64).ceil();
+        self.storage
+            .set(key, serde_json::to_string(&bucket)?, Some(ttl as usize))
+            .await?;
+        Ok(r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::SimpleMemStorage;
+
+    #[test]
+    fn test_bucket_refill() {
+        let limit = RateLimit {
+            per_hour: 6,
+            burst: Some(2),
+        };
+        let mut bucket = TokenBucket::full(&limit, 1000);
+        assert!(bucket.take(&limit, 1000).is_ok());
+        assert!(bucket.take(&limit, 1000).is_ok());
+        // one token per 10 minutes
+        assert_eq!(bucket.take(&limit, 1000), Err(Duration::from_secs(600)));
+        assert_eq!(bucket.take(&limit, 1300), Err(Duration::from_secs(300)));
+        assert!(bucket.take(&limit, 1600).is_ok());
+
+        // refilled up to the burst only
+        bucket.take(&limit, 100_000).unwrap();
+        bucket.take(&limit, 100_000).unwrap();
+        assert!(bucket.take(&limit, 100_000).is_err());
+
+        // clock going back does not refill
+        let mut bucket = TokenBucket::full(&limit, 1000);
+        bucket.take(&limit, 1000).unwrap();
+        bucket.take(&limit, 1000).unwrap();
+        assert!(bucket.take(&limit, 10).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_rate_limiter() {
+        let storage = SimpleMemStorage::<String>::default();
+        let limit = RateLimit {
+            per_hour: 1,
+            burst: None,
+        };
+        let limiter = RateLimiter::new(&storage, limit);
+        assert!(limiter.acquire_at(1, 0).await.unwrap().is_ok());
+        assert_eq!(
+            limiter.acquire_at(1, 60).await.unwrap(),
+            Err(Duration::from_secs(3540))
+        );
+        // other users have their own buckets
+        assert!(limiter.acquire_at(2, 60).await.unwrap().is_ok());
+
+        // a new limiter on the same storage keeps the state
+        let limiter = RateLimiter::new(&storage, limit);
+        assert!(limiter.acquire_at(1, 120).await.unwrap().is_err());
+        assert!(limiter.acquire_at(1, 3600).await.unwrap().is_ok());
+    }
+}
@@ -0,0 +1,192 @@
+//! Redis storage, which can be shared by multiple instances.
+
+use deadpool_redis::{
+    redis::{AsyncCommands, RedisError},
+    Config, Pool, PoolConfig, Runtime,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::config;
+
+use super::KVStorage;
+
+const CONFIG_KEY: &str = "storage";
+const DEFAULT_POOL_SIZE: usize = 16;
+const DEFAULT_KEY_PREFIX: &str = "eh2telegraph";
+
+#[derive(Debug, Deserialize)]
+struct StorageConfig {
+    redis: Option<RedisConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RedisConfig {
+    /// Like `redis://:password@127.0.0.1:6379/0`.
+    pub url: String,
+    pub pool_size: Option<usize>,
+    /// Prepended to all keys, use distinct ones for deployments sharing a redis.
+    pub key_prefix: Option<String>,
+}
+
+/// Values are stored as JSON under `{prefix}:{key}`.
+#[derive(Clone)]
+pub struct RedisStorage {
+    pool: Pool,
+    prefix: String,
+}
+
+impl std::fmt::Debug for RedisStorage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RedisStorage")
+            .field("prefix", &self.prefix)
+            .finish()
+    }
+}
+
+impl RedisStorage {
+    /// The pool connects lazily, so errors of the server show up on first use.
+    pub fn new(config: &RedisConfig) -> anyhow::Result<Self> {
+        let mut pool_config = Config::from_url(&config.url);
+        pool_config.pool = Some(PoolConfig::new(
+            config.pool_size.unwrap_or(DEFAULT_POOL_SIZE),
+        ));
+        Ok(Self {
+            pool: pool_config.create_pool(Some(Runtime::Tokio1))?,
+            prefix: config
+                .key_prefix
+                .clone()
+                .unwrap_or_else(|| DEFAULT_KEY_PREFIX.to_string()),
+        })
+    }
+
+    pub fn new_from_config() -> anyhow::Result<Self> {
+        let config = config::parse::<StorageConfig>(CONFIG_KEY)?
+            .and_then(|c| c.redis)
+            .ok_or_else(|| anyhow::anyhow!("redis config(key: storage.redis) not found"))?;
+        Self::new(&config)
+    }
+
+    fn key(&self, key: &str) -> String {
+        format!("{}:{key}", self.prefix)
+    }
+}
+
+pub(crate) fn validate_config(config: &config::Config, errors: &mut config::ConfigErrors) {
+    let redis = config
+        .section::<StorageConfig>(CONFIG_KEY, errors)
+        .and_then(|c| c.redis);
+    if let Some(Err(e)) = redis.map(|c| RedisStorage::new(&c)) {
+        errors.push("storage.redis", e);
+    }
+}
+
+impl<T> KVStorage<T> for RedisStorage
+where
+    T: DeserializeOwned + Serialize + Send + Sync,
+{
+    async fn get(&self, key: &str) -> anyhow::Result<Option<T>> {
+        let mut conn = self.pool.get().await?;
+        let value: Option<String> = conn.get(self.key(key)).await?;
+        value
+            .map(|v| serde_json::from_str(&v))
+            .transpose()
+            .map_err(Into::into)
+    }
+
+    async fn set(&self, key: String, value: T, expire_ttl: Option<usize>) -> anyhow::Result<()> {
+        let value = serde_json::to_string(&value)?;
+        let mut conn = self.pool.get().await?;
+        let key = self.key(&key);
+        let r: Result<(), RedisError> = match expire_ttl {
+            Some(ttl) => conn.set_ex(key, value, ttl as u64).await,
+            None => conn.set(key, value).await,
+        };
+        r.map_err(Into::into)
+    }
+
+    async fn delete(&self, key: &str) -> anyhow::Result<()> {
+        let mut conn = self.pool.get().await?;
+        let _: usize = conn.del(self.key(key)).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn storage(prefix: &str) -> RedisStorage {
+        let url =
+            std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".to_string());
+        RedisStorage::new(&RedisConfig {
+            url,
+            pool_size: Some(2),
+            key_prefix: Some(format!(
+                "eh2telegraph-test-{prefix}-{}",
+                rand::random::<u32>()
+            )),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_key_prefix() {
+        let storage = RedisStorage::new(&RedisConfig {
+            url: "redis://127.0.0.1:6379".to_string(),
+            pool_size: None,
+            key_prefix: None,
+        })
+        .unwrap();
+        assert_eq!(
+            storage.key("e-hentai|/g/1/x"),
+            "eh2telegraph:e-hentai|/g/1/x"
+        );
+    }
+
+    #[cfg_attr(not(feature = "redis-tests"), ignore)]
+    #[tokio::test]
+    async fn test_get_set_delete() {
+        let storage = storage("kv");
+        let key = "e-hentai|/g/1/x";
+        assert!(KVStorage::<String>::get(&storage, key)
+            .await
+            .unwrap()
+            .is_none());
+
+        storage
+            .set(key.to_string(), "https://telegra.ph/p".to_string(), None)
+            .await
+            .unwrap();
+        let value: Option<String> = storage.get(key).await.unwrap();
+        assert_eq!(value.as_deref(), Some("https://telegra.ph/p"));
+
+        KVStorage::<String>::delete(&storage, key).await.unwrap();
+        assert!(KVStorage::<String>::get(&storage, key)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[cfg_attr(not(feature = "redis-tests"), ignore)]
+    #[tokio::test]
+    async fn test_expire_and_namespace() {
+        let a = storage("a");
+        let b = storage("b");
+        a.set("k".to_string(), "a".to_string(), Some(1))
+            .await
+            .unwrap();
+        b.set("k".to_string(), "b".to_string(), None).await.unwrap();
+        assert_eq!(
+            KVStorage::<String>::get(&b, "k").await.unwrap().as_deref(),
+            Some("b")
+        );
+
+        tokio::time::sleep(std::time::Duration::from_millis(1500)).await;
+        assert!(KVStorage::<String>::get(&a, "k").await.unwrap().is_none());
+        assert_eq!(
+            KVStorage::<String>::get(&b, "k").await.unwrap().as_deref(),
+            Some("b")
+        );
+        KVStorage::<String>::delete(&b, "k").await.unwrap();
+    }
+}
@@ -0,0 +1,328 @@
+//! SQLite storage, which keeps the cache of a single host across restarts.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use parking_lot::Mutex;
+use rusqlite::{Connection, OpenFlags, OptionalExtension};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::config;
+
+use super::KVStorage;
+
+const CONFIG_KEY: &str = "storage";
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+// read only connections, so reads go on while one is writing
+const READERS: usize = 4;
+
+// schema changes are appended here, `user_version` records the applied ones
+const MIGRATIONS: &[&str] = &["CREATE TABLE kv (
+    key TEXT PRIMARY KEY NOT NULL,
+    value TEXT NOT NULL,
+    expire_at INTEGER
+)"];
+
+#[derive(Debug, Deserialize)]
+struct StorageConfig {
+    sqlite: Option<SqliteConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SqliteConfig {
+    /// Database file, created if not exists.
+    pub path: String,
+}
+
+/// Values are stored as JSON with the unix time they expire at. Writes go through one
+/// connection, reads through a few read only ones.
+#[derive(Clone)]
+pub struct SqliteStorage(Arc<Connections>);
+
+struct Connections {
+    path: PathBuf,
+    writer: Mutex<Connection>,
+    readers: Vec<Mutex<Connection>>,
+    // the reader to wait for if all are busy
+    next: AtomicUsize,
+}
+
+impl std::fmt::Debug for SqliteStorage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SqliteStorage")
+            .field("path", &self.0.path)
+            .finish()
+    }
+}
+
+impl Connections {
+    /// A free reader, or the next one in turn if all are busy.
+    fn reader(&self) -> parking_lot::MutexGuard<'_, Connection> {
+        if let Some(conn) = self.readers.iter().find_map(Mutex::try_lock) {
+            return conn;
+        }
+        let next = self.next.fetch_add(1, Ordering::Relaxed) % self.readers.len();
+        self.readers[next].lock()
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+fn migrate(conn: &mut Connection) -> anyhow::Result<()> {
+    let version: usize = conn.pragma_query_value(None, "user_version", |r| r.get(0))?;
+    if version > MIGRATIONS.len() {
+        anyhow::bail!("database schema version {version} is newer than supported");
+    }
+    for (idx, sql) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(sql)?;
+        tx.pragma_update(None, "user_version", idx + 1)?;
+        tx.commit()?;
+        tracing::info!("[sqlite] migrated schema to version {}", idx + 1);
+    }
+    Ok(())
+}
+
+impl SqliteStorage {
+    /// Open the database, the schema is created or migrated on open.
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let mut conn = Connection::open(path)?;
+        // WAL lets readers go on while writing
+        let _: String = conn.pragma_update_and_check(None, "journal_mode", "WAL", |r| r.get(0))?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        migrate(&mut conn)?;
+        let purged = conn.execute(
+            "DELETE FROM kv WHERE expire_at IS NOT NULL AND expire_at <= ?1",
+            [now()],
+        )?;
+        tracing::debug!("[sqlite] purged {purged} expired entries");
+        let readers = (0..READERS)
+            .map(|_| {
+                let flags = OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+                let reader = Connection::open_with_flags(path, flags)?;
+                reader.busy_timeout(BUSY_TIMEOUT)?;
+                Ok(Mutex::new(reader))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self(Arc::new(Connections {
+            path: path.to_path_buf(),
+            writer: Mutex::new(conn),
+            readers,
+            next: AtomicUsize::new(0),
+        })))
+    }
+
+    pub fn new_from_config() -> anyhow::Result<Self> {
+        let config = config::parse::<StorageConfig>(CONFIG_KEY)?
+            .and_then(|c| c.sqlite)
+            .ok_or_else(|| anyhow::anyhow!("sqlite config(key: storage.sqlite) not found"))?;
+        Self::open(&config.path)
+    }
+
+    /// Run the statement with the writer on the blocking pool.
+    async fn write<R, F>(&self, f: F) -> anyhow::Result<R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&Connection) -> rusqlite::Result<R> + Send + 'static,
+    {
+        let conn = self.0.clone();
+        Ok(tokio::task::spawn_blocking(move || f(&conn.writer.lock())).await??)
+    }
+
+    /// Run the query with a reader on the blocking pool.
+    async fn read<R, F>(&self, f: F) -> anyhow::Result<R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&Connection) -> rusqlite::Result<R> + Send + 'static,
+    {
+        let conn = self.0.clone();
+        Ok(tokio::task::spawn_blocking(move || f(&conn.reader())).await??)
+    }
+}
+
+/// The database is created on open, so only its directory must exist.
+pub(crate) fn validate_config(config: &config::Config, errors: &mut config::ConfigErrors) {
+    let Some(sqlite) = config
+        .section::<StorageConfig>(CONFIG_KEY, errors)
+        .and_then(|c| c.sqlite)
+    else {
+        return;
+    };
+    let path = Path::new(&sqlite.path);
+    match path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        Some(dir) if !dir.is_dir() => {
+            errors.push(
+                "storage.sqlite",
+                format!("directory {} does not exist", dir.display()),
+            );
+        }
+        _ => (),
+    }
+}
+
+impl<T> KVStorage<T> for SqliteStorage
+where
+    T: DeserializeOwned + Serialize + Send + Sync,
+{
+    async fn get(&self, key: &str) -> anyhow::Result<Option<T>> {
+        let key = key.to_string();
+        let value: Option<String> = self
+            .read(move |conn| {
+                conn.query_row(
+                    "SELECT value FROM kv WHERE key = ?1 AND (expire_at IS NULL OR expire_at > ?2)",
+                    (key, now()),
+                    |r| r.get(0),
+                )
+                .optional()
+            })
+            .await?;
+        value
+            .map(|v| serde_json::from_str(&v))
+            .transpose()
+            .map_err(Into::into)
+    }
+
+    async fn set(&self, key: String, value: T, expire_ttl: Option<usize>) -> anyhow::Result<()> {
+        let value = serde_json::to_string(&value)?;
+        let expire_at = expire_ttl.map(|ttl| now() + ttl as i64);
+        self.write(move |conn| {
+            conn.execute(
+                "INSERT INTO kv (key, value, expire_at) VALUES (?1, ?2, ?3)
+                ON CONFLICT(key) DO UPDATE SET value = excluded.value, expire_at = excluded.expire_at",
+                (key, value, expire_at),
+            )
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> anyhow::Result<()> {
+        let key = key.to_string();
+        self.write(move |conn| conn.execute("DELETE FROM kv WHERE key = ?1", [key]))
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_get_set_delete() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = SqliteStorage::open(dir.path().join("cache.db")).unwrap();
+        let key = "e-hentai|/g/1/x";
+        assert!(KVStorage::<String>::get(&storage, key)
+            .await
+            .unwrap()
+            .is_none());
+
+        storage
+            .set(key.to_string(), "https://telegra.ph/p".to_string(), None)
+            .await
+            .unwrap();
+        storage
+            .set(key.to_string(), "https://telegra.ph/q".to_string(), None)
+            .await
+            .unwrap();
+        let value: Option<String> = storage.get(key).await.unwrap();
+        assert_eq!(value.as_deref(), Some("https://telegra.ph/q"));
+
+        // zero ttl expires at once
+        storage
+            .set("expired".to_string(), "v".to_string(), Some(0))
+            .await
+            .unwrap();
+        assert!(KVStorage::<String>::get(&storage, "expired")
+            .await
+            .unwrap()
+            .is_none());
+
+        KVStorage::<String>::delete(&storage, key).await.unwrap();
+        assert!(KVStorage::<String>::get(&storage, key)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_read_while_writing() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = SqliteStorage::open(dir.path().join("cache.db")).unwrap();
+        storage
+            .set("k".to_string(), "v".to_string(), None)
+            .await
+            .unwrap();
+
+        // the writer is busy in another thread
+        let (locked_tx, locked_rx) = std::sync::mpsc::channel();
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        let connections = storage.0.clone();
+        let writer = std::thread::spawn(move || {
+            let _writer = connections.writer.lock();
+            locked_tx.send(()).unwrap();
+            let _ = release_rx.recv();
+        });
+        locked_rx.recv().unwrap();
+        let read = tokio::time::timeout(Duration::from_secs(1), storage.get("k")).await;
+        let value: Option<String> = read.expect("reads do not wait for the writer").unwrap();
+        assert_eq!(value.as_deref(), Some("v"));
+        release_tx.send(()).unwrap();
+        writer.join().unwrap();
+
+        // the readers see new writes
+        storage
+            .set("k".to_string(), "w".to_string(), None)
+            .await
+            .unwrap();
+        let values = futures::future::join_all((0..READERS * 2).map(|_| storage.get("k"))).await;
+        for value in values {
+            let value: Option<String> = value.unwrap();
+            assert_eq!(value.as_deref(), Some("w"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.db");
+        {
+            let storage = SqliteStorage::open(&path).unwrap();
+            storage
+                .set("k".to_string(), vec![1, 2, 3], Some(3600))
+                .await
+                .unwrap();
+            storage
+                .set("expired".to_string(), vec![0], Some(0))
+                .await
+                .unwrap();
+        }
+
+        let storage = SqliteStorage::open(&path).unwrap();
+        let value: Option<Vec<u8>> = storage.get("k").await.unwrap();
+        assert_eq!(value, Some(vec![1, 2, 3]));
+        let conn = storage.0.writer.lock();
+        let version: usize = conn
+            .pragma_query_value(None, "user_version", |r| r.get(0))
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len());
+        // expired entries are purged on open
+        let count: usize = conn
+            .query_row("SELECT COUNT(*) FROM kv", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(count, 1);
+    }
+}
@@ -1,8 +1,8 @@
-use std::collections::VecDeque;
+use std::collections::{BTreeSet, VecDeque};
 use std::fmt;
 use std::future::Future;
 
-use futures::FutureExt;
+use futures::{future, FutureExt};
 use tokio::sync::oneshot;
 
 /// We define a AsyncStream to replace futures::Stream since we don't want to implement
@@ -20,6 +20,13 @@ pub trait AsyncStream {
     fn size_hint(&self) -> (usize, Option<usize>) {
         (0, None)
     }
+
+    /// Another attempt at the item at `pos` of `next` which failed, if the stream has
+    /// another source of it, like the archive of a gallery. None by default.
+    #[inline]
+    fn retry(&mut self, _pos: usize) -> Option<Self::Future> {
+        None
+    }
 }
 
 /// Buffered Stream.
@@ -36,7 +43,9 @@ pub struct Buffered<St>
 where
     St: AsyncStream,
 {
-    stream: Option<St>,
+    stream: St,
+    // the stream is kept for `retry` once it ends
+    done: bool,
     queue: VecDeque<oneshot::Receiver<St::Item>>,
     max: usize,
 }
@@ -47,7 +56,8 @@ where
 {
     pub fn new(stream: St, buffer_size: usize) -> Self {
         Self {
-            stream: Some(stream),
+            stream,
+            done: false,
             queue: VecDeque::with_capacity(buffer_size),
             max: buffer_size,
         }
@@ -62,12 +72,40 @@ where
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("Buffered")
             .field("stream", &self.stream)
+            .field("done", &self.done)
             .field("queue", &self.queue)
             .field("max", &self.max)
             .finish()
     }
 }
 
+impl<St> Buffered<St>
+where
+    St: AsyncStream,
+    St::Item: Send + 'static,
+    St::Future: Send + 'static,
+{
+    fn spawn(f: St::Future) -> oneshot::Receiver<St::Item> {
+        let (mut tx, rx) = oneshot::channel::<St::Item>();
+        // stop loading once the stream is dropped
+        tokio::spawn(crate::http_proxy::propagate_scope(async move {
+            tokio::select! {
+                item = f => {
+                    let _ = tx.send(item);
+                }
+                _ = tx.closed() => (),
+            }
+        }));
+        rx
+    }
+}
+
+type Received<T> = future::Map<oneshot::Receiver<T>, fn(Result<T, oneshot::error::RecvError>) -> T>;
+
+fn received<T>(rx: oneshot::Receiver<T>) -> Received<T> {
+    rx.map(|x| x.expect("oneshot tx dropped which is unexpected"))
+}
+
 impl<St> AsyncStream for Buffered<St>
 where
     St: AsyncStream,
@@ -76,33 +114,145 @@ where
 {
     type Item = St::Item;
 
-    type Future = impl std::future::Future<Output = Self::Item>;
+    type Future = Received<St::Item>;
 
     fn next(&mut self) -> Option<Self::Future> {
-        while self.queue.len() < self.max {
-            let item = match self.stream.as_mut() {
-                Some(st) => match st.next() {
-                    Some(item) => Some(item),
-                    None => {
-                        self.stream = None;
-                        None
-                    }
-                },
-                None => None,
-            };
-            match item {
-                Some(f) => {
-                    let (tx, rx) = oneshot::channel::<Self::Item>();
-                    tokio::spawn(async move {
-                        let _ = tx.send(f.await);
-                    });
-                    self.queue.push_back(rx);
-                }
-                None => break,
+        while self.queue.len() < self.max && !self.done {
+            match self.stream.next() {
+                Some(f) => self.queue.push_back(Self::spawn(f)),
+                None => self.done = true,
             }
         }
-        self.queue
-            .pop_front()
-            .map(|x| x.map(|xx| xx.expect("oneshot tx dropped which is unexpected")))
+        self.queue.pop_front().map(received)
+    }
+
+    /// Not buffered, the retried items are rare.
+    fn retry(&mut self, pos: usize) -> Option<Self::Future> {
+        self.stream.retry(pos).map(|f| received(Self::spawn(f)))
+    }
+}
+
+/// Yield only the items at the given sorted indices.
+/// Since futures of `AsyncStream` are lazy, skipped items are dropped without polling,
+/// so they are never loaded.
+#[derive(Debug)]
+pub struct Selected<St> {
+    stream: St,
+    // `None` for all items
+    indices: Option<std::vec::IntoIter<usize>>,
+    // of the yielded items, to retry them
+    selected: Vec<usize>,
+    pos: usize,
+}
+
+impl<St> Selected<St> {
+    pub fn all(stream: St) -> Self {
+        Self {
+            stream,
+            indices: None,
+            selected: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    /// The indices must be sorted and within the stream.
+    pub fn new(stream: St, indices: Vec<usize>) -> Self {
+        Self {
+            stream,
+            selected: indices.clone(),
+            indices: Some(indices.into_iter()),
+            pos: 0,
+        }
+    }
+}
+
+impl<St> AsyncStream for Selected<St>
+where
+    St: AsyncStream,
+{
+    type Item = St::Item;
+    type Future = St::Future;
+
+    fn next(&mut self) -> Option<Self::Future> {
+        let Some(indices) = self.indices.as_mut() else {
+            return self.stream.next();
+        };
+        let target = indices.next()?;
+        while self.pos < target {
+            // dropped without polling
+            drop(self.stream.next()?);
+            self.pos += 1;
+        }
+        self.pos += 1;
+        self.stream.next()
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match &self.indices {
+            Some(indices) => (indices.len(), Some(indices.len())),
+            None => self.stream.size_hint(),
+        }
+    }
+
+    fn retry(&mut self, pos: usize) -> Option<Self::Future> {
+        match self.indices {
+            Some(_) => self.stream.retry(*self.selected.get(pos)?),
+            None => self.stream.retry(pos),
+        }
+    }
+}
+
+/// Tag items with their positions in the stream, skipping the given positions.
+/// Like `Selected`, skipped items are never loaded.
+#[derive(Debug)]
+pub struct Indexed<St> {
+    stream: St,
+    skip: BTreeSet<usize>,
+    pos: usize,
+}
+
+impl<St> Indexed<St> {
+    pub fn new(stream: St, skip: BTreeSet<usize>) -> Self {
+        Self {
+            stream,
+            skip,
+            pos: 0,
+        }
+    }
+}
+
+impl<St> AsyncStream for Indexed<St>
+where
+    St: AsyncStream,
+{
+    type Item = (usize, St::Item);
+    // a named type so that it does not capture `St`
+    type Future = future::Join<future::Ready<usize>, St::Future>;
+
+    fn next(&mut self) -> Option<Self::Future> {
+        while self.skip.contains(&self.pos) {
+            // dropped without polling
+            drop(self.stream.next()?);
+            self.pos += 1;
+        }
+        let fut = self.stream.next()?;
+        let pos = self.pos;
+        self.pos += 1;
+        Some(future::join(future::ready(pos), fut))
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        // the skipped positions may be beyond the end, so only the lower bound is lowered
+        let skipped = self.skip.range(self.pos..).count();
+        let (lower, upper) = self.stream.size_hint();
+        (lower.saturating_sub(skipped), upper)
+    }
+
+    /// Positions are the ones of the inner stream, so they are retried as is.
+    fn retry(&mut self, pos: usize) -> Option<Self::Future> {
+        let fut = self.stream.retry(pos)?;
+        Some(future::join(future::ready(pos), fut))
     }
 }
@@ -1,23 +1,53 @@
 use crate::{
     buffer::{DataSized, ImageBuffer},
     collector::{
-        AlbumMeta, Collector, ImageData, ImageMeta, Param, Registry, URL_FROM_TEXT_RE,
-        URL_FROM_URL_RE,
+        selection::PageSelection, AlbumMeta, Collector, CollectorError, ImageData, ImageMeta,
+        Param, Registry,
     },
-    http_proxy::ProxiedClient,
-    storage::{cloudflare_kv::CFStorage, KVStorage},
-    stream::{AsyncStream, Buffered},
+    http_client::HttpRequestBuilder,
+    http_proxy::{DownloadProgress, ProxiedClient, ProxyError},
+    metrics::{Metrics, SyncMetrics},
+    phash::{self, NearDuplicate, NearDuplicateAction},
+    reencode::ImageReencoder,
+    slicer::ImageSlicer,
+    sniff::{ImageKind, TypeAllowlist},
+    storage::{
+        checkpoint::{Checkpoint, CheckpointImage, CheckpointStore},
+        cloudflare_kv::CFStorage,
+        dedup::{fingerprint, DedupStore, FINGERPRINT_IMAGES},
+        near_dup::NearDupStore,
+        KVStorage,
+    },
+    stream::{AsyncStream, Buffered, Indexed},
     telegraph::{
-        types::{Node, NodeElement, NodeElementAttr, Page, PageCreate, Tag},
-        RandomAccessToken, Telegraph, TelegraphError, MAX_SINGLE_FILE_SIZE,
+        types::{Node, NodeElement, NodeElementAttr, Page, PageCreate, PageEdit, Tag},
+        AccessToken, Telegraph, TelegraphError, TokenPool, MAX_SINGLE_FILE_SIZE, TITLE_LENGTH_MAX,
+    },
+    title::{default_title, truncate_title, TitleTemplate},
+};
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+use singleflight_async::SingleFlight;
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, OnceLock, Weak,
     },
-    util::match_first_group,
+    time::Instant,
 };
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
 
 const ERR_THRESHOLD: usize = 10;
 const BATCH_LEN_THRESHOLD: usize = 20;
 const BATCH_SIZE_THRESHOLD: usize = 5 * 1024 * 1024;
 const DEFAULT_CONCURRENT: usize = 20;
+// Telegraph has 64K limit, since our estimate is not accurate, here we use 48K.
+const PAGE_SIZE_LIMIT: usize = 48 * 1024;
+// Telegraph drops images of long articles, so we keep at most 100 images per page.
+const PAGE_NODE_LIMIT: usize = 100;
 
 #[derive(thiserror::Error, Debug)]
 pub enum UploadError<SE> {
@@ -25,20 +55,460 @@ pub enum UploadError<SE> {
     Stream(SE),
     #[error("telegraph error {0}")]
     Reqwest(#[from] TelegraphError),
+    #[error("sync cancelled")]
+    Cancelled,
+    #[error(transparent)]
+    TooLarge(SizeLimit),
+}
+
+/// Failure of a sync, by what went wrong so callers can tell them apart.
+/// The messages include the original error where there is one, which is kept and
+/// given by `SyncError::cause` instead of being the source, so it is printed once.
+#[derive(thiserror::Error, Debug, Clone)]
+pub enum SyncError {
+    #[error("invalid url {0}")]
+    InvalidUrl(String),
+    #[error("unsupported url {0}")]
+    UnsupportedUrl(String),
+    #[error("invalid page selection: {0:#}")]
+    Pages(SharedError),
+    #[error("no telegraph token given")]
+    NoToken,
+    #[error("gallery not found, maybe it has been deleted")]
+    NotFound,
+    #[error("this gallery needs login cookies, they are missing or expired")]
+    RequiresAuth,
+    #[error("unable to collect the gallery: {0:#}")]
+    Collector(SharedError),
+    #[error("proxy error: {0:#}")]
+    Proxy(SharedError),
+    #[error("telegraph error: {0:#}")]
+    Telegraph(SharedError),
+    #[error(transparent)]
+    TooLarge(SizeLimit),
+    #[error("sync cancelled")]
+    Cancelled,
+    #[error("sync failed: {0:#}")]
+    Other(SharedError),
+}
+
+impl SyncError {
+    /// The original error, like to look for a known one in its chain.
+    pub fn cause(&self) -> Option<&anyhow::Error> {
+        match self {
+            Self::Pages(e)
+            | Self::Collector(e)
+            | Self::Proxy(e)
+            | Self::Telegraph(e)
+            | Self::Other(e) => Some(&e.0),
+            _ => None,
+        }
+    }
+
+    /// Whether syncing again later may succeed.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            Self::Collector(_) | Self::Proxy(_) | Self::Telegraph(_) | Self::Other(_)
+        )
+    }
+
+    /// Classify an error of collecting or downloading a gallery by the first known
+    /// error in its chain, a `Collector` one if there is none.
+    pub fn classify(e: impl Into<anyhow::Error>) -> Self {
+        let e = e.into();
+        for cause in e.chain() {
+            if let Some(e) = cause.downcast_ref::<SyncError>() {
+                return e.clone();
+            }
+            match cause.downcast_ref::<CollectorError>() {
+                Some(CollectorError::NotFound) => return Self::NotFound,
+                Some(CollectorError::RequiresAuth) => return Self::RequiresAuth,
+                _ => (),
+            }
+        }
+        let telegraph = e.chain().any(|e| e.is::<TelegraphError>());
+        let proxy = e.chain().any(|e| e.is::<ProxyError>());
+        if telegraph {
+            Self::Telegraph(SharedError::new(e))
+        } else if proxy {
+            Self::Proxy(SharedError::new(e))
+        } else {
+            Self::Collector(SharedError::new(e))
+        }
+    }
+}
+
+impl<SE: Into<anyhow::Error>> From<UploadError<SE>> for SyncError {
+    fn from(e: UploadError<SE>) -> Self {
+        match e {
+            UploadError::Stream(e) => Self::classify(e),
+            UploadError::Reqwest(e) => Self::Telegraph(SharedError::new(e)),
+            UploadError::Cancelled => Self::Cancelled,
+            UploadError::TooLarge(limit) => Self::TooLarge(limit),
+        }
+    }
+}
+
+/// Limits of a gallery, so a huge one does not use up the disk and bandwidth.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+pub struct SyncLimits {
+    pub max_pages: Option<usize>,
+    /// Of the downloaded images, before re-encoding.
+    pub max_total_bytes: Option<u64>,
+}
+
+/// The limit a gallery exceeded, the message of both `UploadError::TooLarge` and
+/// `SyncError::TooLarge`.
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SizeLimit {
+    #[error("gallery exceeds the limit of {0} pages")]
+    Pages(usize),
+    #[error("gallery exceeds the limit of {0} bytes")]
+    Bytes(u64),
+}
+
+impl SyncLimits {
+    fn check_pages(&self, pages: usize) -> Result<(), SizeLimit> {
+        match self.max_pages {
+            Some(max) if pages > max => Err(SizeLimit::Pages(max)),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_bytes(&self, bytes: u64) -> Result<(), SizeLimit> {
+        match self.max_total_bytes {
+            Some(max) if bytes > max => Err(SizeLimit::Bytes(max)),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Progress of a running sync, `page` counts the pages handled so far.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncProgress {
+    /// Resumed pages are counted as downloaded. `received` bytes include the ones of
+    /// the pages still downloading, so it keeps growing during a large page.
+    Downloading {
+        page: usize,
+        total: Option<usize>,
+        received: u64,
+    },
+    Uploading {
+        page: usize,
+        total: Option<usize>,
+    },
+}
+
+impl fmt::Display for SyncProgress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (action, page, total, received) = match self {
+            SyncProgress::Downloading {
+                page,
+                total,
+                received,
+            } => ("Downloading", page, total, *received),
+            SyncProgress::Uploading { page, total } => ("Uploading", page, total, 0),
+        };
+        write!(f, "{action} page {page}")?;
+        if let Some(total) = total {
+            write!(f, "/{total}")?;
+        }
+        if received > 0 {
+            write!(
+                f,
+                ", {:.1} MiB received",
+                received as f64 / (1024.0 * 1024.0)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Publishes the latest `SyncProgress`, receivers may skip intermediate ones.
+#[derive(Debug, Clone, Default)]
+pub struct ProgressReporter(Arc<parking_lot::Mutex<Vec<watch::Sender<Option<SyncProgress>>>>>);
+
+impl ProgressReporter {
+    pub fn channel() -> (Self, watch::Receiver<Option<SyncProgress>>) {
+        let (tx, rx) = watch::channel(None);
+        (Self(Arc::new(parking_lot::Mutex::new(vec![tx]))), rx)
+    }
+
+    fn report(&self, progress: SyncProgress) {
+        for tx in self.0.lock().iter() {
+            tx.send_replace(Some(progress));
+        }
+    }
+
+    /// Also publish to the receivers of `other` from now on.
+    fn forward_to(&self, other: &ProgressReporter) {
+        let senders = other.0.lock().clone();
+        self.0.lock().extend(senders);
+    }
+}
+
+/// Filled with the metadata once the gallery is fetched, or with the one stored in
+/// the cache on cache hit.
+#[derive(Debug, Clone, Default)]
+pub struct MetaSlot(Arc<OnceLock<(AlbumMeta, bool)>>);
+
+impl MetaSlot {
+    pub fn get(&self) -> Option<&AlbumMeta> {
+        self.0.get().map(|(meta, _)| meta)
+    }
+
+    /// The metadata only if the gallery was fetched, not taken from the cache.
+    pub fn fetched(&self) -> Option<&AlbumMeta> {
+        self.0
+            .get()
+            .and_then(|(meta, fetched)| fetched.then_some(meta))
+    }
+
+    fn set(&self, meta: &AlbumMeta) {
+        let _ = self.0.set((meta.clone(), true));
+    }
+
+    fn set_cached(&self, meta: AlbumMeta) {
+        let _ = self.0.set((meta, false));
+    }
+
+    fn fill_from(&self, other: &MetaSlot) {
+        if let Some(v) = other.0.get() {
+            let _ = self.0.set(v.clone());
+        }
+    }
+}
+
+/// Filled with the first page url of a similar gallery synced before, if found by the
+/// near-duplicate detection.
+#[derive(Debug, Clone, Default)]
+pub struct DuplicateSlot(Arc<OnceLock<String>>);
+
+impl DuplicateSlot {
+    pub fn get(&self) -> Option<&str> {
+        self.0.get().map(String::as_str)
+    }
+
+    fn set(&self, url: &str) {
+        let _ = self.0.set(url.to_string());
+    }
+}
+
+/// Filled with the pages left out of the upload, like images which can not be decoded
+/// for re-encoding.
+#[derive(Debug, Clone, Default)]
+pub struct SkippedSlot(Arc<parking_lot::Mutex<Vec<SkippedPage>>>);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SkippedPage {
+    /// Position of the page in the gallery.
+    pub index: usize,
+    /// Link of the image page, unknown if it could not be downloaded.
+    pub url: Option<String>,
+    pub reason: String,
+}
+
+impl SkippedSlot {
+    pub fn get(&self) -> Vec<SkippedPage> {
+        self.0.lock().clone()
+    }
+
+    fn push(&self, index: usize, meta: Option<&ImageMeta>, reason: String) {
+        self.0.lock().push(SkippedPage {
+            index,
+            url: meta.map(|m| m.url.clone()),
+            reason,
+        });
+    }
+
+    fn extend(&self, pages: Vec<SkippedPage>) {
+        self.0.lock().extend(pages);
+    }
+}
+
+/// Shared by the requesters of a sync in flight, each one gets the progress and the
+/// slots of the upload, which is only cancelled once all of them are.
+#[derive(Debug, Default)]
+struct Flight {
+    progress: ProgressReporter,
+    meta: MetaSlot,
+    duplicate: DuplicateSlot,
+    skipped: SkippedSlot,
+    cancel: CancellationToken,
+    // requesters not cancelled
+    waiting: AtomicUsize,
+}
+
+/// Counts a requester of the flight as waiting until it leaves or is dropped.
+struct Waiting<'a>(Option<&'a Flight>);
+
+impl Waiting<'_> {
+    /// Whether it was the last one waiting.
+    fn leave(&mut self) -> bool {
+        self.0
+            .take()
+            .is_some_and(|f| f.waiting.fetch_sub(1, Ordering::SeqCst) == 1)
+    }
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.leave();
+    }
+}
+
+impl Flight {
+    /// Attach a requester, the upload uses the returned options instead of its own.
+    fn attach(&self, options: &UploadOptions) -> UploadOptions {
+        if let Some(progress) = &options.progress {
+            self.progress.forward_to(progress);
+        }
+        self.waiting.fetch_add(1, Ordering::SeqCst);
+        UploadOptions {
+            progress: Some(self.progress.clone()),
+            cancel: Some(self.cancel.clone()),
+            meta: Some(self.meta.clone()),
+            duplicate: Some(self.duplicate.clone()),
+            skipped: Some(self.skipped.clone()),
+            ..options.clone()
+        }
+    }
+
+    /// Fill the slots of a requester once the upload is done.
+    fn fill(&self, options: &UploadOptions) {
+        if let Some(slot) = &options.meta {
+            slot.fill_from(&self.meta);
+        }
+        if let (Some(slot), Some(url)) = (&options.duplicate, self.duplicate.get()) {
+            slot.set(url);
+        }
+        if let Some(slot) = &options.skipped {
+            slot.extend(self.skipped.get());
+        }
+    }
+}
+
+/// Per upload options, unset fields fall back to the defaults of the `Synchronizer`.
+#[derive(Debug, Clone, Default)]
+pub struct UploadOptions {
+    /// Author name of the created pages, the gallery authors are used if not set.
+    pub author_name: Option<String>,
+    pub author_url: Option<String>,
+    /// Upload only these pages of the gallery, all pages if not set.
+    pub pages: Option<PageSelection>,
+    /// Upload even if the gallery, or one with the same content, has been synced.
+    pub force: bool,
+    /// Put a header of the gallery metadata after the cover image, disabled if not set.
+    pub include_metadata: Option<bool>,
+    /// End each page with the capture date and `footer_text` after the original link,
+    /// disabled if not set.
+    pub include_footer: Option<bool>,
+    /// Extra line of the footer, like `Archived by @bot`.
+    pub footer_text: Option<String>,
+    /// Receives the progress of this upload.
+    pub progress: Option<ProgressReporter>,
+    /// Stop before the next download or upload once cancelled, the checkpoint
+    /// is kept for resume.
+    pub cancel: Option<CancellationToken>,
+    /// Receives the metadata of the gallery.
+    pub meta: Option<MetaSlot>,
+    /// Receives the similar gallery synced before.
+    pub duplicate: Option<DuplicateSlot>,
+    /// Receives the pages left out of the upload.
+    pub skipped: Option<SkippedSlot>,
+}
+
+impl UploadOptions {
+    /// Fill unset fields from `defaults`.
+    pub fn or(self, defaults: &UploadOptions) -> UploadOptions {
+        UploadOptions {
+            author_name: self.author_name.or_else(|| defaults.author_name.clone()),
+            author_url: self.author_url.or_else(|| defaults.author_url.clone()),
+            pages: self.pages.or_else(|| defaults.pages.clone()),
+            force: self.force || defaults.force,
+            include_metadata: self.include_metadata.or(defaults.include_metadata),
+            include_footer: self.include_footer.or(defaults.include_footer),
+            footer_text: self.footer_text.or_else(|| defaults.footer_text.clone()),
+            progress: self.progress.or_else(|| defaults.progress.clone()),
+            cancel: self.cancel.or_else(|| defaults.cancel.clone()),
+            meta: self.meta.or_else(|| defaults.meta.clone()),
+            duplicate: self.duplicate.or_else(|| defaults.duplicate.clone()),
+            skipped: self.skipped.or_else(|| defaults.skipped.clone()),
+        }
+    }
+}
+
+/// What a sync would upload, made by `Synchronizer::dry_run`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DryRunReport {
+    pub name: String,
+    /// Images downloaded.
+    pub pages: usize,
+    pub total_bytes: usize,
+    /// Image count of each detected format.
+    pub formats: BTreeMap<ImageKind, usize>,
+    /// Images over the upload limit, with what a sync makes of them.
+    pub oversized: Vec<OversizedImage>,
+    /// Errors of the images failed to download.
+    pub failed: Vec<String>,
+}
+
+/// An image over the upload limit, see `DryRunReport`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OversizedImage {
+    pub url: String,
+    pub size: usize,
+    /// Sizes of what a sync uploads instead, one per slice, or why it is left out.
+    pub prepared: Vec<Result<usize, String>>,
 }
 
 pub struct Synchronizer<C = CFStorage> {
-    tg: Telegraph<RandomAccessToken, ProxiedClient>,
+    tg: Telegraph<TokenPool, ProxiedClient>,
     limit: Option<usize>,
 
-    author_name: Option<String>,
-    author_url: Option<String>,
+    defaults: UploadOptions,
     cache_ttl: Option<usize>,
+    reencoder: Option<ImageReencoder>,
+    slicer: Option<ImageSlicer>,
+    allowlist: TypeAllowlist,
+    near_duplicate: Option<NearDuplicate>,
+    // loaded on the first search
+    near_dup: NearDupStore,
+    title_template: Option<TitleTemplate>,
+    limits: SyncLimits,
+    metrics: Arc<Metrics>,
+    // syncs of the same cache key at the same time upload once
+    in_flight: SingleFlight<Result<Vec<String>, SyncError>>,
+    flights: parking_lot::Mutex<HashMap<String, Weak<Flight>>>,
 
     registry: Registry,
     cache: C,
 }
 
+/// An error shared with the ones waiting for the same sync. The original error is
+/// the source, so it can still be found by `anyhow::Error::chain`.
+#[derive(Debug, Clone)]
+pub struct SharedError(Arc<anyhow::Error>);
+
+impl SharedError {
+    pub fn new(e: impl Into<anyhow::Error>) -> Self {
+        Self(Arc::new(e.into()))
+    }
+}
+
+impl fmt::Display for SharedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for SharedError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.0.as_ref().as_ref())
+    }
+}
+
 impl<CACHE> Synchronizer<CACHE>
 where
     CACHE: KVStorage<String>,
@@ -46,17 +516,22 @@ where
     // cache ttl is 45 days
     const DEFAULT_CACHE_TTL: usize = 3600 * 24 * 45;
 
-    pub fn new(
-        tg: Telegraph<RandomAccessToken, ProxiedClient>,
-        registry: Registry,
-        cache: CACHE,
-    ) -> Self {
+    pub fn new(tg: Telegraph<TokenPool, ProxiedClient>, registry: Registry, cache: CACHE) -> Self {
         Self {
             tg,
             limit: None,
-            author_name: None,
-            author_url: None,
+            defaults: UploadOptions::default(),
             cache_ttl: None,
+            reencoder: Some(ImageReencoder::default()),
+            slicer: None,
+            allowlist: TypeAllowlist::default(),
+            near_duplicate: None,
+            near_dup: NearDupStore::default(),
+            title_template: None,
+            limits: SyncLimits::default(),
+            metrics: Arc::default(),
+            in_flight: SingleFlight::default(),
+            flights: Default::default(),
             registry,
             cache,
         }
@@ -68,8 +543,21 @@ where
     }
 
     pub fn with_author<S: Into<String>>(mut self, name: Option<S>, url: Option<S>) -> Self {
-        self.author_name = name.map(Into::into);
-        self.author_url = url.map(Into::into);
+        self.defaults.author_name = name.map(Into::into);
+        self.defaults.author_url = url.map(Into::into);
+        self
+    }
+
+    /// Default of `UploadOptions::include_metadata`.
+    pub fn with_metadata_header(mut self, include: Option<bool>) -> Self {
+        self.defaults.include_metadata = include;
+        self
+    }
+
+    /// Default footer of the pages, see `UploadOptions::include_footer`.
+    pub fn with_footer<S: Into<String>>(mut self, include: Option<bool>, text: Option<S>) -> Self {
+        self.defaults.include_footer = include;
+        self.defaults.footer_text = text.map(Into::into);
         self
     }
 
@@ -78,11 +566,156 @@ where
         self
     }
 
+    /// Stop syncing galleries exceeding the limits with `SyncError::TooLarge`.
+    /// The archives of e-hentai are limited by `max_total_bytes` too.
+    pub fn with_limits(mut self, limits: SyncLimits) -> Self {
+        self.registry = self.registry.with_archive_limit(limits.max_total_bytes);
+        self.limits = limits;
+        self
+    }
+
+    /// Re-encode images exceeding the threshold before uploading, `None` to disable.
+    pub fn with_reencoder(mut self, reencoder: Option<ImageReencoder>) -> Self {
+        self.reencoder = reencoder;
+        self
+    }
+
+    /// Downscale or slice images exceeding the dimensions before uploading, `None`
+    /// to upload them as they are.
+    pub fn with_slicer(mut self, slicer: Option<ImageSlicer>) -> Self {
+        self.slicer = slicer;
+        self
+    }
+
+    /// Image types uploaded, see `TypeAllowlist`. Others are transcoded to JPEG if
+    /// it is allowed and they can be decoded, or discarded.
+    pub fn with_allowlist(mut self, allowlist: TypeAllowlist) -> Self {
+        self.allowlist = allowlist;
+        self
+    }
+
+    /// Look for a similar gallery synced before by the perceptual hashes of the
+    /// leading pages, disabled if not set. Syncs with `force` skip the check.
+    pub fn with_near_duplicate(mut self, near_duplicate: Option<NearDuplicate>) -> Self {
+        self.near_duplicate = near_duplicate;
+        self
+    }
+
+    /// Titles of the created pages, the gallery name if not set.
+    pub fn with_title_template(mut self, template: Option<TitleTemplate>) -> Self {
+        self.title_template = template;
+        self
+    }
+
+    /// The storage shared with the sync cache.
+    pub fn cache(&self) -> &CACHE {
+        &self.cache
+    }
+
+    /// The collectors, with the hosts gallery urls are matched by.
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    pub fn metrics(&self) -> SyncMetrics {
+        self.metrics.snapshot()
+    }
+
     pub async fn delete_cache(&self, key: &str) -> anyhow::Result<()> {
+        self.cache.delete(&Self::meta_key(key)).await?;
         self.cache.delete(key).await
     }
 
-    pub async fn sync<C: Collector>(&self, path: String) -> anyhow::Result<String>
+    /// The metadata of a synced gallery is stored next to its urls, for cache hits.
+    fn meta_key(cache_key: &str) -> String {
+        format!("meta|{cache_key}")
+    }
+
+    fn cache_key<C: Collector>(path: &str, pages: Option<&PageSelection>) -> String {
+        let mut key = format!("{}|{}", C::name(), path.trim_end_matches('/'));
+        if let Some(pages) = pages {
+            key.push_str(&format!("#pages={pages}"));
+        }
+        key.replace("exhentai", "e-hentai")
+    }
+
+    /// The urls of a synced gallery in the cache, without syncing it on miss.
+    pub async fn cached<C: Collector>(
+        &self,
+        path: &str,
+        pages: Option<&PageSelection>,
+    ) -> anyhow::Result<Option<Vec<String>>> {
+        let v = self.cache.get(&Self::cache_key::<C>(path, pages)).await?;
+        Ok(v.map(|v| v.split('\n').map(ToString::to_string).collect()))
+    }
+
+    /// The urls of a gallery with the same content synced before, found by the
+    /// fingerprint of its leading images like a sync does. Only these images are
+    /// downloaded, it is not synced on miss.
+    pub async fn cached_content<C: Collector>(
+        &self,
+        path: &str,
+        pages: Option<&PageSelection>,
+    ) -> anyhow::Result<Option<Vec<String>>>
+    where
+        Registry: Param<C>,
+        C::FetchError: Into<anyhow::Error> + Send + 'static,
+    {
+        let collector: &C = self.registry.get();
+        let path = path.trim_end_matches('/').to_string();
+        let (_, mut stream) = collector.fetch_pages(path, pages).await?;
+        let total = match stream.size_hint() {
+            (lower, Some(upper)) if lower == upper => lower,
+            _ => return Ok(None),
+        };
+        let downloads = std::iter::from_fn(|| stream.next())
+            .take(FINGERPRINT_IMAGES)
+            .collect::<Vec<_>>();
+        let leading = futures::future::join_all(downloads)
+            .await
+            .into_iter()
+            .filter_map(|r| r.ok().map(|(_, data)| data))
+            .collect::<Vec<_>>();
+        let fp = tokio::task::spawn_blocking(move || {
+            fingerprint(total, leading.iter().map(AsRef::as_ref))
+        })
+        .await?;
+        let options = UploadOptions {
+            pages: pages.cloned(),
+            ..Default::default()
+        };
+        let variant = dedup_variant(&options.or(&self.defaults));
+        let pages = DedupStore::new(&self.cache).get(&fp, &variant).await?;
+        Ok(pages
+            .filter(|p| !p.is_empty())
+            .map(|p| p.into_iter().map(|p| p.url).collect()))
+    }
+
+    /// Sync the gallery and return the urls of all created pages in order.
+    pub async fn sync<C: Collector>(&self, path: String) -> Result<Vec<String>, SyncError>
+    where
+        Registry: Param<C>,
+        C::FetchError: Into<anyhow::Error> + Send + 'static,
+        C::StreamError:
+            Into<anyhow::Error> + std::fmt::Debug + std::fmt::Display + Send + Sync + 'static,
+        C::ImageStream: Send + 'static,
+        <C::ImageStream as AsyncStream>::Future: Send + 'static,
+    {
+        self.sync_with_options::<C>(path, UploadOptions::default())
+            .await
+    }
+
+    /// Same as `sync`, but with options for this upload.
+    /// Note the result is cached by path and page selection, so other options are
+    /// ignored on cache hit, unless `force` skips the cache. Concurrent syncs of the
+    /// same gallery share the upload of the first one and its other options. Each of
+    /// them gets the progress and the slots of the upload, and stops waiting once its
+    /// own token is cancelled, the upload is cancelled once all of them are.
+    pub async fn sync_with_options<C: Collector>(
+        &self,
+        path: String,
+        options: UploadOptions,
+    ) -> Result<Vec<String>, SyncError>
     where
         Registry: Param<C>,
         C::FetchError: Into<anyhow::Error> + Send + 'static,
@@ -91,70 +724,411 @@ where
         C::ImageStream: Send + 'static,
         <C::ImageStream as AsyncStream>::Future: Send + 'static,
     {
-        // check cache
         let path = path.trim_end_matches('/').to_string();
-        let original_cache_key = format!("{}|{}", C::name(), path);
-        let cache_key = original_cache_key.replace("exhentai", "e-hentai");
-        if let Ok(Some(v)) = self.cache.get(&cache_key).await {
+        let cache_key = Self::cache_key::<C>(&path, options.pages.as_ref());
+        loop {
+            let flight = self.flight(&cache_key);
+            let shared = flight.attach(&options);
+            let mut waiting = Waiting(Some(&flight));
+            let work = self
+                .in_flight
+                .work(&cache_key, || self.sync_once::<C>(path.clone(), shared));
+            tokio::pin!(work);
+            let result = match &options.cancel {
+                Some(cancel) => tokio::select! {
+                    result = &mut work => Some(result),
+                    _ = cancel.cancelled() => None,
+                },
+                None => Some((&mut work).await),
+            };
+            let result = match result {
+                Some(result) => result,
+                // the others still wait for it, the next one takes over if it is this one
+                None if !waiting.leave() => Err(SyncError::Cancelled),
+                // stopped at the next download or upload, the checkpoint is kept
+                None => {
+                    flight.cancel.cancel();
+                    work.await
+                }
+            };
+            // joined an upload cancelled by the ones before, so start another
+            let cancelled = options.cancel.as_ref().is_some_and(|c| c.is_cancelled());
+            if matches!(result, Err(SyncError::Cancelled)) && !cancelled {
+                continue;
+            }
+            drop(waiting);
+            flight.fill(&options);
+            return result;
+        }
+    }
+
+    /// The flight of the cache key, a new one if there is none or it is cancelled.
+    fn flight(&self, cache_key: &str) -> Arc<Flight> {
+        let mut flights = self.flights.lock();
+        if let Some(flight) = flights.get(cache_key).and_then(Weak::upgrade) {
+            if !flight.cancel.is_cancelled() {
+                return flight;
+            }
+        }
+        flights.retain(|_, f| f.strong_count() > 0);
+        let flight = Arc::new(Flight::default());
+        flights.insert(cache_key.to_string(), Arc::downgrade(&flight));
+        flight
+    }
+
+    async fn sync_once<C: Collector>(
+        &self,
+        path: String,
+        options: UploadOptions,
+    ) -> Result<Vec<String>, SyncError>
+    where
+        Registry: Param<C>,
+        C::FetchError: Into<anyhow::Error> + Send + 'static,
+        C::StreamError:
+            Into<anyhow::Error> + std::fmt::Debug + std::fmt::Display + Send + Sync + 'static,
+        C::ImageStream: Send + 'static,
+        <C::ImageStream as AsyncStream>::Future: Send + 'static,
+    {
+        // check cache
+        let cache_key = Self::cache_key::<C>(&path, options.pages.as_ref());
+        let cached = match options.force {
+            true => None,
+            false => self.cache.get(&cache_key).await.ok().flatten(),
+        };
+        if let Some(v) = cached {
             tracing::info!("[cache] hit key {cache_key}");
-            return Ok(v);
+            self.metrics.record_cache_hit();
+            if let Some(slot) = &options.meta {
+                let meta = self.cache.get(&Self::meta_key(&cache_key)).await;
+                if let Some(meta) = meta
+                    .ok()
+                    .flatten()
+                    .and_then(|m| serde_json::from_str(&m).ok())
+                {
+                    slot.set_cached(meta);
+                }
+            }
+            return Ok(v.split('\n').map(ToString::to_string).collect());
         }
         tracing::info!("[cache] miss key {cache_key}");
 
-        let collector: &C = self.registry.get();
-        let (meta, stream) = collector.fetch(path).await.map_err(Into::into)?;
-        let page = self
-            .sync_stream(meta, stream)
-            .await
-            .map_err(anyhow::Error::from)?;
+        let start = Instant::now();
+        let result = async {
+            let collector: &C = self.registry.get();
+            let (meta, stream) = collector
+                .fetch_pages(path, options.pages.as_ref())
+                .await
+                .map_err(SyncError::classify)?;
+            if let Some(slot) = &options.meta {
+                slot.set(&meta);
+            }
+            self.resume_sync_stream(Some(&cache_key), meta.clone(), stream, options)
+                .await
+                .map(|pages| (meta, pages))
+                .map_err(SyncError::from)
+        }
+        .await;
+        self.metrics.record_sync(result.is_ok(), start.elapsed());
+        let (meta, pages) = result?;
+        let urls = pages.into_iter().map(|p| p.url).collect::<Vec<_>>();
 
         // set cache
-        let _ = self
-            .cache
-            .set(
-                cache_key,
-                page.url.clone(),
-                Some(self.cache_ttl.unwrap_or(Self::DEFAULT_CACHE_TTL)),
-            )
-            .await;
-        Ok(page.url)
+        let ttl = Some(self.cache_ttl.unwrap_or(Self::DEFAULT_CACHE_TTL));
+        if let Ok(meta) = serde_json::to_string(&meta) {
+            let _ = self.cache.set(Self::meta_key(&cache_key), meta, ttl).await;
+        }
+        // one url per line
+        let _ = self.cache.set(cache_key, urls.join("\n"), ttl).await;
+        Ok(urls)
     }
 
-    pub async fn sync_stream<S, SE>(
+    /// Collect and download the gallery like `sync`, but report what would be uploaded
+    /// without touching Telegraph or the cache.
+    pub async fn dry_run<C: Collector>(
         &self,
-        meta: AlbumMeta,
-        stream: S,
-    ) -> Result<Page, UploadError<SE>>
+        path: String,
+        pages: Option<&PageSelection>,
+    ) -> anyhow::Result<DryRunReport>
     where
-        SE: Send + std::fmt::Debug + 'static,
+        Registry: Param<C>,
+        C::FetchError: Into<anyhow::Error> + Send + 'static,
+        C::StreamError: fmt::Display + Send + 'static,
+        C::ImageStream: Send + 'static,
+        <C::ImageStream as AsyncStream>::Future: Send + 'static,
+    {
+        let collector: &C = self.registry.get();
+        let path = path.trim_end_matches('/').to_string();
+        let (meta, stream) = collector.fetch_pages(path, pages).await?;
+        Ok(self.dry_run_stream(meta, stream).await)
+    }
+
+    pub async fn dry_run_stream<S, SE>(&self, meta: AlbumMeta, stream: S) -> DryRunReport
+    where
+        SE: fmt::Display + Send + 'static,
         S: AsyncStream<Item = Result<(ImageMeta, ImageData), SE>>,
         S::Future: Send + 'static,
     {
-        let buffered_stream = Buffered::new(stream, self.limit.unwrap_or(DEFAULT_CONCURRENT));
-        let r = self.inner_sync_stream(meta, buffered_stream).await;
-        match &r {
-            Ok(p) => {
-                tracing::info!("[sync] sync success with url {}", p.url);
+        let mut stream = Buffered::new(stream, self.limit.unwrap_or(DEFAULT_CONCURRENT));
+        let mut report = DryRunReport {
+            name: meta.name,
+            ..Default::default()
+        };
+        while let Some(fut) = stream.next() {
+            match fut.await {
+                Ok((meta, data)) => {
+                    report.pages += 1;
+                    report.total_bytes += data.len();
+                    *report.formats.entry(ImageKind::sniff(&data)).or_default() += 1;
+                    if data.len() >= MAX_SINGLE_FILE_SIZE {
+                        let size = data.len();
+                        let prepared = self
+                            .prepare_image(data)
+                            .await
+                            .into_iter()
+                            .map(|r| r.map(|d| d.len()))
+                            .collect();
+                        report.oversized.push(OversizedImage {
+                            url: meta.url,
+                            size,
+                            prepared,
+                        });
+                    }
+                }
+                Err(e) => report.failed.push(e.to_string()),
             }
-            Err(e) => {
-                tracing::error!("[sync] sync fail! {e:?}");
+        }
+        report
+    }
+
+    /// Make a downloaded image ready to upload: slice it if too large, then
+    /// transcode or re-encode it as needed. Each segment is the data to upload, or
+    /// the reason it is left out.
+    async fn prepare_image(&self, raw: ImageData) -> Vec<Result<ImageData, String>> {
+        // too large ones are sliced into segments uploaded in order
+        let segments = match self.slicer {
+            Some(slicer) if slicer.should_slice(&raw) => {
+                let result = tokio::task::spawn_blocking(move || slicer.slice(raw))
+                    .await
+                    .map_err(anyhow::Error::from)
+                    .and_then(|r| r);
+                match result {
+                    Ok(segments) => segments,
+                    Err(e) => return vec![Err(format!("Slicing failed: {e}"))],
+                }
+            }
+            _ => vec![raw],
+        };
+
+        let mut prepared = Vec::with_capacity(segments.len());
+        for raw in segments {
+            prepared.push(self.reencode_segment(raw).await);
+        }
+        prepared
+    }
+
+    async fn reencode_segment(&self, raw: ImageData) -> Result<ImageData, String> {
+        let kind = ImageKind::sniff(&raw);
+        let transcode = !self.allowlist.allows(kind);
+        if transcode
+            && (kind == ImageKind::Unknown
+                || !kind.is_decodable()
+                || !self.allowlist.allows(ImageKind::Jpeg))
+        {
+            let kind = match kind {
+                ImageKind::Unknown => "not an image",
+                kind => kind.mime(),
+            };
+            return Err(format!("Image type not allowed({kind})"));
+        }
+        // formats the upload target rejects are transcoded even if disabled
+        let reencoder = self
+            .reencoder
+            .or_else(|| (transcode || !kind.is_accepted()).then(ImageReencoder::default));
+        let data = match reencoder {
+            Some(reencoder) if transcode || reencoder.should_reencode(&raw) => {
+                tokio::task::spawn_blocking(move || match transcode {
+                    true => reencoder.transcode(raw),
+                    false => reencoder.reencode(raw),
+                })
+                .await
+                .map_err(anyhow::Error::from)
+                .and_then(|r| r)
+                .map_err(|e| format!("Re-encode failed: {e}"))?
             }
+            _ => raw,
+        };
+
+        // if the data size is too big to upload, we will discard it.
+        if data.len() >= MAX_SINGLE_FILE_SIZE {
+            return Err(format!("Too big file({} bytes)", data.len()));
         }
-        r
+        Ok(data)
     }
 
-    async fn inner_sync_stream<S, SE>(
+    pub async fn sync_stream<S, SE>(
         &self,
         meta: AlbumMeta,
-        mut stream: S,
-    ) -> Result<Page, UploadError<SE>>
+        stream: S,
+        options: UploadOptions,
+    ) -> Result<Vec<Page>, UploadError<SE>>
     where
+        SE: Send + std::fmt::Debug + 'static,
         S: AsyncStream<Item = Result<(ImageMeta, ImageData), SE>>,
+        S::Future: Send + 'static,
+    {
+        self.resume_sync_stream(None, meta, stream, options).await
+    }
+
+    /// Same as `sync_stream`, but the uploaded images are saved in the checkpoint
+    /// of the given key after each batch. Images uploaded by an interrupted run with
+    /// the same key are skipped, and the checkpoint is cleared on success.
+    pub async fn resume_sync_stream<S, SE>(
+        &self,
+        checkpoint: Option<&str>,
+        meta: AlbumMeta,
+        stream: S,
+        options: UploadOptions,
+    ) -> Result<Vec<Page>, UploadError<SE>>
+    where
+        SE: Send + std::fmt::Debug + 'static,
+        S: AsyncStream<Item = Result<(ImageMeta, ImageData), SE>>,
+        S::Future: Send + 'static,
+    {
+        let store = CheckpointStore::new(&self.cache);
+        let progress = match checkpoint {
+            Some(key) => match store.load(key).await {
+                Ok(progress) => {
+                    if !progress.images.is_empty() {
+                        tracing::info!(
+                            "[checkpoint] resume {key} with {} images uploaded",
+                            progress.images.len()
+                        );
+                    }
+                    progress
+                }
+                Err(e) => {
+                    tracing::warn!("[checkpoint] unable to load {key}, start over: {e}");
+                    Checkpoint::default()
+                }
+            },
+            None => Checkpoint::default(),
+        };
+
+        // the fingerprint needs the exact page count
+        let total = match stream.size_hint() {
+            (lower, Some(upper)) if lower == upper => Some(lower),
+            _ => None,
+        };
+        // others are checked while downloading
+        if let Some(total) = total {
+            self.limits
+                .check_pages(total)
+                .map_err(UploadError::TooLarge)?;
+        }
+        let stream = Indexed::new(stream, progress.uploaded());
+        let buffered_stream = Buffered::new(stream, self.limit.unwrap_or(DEFAULT_CONCURRENT));
+        let r = self
+            .inner_sync_stream(
+                meta,
+                buffered_stream,
+                options.or(&self.defaults),
+                checkpoint.map(|key| (store, key)),
+                progress,
+                total,
+            )
+            .await;
+        if let (Ok(_), Some(key)) = (&r, checkpoint) {
+            if let Err(e) = store.clear(key).await {
+                tracing::warn!("[checkpoint] unable to clear {key}: {e}");
+            }
+        }
+        match &r {
+            Ok(pages) => {
+                tracing::info!("[sync] sync success with url {}", pages[0].url);
+            }
+            Err(e) => {
+                tracing::error!("[sync] sync fail! {e:?}");
+            }
+        }
+        r
+    }
+
+    async fn inner_sync_stream<S, SE>(
+        &self,
+        meta: AlbumMeta,
+        mut stream: S,
+        mut options: UploadOptions,
+        checkpoint: Option<(CheckpointStore<'_, CACHE>, &str)>,
+        mut progress: Checkpoint,
+        total: Option<usize>,
+    ) -> Result<Vec<Page>, UploadError<SE>>
+    where
+        SE: std::fmt::Debug,
+        S: AsyncStream<Item = (usize, Result<(ImageMeta, ImageData), SE>)>,
     {
         let mut err_count = 0;
-        let mut uploaded = Vec::new();
+        // before the authors of the gallery are filled in
+        let variant = dedup_variant(&options);
+        // positions of the failed images, retried once the stream ends
+        let mut failed = VecDeque::new();
+        // with the position in the stream, since resumed images come first
+        let mut uploaded = progress
+            .images
+            .iter()
+            .map(|i| {
+                let meta = ImageMeta {
+                    id: i.id.clone(),
+                    url: i.url.clone(),
+                    description: i.description.clone(),
+                };
+                let image = UploadedImage {
+                    meta,
+                    src: i.src.clone(),
+                };
+                (i.index, image)
+            })
+            .collect::<Vec<_>>();
+        let reporter = options.progress.clone();
+        let report = |progress| {
+            if let Some(r) = &reporter {
+                r.report(progress);
+            }
+        };
+        let mut downloaded = uploaded.len();
+        // resumed images are not counted, their sizes are unknown
+        let mut downloaded_bytes = 0;
+        // of the response bodies, reported by the downloads as they go
+        let received = Arc::new(AtomicU64::new(0));
+        let pages = Arc::new(AtomicUsize::new(downloaded));
+        let on_chunk = DownloadProgress::new({
+            let (reporter, received, pages) = (reporter.clone(), received.clone(), pages.clone());
+            move |len| {
+                let received = received.fetch_add(len, Ordering::Relaxed) + len;
+                if let Some(r) = &reporter {
+                    r.report(SyncProgress::Downloading {
+                        page: pages.load(Ordering::Relaxed),
+                        total,
+                        received,
+                    });
+                }
+            }
+        });
+        let cancelled = || options.cancel.as_ref().is_some_and(|c| c.is_cancelled());
+
+        let skipped = options.skipped.clone();
+        let skip = |index: usize, meta: Option<&ImageMeta>, reason: String| {
+            tracing::error!("{reason}, discarded. Page: {index}, meta: {meta:?}");
+            if let Some(slot) = &skipped {
+                slot.push(index, meta, reason);
+            }
+        };
 
         let mut buffer = ImageBuffer::new();
+        // of the leading pages, recorded once the pages are created
+        let mut hashes = Vec::new();
+        // as downloaded, for the fingerprint of a new sync
+        let fresh = progress.images.is_empty() && progress.fingerprint.is_none();
+        let mut leading = Vec::new();
 
         // in this big loop, we will download images, and upload them in batch.
         // then, all meta info will be saved in `uploaded`.
@@ -164,9 +1138,45 @@ where
             // which does not require changes on consuming side.
 
             // 1. download images in batch
-            while let Some(fut) = stream.next() {
-                let data = match fut.await {
+            loop {
+                // the downloads are spawned by the stream here
+                let next = on_chunk.sync_scope(|| match stream.next() {
+                    Some(fut) => Some((fut, false)),
+                    None => std::iter::from_fn(|| failed.pop_front())
+                        .find_map(|pos| stream.retry(pos))
+                        .map(|fut| (fut, true)),
+                });
+                let Some((fut, retried)) = next else {
+                    break;
+                };
+                if cancelled() {
+                    return Err(UploadError::Cancelled);
+                }
+                let (index, data) = fut.await;
+                if !retried {
+                    downloaded += 1;
+                    pages.store(downloaded, Ordering::Relaxed);
+                    self.limits
+                        .check_pages(downloaded)
+                        .map_err(UploadError::TooLarge)?;
+                    report(SyncProgress::Downloading {
+                        page: downloaded,
+                        total,
+                        received: received.load(Ordering::Relaxed),
+                    });
+                }
+                let data = match data {
+                    // given up, but counted like the first attempts
+                    Err(e) if retried => {
+                        err_count += 1;
+                        if err_count > ERR_THRESHOLD {
+                            return Err(UploadError::Stream(e));
+                        }
+                        skip(index, None, format!("Unable to download: {e:?}"));
+                        continue;
+                    }
                     Err(e) => {
+                        failed.push_back(index);
                         err_count += 1;
                         if err_count > ERR_THRESHOLD {
                             return Err(UploadError::Stream(e));
@@ -175,17 +1185,25 @@ where
                     }
                     Ok(d) => {
                         err_count = 0;
+                        if fresh && leading.len() < FINGERPRINT_IMAGES {
+                            leading.push(d.1.clone());
+                        }
+                        self.metrics.record_download(d.1.len());
+                        downloaded_bytes += d.1.len() as u64;
+                        self.limits
+                            .check_bytes(downloaded_bytes)
+                            .map_err(UploadError::TooLarge)?;
                         d
                     }
                 };
 
-                // if the data size is too big to upload, we will discard it.
-                if data.1.len() >= MAX_SINGLE_FILE_SIZE {
-                    tracing::error!("Too big file, discarded. Meta: {:?}", data.0);
-                    continue;
+                let (meta, raw) = data;
+                for segment in self.prepare_image(raw).await {
+                    match segment {
+                        Ok(data) => buffer.push((index, meta.clone(), data)),
+                        Err(reason) => skip(index, Some(&meta), reason),
+                    }
                 }
-
-                buffer.push(data);
                 if buffer.len() > BATCH_LEN_THRESHOLD || buffer.size() > BATCH_SIZE_THRESHOLD {
                     break;
                 }
@@ -195,106 +1213,379 @@ where
             if buffer.is_empty() {
                 break;
             }
+            if cancelled() {
+                return Err(UploadError::Cancelled);
+            }
 
             // 2. upload the batch
             let (full_data, size) = buffer.swap();
             let image_count = full_data.len();
+
+            // fingerprint by the first batch of a new sync, and reuse the pages on hit
+            let first_batch = progress.images.is_empty() && progress.fingerprint.is_none();
+            if let Some(total) = total.filter(|_| first_batch) {
+                let images = leading.clone();
+                let fp = tokio::task::spawn_blocking(move || {
+                    fingerprint(total, images.iter().map(AsRef::as_ref))
+                })
+                .await;
+                match fp {
+                    Ok(fp) => {
+                        if !options.force {
+                            match DedupStore::new(&self.cache).get(&fp, &variant).await {
+                                Ok(Some(pages)) if !pages.is_empty() => {
+                                    tracing::info!("[dedup] hit {fp} for {}", meta.link);
+                                    return Ok(pages);
+                                }
+                                Ok(_) => (),
+                                Err(e) => tracing::warn!("[dedup] unable to get {fp}: {e}"),
+                            }
+                        }
+                        progress.fingerprint = Some(fp);
+                    }
+                    Err(e) => tracing::warn!("[dedup] unable to fingerprint {}: {e}", meta.link),
+                }
+            }
+            if let Some(near) = self.near_duplicate.filter(|_| first_batch) {
+                let sample = full_data
+                    .iter()
+                    .take(near.sample)
+                    .map(|(_, _, d)| d.clone())
+                    .collect::<Vec<_>>();
+                hashes = tokio::task::spawn_blocking(move || {
+                    sample.iter().filter_map(|d| phash::dhash(d)).collect()
+                })
+                .await
+                .unwrap_or_default();
+                if !options.force && !hashes.is_empty() {
+                    match self
+                        .near_dup
+                        .find(&self.cache, &hashes, near.threshold)
+                        .await
+                    {
+                        Ok(Some(pages)) if !pages.is_empty() => {
+                            tracing::info!(
+                                "[near dup] {} is similar to {}",
+                                meta.link,
+                                pages[0].url
+                            );
+                            if let Some(slot) = &options.duplicate {
+                                slot.set(&pages[0].url);
+                            }
+                            if near.action == NearDuplicateAction::Skip {
+                                return Ok(pages);
+                            }
+                        }
+                        Ok(_) => (),
+                        Err(e) => tracing::warn!("[near dup] unable to search: {e}"),
+                    }
+                }
+            }
             tracing::debug!("download {image_count} images with size {size}, will upload them",);
 
             let (meta, data) = full_data
                 .into_iter()
-                .map(|(a, b)| (a, b.as_ref().to_owned()))
+                .map(|(idx, a, b)| ((idx, a), b.as_ref().to_owned()))
                 .unzip::<_, _, Vec<_>, Vec<_>>();
+            report(SyncProgress::Uploading {
+                page: uploaded.len() + image_count,
+                total,
+            });
             let medium = self.tg.upload(data).await?;
+            self.metrics.record_upload(medium.len());
             err_count = 0;
 
             // 3. add to uploaded
             tracing::debug!("upload {image_count} images with size {size}, medium: {medium:?}");
-            uploaded.extend(
-                meta.into_iter()
-                    .zip(medium.into_iter().map(|x| x.src))
-                    .map(|(meta, src)| UploadedImage { meta, src }),
-            );
+            for ((index, meta), src) in meta.into_iter().zip(medium.into_iter().map(|x| x.src)) {
+                progress.images.push(CheckpointImage {
+                    index,
+                    id: meta.id.clone(),
+                    url: meta.url.clone(),
+                    description: meta.description.clone(),
+                    src: src.clone(),
+                });
+                uploaded.push((index, UploadedImage { meta, src }));
+            }
+            if let Some((store, key)) = &checkpoint {
+                if let Err(e) = store.save(key, &progress).await {
+                    tracing::warn!("[checkpoint] unable to save {key}: {e}");
+                }
+            }
         }
+        // stable, segments of an image share its index
+        uploaded.sort_by_key(|(index, _)| *index);
 
-        // create telegraph page, or multi pages
-        // Telegraph has 64K limit, since our estimate is not accurate, here we use 48K.
-        const PAGE_SIZE_LIMIT: usize = 48 * 1024;
-        let mut chunks = Vec::with_capacity(8);
-        chunks.push(Vec::new());
-        let mut last_chunk_size = 0;
-        for item in uploaded.into_iter().map(Into::<Node>::into) {
-            let item_size = item.estimate_size();
-            if last_chunk_size + item_size > PAGE_SIZE_LIMIT {
-                chunks.push(Vec::new());
-                last_chunk_size = 0;
-            }
-            last_chunk_size += item_size;
-            chunks.last_mut().unwrap().push(item);
-        }
-
-        let mut last_page: Option<Page> = None;
-        let title = meta.name.replace('|', "");
-        while let Some(last_chunk) = chunks.pop() {
-            let mut content = last_chunk;
-            write_footer(
-                &mut content,
-                meta.link.as_str(),
-                last_page.as_ref().map(|p| p.url.as_str()),
-            );
-            let title = match chunks.len() {
-                0 => title.clone(),
-                n => format!("{}-Page{}", title, n + 1),
-            };
-            tracing::debug!("create page with content: {content:?}");
-            let page = self
-                .tg
-                .create_page(&PageCreate {
-                    title,
-                    content,
-                    author_name: self
-                        .author_name
-                        .clone()
-                        .or_else(|| meta.authors.as_ref().map(|x| x.join(", "))),
-                    author_url: self.author_url.clone(),
-                })
-                .await
-                .map_err(UploadError::Reqwest)?;
+        let title = match &self.title_template {
+            Some(template) => template.render(&meta, uploaded.len()),
+            None => default_title(&meta),
+        };
+        if options.author_name.is_none() {
+            options.author_name = meta.authors.as_ref().map(|x| x.join(", "));
+        }
+        let header = match options.include_metadata.unwrap_or(false) {
+            // the stream holds only the selected pages
+            true => metadata_header(&meta, options.pages.as_ref().and(total)),
+            false => Vec::new(),
+        };
+        let nodes = with_cover(header, uploaded.into_iter().map(|(_, i)| Node::from(i)));
+        let mut footer = attribution(&meta.link);
+        if options.include_footer.unwrap_or(false) {
+            footer.extend(page_footer(options.footer_text.as_deref(), Utc::now()));
+        }
+        let pages = create_pages(&self.tg.pinned(), &title, &options, &footer, nodes)
+            .await
+            .map_err(UploadError::Reqwest)?;
 
-            last_page = Some(page);
+        if let Some(fp) = &progress.fingerprint {
+            let ttl = Some(self.cache_ttl.unwrap_or(Self::DEFAULT_CACHE_TTL));
+            let store = DedupStore::new(&self.cache);
+            if let Err(e) = store.set(fp, &variant, &pages, ttl).await {
+                tracing::warn!("[dedup] unable to set {fp}: {e}");
+            }
         }
-        Ok(last_page.unwrap())
+        if !hashes.is_empty() {
+            if let Err(e) = self.near_dup.add(&self.cache, hashes, &pages).await {
+                tracing::warn!("[near dup] unable to add {}: {e}", meta.link);
+            }
+        }
+        Ok(pages)
     }
 }
 
-fn write_footer(content: &mut Vec<Node>, original_link: &str, next_page: Option<&str>) {
-    if let Some(page) = next_page {
-        content.push(np!(na!(@page, nt!("Next Page"))));
+/// Tells apart the pages of the same content created with `options`, by what of them
+/// ends up in the pages.
+fn dedup_variant(options: &UploadOptions) -> String {
+    let rendered = serde_json::json!([
+        options.author_name,
+        options.author_url,
+        options.pages.as_ref().map(ToString::to_string),
+        options.include_metadata,
+        options.include_footer,
+        options.footer_text,
+    ]);
+    let digest = Sha256::digest(rendered.to_string());
+    digest[..8].iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// The first image, then `header`, then the other images. Link previews take the
+/// first image of the page as the thumbnail and its leading text as the description,
+/// so they show the cover and the metadata.
+fn with_cover(header: Vec<Node>, images: impl IntoIterator<Item = Node>) -> Vec<Node> {
+    let mut images = images.into_iter();
+    let mut nodes = Vec::with_capacity(images.size_hint().0 + header.len());
+    nodes.extend(images.next());
+    nodes.extend(header);
+    nodes.extend(images);
+    nodes
+}
+
+/// Split nodes into chunks which fit in one Telegraph page.
+/// There is always at least one chunk.
+fn split_pages(nodes: Vec<Node>) -> Vec<Vec<Node>> {
+    let mut chunks = Vec::with_capacity(8);
+    chunks.push(Vec::new());
+    let mut last_chunk_size = 0;
+    for item in nodes {
+        let item_size = item.estimate_size();
+        let last_chunk_len = chunks.last().map(Vec::len).unwrap_or_default();
+        if last_chunk_len > 0
+            && (last_chunk_size + item_size > PAGE_SIZE_LIMIT || last_chunk_len >= PAGE_NODE_LIMIT)
+        {
+            chunks.push(Vec::new());
+            last_chunk_size = 0;
+        }
+        last_chunk_size += item_size;
+        chunks.last_mut().unwrap().push(item);
     }
-    content.push(np!(
-        nt!("Generated by "),
-        na!(@"https://github.com/qini7-sese/eh2telegraph", nt!("eh2telegraph"))
-    ));
-    content.push(np!(
-        nt!("Original link: "),
-        na!(@original_link, nt!(original_link))
-    ));
+    chunks
 }
 
-impl Synchronizer {
-    pub fn match_url_from_text(content: &str) -> Option<&str> {
-        match_first_group(&URL_FROM_TEXT_RE, content)
+/// Create one page per chunk and link them with "Previous part" / "Next part".
+/// Pages are created in order knowing the previous one, then edited to add the next one,
+/// so `tg` must use a single token. `footer` is not counted by the split, it is only a
+/// few nodes.
+async fn create_pages<T: AccessToken, C: HttpRequestBuilder>(
+    tg: &Telegraph<T, C>,
+    title: &str,
+    options: &UploadOptions,
+    footer: &[Node],
+    nodes: Vec<Node>,
+) -> Result<Vec<Page>, TelegraphError> {
+    let chunks = split_pages(nodes);
+    let part_title = |idx: usize| match idx {
+        0 => truncate_title(title, TITLE_LENGTH_MAX),
+        n => {
+            let suffix = format!("-Page{}", n + 1);
+            let title = truncate_title(title, TITLE_LENGTH_MAX - suffix.len());
+            format!("{title}{suffix}")
+        }
+    };
+
+    let mut pages: Vec<Page> = Vec::with_capacity(chunks.len());
+    for (idx, chunk) in chunks.iter().enumerate() {
+        let prev = pages.last().map(|p| p.url.as_str());
+        let content = page_content(chunk, footer, prev, None);
+        tracing::debug!("create page with content: {content:?}");
+        let page = tg
+            .create_page(&PageCreate {
+                title: part_title(idx),
+                content,
+                author_name: options.author_name.clone(),
+                author_url: options.author_url.clone(),
+            })
+            .await?;
+        pages.push(page);
+    }
+
+    for idx in 0..pages.len().saturating_sub(1) {
+        let prev = idx.checked_sub(1).map(|i| pages[i].url.as_str());
+        let next = Some(pages[idx + 1].url.as_str());
+        tg.edit_page(&PageEdit {
+            title: part_title(idx),
+            path: pages[idx].path.clone(),
+            content: page_content(&chunks[idx], footer, prev, next),
+            author_name: options.author_name.clone(),
+            author_url: options.author_url.clone(),
+        })
+        .await?;
+    }
+    Ok(pages)
+}
+
+/// The links to the other parts are put around the nodes, only below them on the first
+/// part so it still starts with the cover for link previews.
+fn page_content(
+    nodes: &[Node],
+    footer: &[Node],
+    prev: Option<&str>,
+    next: Option<&str>,
+) -> Vec<Node> {
+    let links = part_links(prev, next);
+    let mut content = Vec::with_capacity(nodes.len() + footer.len() + 2);
+    content.extend(links.clone().filter(|_| prev.is_some()));
+    content.extend_from_slice(nodes);
+    content.extend(links);
+    content.extend_from_slice(footer);
+    content
+}
+
+fn part_links(prev: Option<&str>, next: Option<&str>) -> Option<Node> {
+    match (prev, next) {
+        (Some(prev), Some(next)) => Some(np!(
+            na!(@prev, nt!("Previous part")),
+            nt!(" | "),
+            na!(@next, nt!("Next part"))
+        )),
+        (Some(prev), None) => Some(np!(na!(@prev, nt!("Previous part")))),
+        (None, Some(next)) => Some(np!(na!(@next, nt!("Next part")))),
+        (None, None) => None,
+    }
+}
+
+fn element(tag: Tag, children: Vec<Node>) -> Node {
+    Node::NodeElement(NodeElement {
+        tag,
+        attrs: None,
+        children: Some(children),
+    })
+}
+
+/// `<li><b>{name}: </b>{value}</li>`
+fn field_item(name: &str, value: String) -> Node {
+    element(
+        Tag::Li,
+        vec![element(Tag::B, vec![nt!(format!("{name}: "))]), nt!(value)],
+    )
+}
+
+/// Metadata of the gallery, with tags listed by namespace, and `selected` pages if only
+/// a part of it is uploaded. Empty if there is nothing to show.
+fn metadata_header(meta: &AlbumMeta, selected: Option<usize>) -> Vec<Node> {
+    let mut fields = Vec::new();
+    if let Some(class) = &meta.class {
+        fields.push(field_item("Category", class.clone()));
+    }
+    if let Some(authors) = meta.authors.as_ref().filter(|a| !a.is_empty()) {
+        fields.push(field_item("Artist", authors.join(", ")));
+    }
+    if let Some(language) = &meta.language {
+        fields.push(field_item("Language", language.clone()));
+    }
+    let pages = match (selected, meta.page_count) {
+        (Some(selected), Some(count)) if selected != count => {
+            Some(format!("{selected} of {count}"))
+        }
+        (selected, count) => selected.or(count).map(|n| n.to_string()),
+    };
+    if let Some(pages) = pages {
+        fields.push(field_item("Pages", pages));
+    }
+
+    // namespaces in the order of appearance
+    let mut namespaces: Vec<(&str, Vec<&str>)> = Vec::new();
+    for tag in meta.tags.iter().flatten() {
+        let (namespace, name) = tag.split_once(':').unwrap_or(("misc", tag));
+        match namespaces.iter_mut().find(|(ns, _)| *ns == namespace) {
+            Some((_, names)) => names.push(name),
+            None => namespaces.push((namespace, vec![name])),
+        }
     }
+    let tags = namespaces
+        .into_iter()
+        .map(|(ns, names)| field_item(ns, names.join(", ")))
+        .collect::<Vec<_>>();
 
-    pub fn match_url_from_url(content: &str) -> Option<&str> {
-        match_first_group(&URL_FROM_URL_RE, content)
+    let mut header = Vec::new();
+    if let Some(name) = &meta.japanese_name {
+        header.push(element(Tag::H4, vec![nt!(name.clone())]));
     }
+    if !fields.is_empty() {
+        header.push(element(Tag::Ul, fields));
+    }
+    if !tags.is_empty() {
+        header.push(np!(element(Tag::B, vec![nt!("Tags")])));
+        header.push(element(Tag::Ul, tags));
+    }
+    if !header.is_empty() {
+        header.push(Node::NodeElement(NodeElement {
+            tag: Tag::Hr,
+            attrs: None,
+            children: None,
+        }));
+    }
+    header
 }
 
-impl DataSized for (ImageMeta, ImageData) {
+/// The end of every page.
+fn attribution(original_link: &str) -> Vec<Node> {
+    vec![
+        np!(
+            nt!("Generated by "),
+            na!(@"https://github.com/qini7-sese/eh2telegraph", nt!("eh2telegraph"))
+        ),
+        np!(
+            nt!("Original link: "),
+            na!(@original_link, nt!(original_link))
+        ),
+    ]
+}
+
+/// After the attribution if enabled, see `UploadOptions::include_footer`.
+fn page_footer(text: Option<&str>, captured: DateTime<Utc>) -> Vec<Node> {
+    let mut footer = vec![np!(nt!(format!(
+        "Captured on {}",
+        captured.format("%Y-%m-%d %H:%M UTC")
+    )))];
+    footer.extend(text.map(|text| np!(nt!(text))));
+    footer
+}
+
+// with the position in the stream
+impl DataSized for (usize, ImageMeta, ImageData) {
     #[inline]
     fn size(&self) -> usize {
-        self.1.size()
+        self.2.size()
     }
 }
 
@@ -310,3 +1601,1468 @@ impl From<UploadedImage> for Node {
         Node::new_image(&i.src)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::{
+        collector::{e_hentai::EHCollector, exhentai::EXCollector, nhentai::NHCollector},
+        mock_server::{MockResponse, MockServer},
+        storage::SimpleMemStorage,
+    };
+    use std::sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    };
+
+    /// Images `range` of a gallery, counting the loaded ones.
+    struct TestStream {
+        range: std::ops::Range<usize>,
+        loaded: Arc<AtomicUsize>,
+    }
+
+    impl AsyncStream for TestStream {
+        type Item = anyhow::Result<(ImageMeta, ImageData)>;
+        type Future = impl std::future::Future<Output = Self::Item>;
+
+        fn next(&mut self) -> Option<Self::Future> {
+            let idx = self.range.next()?;
+            let loaded = self.loaded.clone();
+            Some(async move {
+                loaded.fetch_add(1, Ordering::SeqCst);
+                let meta = ImageMeta {
+                    id: idx.to_string(),
+                    url: format!("https://example.com/{idx}.jpg"),
+                    description: None,
+                };
+                Ok((meta, ImageData::from(fake_image(idx))))
+            })
+        }
+
+        fn size_hint(&self) -> (usize, Option<usize>) {
+            self.range.size_hint()
+        }
+    }
+
+    #[test]
+    fn test_upload_options_fallback() {
+        let defaults = UploadOptions {
+            author_name: Some("bot".to_string()),
+            author_url: Some("https://t.me/bot".to_string()),
+            ..Default::default()
+        };
+        let options = UploadOptions {
+            author_name: Some("user".to_string()),
+            author_url: None,
+            ..Default::default()
+        }
+        .or(&defaults);
+        assert_eq!(options.author_name.as_deref(), Some("user"));
+        assert_eq!(options.author_url.as_deref(), Some("https://t.me/bot"));
+
+        let options = UploadOptions::default().or(&UploadOptions::default());
+        assert!(options.author_name.is_none());
+        assert!(options.author_url.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_split_linked_pages() {
+        let server = MockServer::start(|idx, req| {
+            let target = req.header("x-forwarded-for").unwrap_or_default();
+            if target.ends_with("/createPage") {
+                let body = format!(
+                    r#"{{"ok":true,"result":{{"path":"part-{idx}","url":"https://telegra.ph/part-{idx}","title":"t","description":"","views":0}}}}"#
+                );
+                MockResponse::new(200, body)
+            } else {
+                let body = r#"{"ok":true,"result":{"path":"p","url":"https://telegra.ph/p","title":"t","description":"","views":0}}"#;
+                MockResponse::new(200, body)
+            }
+        })
+        .await;
+        let proxy = ProxiedClient::new(&server.url("/"), "token").unwrap();
+        let tg = Telegraph::<crate::telegraph::SingleAccessToken>::new("token".to_string())
+            .with_proxy(proxy);
+
+        let nodes = (0..250)
+            .map(|i| Node::new_image(format!("https://files.catbox.moe/{i}.jpg")))
+            .collect();
+        let options = UploadOptions::default();
+        let footer = attribution("https://e-hentai.org/g/1/x");
+        let pages = create_pages(&tg, "title", &options, &footer, nodes)
+            .await
+            .unwrap();
+        let urls = pages.iter().map(|p| p.url.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            urls,
+            [
+                "https://telegra.ph/part-0",
+                "https://telegra.ph/part-1",
+                "https://telegra.ph/part-2"
+            ]
+        );
+
+        // 3 pages created, then the first 2 edited to add the next part
+        let requests = server.requests();
+        assert_eq!(requests.len(), 5);
+        let forms = requests
+            .iter()
+            .map(|r| {
+                url::form_urlencoded::parse(&r.body)
+                    .into_owned()
+                    .collect::<HashMap<_, _>>()
+            })
+            .collect::<Vec<_>>();
+        let middle = &forms[4];
+        assert!(requests[4]
+            .header("x-forwarded-for")
+            .unwrap()
+            .ends_with("/editPage"));
+        assert_eq!(middle["path"], "part-1");
+        let content: Vec<Node> = serde_json::from_str(&middle["content"]).unwrap();
+        let images = content
+            .iter()
+            .filter(|n| matches!(n, Node::NodeElement(e) if matches!(e.tag, Tag::Img)))
+            .count();
+        assert_eq!(images, 100);
+        let content = &middle["content"];
+        assert!(content.contains("https://telegra.ph/part-0"));
+        assert!(content.contains("https://telegra.ph/part-2"));
+        assert!(content.contains("Previous part"));
+        assert!(content.contains("Next part"));
+
+        // the last part only links back
+        let last = &forms[2]["content"];
+        assert!(last.contains("https://telegra.ph/part-1"));
+        assert!(!last.contains("Next part"));
+    }
+
+    fn synchronizer(
+        server: &MockServer,
+        cache: SimpleMemStorage<String>,
+    ) -> Synchronizer<SimpleMemStorage<String>> {
+        let proxy = ProxiedClient::new(&server.url("/"), "token").unwrap();
+        let tg = Telegraph::<TokenPool>::new(vec!["token".to_string()]).with_proxy(proxy.clone());
+        let registry = Registry::default().with_proxy(proxy);
+        Synchronizer::new(tg, registry, cache)
+    }
+
+    fn album(link: &str) -> AlbumMeta {
+        AlbumMeta {
+            link: link.to_string(),
+            name: "title".to_string(),
+            japanese_name: None,
+            class: None,
+            description: None,
+            authors: None,
+            language: None,
+            tags: None,
+            page_count: None,
+        }
+    }
+
+    /// An `image-{n};` body, sniffed as JPEG so it is allowed.
+    fn fake_image(n: impl fmt::Display) -> Vec<u8> {
+        [b"\xFF\xD8\xFF".as_slice(), format!("image-{n};").as_bytes()].concat()
+    }
+
+    // answers uploads with `image-{n};` bodies, and page creations and edits
+    fn telegraph_response(idx: usize, req: &crate::mock_server::MockRequest) -> MockResponse {
+        let target = req.header("x-forwarded-for").unwrap_or_default();
+        if target.ends_with("/createPage") || target.ends_with("/editPage") {
+            let body = format!(
+                r#"{{"ok":true,"result":{{"path":"p-{idx}","url":"https://telegra.ph/p-{idx}","title":"t","description":"","views":0}}}}"#
+            );
+            return MockResponse::new(200, body);
+        }
+        let body = String::from_utf8_lossy(&req.body);
+        let n = body
+            .split("image-")
+            .nth(1)
+            .and_then(|s| s.split(';').next())
+            .unwrap()
+            .to_string();
+        MockResponse::new(200, format!("https://files.catbox.moe/{n}.jpg"))
+    }
+
+    #[tokio::test]
+    async fn test_resume_from_checkpoint() {
+        let fail_create = Arc::new(AtomicBool::new(true));
+        let fail = fail_create.clone();
+        let server = MockServer::start(move |idx, req| {
+            let target = req.header("x-forwarded-for").unwrap_or_default();
+            if target.ends_with("/createPage") && fail.load(Ordering::SeqCst) {
+                return MockResponse::new(200, r#"{"ok":false,"error":"INTERRUPTED"}"#);
+            }
+            telegraph_response(idx, req)
+        })
+        .await;
+        let cache = SimpleMemStorage::<String>::default();
+        let sync = synchronizer(&server, cache.clone());
+        let meta = || album("https://e-hentai.org/g/1/x");
+        let key = "eh|/g/1/x";
+
+        // the first run is interrupted after 5 of 10 images are uploaded
+        let loaded = Arc::new(AtomicUsize::new(0));
+        let stream = TestStream {
+            range: 0..5,
+            loaded: loaded.clone(),
+        };
+        let r = sync
+            .resume_sync_stream(Some(key), meta(), stream, UploadOptions::default())
+            .await;
+        assert!(r.is_err());
+        let progress = CheckpointStore::new(&cache).load(key).await.unwrap();
+        assert_eq!(progress.uploaded(), (0..5).collect());
+        let is_upload = |r: &crate::mock_server::MockRequest| {
+            !r.header("x-forwarded-for")
+                .unwrap_or_default()
+                .ends_with("/createPage")
+        };
+        let first_run = server.requests().len();
+        assert_eq!(server.requests().iter().filter(|r| is_upload(r)).count(), 5);
+
+        // the resumed run only loads and uploads the rest
+        fail_create.store(false, Ordering::SeqCst);
+        let loaded = Arc::new(AtomicUsize::new(0));
+        let stream = TestStream {
+            range: 0..10,
+            loaded: loaded.clone(),
+        };
+        let pages = sync
+            .resume_sync_stream(Some(key), meta(), stream, UploadOptions::default())
+            .await
+            .unwrap();
+        assert!(pages[0].url.starts_with("https://telegra.ph/p-"));
+        assert_eq!(loaded.load(Ordering::SeqCst), 5);
+        let requests = server.requests();
+        let uploads = requests[first_run..]
+            .iter()
+            .filter(|r| is_upload(r))
+            .count();
+        assert_eq!(uploads, 5);
+        let form = url::form_urlencoded::parse(&requests.last().unwrap().body)
+            .into_owned()
+            .collect::<HashMap<_, _>>();
+        let content: Vec<Node> = serde_json::from_str(&form["content"]).unwrap();
+        let srcs = content
+            .iter()
+            .filter_map(|n| match n {
+                Node::NodeElement(e) if matches!(e.tag, Tag::Img) => {
+                    e.attrs.as_ref().and_then(|a| a.src.clone())
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let expected = (0..10)
+            .map(|i| format!("https://files.catbox.moe/{i}.jpg"))
+            .collect::<Vec<_>>();
+        assert_eq!(srcs, expected);
+        // cleared on success
+        let progress = CheckpointStore::new(&cache).load(key).await.unwrap();
+        assert!(progress.images.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_cached() {
+        let server = MockServer::start(telegraph_response).await;
+        let sync = synchronizer(&server, SimpleMemStorage::default());
+        sync.cache()
+            .set("e-hentai|/g/1/x".to_string(), "a\nb".to_string(), None)
+            .await
+            .unwrap();
+        let urls = Some(vec!["a".to_string(), "b".to_string()]);
+        assert_eq!(
+            sync.cached::<EHCollector>("/g/1/x/", None).await.unwrap(),
+            urls
+        );
+        // exhentai shares the cache of e-hentai
+        assert_eq!(
+            sync.cached::<EXCollector>("/g/1/x", None).await.unwrap(),
+            urls
+        );
+        let pages = "1-3".parse().unwrap();
+        assert!(sync
+            .cached::<EHCollector>("/g/1/x", Some(&pages))
+            .await
+            .unwrap()
+            .is_none());
+        assert!(sync
+            .cached::<NHCollector>("/g/1/x", None)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_dedup_by_content() {
+        let server = MockServer::start(telegraph_response).await;
+        let sync = synchronizer(&server, SimpleMemStorage::default());
+        let stream = || TestStream {
+            range: 0..10,
+            loaded: Arc::new(AtomicUsize::new(0)),
+        };
+
+        let pages = sync
+            .sync_stream(
+                album("https://e-hentai.org/g/1/x"),
+                stream(),
+                Default::default(),
+            )
+            .await
+            .unwrap();
+        let synced = server.requests().len();
+        assert_eq!(synced, 11);
+
+        // the same images under another url
+        let again = sync
+            .sync_stream(
+                album("https://exhentai.org/g/2/y"),
+                stream(),
+                Default::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(again[0].url, pages[0].url);
+        assert_eq!(server.requests().len(), synced);
+
+        let options = UploadOptions {
+            force: true,
+            ..Default::default()
+        };
+        let forced = sync
+            .sync_stream(album("https://exhentai.org/g/2/y"), stream(), options)
+            .await
+            .unwrap();
+        assert_ne!(forced[0].url, pages[0].url);
+        assert_eq!(server.requests().len(), synced * 2);
+
+        // pages with another footer are not the same
+        let options = UploadOptions {
+            footer_text: Some("Archived by @bot".to_string()),
+            ..Default::default()
+        };
+        let other = sync
+            .sync_stream(album("https://exhentai.org/g/2/y"), stream(), options)
+            .await
+            .unwrap();
+        assert_ne!(other[0].url, forced[0].url);
+        assert_eq!(server.requests().len(), synced * 3);
+    }
+
+    #[tokio::test]
+    async fn test_cached_content() {
+        let server = MockServer::start(|idx, req| {
+            let target = req.header("x-forwarded-for").unwrap_or_default();
+            // galleries 1 and 2 are the same images, 3 is another
+            let media = match target.strip_prefix("https://nhentai.net/api/gallery/") {
+                Some("3") => "555",
+                Some(_) => "987",
+                None => "",
+            };
+            if !media.is_empty() {
+                let body = format!(
+                    r#"{{"media_id": "{media}", "title": {{"pretty": "Title"}}, "images": {{"pages": [{{"t": "j"}}, {{"t": "j"}}]}}}}"#
+                );
+                return MockResponse::new(200, body);
+            }
+            match target.split_once("/galleries/") {
+                Some((_, file)) => {
+                    let page = file.trim_end_matches(".jpg").replace('/', "-");
+                    MockResponse::new(200, fake_image(page))
+                }
+                None => telegraph_response(idx, req),
+            }
+        })
+        .await;
+        let proxy = ProxiedClient::new(&server.url("/"), "token").unwrap();
+        let tg = Telegraph::<TokenPool>::new(vec!["token".to_string()]).with_proxy(proxy.clone());
+        let registry = Registry::default().with_proxy(proxy);
+        let sync = Synchronizer::new(tg, registry, SimpleMemStorage::default());
+
+        let links = sync.sync::<NHCollector>("/g/1".to_string()).await.unwrap();
+        assert!(sync
+            .cached::<NHCollector>("/g/2", None)
+            .await
+            .unwrap()
+            .is_none());
+        let cached = sync.cached_content::<NHCollector>("/g/2/", None).await;
+        assert_eq!(cached.unwrap(), Some(links));
+        let other = sync.cached_content::<NHCollector>("/g/3", None).await;
+        assert!(other.unwrap().is_none());
+    }
+
+    /// Images `range` whose first one fails, and is given by `retry`.
+    struct RetriedStream(std::ops::Range<usize>);
+
+    impl AsyncStream for RetriedStream {
+        type Item = anyhow::Result<(ImageMeta, ImageData)>;
+        type Future = std::future::Ready<Self::Item>;
+
+        fn next(&mut self) -> Option<Self::Future> {
+            match self.0.next()? {
+                0 => Some(std::future::ready(Err(anyhow::anyhow!("unavailable")))),
+                idx => self.retry(idx),
+            }
+        }
+
+        fn size_hint(&self) -> (usize, Option<usize>) {
+            self.0.size_hint()
+        }
+
+        fn retry(&mut self, pos: usize) -> Option<Self::Future> {
+            let meta = ImageMeta {
+                id: pos.to_string(),
+                url: format!("https://example.com/{pos}.jpg"),
+                description: None,
+            };
+            Some(std::future::ready(Ok((
+                meta,
+                ImageData::from(fake_image(pos)),
+            ))))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry_failed() {
+        let server = MockServer::start(telegraph_response).await;
+        let sync = synchronizer(&server, SimpleMemStorage::default());
+        sync.sync_stream(
+            album("https://e-hentai.org/g/1/x"),
+            RetriedStream(0..3),
+            Default::default(),
+        )
+        .await
+        .unwrap();
+        let requests = server.requests();
+        let create = requests.last().unwrap();
+        assert!(create
+            .header("x-forwarded-for")
+            .unwrap()
+            .ends_with("/createPage"));
+        // the retried image keeps its place
+        let content = url::form_urlencoded::parse(&create.body)
+            .into_owned()
+            .collect::<HashMap<_, _>>()
+            .remove("content")
+            .unwrap();
+        let position = |n: usize| content.find(&format!("catbox.moe/{n}.jpg")).unwrap();
+        assert!(position(0) < position(1) && position(1) < position(2));
+    }
+
+    /// Like `RetriedStream`, but the retry of the first image fails too.
+    struct UnavailableStream(RetriedStream);
+
+    impl AsyncStream for UnavailableStream {
+        type Item = anyhow::Result<(ImageMeta, ImageData)>;
+        type Future = std::future::Ready<Self::Item>;
+
+        fn next(&mut self) -> Option<Self::Future> {
+            self.0.next()
+        }
+
+        fn size_hint(&self) -> (usize, Option<usize>) {
+            self.0.size_hint()
+        }
+
+        fn retry(&mut self, pos: usize) -> Option<Self::Future> {
+            match pos {
+                0 => Some(std::future::ready(Err(anyhow::anyhow!(
+                    "still unavailable"
+                )))),
+                pos => self.0.retry(pos),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry_failed_skipped() {
+        let server = MockServer::start(telegraph_response).await;
+        let sync = synchronizer(&server, SimpleMemStorage::default());
+        let skipped = SkippedSlot::default();
+        let options = UploadOptions {
+            skipped: Some(skipped.clone()),
+            ..Default::default()
+        };
+        sync.sync_stream(
+            album("https://e-hentai.org/g/1/x"),
+            UnavailableStream(RetriedStream(0..3)),
+            options,
+        )
+        .await
+        .unwrap();
+        let skipped = skipped.get();
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(skipped[0].index, 0);
+        assert_eq!(skipped[0].url, None);
+        assert!(skipped[0].reason.contains("still unavailable"));
+    }
+
+    /// Images `range` whose even ones always fail.
+    struct FlakyStream(std::ops::Range<usize>);
+
+    impl AsyncStream for FlakyStream {
+        type Item = anyhow::Result<(ImageMeta, ImageData)>;
+        type Future = std::future::Ready<Self::Item>;
+
+        fn next(&mut self) -> Option<Self::Future> {
+            let pos = self.0.next()?;
+            self.retry(pos)
+        }
+
+        fn size_hint(&self) -> (usize, Option<usize>) {
+            self.0.size_hint()
+        }
+
+        fn retry(&mut self, pos: usize) -> Option<Self::Future> {
+            match pos % 2 {
+                0 => Some(std::future::ready(Err(anyhow::anyhow!("unavailable")))),
+                _ => RetriedStream(0..0).retry(pos),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry_failed_threshold() {
+        let server = MockServer::start(telegraph_response).await;
+        let sync = synchronizer(&server, SimpleMemStorage::default());
+        // the first attempts never fail in a row, the retries do
+        let stream = FlakyStream(0..2 * (ERR_THRESHOLD + 1));
+        let result = sync
+            .sync_stream(
+                album("https://e-hentai.org/g/1/x"),
+                stream,
+                Default::default(),
+            )
+            .await;
+        assert!(matches!(result, Err(UploadError::Stream(_))));
+    }
+
+    /// Given images of a gallery.
+    struct ImageStream(std::vec::IntoIter<ImageData>);
+
+    impl AsyncStream for ImageStream {
+        type Item = anyhow::Result<(ImageMeta, ImageData)>;
+        type Future = std::future::Ready<Self::Item>;
+
+        fn next(&mut self) -> Option<Self::Future> {
+            let data = self.0.next()?;
+            let meta = ImageMeta {
+                id: data.len().to_string(),
+                url: format!("https://example.com/{}.jpg", data.len()),
+                description: None,
+            };
+            Some(std::future::ready(Ok((meta, data))))
+        }
+
+        fn size_hint(&self) -> (usize, Option<usize>) {
+            self.0.size_hint()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_allowlist() {
+        // the transcoded bmp has no `image-{n};` body
+        let server =
+            MockServer::start(
+                |idx, req| match req.header("x-forwarded-for").unwrap_or_default() {
+                    t if t.ends_with("/createPage") => telegraph_response(idx, req),
+                    _ => MockResponse::new(200, format!("https://files.catbox.moe/{idx}.jpg")),
+                },
+            )
+            .await;
+        let sync = synchronizer(&server, SimpleMemStorage::default());
+        let bmp = {
+            let img = image::RgbImage::from_pixel(8, 4, image::Rgb([200, 0, 0]));
+            let mut out = std::io::Cursor::new(Vec::new());
+            img.write_to(&mut out, image::ImageFormat::Bmp).unwrap();
+            out.into_inner()
+        };
+        let stream = || {
+            ImageStream(
+                vec![
+                    ImageData::from(fake_image(1)),
+                    // an error page served with 200
+                    ImageData::from("<html><body>509 bandwidth exceeded</body></html>"),
+                    ImageData::from(fake_image(3)),
+                    ImageData::from(bmp.clone()),
+                    // can not be decoded for transcoding
+                    ImageData::from_static(b"\x00\x00\x00\x1cftypavif\x00\x00\x00\x00"),
+                ]
+                .into_iter(),
+            )
+        };
+        let skipped = SkippedSlot::default();
+        let options = UploadOptions {
+            skipped: Some(skipped.clone()),
+            ..Default::default()
+        };
+        sync.sync_stream(album("https://e-hentai.org/g/1/x"), stream(), options)
+            .await
+            .unwrap();
+        let reasons = skipped
+            .get()
+            .into_iter()
+            .map(|p| p.reason)
+            .collect::<Vec<_>>();
+        assert_eq!(reasons.len(), 2);
+        assert_eq!(reasons[0], "Image type not allowed(not an image)");
+        // rejected up front without a decoder, otherwise it fails to decode
+        match cfg!(feature = "avif") {
+            true => assert!(reasons[1].starts_with("Re-encode failed: ")),
+            false => assert_eq!(reasons[1], "Image type not allowed(image/avif)"),
+        }
+        let uploads = server
+            .requests()
+            .into_iter()
+            .filter(|r| {
+                !r.header("x-forwarded-for")
+                    .unwrap()
+                    .ends_with("/createPage")
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(uploads.len(), 3);
+        let html = b"<html>".as_slice();
+        assert!(uploads
+            .iter()
+            .all(|r| !r.body.windows(6).any(|w| w == html)));
+
+        // not transcoded if JPEG is not allowed either
+        let server = MockServer::start(telegraph_response).await;
+        let allowlist = TypeAllowlist::from_mimes(&["image/png"]).unwrap();
+        let sync = synchronizer(&server, SimpleMemStorage::default()).with_allowlist(allowlist);
+        sync.sync_stream(
+            album("https://e-hentai.org/g/1/x"),
+            stream(),
+            Default::default(),
+        )
+        .await
+        .unwrap();
+        assert!(server.requests().iter().all(|r| r
+            .header("x-forwarded-for")
+            .unwrap()
+            .ends_with("/createPage")));
+    }
+
+    #[tokio::test]
+    async fn test_near_duplicate() {
+        use image::{DynamicImage, ImageFormat, RgbImage};
+
+        let encode = |seed: u32, format: ImageFormat| {
+            let img = RgbImage::from_fn(90, 120, |x, y| {
+                let v = ((x * (seed + 1) + y * 3) % 256) as u8;
+                image::Rgb([v, v / 2, 255 - v])
+            });
+            let mut out = std::io::Cursor::new(Vec::new());
+            DynamicImage::ImageRgb8(img)
+                .write_to(&mut out, format)
+                .unwrap();
+            ImageData::from(out.into_inner())
+        };
+        let gallery = |format, pages| {
+            ImageStream(
+                (1..=pages)
+                    .map(|s| encode(s, format))
+                    .collect::<Vec<_>>()
+                    .into_iter(),
+            )
+        };
+
+        // the images have no `image-{n};` bodies
+        let server =
+            MockServer::start(
+                |idx, req| match req.header("x-forwarded-for").unwrap_or_default() {
+                    t if t.ends_with("/createPage") => telegraph_response(idx, req),
+                    _ => MockResponse::new(200, format!("https://files.catbox.moe/{idx}.jpg")),
+                },
+            )
+            .await;
+        let near = NearDuplicate {
+            action: NearDuplicateAction::Skip,
+            ..Default::default()
+        };
+        let sync = synchronizer(&server, SimpleMemStorage::default())
+            .with_reencoder(None)
+            .with_near_duplicate(Some(near));
+        let pages = sync
+            .sync_stream(
+                album("https://e-hentai.org/g/1/x"),
+                gallery(ImageFormat::Png, 4),
+                Default::default(),
+            )
+            .await
+            .unwrap();
+        let synced = server.requests().len();
+
+        // re-encoded with a page added, so not the same content
+        let slot = DuplicateSlot::default();
+        let options = UploadOptions {
+            duplicate: Some(slot.clone()),
+            ..Default::default()
+        };
+        let again = sync
+            .sync_stream(
+                album("https://nhentai.net/g/2"),
+                gallery(ImageFormat::Jpeg, 5),
+                options,
+            )
+            .await
+            .unwrap();
+        assert_eq!(again[0].url, pages[0].url);
+        assert_eq!(slot.get(), Some(pages[0].url.as_str()));
+        assert_eq!(server.requests().len(), synced);
+
+        // warned only
+        let near = NearDuplicate {
+            action: NearDuplicateAction::Warn,
+            ..near
+        };
+        let sync = sync.with_near_duplicate(Some(near));
+        let slot = DuplicateSlot::default();
+        let options = UploadOptions {
+            duplicate: Some(slot.clone()),
+            ..Default::default()
+        };
+        let warned = sync
+            .sync_stream(
+                album("https://nhentai.net/g/3"),
+                gallery(ImageFormat::Jpeg, 5),
+                options,
+            )
+            .await
+            .unwrap();
+        assert_ne!(warned[0].url, pages[0].url);
+        assert!(slot.get().is_some());
+        assert!(server.requests().len() > synced);
+    }
+
+    #[tokio::test]
+    async fn test_progress() {
+        let server = MockServer::start(telegraph_response).await;
+        let sync = synchronizer(&server, SimpleMemStorage::default());
+        let (reporter, mut rx) = ProgressReporter::channel();
+        let options = UploadOptions {
+            progress: Some(reporter),
+            ..Default::default()
+        };
+        // 30 images are uploaded in 2 batches
+        let stream = TestStream {
+            range: 0..30,
+            loaded: Arc::new(AtomicUsize::new(0)),
+        };
+        let watcher = tokio::spawn(async move {
+            let mut seen = Vec::new();
+            while rx.changed().await.is_ok() {
+                seen.extend(*rx.borrow_and_update());
+            }
+            seen
+        });
+        sync.sync_stream(album("https://e-hentai.org/g/1/x"), stream, options)
+            .await
+            .unwrap();
+        // the watcher may skip some, but ends with the last upload
+        let seen = watcher.await.unwrap();
+        let total = Some(30);
+        assert!(seen.iter().all(|p| match p {
+            SyncProgress::Downloading { page, total: t, .. }
+            | SyncProgress::Uploading { page, total: t } => *t == total && (1..=30).contains(page),
+        }));
+        assert_eq!(
+            seen.last(),
+            Some(&SyncProgress::Uploading { page: 30, total })
+        );
+        assert_eq!(seen.last().unwrap().to_string(), "Uploading page 30/30");
+        let downloading = SyncProgress::Downloading {
+            page: 3,
+            total: None,
+            received: 0,
+        };
+        assert_eq!(downloading.to_string(), "Downloading page 3");
+        let downloading = SyncProgress::Downloading {
+            page: 3,
+            total: Some(30),
+            received: 3 << 19,
+        };
+        assert_eq!(
+            downloading.to_string(),
+            "Downloading page 3/30, 1.5 MiB received"
+        );
+    }
+
+    /// Images downloaded from `base` like the ones of the collectors.
+    struct RemoteStream {
+        base: String,
+        range: std::ops::Range<usize>,
+    }
+
+    impl AsyncStream for RemoteStream {
+        type Item = anyhow::Result<(ImageMeta, ImageData)>;
+        type Future = impl std::future::Future<Output = Self::Item>;
+
+        fn next(&mut self) -> Option<Self::Future> {
+            let idx = self.range.next()?;
+            let url = format!("{}/{idx}.jpg", self.base);
+            Some(async move {
+                let data = crate::util::get_bytes(&ProxiedClient::default(), &url).await?;
+                let meta = ImageMeta {
+                    id: idx.to_string(),
+                    url,
+                    description: None,
+                };
+                Ok((meta, data))
+            })
+        }
+
+        fn size_hint(&self) -> (usize, Option<usize>) {
+            self.range.size_hint()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_download_progress() {
+        let images = MockServer::start(|_, req| {
+            let idx = req.path.trim_start_matches('/').trim_end_matches(".jpg");
+            let body = [fake_image(idx), vec![0; 16 * 1024]].concat();
+            MockResponse::new(200, body).chunked(1024, std::time::Duration::from_millis(2))
+        })
+        .await;
+        let server = MockServer::start(telegraph_response).await;
+        let sync = synchronizer(&server, SimpleMemStorage::default()).with_concurrent_limit(1);
+        let (reporter, mut rx) = ProgressReporter::channel();
+        let options = UploadOptions {
+            progress: Some(reporter),
+            ..Default::default()
+        };
+        let watcher = tokio::spawn(async move {
+            let mut seen = Vec::new();
+            while rx.changed().await.is_ok() {
+                seen.extend(*rx.borrow_and_update());
+            }
+            seen
+        });
+        let stream = RemoteStream {
+            base: images.url(""),
+            range: 0..2,
+        };
+        sync.sync_stream(album("https://e-hentai.org/g/1/x"), stream, options)
+            .await
+            .unwrap();
+        // reported while the first page is still downloading
+        let received = watcher
+            .await
+            .unwrap()
+            .into_iter()
+            .filter_map(|p| match p {
+                SyncProgress::Downloading { page, received, .. } => Some((page, received)),
+                SyncProgress::Uploading { .. } => None,
+            })
+            .collect::<Vec<_>>();
+        let image = (fake_image(0).len() + 16 * 1024) as u64;
+        assert!(received
+            .iter()
+            .any(|&(page, r)| page == 0 && r > 0 && r < image));
+        assert!(received.windows(2).all(|w| w[0].1 <= w[1].1));
+        assert_eq!(received.last(), Some(&(2, 2 * image)));
+    }
+
+    /// Images taking 10ms each to load, counting the loaded ones.
+    struct SlowStream {
+        range: std::ops::Range<usize>,
+        loaded: Arc<AtomicUsize>,
+    }
+
+    impl AsyncStream for SlowStream {
+        type Item = anyhow::Result<(ImageMeta, ImageData)>;
+        type Future = impl std::future::Future<Output = Self::Item>;
+
+        fn next(&mut self) -> Option<Self::Future> {
+            let idx = self.range.next()?;
+            let loaded = self.loaded.clone();
+            Some(async move {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+                loaded.fetch_add(1, Ordering::SeqCst);
+                let meta = ImageMeta {
+                    id: idx.to_string(),
+                    url: format!("https://example.com/{idx}.jpg"),
+                    description: None,
+                };
+                Ok((meta, ImageData::from(fake_image(idx))))
+            })
+        }
+
+        fn size_hint(&self) -> (usize, Option<usize>) {
+            self.range.size_hint()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_limits() {
+        let server = MockServer::start(telegraph_response).await;
+        let limits = SyncLimits {
+            max_pages: Some(10),
+            max_total_bytes: Some(20),
+        };
+        let sync = synchronizer(&server, SimpleMemStorage::default())
+            .with_concurrent_limit(2)
+            .with_limits(limits);
+
+        // rejected before downloading any page
+        let loaded = Arc::new(AtomicUsize::new(0));
+        let stream = TestStream {
+            range: 0..30,
+            loaded: loaded.clone(),
+        };
+        let r = sync
+            .sync_stream(
+                album("https://e-hentai.org/g/1/x"),
+                stream,
+                Default::default(),
+            )
+            .await;
+        assert!(matches!(
+            r,
+            Err(UploadError::TooLarge(SizeLimit::Pages(10)))
+        ));
+        assert_eq!(loaded.load(Ordering::SeqCst), 0);
+
+        // 8 bytes each, stopped at the third one
+        let stream = TestStream {
+            range: 0..5,
+            loaded: loaded.clone(),
+        };
+        let r = sync
+            .sync_stream(
+                album("https://e-hentai.org/g/2/x"),
+                stream,
+                Default::default(),
+            )
+            .await;
+        assert!(matches!(
+            r,
+            Err(UploadError::TooLarge(SizeLimit::Bytes(20)))
+        ));
+        assert!(loaded.load(Ordering::SeqCst) < 5);
+        assert!(server.requests().is_empty());
+        assert_eq!(
+            SyncError::TooLarge(SizeLimit::Bytes(20)).to_string(),
+            "gallery exceeds the limit of 20 bytes"
+        );
+        // printed once when chained
+        let e = anyhow::Error::from(UploadError::<anyhow::Error>::TooLarge(SizeLimit::Pages(10)));
+        assert_eq!(format!("{e:#}"), "gallery exceeds the limit of 10 pages");
+    }
+
+    #[tokio::test]
+    async fn test_cancel() {
+        let server = MockServer::start(telegraph_response).await;
+        let cache = SimpleMemStorage::<String>::default();
+        let sync = synchronizer(&server, cache.clone()).with_concurrent_limit(2);
+        let cancel = CancellationToken::new();
+        let options = UploadOptions {
+            cancel: Some(cancel.clone()),
+            ..Default::default()
+        };
+        let loaded = Arc::new(AtomicUsize::new(0));
+        let stream = SlowStream {
+            range: 0..1000,
+            loaded: loaded.clone(),
+        };
+        let key = "eh|/g/1/x";
+        tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+            cancel.cancel();
+        });
+
+        let started = std::time::Instant::now();
+        let r = sync
+            .resume_sync_stream(
+                Some(key),
+                album("https://e-hentai.org/g/1/x"),
+                stream,
+                options,
+            )
+            .await;
+        assert!(matches!(r, Err(UploadError::Cancelled)));
+        assert!(started.elapsed() < std::time::Duration::from_secs(2));
+
+        // downloads in flight are stopped too
+        let stopped_at = loaded.load(Ordering::SeqCst);
+        assert!(stopped_at < 1000);
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert_eq!(loaded.load(Ordering::SeqCst), stopped_at);
+
+        // no page is created, and the uploaded images are kept for resume
+        let requests = server.requests();
+        assert!(requests.iter().all(|r| !r
+            .header("x-forwarded-for")
+            .unwrap_or_default()
+            .ends_with("/createPage")));
+        let progress = CheckpointStore::new(&cache).load(key).await.unwrap();
+        assert_eq!(progress.images.len(), requests.len());
+    }
+
+    #[tokio::test]
+    async fn test_metadata_header() {
+        let html = include_str!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/fixtures/e_hentai_gallery.html"
+        ));
+        let meta = crate::collector::e_hentai::parse_gallery_meta(
+            html,
+            "https://e-hentai.org/g/1/x".to_string(),
+            String::new,
+        );
+        let header = metadata_header(&meta, None);
+        let text = |n: &Node| serde_json::to_string(n).unwrap();
+        assert_eq!(header.len(), 5);
+        assert!(matches!(&header[0], Node::NodeElement(e) if matches!(e.tag, Tag::H4)));
+        assert!(text(&header[0]).contains("サンプル画廊"));
+        let items = |n: &Node| match n {
+            Node::NodeElement(e) if matches!(e.tag, Tag::Ul) => e.children.clone().unwrap(),
+            _ => panic!("list expected"),
+        };
+        let fields = items(&header[1]);
+        assert_eq!(fields.len(), 4);
+        assert_eq!(
+            text(&fields[0]),
+            r#"{"tag":"Li","children":[{"tag":"B","children":["Category: "]},"Doujinshi"]}"#
+        );
+        assert!(text(&fields[1]).contains("artist name"));
+        assert!(text(&fields[2]).contains("English"));
+        assert!(text(&fields[3]).contains("24"));
+        assert!(text(&header[2]).contains("Tags"));
+        let tags = items(&header[3]);
+        assert_eq!(tags.len(), 6);
+        assert!(text(&tags[0]).contains(r#""language: "]},"english, translated""#));
+        assert!(text(&tags[4]).contains(r#""female: "]},"glasses, twintails""#));
+        assert!(matches!(&header[4], Node::NodeElement(e) if matches!(e.tag, Tag::Hr)));
+        assert!(metadata_header(&album("https://e-hentai.org/g/1/x"), None).is_empty());
+        // the selected ones of the pages
+        let fields = items(&metadata_header(&meta, Some(5))[1]);
+        assert!(text(&fields[3]).contains(r#""Pages: "]},"5 of 24""#));
+        let fields = items(&metadata_header(&meta, Some(24))[1]);
+        assert!(text(&fields[3]).contains(r#""Pages: "]},"24""#));
+
+        // disabled unless enabled by options
+        let server = MockServer::start(telegraph_response).await;
+        let sync = synchronizer(&server, SimpleMemStorage::default());
+        let created = |with_header: Option<bool>, pages: Option<&'static str>| {
+            let meta = meta.clone();
+            let sync = &sync;
+            let server = &server;
+            async move {
+                let options = UploadOptions {
+                    include_metadata: with_header,
+                    pages: pages.map(|p| p.parse().unwrap()),
+                    force: true,
+                    ..Default::default()
+                };
+                let stream = TestStream {
+                    range: 0..2,
+                    loaded: Arc::new(AtomicUsize::new(0)),
+                };
+                sync.sync_stream(meta, stream, options).await.unwrap();
+                let requests = server.requests();
+                let content = url::form_urlencoded::parse(&requests.last().unwrap().body)
+                    .into_owned()
+                    .collect::<HashMap<_, _>>()
+                    .remove("content")
+                    .unwrap();
+                serde_json::from_str::<Vec<Node>>(&content).unwrap()
+            }
+        };
+        let tag = |n: &Node| match n {
+            Node::NodeElement(e) => format!("{:?}", e.tag),
+            Node::Text(_) => "text".to_string(),
+        };
+        // the cover goes first for link previews, followed by the header
+        let nodes = created(Some(true), None).await;
+        assert!(text(&nodes[0]).contains(r#""src":"https://files.catbox.moe/0.jpg""#));
+        assert_eq!(tag(&nodes[1]), "H4");
+        assert!(text(&nodes[2]).contains(r#""Pages: "]},"24""#));
+        assert_eq!(tag(&nodes[5]), "Hr");
+        assert!(text(&nodes[6]).contains("https://files.catbox.moe/1.jpg"));
+        // the stream holds the selected pages
+        let nodes = created(Some(true), Some("3-4")).await;
+        assert!(text(&nodes[2]).contains(r#""Pages: "]},"2 of 24""#));
+        for with_header in [None, Some(false)] {
+            let nodes = created(with_header, None).await;
+            assert!(!nodes.iter().any(|n| text(n).contains("Doujinshi")));
+            assert!(text(&nodes[0]).contains("https://files.catbox.moe/0.jpg"));
+        }
+
+        // the first part of a split gallery too, its link to the next part is below
+        let options = UploadOptions {
+            include_metadata: Some(true),
+            force: true,
+            ..Default::default()
+        };
+        let stream = TestStream {
+            range: 0..PAGE_NODE_LIMIT + 10,
+            loaded: Arc::new(AtomicUsize::new(0)),
+        };
+        let pages = sync
+            .sync_stream(meta.clone(), stream, options)
+            .await
+            .unwrap();
+        assert_eq!(pages.len(), 2);
+        let requests = server.requests();
+        let edit = requests
+            .iter()
+            .find(|r| r.header("x-forwarded-for").unwrap().ends_with("/editPage"))
+            .unwrap();
+        let content = url::form_urlencoded::parse(&edit.body)
+            .into_owned()
+            .collect::<HashMap<_, _>>()
+            .remove("content")
+            .unwrap();
+        let nodes = serde_json::from_str::<Vec<Node>>(&content).unwrap();
+        assert!(text(&nodes[0]).contains(r#""src":"https://files.catbox.moe/0.jpg""#));
+        assert_eq!(tag(&nodes[1]), "H4");
+        let links = nodes.iter().position(|n| text(n).contains("Next part"));
+        assert!(links.unwrap() > PAGE_NODE_LIMIT / 2);
+
+        assert_eq!(with_cover(header.clone(), []).len(), header.len());
+        assert!(with_cover(Vec::new(), [nt!("a"), nt!("b")])
+            .iter()
+            .map(text)
+            .eq([r#""a""#, r#""b""#]));
+    }
+
+    #[tokio::test]
+    async fn test_footer() {
+        let captured = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let footer = page_footer(Some("Archived by @bot"), captured);
+        let text = |node: &Node| serde_json::to_string(node).unwrap();
+        assert_eq!(footer.len(), 2);
+        assert!(text(&footer[0]).contains("Captured on 2023-11-14 22:13 UTC"));
+        assert!(text(&footer[1]).contains("Archived by @bot"));
+        assert_eq!(page_footer(None, captured).len(), 1);
+
+        // the last nodes of the pages after the attribution, only if enabled
+        let server = MockServer::start(telegraph_response).await;
+        let sync = synchronizer(&server, SimpleMemStorage::default())
+            .with_footer(None, Some("Archived by @bot"));
+        let contents = |include_footer: Option<bool>| {
+            let sync = &sync;
+            let server = &server;
+            async move {
+                let options = UploadOptions {
+                    include_footer,
+                    force: true,
+                    ..Default::default()
+                };
+                let stream = TestStream {
+                    range: 0..2,
+                    loaded: Arc::new(AtomicUsize::new(0)),
+                };
+                sync.sync_stream(album("https://e-hentai.org/g/1/x"), stream, options)
+                    .await
+                    .unwrap();
+                let requests = server.requests();
+                let content = url::form_urlencoded::parse(&requests.last().unwrap().body)
+                    .into_owned()
+                    .collect::<HashMap<_, _>>()
+                    .remove("content")
+                    .unwrap();
+                serde_json::from_str::<Vec<Node>>(&content).unwrap()
+            }
+        };
+        let content = contents(Some(true)).await;
+        let last = content.iter().rev().take(4).map(text).collect::<Vec<_>>();
+        assert!(last[0].contains("Archived by @bot"), "{last:?}");
+        assert!(last[1].contains("Captured on "));
+        assert!(last[2].contains(r#""href":"https://e-hentai.org/g/1/x""#));
+        assert!(last[3].contains("Generated by "));
+        for include_footer in [None, Some(false)] {
+            let content = contents(include_footer).await;
+            let last = content.iter().rev().take(2).map(text).collect::<Vec<_>>();
+            assert!(last[0].contains("https://e-hentai.org/g/1/x"), "{last:?}");
+            assert!(last[1].contains("Generated by "));
+            assert!(!content.iter().map(text).any(|t| t.contains("Captured on ")));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dry_run() {
+        let server = MockServer::start(telegraph_response).await;
+        let cache = SimpleMemStorage::<String>::default();
+        let sync = synchronizer(&server, cache.clone());
+        let loaded = Arc::new(AtomicUsize::new(0));
+        let stream = TestStream {
+            range: 0..12,
+            loaded: loaded.clone(),
+        };
+        let report = sync
+            .dry_run_stream(album("https://e-hentai.org/g/1/x"), stream)
+            .await;
+        assert_eq!(
+            report,
+            DryRunReport {
+                name: "title".to_string(),
+                pages: 12,
+                total_bytes: (0..12).map(|i| fake_image(i).len()).sum(),
+                formats: [(ImageKind::Jpeg, 12)].into_iter().collect(),
+                oversized: vec![],
+                failed: vec![],
+            }
+        );
+        assert_eq!(loaded.load(Ordering::SeqCst), 12);
+        // neither uploaded nor cached
+        assert!(server.requests().is_empty());
+        assert!(cache.get("eh|/g/1/x").await.unwrap().is_none());
+
+        // oversized ones are re-encoded like a sync does
+        let img = image::RgbImage::from_pixel(1400, 1400, image::Rgb([200, 0, 0]));
+        let mut bmp = std::io::Cursor::new(Vec::new());
+        img.write_to(&mut bmp, image::ImageFormat::Bmp).unwrap();
+        let bmp = ImageData::from(bmp.into_inner());
+        let broken = ImageData::from([fake_image(0), vec![0; MAX_SINGLE_FILE_SIZE]].concat());
+        let (bmp_len, broken_len) = (bmp.len(), broken.len());
+        let stream = ImageStream(vec![bmp, broken].into_iter());
+        let report = sync
+            .dry_run_stream(album("https://e-hentai.org/g/1/x"), stream)
+            .await;
+        let [bmp, broken] = &report.oversized[..] else {
+            panic!("{:?}", report.oversized);
+        };
+        assert_eq!(bmp.size, bmp_len);
+        assert!(matches!(bmp.prepared[..], [Ok(size)] if size < MAX_SINGLE_FILE_SIZE));
+        assert_eq!(broken.size, broken_len);
+        assert!(
+            matches!(&broken.prepared[..], [Err(e)] if e.starts_with("Re-encode failed")),
+            "{broken:?}"
+        );
+    }
+
+    /// nhentai galleries of 2 pages, reached through the proxy like telegraph.
+    fn nhentai_response(idx: usize, req: &crate::mock_server::MockRequest) -> MockResponse {
+        let body = r#"{"media_id": "987", "title": {"pretty": "Title"}, "images": {"pages": [{"t": "j"}, {"t": "j"}]}}"#;
+        let target = req.header("x-forwarded-for").unwrap_or_default();
+        match target.split_once("/galleries/987/") {
+            _ if target.starts_with("https://nhentai.net/api/gallery/") => {
+                MockResponse::new(200, body)
+            }
+            Some((_, file)) => {
+                let page = file.trim_end_matches(".jpg");
+                MockResponse::new(200, fake_image(page))
+            }
+            None => telegraph_response(idx, req),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let server = MockServer::start(nhentai_response).await;
+        let cache = SimpleMemStorage::<String>::default();
+        let sync = synchronizer(&server, cache.clone());
+        let stream = TestStream {
+            range: 0..3,
+            loaded: Arc::new(AtomicUsize::new(0)),
+        };
+        sync.sync_stream(
+            album("https://e-hentai.org/g/1/x"),
+            stream,
+            Default::default(),
+        )
+        .await
+        .unwrap();
+        cache
+            .set(
+                "nhentai|/g/1".to_string(),
+                "https://telegra.ph/1".to_string(),
+                None,
+            )
+            .await
+            .unwrap();
+        sync.sync::<NHCollector>("/g/1/".to_string()).await.unwrap();
+        // rejected before sending any request
+        sync.sync::<EHCollector>("/bad".to_string())
+            .await
+            .unwrap_err();
+
+        let metrics = sync.metrics();
+        assert_eq!(metrics.images_uploaded, 3);
+        let bytes: u64 = (0..3).map(|i| fake_image(i).len() as u64).sum();
+        assert_eq!(metrics.bytes_downloaded, bytes);
+        assert_eq!(metrics.cache_hits, 1);
+        assert_eq!(metrics.galleries_failed, 1);
+        assert_eq!(metrics.galleries_synced, 0);
+        assert_eq!(metrics.average_sync_time(), None);
+
+        // a gallery synced from its collector
+        sync.sync::<NHCollector>("/g/2/".to_string()).await.unwrap();
+        let metrics = sync.metrics();
+        assert_eq!(metrics.galleries_synced, 1);
+        assert_eq!(metrics.galleries_failed, 1);
+        assert_eq!(metrics.cache_hits, 1);
+        assert_eq!(metrics.images_uploaded, 5);
+        let pages: u64 = (1..=2).map(|i| fake_image(i).len() as u64).sum();
+        assert_eq!(metrics.bytes_downloaded, bytes + pages);
+        assert_eq!(metrics.average_sync_time(), Some(metrics.sync_time));
+        // and the cache hit of it
+        sync.sync::<NHCollector>("/g/2".to_string()).await.unwrap();
+        assert_eq!(sync.metrics().cache_hits, 2);
+        assert_eq!(sync.metrics().galleries_synced, 1);
+    }
+
+    #[tokio::test]
+    async fn test_single_flight() {
+        let server = MockServer::start(nhentai_response).await;
+        let sync = synchronizer(&server, SimpleMemStorage::default());
+
+        let (a, b) = tokio::join!(
+            sync.sync::<NHCollector>("/g/1/".to_string()),
+            sync.sync::<NHCollector>("/g/1".to_string())
+        );
+        let links = a.unwrap();
+        assert_eq!(links, b.unwrap());
+        let targets = server
+            .requests()
+            .iter()
+            .map(|r| r.header("x-forwarded-for").unwrap_or_default().to_string())
+            .collect::<Vec<_>>();
+        let count = |suffix: &str| targets.iter().filter(|t| t.ends_with(suffix)).count();
+        assert_eq!(count("/api/gallery/1"), 1, "{targets:#?}");
+        assert_eq!(count("/createPage"), 1, "{targets:#?}");
+        assert_eq!(sync.metrics().galleries_synced, 1);
+
+        // a cache hit gets the stored meta
+        let meta = MetaSlot::default();
+        let options = UploadOptions {
+            meta: Some(meta.clone()),
+            ..Default::default()
+        };
+        let cached = sync.sync_with_options::<NHCollector>("/g/1".to_string(), options);
+        assert_eq!(cached.await.unwrap(), links);
+        assert_eq!(meta.get().unwrap().page_count, Some(2));
+        assert!(meta.fetched().is_none());
+
+        // a waiter gets the progress and meta, and is not stopped by the first one
+        let options = |cancel: CancellationToken| {
+            let (progress, rx) = ProgressReporter::channel();
+            let meta = MetaSlot::default();
+            let options = UploadOptions {
+                progress: Some(progress),
+                cancel: Some(cancel),
+                meta: Some(meta.clone()),
+                ..Default::default()
+            };
+            (options, rx, meta)
+        };
+        let cancel = CancellationToken::new();
+        let (first, _, _) = options(cancel.clone());
+        let (second, progress, meta) = options(CancellationToken::new());
+        let (a, b, _) = tokio::join!(
+            sync.sync_with_options::<NHCollector>("/g/2".to_string(), first),
+            sync.sync_with_options::<NHCollector>("/g/2".to_string(), second),
+            async { cancel.cancel() },
+        );
+        assert!(matches!(a, Err(SyncError::Cancelled)));
+        assert_eq!(b.unwrap().len(), 1);
+        assert_eq!(meta.fetched().unwrap().name, "Title");
+        assert!(progress.borrow().is_some());
+
+        // the upload is cancelled once all of them are
+        let cancel = CancellationToken::new();
+        let (first, _, _) = options(cancel.clone());
+        let (second, _, _) = options(cancel.clone());
+        let (a, b, _) = tokio::join!(
+            sync.sync_with_options::<NHCollector>("/g/3".to_string(), first),
+            sync.sync_with_options::<NHCollector>("/g/3".to_string(), second),
+            async { cancel.cancel() },
+        );
+        assert!(matches!(a, Err(SyncError::Cancelled)));
+        assert!(matches!(b, Err(SyncError::Cancelled)));
+        assert_eq!(sync.metrics().galleries_synced, 2);
+    }
+
+    #[tokio::test]
+    async fn test_shared_client() {
+        let body = r#"{"media_id": "987", "title": {"pretty": "Title"}, "images": {"pages": []}}"#;
+        let server = MockServer::start(move |idx, req| {
+            match req.header("x-forwarded-for").unwrap_or_default() {
+                "https://nhentai.net/api/gallery/1" => MockResponse::new(200, body),
+                _ => telegraph_response(idx, req),
+            }
+        })
+        .await;
+        let proxy = ProxiedClient::new(&server.url("/"), "token").unwrap();
+        let tg = Telegraph::<TokenPool>::new(vec!["token".to_string()]).with_proxy(proxy.clone());
+        let registry = Registry::default().with_proxy(proxy.clone());
+
+        let collector: &NHCollector = registry.get();
+        collector.fetch("/g/1".to_string()).await.unwrap();
+        tg.create_page(&PageCreate {
+            title: "t".to_string(),
+            content: vec![],
+            author_name: None,
+            author_url: None,
+        })
+        .await
+        .unwrap();
+        // both went through the one client
+        assert_eq!(server.requests().len(), 2);
+        assert_eq!(proxy.metrics().total_requests, 2);
+    }
+
+    #[test]
+    fn test_classify() {
+        let fetch =
+            |e: CollectorError| SyncError::classify(anyhow::Error::from(e).context("fetch"));
+        assert!(matches!(
+            fetch(CollectorError::NotFound),
+            SyncError::NotFound
+        ));
+        assert!(matches!(
+            fetch(CollectorError::RequiresAuth),
+            SyncError::RequiresAuth
+        ));
+        assert!(matches!(
+            fetch(CollectorError::Request(ProxyError::CircuitOpen)),
+            SyncError::Proxy(_)
+        ));
+        assert!(matches!(
+            fetch(CollectorError::Status(reqwest::StatusCode::BAD_GATEWAY)),
+            SyncError::Collector(_)
+        ));
+        // known errors are kept
+        let e = anyhow::Error::from(SyncError::TooLarge(SizeLimit::Pages(1))).context("sync");
+        assert!(matches!(
+            SyncError::classify(e),
+            SyncError::TooLarge(SizeLimit::Pages(1))
+        ));
+
+        let upload = |e: UploadError<anyhow::Error>| SyncError::from(e);
+        assert!(matches!(
+            upload(UploadError::Reqwest(TelegraphError::Server)),
+            SyncError::Telegraph(_)
+        ));
+        assert!(matches!(
+            upload(UploadError::Cancelled),
+            SyncError::Cancelled
+        ));
+        assert!(matches!(
+            upload(UploadError::TooLarge(SizeLimit::Bytes(1))),
+            SyncError::TooLarge(SizeLimit::Bytes(1))
+        ));
+        let e = upload(UploadError::Stream(
+            CollectorError::Request(ProxyError::CircuitOpen).into(),
+        ));
+        assert!(matches!(e, SyncError::Proxy(_)));
+
+        // the original error is kept and printed once
+        assert!(e
+            .cause()
+            .unwrap()
+            .chain()
+            .any(|e| matches!(e.downcast_ref(), Some(ProxyError::CircuitOpen))));
+        assert_eq!(
+            format!("{:#}", anyhow::Error::from(e.clone())),
+            e.to_string()
+        );
+        assert_eq!(SyncError::Cancelled.to_string(), "sync cancelled");
+    }
+}
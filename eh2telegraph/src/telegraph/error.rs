@@ -1,5 +1,7 @@
 // Partly borrowed from https://github.com/Aloxaf/telegraph-rs/blob/master/src/error.rs
 
+use std::time::Duration;
+
 use serde::Deserialize;
 
 use super::types::MediaInfo;
@@ -12,6 +14,17 @@ pub enum TelegraphError {
     Reqwest(#[from] reqwest::Error),
     #[error("unexpected server result")]
     Server,
+    #[error("flood wait for {0:?}")]
+    FloodWait(Duration),
+}
+
+/// Parse `FLOOD_WAIT_<seconds>` returned by Telegraph.
+pub(crate) fn flood_wait(error: &str) -> Option<Duration> {
+    error
+        .strip_prefix("FLOOD_WAIT_")?
+        .parse()
+        .ok()
+        .map(Duration::from_secs)
 }
 
 #[derive(Debug, Deserialize)]
@@ -25,7 +38,10 @@ impl<T> From<ApiResult<T>> for Result<T, TelegraphError> {
     fn from(r: ApiResult<T>) -> Self {
         match r {
             ApiResult::Ok { result: v } => Ok(v),
-            ApiResult::Err { error: e, .. } => Err(TelegraphError::Api(e)),
+            ApiResult::Err { error: e, .. } => match flood_wait(&e) {
+                Some(wait) => Err(TelegraphError::FloodWait(wait)),
+                None => Err(TelegraphError::Api(e)),
+            },
         }
     }
 }
@@ -3,25 +3,38 @@ pub use error::TelegraphError;
 #[macro_use]
 pub mod types;
 pub const MAX_SINGLE_FILE_SIZE: usize = 5 * 1024 * 1024;
+pub const DEFAULT_UPLOAD_CONCURRENCY: usize = 4;
+pub const DEFAULT_API_BASE: &str = "https://api.telegra.ph";
 
 mod error;
 
-use std::{borrow::Cow, sync::Arc};
+use std::{
+    borrow::Cow,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
+use futures::{StreamExt, TryStreamExt};
 use reqwest::{
     multipart::{Form, Part},
-    Client, Response,
+    Client, RequestBuilder, Response, StatusCode,
 };
-use serde::Serialize;
+use serde::{de::DeserializeOwned, Serialize};
 
-use crate::http_client::HttpRequestBuilder;
+use crate::{
+    http_client::{without_decompression, HttpRequestBuilder},
+    http_proxy::{retry_after, RetryPolicy},
+    sniff::ImageKind,
+};
 
 use self::{
-    error::{ApiResult, UploadResult},
-    types::{MediaInfo, Node, Page, PageCreate, PageEdit},
+    error::ApiResult,
+    types::{MediaInfo, Page, PageCreate, PageEdit},
 };
 
-const TITLE_LENGTH_MAX: usize = 200;
+/// In characters.
+pub const TITLE_LENGTH_MAX: usize = 200;
 
 #[derive(Debug, Clone)]
 pub struct Telegraph<T, C = Client> {
@@ -29,6 +42,12 @@ pub struct Telegraph<T, C = Client> {
     client: C,
     // access token
     access_token: T,
+    // max files uploaded at the same time
+    upload_concurrency: usize,
+    // retry on flood wait
+    retry: RetryPolicy,
+    // without the trailing slash
+    api_base: Arc<str>,
 }
 
 pub trait AccessToken {
@@ -36,14 +55,13 @@ pub trait AccessToken {
     fn select_token(&self, _path: &str) -> &str {
         Self::token(self)
     }
+    /// Called when Telegraph asks the account of `token` to wait before the next call.
+    fn cool_down(&self, _token: &str, _wait: Duration) {}
 }
 
 #[derive(Debug, Clone)]
 pub struct SingleAccessToken(pub Arc<String>);
 
-#[derive(Debug, Clone)]
-pub struct RandomAccessToken(pub Arc<Vec<String>>);
-
 impl AccessToken for SingleAccessToken {
     fn token(&self) -> &str {
         &self.0
@@ -56,38 +74,90 @@ impl From<String> for SingleAccessToken {
     }
 }
 
-impl AccessToken for RandomAccessToken {
+/// Tokens of several accounts, so the flood limit of one does not slow down the
+/// others. The least used token is taken, skipping the ones asked to wait unless all
+/// of them are.
+#[derive(Debug, Clone)]
+pub struct TokenPool {
+    tokens: Arc<Vec<String>>,
+    state: Arc<Mutex<Vec<TokenState>>>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct TokenState {
+    uses: u64,
+    cooling_until: Option<Instant>,
+}
+
+/// The former random choice of tokens, kept for compatibility.
+#[deprecated(note = "use `TokenPool`")]
+pub type RandomAccessToken = TokenPool;
+
+impl TokenPool {
+    /// Times each token has been taken, in the order of the tokens.
+    pub fn usage(&self) -> Vec<u64> {
+        let state = self.state.lock().unwrap();
+        state.iter().map(|s| s.uses).collect()
+    }
+}
+
+impl AccessToken for TokenPool {
     fn token(&self) -> &str {
-        use rand::prelude::SliceRandom;
-        self.0
-            .choose(&mut rand::thread_rng())
-            .expect("token list must contains at least one element")
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        let idx = (0..state.len())
+            .min_by_key(|&i| {
+                let cooling = state[i].cooling_until.filter(|until| *until > now);
+                (cooling, state[i].uses)
+            })
+            .expect("token list must contains at least one element");
+        state[idx].uses += 1;
+        &self.tokens[idx]
+    }
+
+    fn cool_down(&self, token: &str, wait: Duration) {
+        let Some(idx) = self.tokens.iter().position(|t| t == token) else {
+            return;
+        };
+        let until = Instant::now() + wait;
+        let mut state = self.state.lock().unwrap();
+        let cooling = &mut state[idx].cooling_until;
+        *cooling = (*cooling).max(Some(until));
+        tracing::info!("[telegraph] token {idx} cooling down for {wait:?}");
     }
 }
 
-impl From<String> for RandomAccessToken {
+impl From<String> for TokenPool {
     fn from(s: String) -> Self {
-        Self(Arc::new(vec![s]))
+        vec![s].into()
     }
 }
 
-impl From<Vec<String>> for RandomAccessToken {
+impl From<Vec<String>> for TokenPool {
     fn from(ts: Vec<String>) -> Self {
         assert!(!ts.is_empty());
-        Self(Arc::new(ts))
+        Self {
+            state: Arc::new(Mutex::new(vec![TokenState::default(); ts.len()])),
+            tokens: Arc::new(ts),
+        }
     }
 }
 
-macro_rules! execute {
-    ($send: expr) => {
-        $send
-            .send()
-            .await
-            .and_then(Response::error_for_status)?
-            .json::<ApiResult<_>>()
-            .await?
-            .into()
-    };
+/// One token taken from `T`, which is still told about the flood waits of it.
+#[derive(Debug, Clone)]
+pub struct PinnedAccessToken<T> {
+    token: Arc<String>,
+    from: T,
+}
+
+impl<T: AccessToken> AccessToken for PinnedAccessToken<T> {
+    fn token(&self) -> &str {
+        &self.token
+    }
+
+    fn cool_down(&self, token: &str, wait: Duration) {
+        self.from.cool_down(token, wait);
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, derive_more::From, derive_more::Into)]
@@ -99,8 +169,13 @@ impl<T> Telegraph<T, Client> {
         AT: Into<T>,
     {
         Telegraph {
-            client: Client::new(),
+            client: without_decompression(Client::builder())
+                .build()
+                .expect("build reqwest client failed"),
             access_token: access_token.into(),
+            upload_concurrency: DEFAULT_UPLOAD_CONCURRENCY,
+            retry: RetryPolicy::default(),
+            api_base: DEFAULT_API_BASE.into(),
         }
     }
 }
@@ -110,6 +185,53 @@ impl<T, C> Telegraph<T, C> {
         Telegraph {
             client: proxy,
             access_token: self.access_token,
+            upload_concurrency: self.upload_concurrency,
+            retry: self.retry,
+            api_base: self.api_base,
+        }
+    }
+
+    pub fn with_upload_concurrency(mut self, concurrency: usize) -> Self {
+        self.upload_concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Policy used when Telegraph asks to slow down. Only `max_attempts`, `base_delay`
+    /// and `jitter` are used, the wait time given by Telegraph takes precedence.
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry = policy;
+        self
+    }
+
+    /// Send the API calls to a telegra.ph compatible service or a mirror instead.
+    /// Uploads are not affected.
+    pub fn with_api_base(mut self, base: &str) -> Self {
+        self.api_base = base.trim_end_matches('/').into();
+        self
+    }
+
+    fn api_url(&self, method: &str) -> String {
+        format!("{}/{method}", self.api_base)
+    }
+}
+
+impl<T, C> Telegraph<T, C>
+where
+    T: AccessToken + Clone,
+    C: Clone,
+{
+    /// Pick one token and use it for every request, so pages created
+    /// with the returned client can be edited later.
+    pub fn pinned(&self) -> Telegraph<PinnedAccessToken<T>, C> {
+        Telegraph {
+            client: self.client.clone(),
+            access_token: PinnedAccessToken {
+                token: Arc::new(self.access_token.token().to_string()),
+                from: self.access_token.clone(),
+            },
+            upload_concurrency: self.upload_concurrency,
+            retry: self.retry.clone(),
+            api_base: self.api_base.clone(),
         }
     }
 }
@@ -151,8 +273,9 @@ where
             .collect::<String>();
         let content =
             serde_json::to_string(&page.content).expect("unable to content serialize json");
+        let access_token = self.access_token.token();
         let to_post = PagePostWithToken {
-            access_token: self.access_token.token(),
+            access_token,
             page: &PageCreateShadow {
                 title: &title,
                 content: &content,
@@ -160,10 +283,14 @@ where
                 author_url: &page.author_url,
             },
         };
-        execute!(self
-            .client
-            .post_builder("https://api.telegra.ph/createPage")
-            .form(&to_post))
+        self.with_flood_wait(Some(access_token), || {
+            self.send_api(
+                self.client
+                    .post_builder(&self.api_url("createPage"))
+                    .form(&to_post),
+            )
+        })
+        .await
     }
 
     /// Edit page.
@@ -175,7 +302,7 @@ where
             /// Path to the page.
             pub path: &'a str,
             /// Content of the page.
-            pub content: &'a Vec<Node>,
+            pub content: &'a str,
 
             /// Optional. Name of the author, displayed below the title.
             #[serde(skip_serializing_if = "Option::is_none")]
@@ -198,20 +325,28 @@ where
             .chars()
             .take(TITLE_LENGTH_MAX)
             .collect::<String>();
+        // form can not encode nested values
+        let content =
+            serde_json::to_string(&page.content).expect("unable to content serialize json");
+        let access_token = self.access_token.select_token(&page.path);
         let to_post = PageEditWithToken {
-            access_token: self.access_token.select_token(&page.path),
+            access_token,
             page: &PageEditShadow {
                 title: &title,
                 path: &page.path,
-                content: &page.content,
+                content: &content,
                 author_name: &page.author_name,
                 author_url: &page.author_url,
             },
         };
-        execute!(self
-            .client
-            .post_builder("https://api.telegra.ph/editPage")
-            .form(&to_post))
+        self.with_flood_wait(Some(access_token), || {
+            self.send_api(
+                self.client
+                    .post_builder(&self.api_url("editPage"))
+                    .form(&to_post),
+            )
+        })
+        .await
     }
 
     /// Get page.
@@ -229,54 +364,178 @@ where
             path,
             return_content: Some(true),
         };
-        execute!(self
-            .client
-            .post_builder("https://api.telegra.ph/getPage")
-            .form(&to_post))
+        self.with_flood_wait(None, || {
+            self.send_api(
+                self.client
+                    .post_builder(&self.api_url("getPage"))
+                    .form(&to_post),
+            )
+        })
+        .await
     }
 
-    /// Upload file.
-    /// If the result is Ok, it's length must eq to files'.
+    /// Upload files, at most `upload_concurrency` at the same time.
+    /// If the result is Ok, it's length must eq to files' and the order is kept.
     pub async fn upload<IT, I>(&self, files: IT) -> Result<Vec<MediaInfo>, TelegraphError>
     where
         IT: IntoIterator<Item = I>,
         I: Into<Cow<'static, [u8]>>,
     {
-        let mut results = Vec::new();
-
-        for data in files.into_iter() {
-            let form = Form::new()
-                .text("reqtype", "fileupload")
-                .text("userhash", "") // Empty string for anonymous upload
-                .part("fileToUpload", Part::bytes(data).file_name("image.jpg"));
-
-            let response = self
-                .client
-                .post_builder("https://catbox.moe/user/api.php")
-                .multipart(form)
-                .send()
-                .await
-                .and_then(Response::error_for_status)?;
-
-            let url = response.text().await?;
-
-            // catbox.moe returns just the URL as plain text
-            if url.starts_with("https://files.catbox.moe/") {
-                results.push(MediaInfo { src: url });
-            } else {
-                return Err(TelegraphError::Server);
+        // create the futures upfront, a closure inside the stream breaks Send inference
+        let uploads = files
+            .into_iter()
+            .enumerate()
+            .map(|(idx, data)| self.upload_indexed(idx, data.into()))
+            .collect::<Vec<_>>();
+        let mut results = futures::stream::iter(uploads)
+            .buffer_unordered(self.upload_concurrency)
+            .try_collect::<Vec<_>>()
+            .await?;
+        results.sort_unstable_by_key(|(idx, _)| *idx);
+        Ok(results.into_iter().map(|(_, m)| m).collect())
+    }
+
+    async fn upload_indexed(
+        &self,
+        idx: usize,
+        data: Cow<'static, [u8]>,
+    ) -> Result<(usize, MediaInfo), TelegraphError> {
+        self.upload_one(data).await.map(|m| (idx, m))
+    }
+
+    async fn upload_one(&self, data: Cow<'static, [u8]>) -> Result<MediaInfo, TelegraphError> {
+        self.with_flood_wait(None, || self.upload_once(data.clone()))
+            .await
+    }
+
+    async fn upload_once(&self, data: Cow<'static, [u8]>) -> Result<MediaInfo, TelegraphError> {
+        // the host trusts the file name, so it must match the content
+        let kind = ImageKind::sniff(&data);
+        let part = Part::bytes(data)
+            .file_name(format!("image.{}", kind.extension()))
+            .mime_str(kind.mime())?;
+        let form = Form::new()
+            .text("reqtype", "fileupload")
+            .text("userhash", "") // Empty string for anonymous upload
+            .part("fileToUpload", part);
+
+        let response = self
+            .client
+            .send_request(
+                self.client
+                    .post_builder("https://catbox.moe/user/api.php")
+                    .multipart(form),
+            )
+            .await?;
+        // the image host is not the Telegraph API, only its Retry-After is honoured
+        let url = check_retry_after(response)?
+            .error_for_status()?
+            .text()
+            .await?;
+
+        // catbox.moe returns just the URL as plain text
+        if url.starts_with("https://files.catbox.moe/") {
+            Ok(MediaInfo { src: url })
+        } else {
+            Err(TelegraphError::Server)
+        }
+    }
+
+    async fn send_api<R: DeserializeOwned>(
+        &self,
+        req: RequestBuilder,
+    ) -> Result<R, TelegraphError> {
+        let response = self.client.send_request(req).await?;
+        check_flood_wait(response)
+            .await?
+            .error_for_status()?
+            .json::<ApiResult<R>>()
+            .await?
+            .into()
+    }
+
+    /// Call `f` again after the wait time when it fails with `TelegraphError::FloodWait`,
+    /// so callers resume from the failed request. The wait of `token` is told to the
+    /// access token, so others are preferred meanwhile.
+    async fn with_flood_wait<F, Fut, R>(
+        &self,
+        token: Option<&str>,
+        f: F,
+    ) -> Result<R, TelegraphError>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<R, TelegraphError>>,
+    {
+        let mut retry = 0;
+        loop {
+            match f().await {
+                Err(TelegraphError::FloodWait(wait)) => {
+                    if let Some(token) = token {
+                        self.access_token.cool_down(token, wait);
+                    }
+                    if retry + 1 >= self.retry.max_attempts {
+                        return Err(TelegraphError::FloodWait(wait));
+                    }
+                    let jitter =
+                        rand::Rng::gen_range(&mut rand::thread_rng(), 0.0..=self.retry.jitter);
+                    let delay = wait.max(self.retry.base_delay).mul_f64(1.0 + jitter);
+                    tracing::warn!("[telegraph] flood wait, retry after {delay:?}");
+                    tokio::time::sleep(delay).await;
+                    retry += 1;
+                }
+                r => return r,
             }
         }
+    }
+}
 
-        Ok(results)
+/// Turn a 429 response with `Retry-After` into `TelegraphError::FloodWait`.
+fn check_retry_after(response: Response) -> Result<Response, TelegraphError> {
+    if response.status() != StatusCode::TOO_MANY_REQUESTS {
+        return Ok(response);
+    }
+    match retry_after(&response) {
+        Some(wait) => Err(TelegraphError::FloodWait(wait)),
+        None => Ok(response),
+    }
+}
+
+/// Turn a 429 response of the API into `TelegraphError::FloodWait`, the wait time is
+/// taken from `Retry-After` or a `FLOOD_WAIT_<seconds>` error in the body. Without
+/// either it fails with the status, so only the flood waits are retried.
+async fn check_flood_wait(response: Response) -> Result<Response, TelegraphError> {
+    let response = check_retry_after(response)?;
+    if response.status() != StatusCode::TOO_MANY_REQUESTS {
+        return Ok(response);
+    }
+    let status = response
+        .error_for_status_ref()
+        .expect_err("429 is an error status");
+    let body = response.text().await.unwrap_or_default();
+    let wait = serde_json::from_str::<ApiResult<serde_json::Value>>(&body)
+        .ok()
+        .and_then(|r| match r {
+            ApiResult::Err { error } => error::flood_wait(&error),
+            ApiResult::Ok { .. } => None,
+        });
+    match wait {
+        Some(wait) => Err(TelegraphError::FloodWait(wait)),
+        None => Err(status.into()),
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::time::Duration;
+
+    use crate::{
+        http_proxy::ProxiedClient,
+        mock_server::{MockResponse, MockServer},
+    };
+
     use crate::telegraph::{
         types::{Node, PageCreate},
-        SingleAccessToken, Telegraph,
+        AccessToken, SingleAccessToken, Telegraph, TelegraphError, TokenPool,
     };
 
     use super::types::{NodeElement, NodeElementAttr, Tag};
@@ -284,6 +543,211 @@ mod tests {
     pub const TELEGRAPH_TOKEN: &str =
         "f42d3570f95412b59b08d64450049e4d609b1f2a57657fce6ce8acc908aa";
 
+    #[tokio::test]
+    async fn test_flood_wait() {
+        let server = MockServer::start(|idx, req| {
+            let target = req.header("x-forwarded-for").unwrap_or_default();
+            match idx {
+                0 => MockResponse::new(429, r#"{"ok":false,"error":"FLOOD_WAIT_0"}"#),
+                5 => MockResponse::new(429, ""),
+                _ if target.ends_with("/createPage") => MockResponse::new(
+                    200,
+                    r#"{"ok":true,"result":{"path":"p","url":"https://telegra.ph/p","title":"t","description":"","views":0}}"#,
+                ),
+                2 => MockResponse::new(429, "").header("Retry-After", "0"),
+                _ => MockResponse::new(200, format!("https://files.catbox.moe/{idx}.jpg")),
+            }
+        })
+        .await;
+        let proxy = ProxiedClient::new(&server.url("/"), "token").unwrap();
+        let telegraph = Telegraph::<SingleAccessToken>::new(TELEGRAPH_TOKEN.to_string())
+            .with_proxy(proxy)
+            .with_upload_concurrency(1)
+            .with_retry_policy(crate::http_proxy::RetryPolicy::new(
+                3,
+                Duration::from_millis(10),
+            ));
+
+        let page = telegraph
+            .create_page(&PageCreate {
+                title: "t".to_string(),
+                content: vec![],
+                author_name: None,
+                author_url: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(page.url, "https://telegra.ph/p");
+        assert_eq!(server.requests().len(), 2);
+
+        // the failed image is uploaded again, and the following ones go on
+        let files = (0..2).map(|i| vec![i]);
+        let medium = telegraph.upload(files).await.unwrap();
+        let srcs = medium.into_iter().map(|m| m.src).collect::<Vec<_>>();
+        assert_eq!(
+            srcs,
+            [
+                "https://files.catbox.moe/3.jpg",
+                "https://files.catbox.moe/4.jpg"
+            ]
+        );
+        assert_eq!(server.requests().len(), 5);
+
+        // without a wait time it is not a flood wait, so it is not retried
+        let page = PageCreate {
+            title: "t".to_string(),
+            content: vec![],
+            author_name: None,
+            author_url: None,
+        };
+        let err = telegraph.create_page(&page).await.unwrap_err();
+        assert!(matches!(err, TelegraphError::Reqwest(_)), "{err:?}");
+        assert_eq!(server.requests().len(), 6);
+    }
+
+    #[test]
+    fn test_token_pool() {
+        let pool = TokenPool::from(vec!["a".to_string(), "b".to_string(), "c".to_string()]);
+        let taken = (0..6).map(|_| pool.token().to_string()).collect::<Vec<_>>();
+        assert_eq!(taken, ["a", "b", "c", "a", "b", "c"]);
+        assert_eq!(pool.usage(), [2, 2, 2]);
+
+        // skipped while cooling down, the sooner one is taken if all are
+        pool.cool_down("a", Duration::from_secs(60));
+        assert_eq!([pool.token(), pool.token()], ["b", "c"]);
+        pool.cool_down("b", Duration::from_secs(30));
+        pool.cool_down("c", Duration::from_secs(90));
+        assert_eq!(pool.token(), "b");
+        pool.cool_down("unknown", Duration::from_secs(60));
+        assert_eq!(pool.usage(), [2, 4, 3]);
+
+        let pool = TokenPool::from("a".to_string());
+        pool.cool_down("a", Duration::ZERO);
+        assert_eq!(pool.token(), "a");
+    }
+
+    #[tokio::test]
+    async fn test_token_pool_flood_wait() {
+        let server = MockServer::start(|_, req| {
+            let body = String::from_utf8_lossy(&req.body);
+            match body.contains("access_token=a&") {
+                true => MockResponse::new(429, r#"{"ok":false,"error":"FLOOD_WAIT_60"}"#),
+                false => MockResponse::new(
+                    200,
+                    r#"{"ok":true,"result":{"path":"p","url":"https://telegra.ph/p","title":"t","description":"","views":0}}"#,
+                ),
+            }
+        })
+        .await;
+        let page = PageCreate {
+            title: "t".to_string(),
+            content: vec![],
+            author_name: None,
+            author_url: None,
+        };
+        let proxy = ProxiedClient::new(&server.url("/"), "token").unwrap();
+        let telegraph = Telegraph::<TokenPool>::new(vec!["a".to_string(), "b".to_string()])
+            .with_proxy(proxy)
+            .with_retry_policy(crate::http_proxy::RetryPolicy::new(
+                1,
+                Duration::from_millis(10),
+            ));
+
+        // galleries are spread over the tokens, until one is asked to wait
+        let first = telegraph.pinned();
+        let second = telegraph.pinned();
+        assert!(matches!(
+            first.create_page(&page).await,
+            Err(super::TelegraphError::FloodWait(_))
+        ));
+        second.create_page(&page).await.unwrap();
+        for _ in 0..3 {
+            telegraph.pinned().create_page(&page).await.unwrap();
+        }
+        let tokens = server
+            .requests()
+            .iter()
+            .map(|r| {
+                let body = String::from_utf8_lossy(&r.body).into_owned();
+                body.split('&').next().unwrap().to_string()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            tokens,
+            [
+                "access_token=a",
+                "access_token=b",
+                "access_token=b",
+                "access_token=b",
+                "access_token=b"
+            ]
+        );
+        assert_eq!(telegraph.access_token.usage(), [1, 4]);
+    }
+
+    #[tokio::test]
+    async fn test_api_base() {
+        let server = MockServer::start(|_, _| {
+            MockResponse::new(
+                200,
+                r#"{"ok":true,"result":{"path":"p","url":"https://telegra.example.com/p","title":"t","description":"","views":0}}"#,
+            )
+        })
+        .await;
+        let page = PageCreate {
+            title: "t".to_string(),
+            content: vec![],
+            author_name: None,
+            author_url: None,
+        };
+        let telegraph = Telegraph::<SingleAccessToken>::new(TELEGRAPH_TOKEN.to_string())
+            .with_api_base(&server.url("/api/"));
+        let created = telegraph.create_page(&page).await.unwrap();
+        assert_eq!(created.url, "https://telegra.example.com/p");
+        assert_eq!(server.requests()[0].path, "/api/createPage");
+
+        // kept by the pinned client and through the proxy
+        let proxy = ProxiedClient::new(&server.url("/"), "token").unwrap();
+        let telegraph = telegraph.with_proxy(proxy).pinned();
+        telegraph.create_page(&page).await.unwrap();
+        assert_eq!(
+            server.requests()[1].header("x-forwarded-for"),
+            Some(server.url("/api/createPage").as_str())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_upload_keeps_order() {
+        // earlier images finish later
+        let server = MockServer::start(|_, req| {
+            let body = String::from_utf8_lossy(&req.body);
+            let idx: u64 = body
+                .split("image-")
+                .nth(1)
+                .and_then(|s| s.split(';').next())
+                .unwrap()
+                .parse()
+                .unwrap();
+            MockResponse::new(200, format!("https://files.catbox.moe/{idx}.jpg"))
+                .delay(Duration::from_millis(100 - idx * 10))
+        })
+        .await;
+        let proxy = ProxiedClient::new(&server.url("/"), "token").unwrap();
+        let telegraph = Telegraph::<SingleAccessToken>::new(TELEGRAPH_TOKEN.to_string())
+            .with_proxy(proxy)
+            .with_upload_concurrency(3);
+        let files = (0..10).map(|i| format!("image-{i};").into_bytes());
+        let medium = telegraph.upload(files).await.unwrap();
+        let srcs = medium.into_iter().map(|m| m.src).collect::<Vec<_>>();
+        let expected = (0..10)
+            .map(|i| format!("https://files.catbox.moe/{i}.jpg"))
+            .collect::<Vec<_>>();
+        assert_eq!(srcs, expected);
+        assert_eq!(server.requests().len(), 10);
+        assert!(server.max_in_flight() <= 3);
+        assert!(server.max_in_flight() > 1);
+    }
+
     #[ignore]
     #[tokio::test]
     async fn demo_create_page() {
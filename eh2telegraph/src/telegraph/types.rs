@@ -73,7 +73,7 @@ pub struct PageEdit {
 }
 
 /// This object represents a page on Telegraph.
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Page {
     /// Path to the page.
     pub path: String,
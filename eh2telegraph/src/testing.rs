@@ -0,0 +1,102 @@
+//! Test helpers for code built on [`HttpFetcher`].
+
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use parking_lot::Mutex;
+use reqwest::Method;
+
+use crate::http_client::{FetchResponse, HttpFetcher};
+
+/// A request received by [`MockFetcher`].
+#[derive(Debug, Clone)]
+pub struct FetchedRequest {
+    pub method: Method,
+    pub url: String,
+    pub body: Option<Bytes>,
+}
+
+/// Returns canned responses keyed by url pattern.
+/// A pattern matches when the url contains it, the first matching route wins.
+/// Requests matching no route fail.
+#[derive(Debug, Default)]
+pub struct MockFetcher {
+    routes: Vec<(String, FetchResponse)>,
+    requests: Mutex<Vec<FetchedRequest>>,
+}
+
+impl MockFetcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_response(mut self, pattern: &str, response: FetchResponse) -> Self {
+        self.routes.push((pattern.to_string(), response));
+        self
+    }
+
+    pub fn with_body(self, pattern: &str, status: u16, body: impl Into<Bytes>) -> Self {
+        self.with_response(pattern, FetchResponse::new(status, body))
+    }
+
+    /// Requests received so far, in order.
+    pub fn requests(&self) -> Vec<FetchedRequest> {
+        self.requests.lock().clone()
+    }
+}
+
+impl HttpFetcher for MockFetcher {
+    fn request<'a>(
+        &'a self,
+        method: Method,
+        url: &'a str,
+        body: Option<Bytes>,
+    ) -> BoxFuture<'a, anyhow::Result<FetchResponse>> {
+        self.requests.lock().push(FetchedRequest {
+            method,
+            url: url.to_string(),
+            body,
+        });
+        let resp = match self.routes.iter().find(|(p, _)| url.contains(p.as_str())) {
+            Some((_, resp)) => Ok(resp.clone()),
+            None => Err(anyhow::anyhow!("no mock response for {url}")),
+        };
+        Box::pin(std::future::ready(resp))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_mock_fetcher_routes() {
+        let fetcher = MockFetcher::new()
+            .with_body("/api/gallery/1", 200, r#"{"id":1}"#)
+            .with_body("/api/", 404, "not found");
+
+        let resp = fetcher
+            .get("https://nhentai.net/api/gallery/1")
+            .await
+            .unwrap();
+        assert_eq!(resp.status, 200);
+        assert_eq!(resp.json::<serde_json::Value>().unwrap()["id"], 1);
+
+        let resp = fetcher
+            .get("https://nhentai.net/api/gallery/2")
+            .await
+            .unwrap();
+        assert_eq!(resp.status, 404);
+        assert!(resp.error_for_status().is_err());
+
+        assert!(fetcher.get("https://example.com/").await.is_err());
+
+        fetcher
+            .post("https://nhentai.net/api/", Bytes::from_static(b"q"))
+            .await
+            .unwrap();
+        let requests = fetcher.requests();
+        assert_eq!(requests.len(), 4);
+        assert_eq!(requests[3].method, Method::POST);
+        assert_eq!(requests[3].body.as_deref(), Some(&b"q"[..]));
+    }
+}
@@ -0,0 +1,115 @@
+//! Titles of the created pages from a template like `{title} [{artist}] ({pages}p)`.
+//!
+//! Placeholders:
+//! - `{title}`, `{japanese_title}`: names of the gallery
+//! - `{artist}`: authors joined by `, `
+//! - `{category}`, `{language}`
+//! - `{pages}`: images uploaded, which follows the page selection
+//!
+//! Missing fields and unknown placeholders are left blank.
+
+use crate::{collector::AlbumMeta, telegraph::TITLE_LENGTH_MAX};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TitleTemplate(String);
+
+impl TitleTemplate {
+    pub fn new<S: Into<String>>(template: S) -> Self {
+        Self(template.into())
+    }
+
+    /// Fill the template, falling back to the gallery name if it renders empty.
+    pub fn render(&self, meta: &AlbumMeta, pages: usize) -> String {
+        let mut title = String::with_capacity(self.0.len() + meta.name.len());
+        let mut rest = self.0.as_str();
+        while let Some(start) = rest.find('{') {
+            // an unclosed brace is kept as is
+            let Some(len) = rest[start..].find('}') else {
+                break;
+            };
+            title.push_str(&rest[..start]);
+            title.push_str(&field(meta, pages, &rest[start + 1..start + len]));
+            rest = &rest[start + len + 1..];
+        }
+        title.push_str(rest);
+        let title = title.trim();
+        if title.is_empty() {
+            return default_title(meta);
+        }
+        truncate_title(&title.replace('|', ""), TITLE_LENGTH_MAX)
+    }
+}
+
+/// The title without a template.
+pub fn default_title(meta: &AlbumMeta) -> String {
+    truncate_title(&meta.name.replace('|', ""), TITLE_LENGTH_MAX)
+}
+
+/// Cut to at most `max` characters, the limit of Telegraph counts characters.
+pub fn truncate_title(title: &str, max: usize) -> String {
+    match title.char_indices().nth(max) {
+        Some((idx, _)) => title[..idx].trim_end().to_string(),
+        None => title.to_string(),
+    }
+}
+
+fn field(meta: &AlbumMeta, pages: usize, name: &str) -> String {
+    let value = match name.trim() {
+        "title" => Some(meta.name.clone()),
+        "japanese_title" => meta.japanese_name.clone(),
+        "artist" => meta.authors.as_ref().map(|a| a.join(", ")),
+        "category" => meta.class.clone(),
+        "language" => meta.language.clone(),
+        "pages" => Some(pages.to_string()),
+        _ => None,
+    };
+    value.unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meta() -> AlbumMeta {
+        AlbumMeta {
+            link: "https://e-hentai.org/g/1/x".to_string(),
+            name: "Some Title".to_string(),
+            japanese_name: None,
+            class: Some("Doujinshi".to_string()),
+            description: None,
+            authors: Some(vec!["alice".to_string(), "bob".to_string()]),
+            language: None,
+            tags: None,
+            page_count: Some(30),
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let template = TitleTemplate::new("{title} [{artist}] ({pages}p)");
+        assert_eq!(
+            template.render(&meta(), 24),
+            "Some Title [alice, bob] (24p)"
+        );
+        // missing fields and unknown placeholders are blank
+        let template = TitleTemplate::new("{japanese_title}{title} {language}{unknown}");
+        assert_eq!(template.render(&meta(), 24), "Some Title");
+        let template = TitleTemplate::new("{category}: {title} {oops");
+        assert_eq!(template.render(&meta(), 1), "Doujinshi: Some Title {oops");
+        // nothing left falls back to the name
+        let template = TitleTemplate::new("{language} ");
+        assert_eq!(template.render(&meta(), 1), "Some Title");
+    }
+
+    #[test]
+    fn test_truncate() {
+        let mut long = meta();
+        long.name = "長".repeat(300);
+        let title = TitleTemplate::new("{title} ({pages}p)").render(&long, 1);
+        assert_eq!(title.chars().count(), TITLE_LENGTH_MAX);
+        assert!(title.chars().all(|c| c == '長'));
+        assert_eq!(default_title(&long), title);
+        assert_eq!(truncate_title("ab cd", 3), "ab");
+        assert_eq!(truncate_title("abc", 3), "abc");
+    }
+}
@@ -1,11 +1,12 @@
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
 use regex::Regex;
-use reqwest::Response;
+use reqwest::{header::HeaderMap, Response};
+use url::Url;
 
-use crate::http_client::HttpRequestBuilder;
+use crate::{collector::hosts::Hosts, http_client::HttpRequestBuilder, http_proxy};
 
 #[inline]
-pub fn match_first_group<'a>(regexp: &'a Regex, content: &'a str) -> Option<&'a str> {
+pub fn match_first_group<'a>(regexp: &Regex, content: &'a str) -> Option<&'a str> {
     regexp.captures(content).map(|c| {
         c.get(1)
             .expect("regexp is matched but no group 1 found")
@@ -13,24 +14,253 @@ pub fn match_first_group<'a>(regexp: &'a Regex, content: &'a str) -> Option<&'a
     })
 }
 
+/// Decode the character references of html text, like `&amp;` and `&#39;`.
+/// Unknown or malformed ones are kept as they are.
+pub fn unescape_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let decoded = rest[1..].find(';').and_then(|end| {
+            let name = &rest[1..end + 1];
+            let c = match name {
+                "amp" => '&',
+                "lt" => '<',
+                "gt" => '>',
+                "quot" => '"',
+                "apos" => '\'',
+                "nbsp" => '\u{a0}',
+                _ => {
+                    let code = match name.strip_prefix('#')? {
+                        n if n.starts_with(['x', 'X']) => u32::from_str_radix(&n[1..], 16),
+                        n => n.parse(),
+                    };
+                    char::from_u32(code.ok()?)?
+                }
+            };
+            Some((c, end + 2))
+        });
+        match decoded {
+            Some((c, len)) => {
+                out.push(c);
+                rest = &rest[len..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Read the body chunk by chunk, so the progress of large images is reported, see
+/// `http_proxy::with_download_progress`.
+async fn read_body(mut resp: Response) -> reqwest::Result<Bytes> {
+    let mut body = BytesMut::new();
+    while let Some(chunk) = resp.chunk().await? {
+        http_proxy::report_downloaded(chunk.len() as u64);
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body.freeze())
+}
+
 #[inline]
 pub async fn get_bytes<C: HttpRequestBuilder>(client: &C, link: &str) -> reqwest::Result<Bytes> {
-    client
-        .get_builder(link)
-        .send()
+    let resp = client
+        .send_request(client.get_builder(link))
         .await
-        .and_then(Response::error_for_status)?
-        .bytes()
+        .and_then(Response::error_for_status)?;
+    read_body(resp).await
+}
+
+#[inline]
+pub async fn get_bytes_with_headers<C: HttpRequestBuilder>(
+    client: &C,
+    link: &str,
+    headers: HeaderMap,
+) -> reqwest::Result<Bytes> {
+    let resp = client
+        .send_request(client.get_builder_with_headers(link, headers))
         .await
+        .and_then(Response::error_for_status)?;
+    read_body(resp).await
 }
 
 #[inline]
 pub async fn get_string<C: HttpRequestBuilder>(client: &C, link: &str) -> reqwest::Result<String> {
     client
-        .get_builder(link)
-        .send()
+        .send_request(client.get_builder(link))
         .await
         .and_then(Response::error_for_status)?
         .text()
         .await
 }
+
+// mirrors like `m.` and `www.` of the supported sites are reduced to their hosts
+const HITOMI_HOST: &str = "hitomi.la";
+const HOST_PREFIXES: [&str; 3] = ["www.", "m.", "g."];
+
+fn is_tracking_param(name: &str) -> bool {
+    name.starts_with("utm_")
+        || matches!(
+            name,
+            "fbclid" | "gclid" | "igshid" | "ref" | "ref_src" | "share" | "si"
+        )
+}
+
+/// The canonical form of a gallery url, so equivalent urls share the dedup and cache
+/// keys. Tracking params and fragments other than `#pages=` are removed, the host is
+/// normalized and the gallery path of e-hentai, exhentai and nhentai is reduced to
+/// `/g/{id}/{token}/` or `/g/{id}/`, where their query is only a view setting. The
+/// sites are the ones of `hosts`, see `Registry::hosts`.
+pub fn canonicalize_url(raw: &str, hosts: &Hosts) -> Result<Url, url::ParseError> {
+    let raw = raw.trim();
+    let mut url = match Url::parse(raw) {
+        Err(url::ParseError::RelativeUrlWithoutBase) if !raw.starts_with('/') => {
+            Url::parse(&format!("https://{raw}"))?
+        }
+        r => r?,
+    };
+    if url.scheme() == "http" {
+        let _ = url.set_scheme("https");
+    }
+
+    let host = url.host_str().unwrap_or_default();
+    let is_site = |h: &str| h == HITOMI_HOST || hosts.sites().iter().any(|(s, _)| s.contains(h));
+    let host = HOST_PREFIXES
+        .iter()
+        .filter_map(|p| host.strip_prefix(p))
+        .find(|h| is_site(h))
+        .unwrap_or(host)
+        .to_string();
+    url.set_host(Some(&host))?;
+    let with_token = hosts
+        .sites()
+        .into_iter()
+        .find_map(|(site, with_token)| site.contains(&host).then_some(with_token));
+
+    let segments: Vec<&str> = url
+        .path_segments()
+        .map(|s| s.filter(|s| !s.is_empty()).collect())
+        .unwrap_or_default();
+    let gallery_path = match (with_token, segments.as_slice()) {
+        (Some(true), ["g", id, token, ..]) => Some(format!("/g/{id}/{token}/")),
+        (Some(false), ["g", id, ..]) => Some(format!("/g/{id}/")),
+        _ => None,
+    };
+    match gallery_path {
+        Some(path) => {
+            url.set_path(&path);
+            url.set_query(None);
+        }
+        None => {
+            let query: Vec<(String, String)> = url
+                .query_pairs()
+                .filter(|(k, _)| !is_tracking_param(k))
+                .map(|(k, v)| (k.into_owned(), v.into_owned()))
+                .collect();
+            if query.is_empty() {
+                url.set_query(None);
+            } else {
+                url.query_pairs_mut().clear().extend_pairs(query);
+            }
+        }
+    }
+
+    if !url.fragment().is_some_and(|f| f.starts_with("pages=")) {
+        url.set_fragment(None);
+    }
+    Ok(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unescape_html() {
+        assert_eq!(
+            unescape_html("Tom &amp; Jerry&#39;s &quot;&lt;3&gt;&quot; &#x2665;"),
+            "Tom & Jerry's \"<3>\" \u{2665}"
+        );
+        assert_eq!(
+            unescape_html("a & b &unknown; &#xZZ; &"),
+            "a & b &unknown; &#xZZ; &"
+        );
+        assert_eq!(unescape_html("&amp;amp;"), "&amp;");
+    }
+
+    #[test]
+    fn test_canonicalize_url() {
+        let cases = [
+            (
+                "https://e-hentai.org/g/2127986/da1deffea5/",
+                [
+                    "https://e-hentai.org/g/2127986/da1deffea5",
+                    "http://e-hentai.org/g/2127986/da1deffea5/?p=2",
+                    "https://m.e-hentai.org/g/2127986/da1deffea5/0/",
+                    " https://g.e-hentai.org/g/2127986/da1deffea5/?utm_source=x#comments",
+                    "e-hentai.org/g/2127986/da1deffea5//",
+                ],
+            ),
+            (
+                "https://nhentai.net/g/177013/#pages=1-3",
+                [
+                    "https://nhentai.net/g/177013#pages=1-3",
+                    "https://www.nhentai.net/g/177013/?fbclid=abc#pages=1-3",
+                    "https://nhentai.net/g/177013/1/#pages=1-3",
+                    "HTTPS://NHENTAI.NET/g/177013/#pages=1-3",
+                    "http://nhentai.net/g/177013/?utm_medium=share#pages=1-3",
+                ],
+            ),
+            (
+                "https://hitomi.la/doujinshi/title-123.html?lang=en",
+                [
+                    "https://hitomi.la/doujinshi/title-123.html?lang=en&utm_source=x",
+                    "https://www.hitomi.la/doujinshi/title-123.html?si=1&lang=en",
+                    "http://hitomi.la/doujinshi/title-123.html?lang=en#1",
+                    "https://hitomi.la/doujinshi/title-123.html?ref=home&lang=en",
+                    "hitomi.la/doujinshi/title-123.html?lang=en",
+                ],
+            ),
+        ];
+        let defaults = Hosts::default();
+        let canonicalize_url = |raw| canonicalize_url(raw, &defaults);
+        for (canonical, variants) in cases {
+            assert_eq!(canonicalize_url(canonical).unwrap().as_str(), canonical);
+            for variant in variants {
+                assert_eq!(
+                    canonicalize_url(variant).unwrap().as_str(),
+                    canonical,
+                    "{variant}"
+                );
+            }
+        }
+        // other hosts are kept
+        assert_eq!(
+            canonicalize_url("https://m.example.com/a?utm_source=x")
+                .unwrap()
+                .as_str(),
+            "https://m.example.com/a"
+        );
+        assert!(canonicalize_url("/g/1/").is_err());
+
+        // configured hosts replace the defaults
+        let config = "exhentai:\n  hosts: [ex.example]";
+        let hosts = Hosts::from(
+            serde_yaml::from_str::<crate::collector::hosts::CollectorsConfig>(config).unwrap(),
+        );
+        let canonical = |raw| super::canonicalize_url(raw, &hosts).unwrap().to_string();
+        assert_eq!(
+            canonical("https://www.ex.example/g/1/abc/?p=2"),
+            "https://ex.example/g/1/abc/"
+        );
+        assert_eq!(
+            canonical("https://www.exhentai.org/g/1/abc/?p=2"),
+            "https://www.exhentai.org/g/1/abc/?p=2"
+        );
+    }
+}
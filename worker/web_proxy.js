@@ -51,7 +51,9 @@ async function handleRequest(request) {
     req.headers.delete("CF-Worker");
     req.headers.delete("CF-EW-Via");
 
-    // send request
+    // send request, and tell the client which status came from the target site
     var result = await fetch(req);
-    return result;
+    var response = new Response(result.body, result);
+    response.headers.set("X-Upstream-Status", result.status.toString());
+    return response;
 }
\ No newline at end of file